@@ -0,0 +1,22 @@
+create table if not exists auth.signing_keys (
+  id uuid primary key,
+  kid text not null,
+  algorithm text not null,
+  status text not null default 'next',
+  private_key text not null,
+  created_at timestamptz not null default now(),
+  activated_at timestamptz null,
+  retired_at timestamptz null,
+  revoked_at timestamptz null,
+  updated_at timestamptz not null default now(),
+  constraint "signing_key_kid_not_empty" check (char_length(trim(kid)) > 0),
+  constraint "signing_key_algorithm_check" check (algorithm in ('HS256', 'RS256', 'ES256', 'EdDSA')),
+  constraint "signing_key_status_check" check (status in ('next', 'active', 'retiring', 'revoked'))
+);
+
+comment on table auth.signing_keys is 'auth: JWT signing keyring. private_key is encrypted with MFA_ENCRYPTION_KEY.';
+create unique index if not exists signing_keys_kid_idx on auth.signing_keys (kid);
+create unique index if not exists signing_keys_single_active_idx on auth.signing_keys (status) where status = 'active';
+
+alter table auth.signing_keys enable row level security;
+
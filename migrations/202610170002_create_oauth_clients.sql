@@ -0,0 +1,33 @@
+create table if not exists auth.oauth_clients (
+  id uuid primary key,
+  client_id text not null,
+  client_secret_hash text null,
+  name text not null,
+  redirect_uris jsonb not null default '[]'::jsonb,
+  grant_types jsonb not null default '["authorization_code","refresh_token"]'::jsonb,
+  scopes jsonb not null default '["openid","email","profile"]'::jsonb,
+  token_endpoint_auth_method text not null default 'client_secret_basic',
+  created_at timestamptz null,
+  updated_at timestamptz null,
+  constraint "oauth_client_client_id_not_empty" check (char_length(trim(client_id)) > 0),
+  constraint "oauth_client_name_not_empty" check (char_length(trim(name)) > 0),
+  constraint "oauth_client_auth_method_check" check (token_endpoint_auth_method in ('client_secret_basic', 'client_secret_post', 'none')),
+  constraint "oauth_client_secret_matches_auth_method" check ((token_endpoint_auth_method = 'none') = (client_secret_hash is null))
+);
+
+comment on table auth.oauth_clients is 'auth: OAuth 2.0 / OpenID Connect client applications that sign users in through Haya.';
+create unique index if not exists oauth_clients_client_id_idx on auth.oauth_clients (client_id);
+
+alter table auth.oauth_clients enable row level security;
+
+alter table auth.flow_state
+  add column if not exists oauth_client_id text null references auth.oauth_clients (client_id) on delete cascade,
+  add column if not exists scope text null,
+  add column if not exists client_state text null,
+  add column if not exists session_id uuid null;
+
+alter table auth.sessions
+  add column if not exists oauth_client_id text null references auth.oauth_clients (client_id) on delete cascade,
+  add column if not exists scope text null;
+
+create index if not exists sessions_oauth_client_id_idx on auth.sessions (oauth_client_id);
@@ -0,0 +1,9 @@
+alter table auth.oauth_clients
+  add column if not exists role text null,
+  add column if not exists disabled_at timestamptz null;
+
+alter table auth.oauth_clients
+  add constraint "oauth_client_role_not_empty" check (role is null or char_length(trim(role)) > 0);
+
+comment on column auth.oauth_clients.role is 'auth: Postgres role placed in client_credentials access tokens.';
+comment on column auth.oauth_clients.disabled_at is 'auth: Disabled clients fail client authentication and their tokens stop introspecting as active.';
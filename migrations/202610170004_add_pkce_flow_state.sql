@@ -0,0 +1,4 @@
+alter table auth.flow_state
+  add column if not exists auth_code_issued_at timestamptz null;
+
+comment on column auth.flow_state.auth_code_issued_at is 'auth: When a PKCE flow handed out its auth code. Only issued codes can be exchanged with grant_type=pkce.';
@@ -0,0 +1,10 @@
+alter table auth.flow_state
+  add column if not exists user_code text null,
+  add column if not exists last_polled_at timestamptz null,
+  add column if not exists denied_at timestamptz null;
+
+create unique index if not exists flow_state_user_code_idx on auth.flow_state (user_code) where user_code is not null;
+
+comment on column auth.flow_state.user_code is 'auth: Short code the user enters to approve a device authorization request.';
+comment on column auth.flow_state.last_polled_at is 'auth: Last time the device polled the token endpoint; faster polling gets slow_down.';
+comment on column auth.flow_state.denied_at is 'auth: Set when the user denied a device authorization request.';
@@ -0,0 +1,9 @@
+alter table auth.oauth_clients
+  add column if not exists session_binding text null;
+
+alter table auth.oauth_clients
+  add constraint "oauth_client_session_binding_valid" check (
+    session_binding is null or session_binding in ('none', 'subnet', 'user_agent_family', 'strict')
+  );
+
+comment on column auth.oauth_clients.session_binding is 'auth: Session-binding policy for refreshes of this client''s sessions; null follows SESSION_BINDING_POLICY.';
@@ -0,0 +1,8 @@
+alter table auth.flow_state
+  add column if not exists webauthn_challenge text null;
+
+create unique index if not exists mfa_factors_web_authn_credential_id_idx on auth.mfa_factors ((web_authn_credential ->> 'id')) where web_authn_credential is not null;
+
+comment on column auth.flow_state.webauthn_challenge is 'auth: WebAuthn challenge a pending MFA login must sign with one of the user''s security keys.';
+comment on column auth.mfa_factors.web_authn_credential is 'auth: Verified WebAuthn credential: base64url id and COSE public key, signature counter and transports.';
+comment on column auth.mfa_factors.web_authn_session_data is 'auth: Challenge a WebAuthn factor was created with, until its registration is verified.';
@@ -0,0 +1,9 @@
+alter table auth.mfa_challenges
+  add column if not exists expires_at timestamptz null,
+  add column if not exists attempts integer not null default 0;
+
+create index if not exists mfa_challenges_factor_id_created_at_idx on auth.mfa_challenges (factor_id, created_at desc);
+
+comment on column auth.mfa_challenges.otp_code is 'auth: SHA-256 hash of the code sent to a phone factor.';
+comment on column auth.mfa_challenges.expires_at is 'auth: When the challenge can no longer be verified.';
+comment on column auth.mfa_challenges.attempts is 'auth: Failed verifications of the challenge so far.';
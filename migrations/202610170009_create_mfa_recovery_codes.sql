@@ -0,0 +1,15 @@
+create table if not exists auth.mfa_recovery_codes(
+  id uuid not null,
+  user_id uuid not null,
+  code_hash text not null,
+  created_at timestamptz not null,
+  used_at timestamptz null,
+  constraint mfa_recovery_codes_pkey primary key (id),
+  constraint mfa_recovery_codes_user_id_fkey foreign key (user_id) references auth.users(id) on delete cascade
+);
+
+create unique index if not exists mfa_recovery_codes_user_id_code_hash_idx on auth.mfa_recovery_codes (user_id, code_hash);
+
+comment on table auth.mfa_recovery_codes is 'auth: One-time codes that stand in for a TOTP code when the user has lost their authenticator.';
+comment on column auth.mfa_recovery_codes.code_hash is 'auth: SHA-256 hash of the normalized code.';
+comment on column auth.mfa_recovery_codes.used_at is 'auth: When the code was used; a used code cannot be used again.';
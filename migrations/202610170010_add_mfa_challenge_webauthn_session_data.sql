@@ -0,0 +1,4 @@
+alter table auth.mfa_challenges
+  add column if not exists web_authn_session_data jsonb null;
+
+comment on column auth.mfa_challenges.web_authn_session_data is 'auth: Challenge a WebAuthn factor must sign to answer this challenge.';
@@ -0,0 +1,18 @@
+alter table auth.mfa_factors
+  add column if not exists totp_algorithm text not null default 'SHA1',
+  add column if not exists totp_digits integer not null default 6,
+  add column if not exists totp_period integer not null default 30;
+
+alter table auth.mfa_factors drop constraint if exists mfa_factors_totp_algorithm_check;
+alter table auth.mfa_factors
+  add constraint mfa_factors_totp_algorithm_check check (totp_algorithm in ('SHA1', 'SHA256', 'SHA512'));
+alter table auth.mfa_factors drop constraint if exists mfa_factors_totp_digits_check;
+alter table auth.mfa_factors
+  add constraint mfa_factors_totp_digits_check check (totp_digits between 6 and 8);
+alter table auth.mfa_factors drop constraint if exists mfa_factors_totp_period_check;
+alter table auth.mfa_factors
+  add constraint mfa_factors_totp_period_check check (totp_period between 15 and 300);
+
+comment on column auth.mfa_factors.totp_algorithm is 'auth: HMAC algorithm of a TOTP factor, fixed at enrollment.';
+comment on column auth.mfa_factors.totp_digits is 'auth: Number of digits in a TOTP factor''s codes, fixed at enrollment.';
+comment on column auth.mfa_factors.totp_period is 'auth: Seconds each code of a TOTP factor is valid for, fixed at enrollment.';
@@ -0,0 +1,21 @@
+create table if not exists auth.mfa_trusted_devices(
+  id uuid not null,
+  user_id uuid not null,
+  factor_id uuid not null,
+  token_hash text not null,
+  user_agent text null,
+  ip inet null,
+  created_at timestamptz not null,
+  last_used_at timestamptz null,
+  expires_at timestamptz not null,
+  constraint mfa_trusted_devices_pkey primary key (id),
+  constraint mfa_trusted_devices_user_id_fkey foreign key (user_id) references auth.users(id) on delete cascade,
+  constraint mfa_trusted_devices_factor_id_fkey foreign key (factor_id) references auth.mfa_factors(id) on delete cascade
+);
+
+create unique index if not exists mfa_trusted_devices_token_hash_idx on auth.mfa_trusted_devices (token_hash);
+create index if not exists mfa_trusted_devices_user_id_idx on auth.mfa_trusted_devices (user_id);
+
+comment on table auth.mfa_trusted_devices is 'auth: Devices a user chose to remember after MFA, whose password sign-ins skip the MFA step until expires_at.';
+comment on column auth.mfa_trusted_devices.factor_id is 'auth: Factor verified when the device was trusted; deleting it revokes the device.';
+comment on column auth.mfa_trusted_devices.token_hash is 'auth: SHA-256 hash of the token held by the device.';
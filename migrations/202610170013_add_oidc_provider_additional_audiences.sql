@@ -0,0 +1,4 @@
+alter table auth.oidc_providers
+  add column if not exists additional_audiences jsonb not null default '[]'::jsonb;
+
+comment on column auth.oidc_providers.additional_audiences is 'auth: Audiences besides client_id accepted on ID tokens, such as native app bundle IDs.';
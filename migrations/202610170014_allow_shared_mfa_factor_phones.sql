@@ -0,0 +1,4 @@
+-- A phone number may back factors on several accounts, such as a shared
+-- household line; mfa_factors_unique_phone_factor_per_user_idx still keeps
+-- it to one factor per user.
+alter table auth.mfa_factors drop constraint if exists mfa_factors_phone_key;
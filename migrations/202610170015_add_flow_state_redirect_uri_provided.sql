@@ -0,0 +1,5 @@
+-- RFC 6749 section 4.1.3: a token request must repeat redirect_uri when the
+-- authorization request included one, but not when it fell back to the
+-- client's only registered URI.
+alter table auth.flow_state
+  add column if not exists redirect_uri_provided boolean not null default false;
@@ -0,0 +1,270 @@
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+pub const SESSION_BINDING_POLICIES: &[&str] = &["none", "subnet", "user_agent_family", "strict"];
+/// Audit event for a difference the policy tolerated
+pub const SOFT_MISMATCH_EVENT: &str = "session_binding_mismatch";
+/// Audit event for a difference that revoked the session
+pub const VIOLATION_EVENT: &str = "session_binding_violation";
+
+/// How closely a refresh request must match the IP and User-Agent a session
+/// was created from (env: `SESSION_BINDING_POLICY`, or per OAuth client).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionBindingPolicy {
+  /// Differences are only audited
+  None,
+  /// Same /24 (IPv4) or /48 (IPv6) network and the same User-Agent family
+  Subnet,
+  /// Same User-Agent family from any network
+  UserAgentFamily,
+  /// Same IP and the exact same User-Agent
+  Strict,
+}
+
+impl SessionBindingPolicy {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      SessionBindingPolicy::None => "none",
+      SessionBindingPolicy::Subnet => "subnet",
+      SessionBindingPolicy::UserAgentFamily => "user_agent_family",
+      SessionBindingPolicy::Strict => "strict",
+    }
+  }
+}
+
+impl FromStr for SessionBindingPolicy {
+  type Err = String;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value.trim().to_ascii_lowercase().as_str() {
+      "none" => Ok(SessionBindingPolicy::None),
+      "subnet" => Ok(SessionBindingPolicy::Subnet),
+      "user_agent_family" => Ok(SessionBindingPolicy::UserAgentFamily),
+      "strict" => Ok(SessionBindingPolicy::Strict),
+      other => Err(format!(
+        "unsupported session binding policy {other:?}; expected one of {}",
+        SESSION_BINDING_POLICIES.join(", ")
+      )),
+    }
+  }
+}
+
+/// Result of comparing a refresh request with its session. `soft` lists the
+/// differences the policy tolerated and `hard` the ones it did not, each as
+/// `ip` or `user_agent`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BindingCheck {
+  pub soft: Vec<&'static str>,
+  pub hard: Vec<&'static str>,
+}
+
+impl BindingCheck {
+  pub fn is_violation(&self) -> bool {
+    !self.hard.is_empty()
+  }
+}
+
+/// Compares the session's recorded IP and User-Agent with the current
+/// request. Sessions that recorded neither are never bound.
+pub fn check(
+  policy: SessionBindingPolicy,
+  session_ip: Option<&str>,
+  session_user_agent: Option<&str>,
+  client_ip: IpAddr,
+  user_agent: Option<&str>,
+) -> BindingCheck {
+  let mut result = BindingCheck::default();
+
+  if let Some(session_ip) = session_ip {
+    let session_ip = session_ip.parse::<IpAddr>().ok();
+    if session_ip.map(|ip| ip.to_canonical()) != Some(client_ip.to_canonical()) {
+      let tolerated = match policy {
+        SessionBindingPolicy::None | SessionBindingPolicy::UserAgentFamily => true,
+        SessionBindingPolicy::Subnet => session_ip.is_some_and(|ip| same_subnet(ip, client_ip)),
+        SessionBindingPolicy::Strict => false,
+      };
+      push(&mut result, "ip", tolerated);
+    }
+  }
+
+  if let Some(session_user_agent) = session_user_agent
+    && user_agent != Some(session_user_agent)
+  {
+    let tolerated = match policy {
+      SessionBindingPolicy::None => true,
+      SessionBindingPolicy::Subnet | SessionBindingPolicy::UserAgentFamily => {
+        user_agent.is_some_and(|value| user_agent_family(value) == user_agent_family(session_user_agent))
+      },
+      SessionBindingPolicy::Strict => false,
+    };
+    push(&mut result, "user_agent", tolerated);
+  }
+
+  result
+}
+
+fn push(result: &mut BindingCheck, kind: &'static str, tolerated: bool) {
+  if tolerated {
+    result.soft.push(kind);
+  } else {
+    result.hard.push(kind);
+  }
+}
+
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+  match (a.to_canonical(), b.to_canonical()) {
+    (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..3] == b.octets()[..3],
+    (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..3] == b.segments()[..3],
+    _ => false,
+  }
+}
+
+/// Browser or app name and operating system read from a User-Agent header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserAgentInfo {
+  pub browser: String,
+  pub os: String,
+}
+
+pub fn parse_user_agent(user_agent: &str) -> UserAgentInfo {
+  const BROWSERS: &[(&str, &str)] = &[
+    ("Edg/", "Edge"),
+    ("OPR/", "Opera"),
+    ("SamsungBrowser/", "Samsung Internet"),
+    ("Firefox/", "Firefox"),
+    ("FxiOS/", "Firefox"),
+    ("CriOS/", "Chrome"),
+    ("Chrome/", "Chrome"),
+    ("Version/", "Safari"),
+  ];
+  const PLATFORMS: &[(&str, &str)] = &[
+    ("Android", "Android"),
+    ("iPhone", "iOS"),
+    ("iPad", "iOS"),
+    ("CrOS", "ChromeOS"),
+    ("Windows", "Windows"),
+    ("Mac OS X", "macOS"),
+    ("Macintosh", "macOS"),
+    ("Linux", "Linux"),
+  ];
+
+  let browser = BROWSERS
+    .iter()
+    .find(|(marker, _)| user_agent.contains(marker))
+    .map(|(_, family)| (*family).to_string())
+    .unwrap_or_else(|| {
+      user_agent
+        .split(['/', ' '])
+        .next()
+        .unwrap_or_default()
+        .to_string()
+    });
+  let os = PLATFORMS
+    .iter()
+    .find(|(marker, _)| user_agent.contains(marker))
+    .map(|(_, platform)| *platform)
+    .unwrap_or("Other")
+    .to_string();
+  UserAgentInfo { browser, os }
+}
+
+/// Reduces a User-Agent to its browser or app name and operating system, so
+/// version upgrades do not count as a different client.
+pub fn user_agent_family(user_agent: &str) -> String {
+  let info = parse_user_agent(user_agent);
+  format!("{} ({})", info.browser, info.os)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CHROME_120: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+  const CHROME_121: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/121.0.0.0 Safari/537.36";
+  const FIREFOX: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:121.0) Gecko/20100101 Firefox/121.0";
+
+  fn ip(value: &str) -> IpAddr {
+    value.parse().unwrap()
+  }
+
+  #[test]
+  fn user_agent_family_ignores_versions() {
+    assert_eq!(user_agent_family(CHROME_120), "Chrome (Windows)");
+    assert_eq!(user_agent_family(CHROME_120), user_agent_family(CHROME_121));
+    assert_ne!(user_agent_family(CHROME_120), user_agent_family(FIREFOX));
+    assert_eq!(user_agent_family("okhttp/4.12.0"), "okhttp (Other)");
+  }
+
+  #[test]
+  fn strict_rejects_any_difference() {
+    let result = check(
+      SessionBindingPolicy::Strict,
+      Some("10.0.0.1"),
+      Some(CHROME_120),
+      ip("10.0.0.2"),
+      Some(CHROME_120),
+    );
+    assert_eq!(result.hard, ["ip"]);
+  }
+
+  #[test]
+  fn subnet_tolerates_moves_within_the_network() {
+    let nearby = check(
+      SessionBindingPolicy::Subnet,
+      Some("203.0.113.7"),
+      Some(CHROME_120),
+      ip("203.0.113.99"),
+      Some(CHROME_121),
+    );
+    assert_eq!(nearby.soft, ["ip", "user_agent"]);
+    assert!(!nearby.is_violation());
+
+    let far = check(
+      SessionBindingPolicy::Subnet,
+      Some("2001:db8:1::1"),
+      None,
+      ip("2001:db8:2::1"),
+      None,
+    );
+    assert_eq!(far.hard, ["ip"]);
+  }
+
+  #[test]
+  fn user_agent_family_policy_ignores_the_network() {
+    let result = check(
+      SessionBindingPolicy::UserAgentFamily,
+      Some("192.0.2.1"),
+      Some(CHROME_120),
+      ip("198.51.100.1"),
+      Some(FIREFOX),
+    );
+    assert_eq!(result.soft, ["ip"]);
+    assert_eq!(result.hard, ["user_agent"]);
+  }
+
+  #[test]
+  fn none_only_reports_differences() {
+    let result = check(
+      SessionBindingPolicy::None,
+      Some("192.0.2.1"),
+      Some(CHROME_120),
+      ip("198.51.100.1"),
+      None,
+    );
+    assert_eq!(result.soft, ["ip", "user_agent"]);
+    assert!(!result.is_violation());
+  }
+
+  #[test]
+  fn parses_policy_names() {
+    for name in SESSION_BINDING_POLICIES {
+      assert_eq!(name.parse::<SessionBindingPolicy>().unwrap().as_str(), *name);
+    }
+    assert!("loose".parse::<SessionBindingPolicy>().is_err());
+  }
+}
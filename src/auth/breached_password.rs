@@ -0,0 +1,308 @@
+//! Offline breached-password corpus. New passwords are rejected when their
+//! SHA-1 hash is in it.
+//!
+//! `BREACHED_PASSWORDS_PATH` points at either a HIBP-style text file, one
+//! `<SHA-1 hex>[:<count>]` line per password, or a bloom filter written by
+//! `haya passwords build-filter`. A text file is loaded whole into memory, so
+//! for the full HIBP corpus build a filter instead.
+
+use std::fs::File;
+use std::io::{
+  BufRead,
+  BufReader,
+  BufWriter,
+  Read,
+  Write,
+};
+use std::path::Path;
+
+use anyhow::{
+  Context as _,
+  bail,
+};
+use sha1::{
+  Digest,
+  Sha1,
+};
+
+type Sha1Hash = [u8; 20];
+
+const FILTER_MAGIC: &[u8; 8] = b"HAYABF01";
+const FILTER_HEADER_LEN: usize = FILTER_MAGIC.len() + 8 + 4 + 8;
+const MAX_FILTER_HASHES: u32 = 32;
+
+/// Passwords known from breaches.
+#[derive(Debug)]
+pub enum BreachedPasswords {
+  /// Sorted hashes from a text file
+  Exact(Vec<Sha1Hash>),
+  /// Bloom filter from `haya passwords build-filter`
+  Filter(BloomFilter),
+}
+
+impl BreachedPasswords {
+  /// Loads a text file or bloom filter, telling them apart by the filter's
+  /// magic bytes.
+  pub fn load(path: &Path) -> anyhow::Result<Self> {
+    let mut file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut magic = [0u8; FILTER_MAGIC.len()];
+    let is_filter = file.read(&mut magic)? == magic.len() && &magic == FILTER_MAGIC;
+    drop(file);
+
+    if is_filter {
+      let bytes = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+      let filter =
+        BloomFilter::from_bytes(bytes).with_context(|| format!("invalid filter {}", path.display()))?;
+      return Ok(BreachedPasswords::Filter(filter));
+    }
+
+    let mut hashes = Vec::new();
+    for_each_hash(path, 1, |hash| hashes.push(hash))?;
+    hashes.sort_unstable();
+    hashes.dedup();
+    Ok(BreachedPasswords::Exact(hashes))
+  }
+
+  pub fn contains(&self, password: &str) -> bool {
+    let hash: Sha1Hash = Sha1::digest(password.as_bytes()).into();
+    match self {
+      BreachedPasswords::Exact(hashes) => hashes.binary_search(&hash).is_ok(),
+      BreachedPasswords::Filter(filter) => filter.contains(&hash),
+    }
+  }
+
+  /// Number of passwords in the corpus.
+  pub fn len(&self) -> u64 {
+    match self {
+      BreachedPasswords::Exact(hashes) => hashes.len() as u64,
+      BreachedPasswords::Filter(filter) => filter.items,
+    }
+  }
+}
+
+/// Bloom filter over SHA-1 hashes. The hashes are already uniform, so the
+/// probe positions come straight from their bytes by double hashing.
+#[derive(Debug)]
+pub struct BloomFilter {
+  bits: Vec<u8>,
+  num_bits: u64,
+  num_hashes: u32,
+  items: u64,
+}
+
+impl BloomFilter {
+  /// Sizes a filter for `items` entries at the given false-positive rate.
+  pub fn with_capacity(items: u64, false_positive_rate: f64) -> anyhow::Result<Self> {
+    if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+      bail!("false positive rate must be between 0 and 1");
+    }
+    let items_f = items.max(1) as f64;
+    let ln2 = std::f64::consts::LN_2;
+    let num_bits = (-(items_f * false_positive_rate.ln()) / (ln2 * ln2))
+      .ceil()
+      .max(64.0) as u64;
+    let num_hashes = ((num_bits as f64 / items_f) * ln2)
+      .round()
+      .clamp(1.0, f64::from(MAX_FILTER_HASHES)) as u32;
+    let len = usize::try_from(num_bits.div_ceil(8)).context("filter is too large for this platform")?;
+    Ok(Self {
+      bits: vec![0; len],
+      num_bits,
+      num_hashes,
+      items: 0,
+    })
+  }
+
+  pub fn insert(&mut self, hash: &Sha1Hash) {
+    for bit in self.positions(hash) {
+      self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+    }
+    self.items += 1;
+  }
+
+  pub fn contains(&self, hash: &Sha1Hash) -> bool {
+    self
+      .positions(hash)
+      .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+  }
+
+  fn positions(&self, hash: &Sha1Hash) -> impl Iterator<Item = u64> + use<> {
+    let h1 = u64::from_le_bytes(hash[0..8].try_into().expect("8 bytes"));
+    let h2 = u64::from_le_bytes(hash[8..16].try_into().expect("8 bytes")) | 1;
+    let num_bits = self.num_bits;
+    (0..u64::from(self.num_hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+  }
+
+  pub fn write_to(&self, mut writer: impl Write) -> std::io::Result<()> {
+    writer.write_all(FILTER_MAGIC)?;
+    writer.write_all(&self.num_bits.to_le_bytes())?;
+    writer.write_all(&self.num_hashes.to_le_bytes())?;
+    writer.write_all(&self.items.to_le_bytes())?;
+    writer.write_all(&self.bits)?;
+    writer.flush()
+  }
+
+  pub fn from_bytes(mut bytes: Vec<u8>) -> anyhow::Result<Self> {
+    if bytes.len() < FILTER_HEADER_LEN || &bytes[..FILTER_MAGIC.len()] != FILTER_MAGIC {
+      bail!("not a haya password filter");
+    }
+    let mut offset = FILTER_MAGIC.len();
+    let num_bits = u64::from_le_bytes(bytes[offset..offset + 8].try_into()?);
+    offset += 8;
+    let num_hashes = u32::from_le_bytes(bytes[offset..offset + 4].try_into()?);
+    offset += 4;
+    let items = u64::from_le_bytes(bytes[offset..offset + 8].try_into()?);
+    if num_bits == 0 || num_hashes == 0 || num_hashes > MAX_FILTER_HASHES {
+      bail!("filter header is corrupt");
+    }
+    if (bytes.len() - FILTER_HEADER_LEN) as u64 != num_bits.div_ceil(8) {
+      bail!("filter is truncated");
+    }
+    bytes.drain(..FILTER_HEADER_LEN);
+    Ok(Self {
+      bits: bytes,
+      num_bits,
+      num_hashes,
+      items,
+    })
+  }
+}
+
+/// What `build_filter` wrote.
+#[derive(Debug, serde::Serialize)]
+pub struct FilterSummary {
+  pub items: u64,
+  pub bytes: u64,
+  pub hash_functions: u32,
+  pub false_positive_rate: f64,
+}
+
+/// Builds a bloom filter from a HIBP-style text file, skipping hashes seen
+/// fewer than `min_count` times. The input is streamed twice, once to size
+/// the filter and once to fill it, so it never has to fit in memory.
+pub fn build_filter(
+  input: &Path,
+  output: &Path,
+  false_positive_rate: f64,
+  min_count: u64,
+) -> anyhow::Result<FilterSummary> {
+  let mut items = 0u64;
+  for_each_hash(input, min_count, |_| items += 1)?;
+  let mut filter = BloomFilter::with_capacity(items, false_positive_rate)?;
+  for_each_hash(input, min_count, |hash| filter.insert(&hash))?;
+
+  let file = File::create(output).with_context(|| format!("failed to create {}", output.display()))?;
+  filter.write_to(BufWriter::new(file))?;
+  Ok(FilterSummary {
+    items: filter.items,
+    bytes: (FILTER_HEADER_LEN + filter.bits.len()) as u64,
+    hash_functions: filter.num_hashes,
+    false_positive_rate,
+  })
+}
+
+fn for_each_hash(path: &Path, min_count: u64, mut f: impl FnMut(Sha1Hash)) -> anyhow::Result<()> {
+  let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+  for (index, line) in BufReader::new(file).lines().enumerate() {
+    let line = line?;
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let (hash, count) = parse_line(line).with_context(|| format!("{}:{}", path.display(), index + 1))?;
+    if count >= min_count {
+      f(hash);
+    }
+  }
+  Ok(())
+}
+
+fn parse_line(line: &str) -> anyhow::Result<(Sha1Hash, u64)> {
+  let (hex, count) = match line.split_once(':') {
+    Some((hex, count)) => (hex, count.trim().parse().context("count is not a number")?),
+    None => (line, 1),
+  };
+  let hex = hex.trim();
+  if hex.len() != 40 || !hex.is_ascii() {
+    bail!("expected a 40-character SHA-1 hash");
+  }
+  let mut hash = [0u8; 20];
+  for (i, byte) in hash.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).context("hash is not hexadecimal")?;
+  }
+  Ok((hash, count))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn write_corpus(lines: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("haya-pwned-{}.txt", uuid::Uuid::new_v4()));
+    std::fs::write(&path, lines).unwrap();
+    path
+  }
+
+  fn sha1_hex(password: &str) -> String {
+    Sha1::digest(password.as_bytes())
+      .iter()
+      .map(|b| format!("{b:02X}"))
+      .collect()
+  }
+
+  #[test]
+  fn text_corpus_matches_listed_passwords() {
+    let path = write_corpus(&format!(
+      "{}:52579\n{}\n\n",
+      sha1_hex("password123"),
+      sha1_hex("letmein!").to_lowercase()
+    ));
+    let corpus = BreachedPasswords::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(corpus, BreachedPasswords::Exact(_)));
+    assert_eq!(corpus.len(), 2);
+    assert!(corpus.contains("password123"));
+    assert!(corpus.contains("letmein!"));
+    assert!(!corpus.contains("correct-horse-9"));
+  }
+
+  #[test]
+  fn built_filter_round_trips_and_honors_min_count() {
+    let input = write_corpus(&format!(
+      "{}:10\n{}:1\n",
+      sha1_hex("password123"),
+      sha1_hex("rarely-seen-1")
+    ));
+    let output = input.with_extension("bf");
+    let summary = build_filter(&input, &output, 0.001, 2).unwrap();
+    let corpus = BreachedPasswords::load(&output).unwrap();
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&output).unwrap();
+
+    assert_eq!(summary.items, 1);
+    assert!(matches!(corpus, BreachedPasswords::Filter(_)));
+    assert!(corpus.contains("password123"));
+    assert!(!corpus.contains("rarely-seen-1"));
+  }
+
+  #[test]
+  fn filter_false_positive_rate_is_near_target() {
+    let mut filter = BloomFilter::with_capacity(10_000, 0.01).unwrap();
+    for i in 0..10_000u32 {
+      filter.insert(&Sha1::digest(i.to_le_bytes()).into());
+    }
+    let false_positives = (10_000..110_000u32)
+      .filter(|i| filter.contains(&Sha1::digest(i.to_le_bytes()).into()))
+      .count();
+    assert!(false_positives < 2_000, "{false_positives} false positives");
+  }
+
+  #[test]
+  fn malformed_input_is_rejected() {
+    assert!(parse_line("not-a-hash").is_err());
+    assert!(parse_line(&"é".repeat(20)).is_err());
+    assert!(parse_line(&format!("{}:many", sha1_hex("x"))).is_err());
+    assert!(BloomFilter::from_bytes(b"HAYABF01".to_vec()).is_err());
+  }
+}
@@ -0,0 +1,412 @@
+use std::time::Duration;
+
+use anyhow::Context as _;
+use axum::http::StatusCode;
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD;
+use chrono::Utc;
+use hmac::{
+  Hmac,
+  Mac,
+};
+use serde::Serialize;
+use serde_json::{
+  Map,
+  Value,
+};
+use sha2::Sha256;
+use url::Url;
+use uuid::Uuid;
+
+use crate::auth::jwt;
+use crate::error::{
+  AuthError,
+  Result,
+};
+use crate::model::UserResponse;
+use crate::state::AppState;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const PG_FUNCTIONS_SCHEME: &str = "pg-functions";
+const WEBHOOK_SECRET_PREFIX: &str = "whsec_";
+const MIN_WEBHOOK_SECRET_BYTES: usize = 32;
+
+/// Claims the hook must hand back unchanged. Everything else, including
+/// `role`, `email` and the metadata objects, may be rewritten.
+pub const RESERVED_CLAIMS: &[&str] = &[
+  "iss",
+  "sub",
+  "aud",
+  "exp",
+  "iat",
+  "session_id",
+  "aal",
+  "amr",
+  "is_anonymous",
+  "client_id",
+  "scope",
+  "act",
+];
+
+/// Where the custom access-token hook (env: `ACCESS_TOKEN_HOOK_URI`) runs.
+#[derive(Clone)]
+pub enum HookTarget {
+  /// `pg-functions://postgres/<schema>/<function>`, called as `<schema>.<function>(event jsonb)`
+  PgFunction { schema: String, function: String },
+  /// An `http(s)://` endpoint receiving a Standard Webhooks signed POST
+  Http { url: Url, secret: Vec<u8> },
+}
+
+impl std::fmt::Debug for HookTarget {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      HookTarget::PgFunction { schema, function } => write!(f, "PgFunction({schema}.{function})"),
+      HookTarget::Http { url, .. } => write!(f, "Http({url})"),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessTokenHook {
+  pub target: HookTarget,
+  pub timeout: Duration,
+}
+
+impl AccessTokenHook {
+  pub fn new(uri: &str, secret: Option<&str>, timeout: Duration) -> anyhow::Result<Self> {
+    let url = Url::parse(uri).context("ACCESS_TOKEN_HOOK_URI is not a valid URI")?;
+    let target = match url.scheme() {
+      PG_FUNCTIONS_SCHEME => {
+        let segments: Vec<&str> = url
+          .path_segments()
+          .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+          .unwrap_or_default();
+        let [schema, function] = segments[..] else {
+          anyhow::bail!("ACCESS_TOKEN_HOOK_URI must look like pg-functions://postgres/<schema>/<function>");
+        };
+        if !is_identifier(schema) || !is_identifier(function) {
+          anyhow::bail!("ACCESS_TOKEN_HOOK_URI schema and function must be lowercase SQL identifiers");
+        }
+        HookTarget::PgFunction {
+          schema: schema.to_string(),
+          function: function.to_string(),
+        }
+      },
+      "http" | "https" => {
+        let secret = secret
+          .filter(|secret| !secret.trim().is_empty())
+          .context("ACCESS_TOKEN_HOOK_SECRET is required for HTTP hooks")?;
+        HookTarget::Http {
+          url,
+          secret: parse_webhook_secret(secret)?,
+        }
+      },
+      other => anyhow::bail!("unsupported ACCESS_TOKEN_HOOK_URI scheme {other}"),
+    };
+    Ok(Self { target, timeout })
+  }
+}
+
+fn is_identifier(value: &str) -> bool {
+  let mut chars = value.chars();
+  chars
+    .next()
+    .is_some_and(|first| first.is_ascii_lowercase() || first == '_')
+    && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Accepts a Standard Webhooks secret (`v1,whsec_<base64>` or `whsec_<base64>`)
+/// or a raw string of at least 32 bytes.
+fn parse_webhook_secret(secret: &str) -> anyhow::Result<Vec<u8>> {
+  let secret = secret.trim();
+  let secret = secret.strip_prefix("v1,").unwrap_or(secret);
+  let bytes = match secret.strip_prefix(WEBHOOK_SECRET_PREFIX) {
+    Some(encoded) => STANDARD
+      .decode(encoded)
+      .context("ACCESS_TOKEN_HOOK_SECRET is not valid base64")?,
+    None => secret.as_bytes().to_vec(),
+  };
+  if bytes.len() < MIN_WEBHOOK_SECRET_BYTES {
+    anyhow::bail!("ACCESS_TOKEN_HOOK_SECRET must be at least {MIN_WEBHOOK_SECRET_BYTES} bytes");
+  }
+  Ok(bytes)
+}
+
+/// The event passed to the hook, shaped like Supabase's `custom_access_token_hook` input.
+#[derive(Debug, Serialize)]
+pub struct AccessTokenHookEvent<'a> {
+  pub user_id: Uuid,
+  pub session_id: Uuid,
+  pub authentication_method: Option<&'a str>,
+  pub claims: &'a Map<String, Value>,
+  pub user: &'a UserResponse,
+}
+
+pub fn claims_map(claims: &jwt::Claims) -> Result<Map<String, Value>> {
+  match serde_json::to_value(claims) {
+    Ok(Value::Object(claims)) => Ok(claims),
+    Ok(_) => Err(AuthError::InternalError(
+      "access token claims are not an object".to_string(),
+    )),
+    Err(e) => Err(AuthError::InternalError(e.to_string())),
+  }
+}
+
+/// Runs the configured hook and returns the claims to sign.
+///
+/// The hook fails closed: a timeout, transport error, malformed response or a
+/// change to a reserved claim aborts token issuance with `hook_failed`. A hook
+/// can also refuse issuance by returning `{"error": {"http_code", "message"}}`.
+pub async fn customize_access_token(
+  state: &AppState,
+  hook: &AccessTokenHook,
+  event: &AccessTokenHookEvent<'_>,
+) -> Result<Map<String, Value>> {
+  let output = tokio::time::timeout(hook.timeout, invoke(state, hook, event))
+    .await
+    .map_err(|_| AuthError::HookFailed(format!("access token hook timed out after {:?}", hook.timeout)))??;
+  apply_hook_output(event.claims, output)
+}
+
+async fn invoke(state: &AppState, hook: &AccessTokenHook, event: &AccessTokenHookEvent<'_>) -> Result<Value> {
+  match &hook.target {
+    HookTarget::PgFunction { schema, function } => {
+      let mut tx = state.db.begin().await?;
+      // Also stop the function server-side once the hook times out.
+      sqlx::query("SELECT set_config('statement_timeout', $1, true)")
+        .bind(hook.timeout.as_millis().to_string())
+        .execute(&mut *tx)
+        .await?;
+      let output: Option<Value> =
+        sqlx::query_scalar(&format!(r#"SELECT "{schema}"."{function}"($1::jsonb)"#))
+          .bind(sqlx::types::Json(event))
+          .fetch_one(&mut *tx)
+          .await
+          .map_err(|e| AuthError::HookFailed(format!("access token hook {schema}.{function} failed: {e}")))?;
+      tx.commit().await?;
+      output
+        .ok_or_else(|| AuthError::HookFailed(format!("access token hook {schema}.{function} returned null")))
+    },
+    HookTarget::Http { url, secret } => {
+      let body = serde_json::to_vec(event).map_err(|e| AuthError::InternalError(e.to_string()))?;
+      let message_id = Uuid::new_v4().to_string();
+      let timestamp = Utc::now().timestamp();
+      let response = state
+        .http_client
+        .post(url.clone())
+        .header("content-type", "application/json")
+        .header("webhook-id", &message_id)
+        .header("webhook-timestamp", timestamp.to_string())
+        .header(
+          "webhook-signature",
+          sign_webhook(secret, &message_id, timestamp, &body),
+        )
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| AuthError::HookFailed(format!("access token hook request failed: {e}")))?;
+      let status = response.status();
+      let output = response.json::<Value>().await.map_err(|e| {
+        AuthError::HookFailed(format!("access token hook returned invalid JSON ({status}): {e}"))
+      })?;
+      if !status.is_success() && output.get("error").is_none() {
+        return Err(AuthError::HookFailed(format!(
+          "access token hook returned {status}"
+        )));
+      }
+      Ok(output)
+    },
+  }
+}
+
+/// `webhook-signature` header value per the Standard Webhooks spec.
+fn sign_webhook(secret: &[u8], message_id: &str, timestamp: i64, body: &[u8]) -> String {
+  let mut mac = <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+  mac.update(format!("{message_id}.{timestamp}.").as_bytes());
+  mac.update(body);
+  format!("v1,{}", STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+fn apply_hook_output(proposed: &Map<String, Value>, output: Value) -> Result<Map<String, Value>> {
+  if let Some(error) = output.get("error").filter(|error| !error.is_null()) {
+    let message = error
+      .get("message")
+      .and_then(Value::as_str)
+      .unwrap_or("Access token hook rejected the request")
+      .to_string();
+    let status = error
+      .get("http_code")
+      .and_then(Value::as_u64)
+      .and_then(|code| u16::try_from(code).ok())
+      .and_then(|code| StatusCode::from_u16(code).ok())
+      .filter(StatusCode::is_client_error)
+      .unwrap_or(StatusCode::FORBIDDEN);
+    return Err(AuthError::HookRejected { status, message });
+  }
+
+  let Some(Value::Object(claims)) = output.get("claims") else {
+    return Err(AuthError::HookFailed(
+      "access token hook response has no claims object".to_string(),
+    ));
+  };
+  if let Some(claim) = RESERVED_CLAIMS
+    .iter()
+    .find(|claim| proposed.get(**claim) != claims.get(**claim))
+  {
+    return Err(AuthError::HookFailed(format!(
+      "access token hook must not change the {claim} claim"
+    )));
+  }
+  if claims
+    .get("role")
+    .and_then(Value::as_str)
+    .is_none_or(str::is_empty)
+  {
+    return Err(AuthError::HookFailed(
+      "access token hook must return a non-empty role claim".to_string(),
+    ));
+  }
+  // Whatever the hook returns still has to decode as a Haya access token.
+  serde_json::from_value::<jwt::Claims>(Value::Object(claims.clone()))
+    .map_err(|e| AuthError::HookFailed(format!("access token hook returned invalid claims: {e}")))?;
+
+  Ok(claims.clone())
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  fn proposed_claims() -> Map<String, Value> {
+    let Value::Object(claims) = json!({
+      "sub": "00000000-0000-0000-0000-000000000001",
+      "aud": "authenticated",
+      "exp": 1_700_003_600,
+      "iat": 1_700_000_000,
+      "iss": "http://localhost:9999",
+      "email": "user@example.com",
+      "phone": null,
+      "role": "authenticated",
+      "aal": "aal1",
+      "amr": [{"method": "password", "timestamp": 1_700_000_000}],
+      "session_id": "00000000-0000-0000-0000-000000000002",
+      "is_anonymous": false,
+      "user_metadata": {},
+      "app_metadata": {},
+    }) else {
+      unreachable!()
+    };
+    claims
+  }
+
+  #[test]
+  fn hook_can_add_claims_and_change_role() {
+    let mut claims = proposed_claims();
+    claims.insert("role".to_string(), json!("editor"));
+    claims.insert("tenant_id".to_string(), json!("acme"));
+    claims.insert("permissions".to_string(), json!(["posts:write"]));
+
+    let output = apply_hook_output(&proposed_claims(), json!({ "claims": claims })).unwrap();
+
+    assert_eq!(output["tenant_id"], "acme");
+    assert_eq!(output["role"], "editor");
+  }
+
+  #[test]
+  fn hook_cannot_change_reserved_claims() {
+    for (claim, value) in [
+      ("sub", json!("someone-else")),
+      ("aal", json!("aal2")),
+      ("session_id", json!("00000000-0000-0000-0000-000000000003")),
+      ("client_id", json!("injected")),
+    ] {
+      let mut claims = proposed_claims();
+      claims.insert(claim.to_string(), value);
+
+      let error = apply_hook_output(&proposed_claims(), json!({ "claims": claims })).unwrap_err();
+      assert!(matches!(error, AuthError::HookFailed(message) if message.contains(claim)));
+    }
+
+    let mut claims = proposed_claims();
+    claims.remove("aud");
+    assert!(apply_hook_output(&proposed_claims(), json!({ "claims": claims })).is_err());
+  }
+
+  #[test]
+  fn hook_output_must_still_decode_as_access_token() {
+    let mut claims = proposed_claims();
+    claims.remove("user_metadata");
+    assert!(matches!(
+      apply_hook_output(&proposed_claims(), json!({ "claims": claims })),
+      Err(AuthError::HookFailed(_))
+    ));
+
+    let mut claims = proposed_claims();
+    claims.insert("role".to_string(), json!(""));
+    assert!(apply_hook_output(&proposed_claims(), json!({ "claims": claims })).is_err());
+
+    assert!(apply_hook_output(&proposed_claims(), json!({ "claims": null })).is_err());
+  }
+
+  #[test]
+  fn hook_error_object_rejects_issuance() {
+    let error = apply_hook_output(
+      &proposed_claims(),
+      json!({ "error": { "http_code": 402, "message": "subscription expired" } }),
+    )
+    .unwrap_err();
+    assert!(matches!(
+      error,
+      AuthError::HookRejected { status: StatusCode::PAYMENT_REQUIRED, ref message } if message == "subscription expired"
+    ));
+
+    let error = apply_hook_output(&proposed_claims(), json!({ "error": { "http_code": 500 } })).unwrap_err();
+    assert!(matches!(
+      error,
+      AuthError::HookRejected {
+        status: StatusCode::FORBIDDEN,
+        ..
+      }
+    ));
+  }
+
+  #[test]
+  fn parses_hook_uris() {
+    let timeout = Duration::from_secs(2);
+    let hook = AccessTokenHook::new(
+      "pg-functions://postgres/public/custom_access_token_hook",
+      None,
+      timeout,
+    )
+    .unwrap();
+    assert!(matches!(
+      hook.target,
+      HookTarget::PgFunction { ref schema, ref function } if schema == "public" && function == "custom_access_token_hook"
+    ));
+    assert!(AccessTokenHook::new("pg-functions://postgres/public", None, timeout).is_err());
+    assert!(AccessTokenHook::new("pg-functions://postgres/public/drop;table", None, timeout).is_err());
+
+    let secret = format!("v1,whsec_{}", STANDARD.encode([7u8; 32]));
+    let hook = AccessTokenHook::new("https://hooks.example.com/token", Some(&secret), timeout).unwrap();
+    assert!(matches!(hook.target, HookTarget::Http { ref secret, .. } if secret == &[7u8; 32]));
+    assert!(AccessTokenHook::new("https://hooks.example.com/token", None, timeout).is_err());
+    assert!(AccessTokenHook::new("https://hooks.example.com/token", Some("short"), timeout).is_err());
+    assert!(AccessTokenHook::new("ftp://hooks.example.com/token", Some(&secret), timeout).is_err());
+  }
+
+  #[test]
+  fn signs_webhooks_per_standard_webhooks() {
+    // Test vector from the Standard Webhooks reference implementation.
+    let secret = STANDARD.decode("MfKQ9r8GKYqrTwjUPD8ILPZIo2LaLaSw").unwrap();
+    let signature = sign_webhook(
+      &secret,
+      "msg_p5jXN8AQM9LWM0D4loKWxJek",
+      1_614_265_330,
+      br#"{"test": 2432232314}"#,
+    );
+    assert_eq!(signature, "v1,g0hM9SsE+OTPJTGt/tmIKtSyZlE3uFJELVlNIOLJ1OE=");
+  }
+}
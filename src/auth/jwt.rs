@@ -1,4 +1,25 @@
+use anyhow::{
+  Context,
+  bail,
+};
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use chrono::Utc;
+use jsonwebtoken::jwk::{
+  AlgorithmParameters,
+  CommonParameters,
+  EllipticCurve,
+  EllipticCurveKeyParameters,
+  EllipticCurveKeyType,
+  Jwk,
+  JwkSet,
+  KeyAlgorithm,
+  OctetKeyPairParameters,
+  OctetKeyPairType,
+  PublicKeyUse,
+  RSAKeyParameters,
+  RSAKeyType,
+};
 use jsonwebtoken::{
   Algorithm,
   DecodingKey,
@@ -7,12 +28,28 @@ use jsonwebtoken::{
   TokenData,
   Validation,
   decode,
+  decode_header,
   encode,
 };
+use p256::elliptic_curve::sec1::ToEncodedPoint as _;
+use rsa::pkcs1::{
+  DecodeRsaPrivateKey as _,
+  EncodeRsaPrivateKey as _,
+};
+use rsa::pkcs8::{
+  DecodePrivateKey as _,
+  EncodePrivateKey as _,
+};
+use rsa::traits::PublicKeyParts as _;
+use serde::de::DeserializeOwned;
 use serde::{
   Deserialize,
   Serialize,
 };
+use sha2::{
+  Digest,
+  Sha256,
+};
 use uuid::Uuid;
 
 use crate::error::AuthError;
@@ -43,6 +80,246 @@ pub struct Claims {
   pub is_anonymous: bool,
   pub user_metadata: serde_json::Value,
   pub app_metadata: serde_json::Value,
+  /// OAuth client the token was issued to through `/oauth/token`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub client_id: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub scope: Option<String>,
+  /// RFC 8693 actor: the admin acting as `sub` through an impersonation token
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub act: Option<Actor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Actor {
+  pub sub: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub email: Option<String>,
+}
+
+/// Claims of a `client_credentials` access token. No user stands behind it:
+/// `sub` is the client ID and there is no session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientClaims {
+  pub sub: String,
+  pub aud: String,
+  pub exp: i64,
+  pub iat: i64,
+  pub iss: String,
+  pub role: String,
+  pub client_id: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub scope: Option<String>,
+}
+
+/// A key that access tokens can be signed and verified with.
+///
+/// HMAC keys carry no public half and are never published; asymmetric keys
+/// expose their public JWK through `/.well-known/jwks.json`.
+#[derive(Clone)]
+pub struct JwtKey {
+  pub kid: Option<String>,
+  pub algorithm: Algorithm,
+  encoding_key: EncodingKey,
+  decoding_key: DecodingKey,
+  public_jwk: Option<Jwk>,
+}
+
+impl std::fmt::Debug for JwtKey {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("JwtKey")
+      .field("kid", &self.kid)
+      .field("algorithm", &self.algorithm)
+      .finish_non_exhaustive()
+  }
+}
+
+impl JwtKey {
+  pub fn hmac(secret: &str) -> Self {
+    Self {
+      kid: None,
+      algorithm: Algorithm::HS256,
+      encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+      decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+      public_jwk: None,
+    }
+  }
+
+  /// Loads a private key in PEM form. When `kid` is `None` the RFC 7638
+  /// thumbprint of the public key is used instead.
+  pub fn from_pem(algorithm: Algorithm, pem: &str, kid: Option<String>) -> anyhow::Result<Self> {
+    let (encoding_key, parameters) = match algorithm {
+      Algorithm::RS256 => {
+        let key = rsa::RsaPrivateKey::from_pkcs8_pem(pem)
+          .or_else(|_| rsa::RsaPrivateKey::from_pkcs1_pem(pem))
+          .context("invalid RSA private key")?;
+        let der = key.to_pkcs1_der().context("failed to encode RSA private key")?;
+        let n = URL_SAFE_NO_PAD.encode(key.n().to_bytes_be());
+        let e = URL_SAFE_NO_PAD.encode(key.e().to_bytes_be());
+        (
+          EncodingKey::from_rsa_der(der.as_bytes()),
+          AlgorithmParameters::RSA(RSAKeyParameters {
+            key_type: RSAKeyType::RSA,
+            n,
+            e,
+          }),
+        )
+      },
+      Algorithm::ES256 => {
+        let key = p256::SecretKey::from_pkcs8_pem(pem)
+          .or_else(|_| p256::SecretKey::from_sec1_pem(pem))
+          .context("invalid P-256 private key")?;
+        let der = key.to_pkcs8_der().context("failed to encode P-256 private key")?;
+        let point = key.public_key().to_encoded_point(false);
+        let (Some(x), Some(y)) = (point.x(), point.y()) else {
+          bail!("invalid P-256 public key");
+        };
+        let x = URL_SAFE_NO_PAD.encode(x);
+        let y = URL_SAFE_NO_PAD.encode(y);
+        (
+          EncodingKey::from_ec_der(der.as_bytes()),
+          AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+            key_type: EllipticCurveKeyType::EC,
+            curve: EllipticCurve::P256,
+            x,
+            y,
+          }),
+        )
+      },
+      Algorithm::EdDSA => {
+        let key = ed25519_dalek::SigningKey::from_pkcs8_pem(pem).context("invalid Ed25519 private key")?;
+        let der = key
+          .to_pkcs8_der()
+          .context("failed to encode Ed25519 private key")?;
+        let x = URL_SAFE_NO_PAD.encode(key.verifying_key().as_bytes());
+        (
+          EncodingKey::from_ed_der(der.as_bytes()),
+          AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x,
+          }),
+        )
+      },
+      other => bail!("unsupported asymmetric signing algorithm {other:?}"),
+    };
+
+    let kid = kid.unwrap_or_else(|| jwk_thumbprint(&parameters));
+    let jwk = Jwk {
+      common: CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        key_algorithm: Some(key_algorithm(algorithm)),
+        key_id: Some(kid.clone()),
+        ..Default::default()
+      },
+      algorithm: parameters,
+    };
+    let decoding_key = DecodingKey::from_jwk(&jwk).context("failed to derive verification key")?;
+
+    Ok(Self {
+      kid: Some(kid),
+      algorithm,
+      encoding_key,
+      decoding_key,
+      public_jwk: Some(jwk),
+    })
+  }
+
+  pub fn with_kid(mut self, kid: String) -> Self {
+    if let Some(jwk) = self.public_jwk.as_mut() {
+      jwk.common.key_id = Some(kid.clone());
+    }
+    self.kid = Some(kid);
+    self
+  }
+
+  pub fn public_jwk(&self) -> Option<&Jwk> {
+    self.public_jwk.as_ref()
+  }
+
+  fn matches(&self, header: &Header) -> bool {
+    self.algorithm == header.alg && self.kid.as_deref() == header.kid.as_deref()
+  }
+}
+
+/// The keys Haya signs new access tokens with and accepts on decode.
+#[derive(Debug, Clone)]
+pub struct JwtKeyring {
+  signing: JwtKey,
+  verification: Vec<JwtKey>,
+}
+
+impl JwtKeyring {
+  pub fn new(signing: JwtKey) -> Self {
+    Self {
+      signing,
+      verification: Vec::new(),
+    }
+  }
+
+  /// Adds a key that is accepted on decode but never used to sign.
+  pub fn with_verification_key(mut self, key: JwtKey) -> Self {
+    self.verification.push(key);
+    self
+  }
+
+  pub fn signing_key(&self) -> &JwtKey {
+    &self.signing
+  }
+
+  /// The key id_tokens are signed with: the signing key, unless it is an
+  /// HMAC secret. Relying parties verify id_tokens, and must never learn the
+  /// secret that signs access tokens.
+  pub fn id_token_signing_key(&self) -> Option<&JwtKey> {
+    self.signing.public_jwk().is_some().then_some(&self.signing)
+  }
+
+  /// Every key accepted on decode, starting with the signing key.
+  pub fn keys(&self) -> impl Iterator<Item = &JwtKey> {
+    std::iter::once(&self.signing).chain(self.verification.iter())
+  }
+
+  fn find(&self, header: &Header) -> Option<&JwtKey> {
+    self.keys().find(|key| key.matches(header))
+  }
+
+  pub fn jwks(&self) -> JwkSet {
+    JwkSet {
+      keys: self.keys().filter_map(|key| key.public_jwk().cloned()).collect(),
+    }
+  }
+}
+
+pub fn parse_signing_algorithm(value: &str) -> Option<Algorithm> {
+  match value.trim().to_ascii_uppercase().as_str() {
+    "HS256" => Some(Algorithm::HS256),
+    "RS256" => Some(Algorithm::RS256),
+    "ES256" => Some(Algorithm::ES256),
+    "EDDSA" => Some(Algorithm::EdDSA),
+    _ => None,
+  }
+}
+
+/// Computes the RFC 7638 thumbprint of a public key.
+fn jwk_thumbprint(parameters: &AlgorithmParameters) -> String {
+  let canonical = match parameters {
+    AlgorithmParameters::RSA(rsa) => format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, rsa.e, rsa.n),
+    AlgorithmParameters::EllipticCurve(ec) => {
+      format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, ec.x, ec.y)
+    },
+    AlgorithmParameters::OctetKeyPair(okp) => format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, okp.x),
+    AlgorithmParameters::OctetKey(oct) => format!(r#"{{"k":"{}","kty":"oct"}}"#, oct.value),
+  };
+  URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()))
+}
+
+fn key_algorithm(algorithm: Algorithm) -> KeyAlgorithm {
+  match algorithm {
+    Algorithm::RS256 => KeyAlgorithm::RS256,
+    Algorithm::ES256 => KeyAlgorithm::ES256,
+    Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+    _ => KeyAlgorithm::HS256,
+  }
 }
 
 #[derive(Debug)]
@@ -57,14 +334,23 @@ pub struct EncodeTokenParams<'a> {
   pub amr: Vec<AmrEntry>,
   pub user_metadata: serde_json::Value,
   pub app_metadata: serde_json::Value,
-  pub jwt_secret: &'a str,
+  pub client_id: Option<&'a str>,
+  pub scope: Option<&'a str>,
+  pub act: Option<Actor>,
+  pub signing_key: &'a JwtKey,
   pub jwt_exp: i64,
   pub issuer: &'a str,
 }
 
 pub fn encode_token(params: EncodeTokenParams<'_>) -> Result<String, AuthError> {
+  let signing_key = params.signing_key;
+  encode_claims(signing_key, &build_claims(params))
+}
+
+/// Builds the standard access-token claims without signing them.
+pub fn build_claims(params: EncodeTokenParams<'_>) -> Claims {
   let now = Utc::now().timestamp();
-  let claims = Claims {
+  Claims {
     sub: params.user_id.to_string(),
     aud: JWT_AUDIENCE.to_string(),
     exp: now + params.jwt_exp,
@@ -79,26 +365,36 @@ pub fn encode_token(params: EncodeTokenParams<'_>) -> Result<String, AuthError>
     is_anonymous: params.is_anonymous,
     user_metadata: params.user_metadata,
     app_metadata: params.app_metadata,
-  };
-  encode(
-    &Header::new(Algorithm::HS256),
-    &claims,
-    &EncodingKey::from_secret(params.jwt_secret.as_bytes()),
-  )
-  .map_err(|e| AuthError::InternalError(e.to_string()))
+    client_id: params.client_id.map(str::to_string),
+    scope: params.scope.map(str::to_string),
+    act: params.act,
+  }
+}
+
+/// Signs arbitrary claims, such as an OpenID Connect `id_token`, with `signing_key`.
+pub fn encode_claims<T: Serialize>(signing_key: &JwtKey, claims: &T) -> Result<String, AuthError> {
+  let mut header = Header::new(signing_key.algorithm);
+  header.kid = signing_key.kid.clone();
+  encode(&header, claims, &signing_key.encoding_key).map_err(|e| AuthError::InternalError(e.to_string()))
+}
+
+pub fn decode_token(token: &str, keys: &JwtKeyring, issuer: &str) -> Result<TokenData<Claims>, AuthError> {
+  decode_claims(token, keys, issuer)
 }
 
-pub fn decode_token(token: &str, jwt_secret: &str, issuer: &str) -> Result<TokenData<Claims>, AuthError> {
-  let mut validation = Validation::new(Algorithm::HS256);
+/// Verifies a Haya-issued token and decodes its claims as `T`.
+pub fn decode_claims<T: DeserializeOwned>(
+  token: &str,
+  keys: &JwtKeyring,
+  issuer: &str,
+) -> Result<TokenData<T>, AuthError> {
+  let header = decode_header(token).map_err(|_| AuthError::InvalidToken)?;
+  let key = keys.find(&header).ok_or(AuthError::InvalidToken)?;
+  let mut validation = Validation::new(key.algorithm);
   validation.set_audience(&[JWT_AUDIENCE]);
   validation.set_issuer(&[issuer]);
   validation.leeway = JWT_LEEWAY_SECONDS;
-  decode::<Claims>(
-    token,
-    &DecodingKey::from_secret(jwt_secret.as_bytes()),
-    &validation,
-  )
-  .map_err(|e| match e.kind() {
+  decode::<T>(token, &key.decoding_key, &validation).map_err(|e| match e.kind() {
     jsonwebtoken::errors::ErrorKind::ExpiredSignature => AuthError::TokenExpired,
     _ => AuthError::InvalidToken,
   })
@@ -106,13 +402,45 @@ pub fn decode_token(token: &str, jwt_secret: &str, issuer: &str) -> Result<Token
 
 #[cfg(test)]
 mod tests {
+  use argon2::password_hash::rand_core::OsRng;
+  use rsa::pkcs8::LineEnding;
+
   use super::*;
 
+  fn sample_token(signing_key: &JwtKey) -> String {
+    encode_token(EncodeTokenParams {
+      user_id: Uuid::new_v4(),
+      email: None,
+      phone: None,
+      role: "authenticated",
+      session_id: Uuid::new_v4(),
+      is_anonymous: false,
+      aal: "aal1",
+      amr: vec![],
+      user_metadata: serde_json::json!({}),
+      app_metadata: serde_json::json!({}),
+      client_id: None,
+      scope: None,
+      act: None,
+      signing_key,
+      jwt_exp: 3600,
+      issuer: "https://example.com",
+    })
+    .unwrap()
+  }
+
+  fn es256_key(kid: Option<String>) -> JwtKey {
+    let pem = p256::SecretKey::random(&mut OsRng)
+      .to_pkcs8_pem(LineEnding::LF)
+      .unwrap();
+    JwtKey::from_pem(Algorithm::ES256, &pem, kid).unwrap()
+  }
+
   #[test]
   fn test_encode_decode_token() {
     let user_id = Uuid::new_v4();
     let session_id = Uuid::new_v4();
-    let secret = "test-secret-key";
+    let key = JwtKey::hmac("test-secret-key");
 
     let token = encode_token(EncodeTokenParams {
       user_id,
@@ -128,13 +456,16 @@ mod tests {
       }],
       user_metadata: serde_json::json!({}),
       app_metadata: serde_json::json!({"provider": "email"}),
-      jwt_secret: secret,
+      client_id: None,
+      scope: None,
+      act: None,
+      signing_key: &key,
       jwt_exp: 3600,
       issuer: "https://example.com",
     })
     .unwrap();
 
-    let decoded = decode_token(&token, secret, "https://example.com").unwrap();
+    let decoded = decode_token(&token, &JwtKeyring::new(key), "https://example.com").unwrap();
     assert_eq!(decoded.claims.sub, user_id.to_string());
     assert_eq!(decoded.claims.aud, "authenticated");
     assert_eq!(decoded.claims.role, "authenticated");
@@ -144,7 +475,7 @@ mod tests {
   fn test_expired_token_returns_token_expired() {
     let user_id = Uuid::new_v4();
     let session_id = Uuid::new_v4();
-    let secret = "test-secret-key";
+    let key = JwtKey::hmac("test-secret-key");
 
     let token = encode_token(EncodeTokenParams {
       user_id,
@@ -160,13 +491,131 @@ mod tests {
       }],
       user_metadata: serde_json::json!({}),
       app_metadata: serde_json::json!({}),
-      jwt_secret: secret,
+      client_id: None,
+      scope: None,
+      act: None,
+      signing_key: &key,
       jwt_exp: -((JWT_LEEWAY_SECONDS as i64) + 10),
       issuer: "https://example.com",
     })
     .unwrap();
 
-    let result = decode_token(&token, secret, "https://example.com");
+    let result = decode_token(&token, &JwtKeyring::new(key), "https://example.com");
     assert!(matches!(result, Err(AuthError::TokenExpired)));
   }
+
+  #[test]
+  fn actor_claim_is_only_present_on_impersonation_tokens() {
+    let key = JwtKey::hmac("test-secret-key");
+    let keyring = JwtKeyring::new(key.clone());
+    let plain = decode_token(&sample_token(&key), &keyring, "https://example.com").unwrap();
+    assert!(plain.claims.act.is_none());
+    assert!(serde_json::to_value(&plain.claims).unwrap().get("act").is_none());
+
+    let actor = Actor {
+      sub: Uuid::new_v4().to_string(),
+      email: Some("admin@example.com".to_string()),
+    };
+    let mut claims = plain.claims;
+    claims.act = Some(actor.clone());
+    let token = encode_claims(&key, &claims).unwrap();
+    let decoded = decode_token(&token, &keyring, "https://example.com").unwrap();
+    assert_eq!(decoded.claims.act, Some(actor));
+  }
+
+  #[test]
+  fn asymmetric_tokens_carry_kid_and_verify() {
+    let rsa_pem = rsa::RsaPrivateKey::new(&mut OsRng, 2048)
+      .unwrap()
+      .to_pkcs8_pem(LineEnding::LF)
+      .unwrap();
+    let ed_pem = ed25519_dalek::SigningKey::generate(&mut OsRng)
+      .to_pkcs8_pem(LineEnding::LF)
+      .unwrap();
+    let keys = [
+      JwtKey::from_pem(Algorithm::RS256, &rsa_pem, None).unwrap(),
+      es256_key(Some("es-key".to_string())),
+      JwtKey::from_pem(Algorithm::EdDSA, &ed_pem, None).unwrap(),
+    ];
+
+    for key in keys {
+      let token = sample_token(&key);
+      let header = decode_header(&token).unwrap();
+      assert_eq!(header.alg, key.algorithm);
+      assert_eq!(header.kid, key.kid);
+      assert!(decode_token(&token, &JwtKeyring::new(key), "https://example.com").is_ok());
+    }
+  }
+
+  #[test]
+  fn kid_defaults_to_jwk_thumbprint() {
+    // RFC 7638 section 3.1 example key.
+    let n = "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw";
+    let parameters = AlgorithmParameters::RSA(RSAKeyParameters {
+      key_type: RSAKeyType::RSA,
+      n: n.to_string(),
+      e: "AQAB".to_string(),
+    });
+    assert_eq!(
+      jwk_thumbprint(&parameters),
+      "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+    );
+
+    let key = es256_key(None);
+    let jwk = key.public_jwk().unwrap();
+    assert_eq!(jwk.common.key_id, key.kid);
+  }
+
+  #[test]
+  fn legacy_hmac_tokens_remain_valid_after_switching_to_asymmetric_signing() {
+    let legacy = JwtKey::hmac("legacy-secret-with-at-least-32-characters");
+    let legacy_token = sample_token(&legacy);
+    let keyring = JwtKeyring::new(es256_key(None)).with_verification_key(legacy);
+
+    assert!(decode_token(&legacy_token, &keyring, "https://example.com").is_ok());
+    assert!(
+      decode_token(
+        &sample_token(keyring.signing_key()),
+        &keyring,
+        "https://example.com"
+      )
+      .is_ok()
+    );
+  }
+
+  #[test]
+  fn tokens_from_unknown_keys_are_rejected() {
+    let keyring = JwtKeyring::new(es256_key(Some("current".to_string())));
+    let foreign = es256_key(Some("current".to_string()));
+    let hmac = JwtKey::hmac("some-other-secret-with-at-least-32-chars");
+
+    assert!(matches!(
+      decode_token(&sample_token(&foreign), &keyring, "https://example.com"),
+      Err(AuthError::InvalidToken)
+    ));
+    assert!(matches!(
+      decode_token(&sample_token(&hmac), &keyring, "https://example.com"),
+      Err(AuthError::InvalidToken)
+    ));
+  }
+
+  #[test]
+  fn jwks_only_publishes_asymmetric_keys() {
+    let keyring = JwtKeyring::new(es256_key(Some("current".to_string())))
+      .with_verification_key(JwtKey::hmac("legacy-secret-with-at-least-32-characters"));
+
+    let jwks = keyring.jwks();
+    assert_eq!(jwks.keys.len(), 1);
+    assert!(jwks.find("current").is_some());
+    assert!(JwtKeyring::new(JwtKey::hmac("secret")).jwks().keys.is_empty());
+  }
+
+  #[test]
+  fn parses_supported_signing_algorithms() {
+    assert_eq!(parse_signing_algorithm("hs256"), Some(Algorithm::HS256));
+    assert_eq!(parse_signing_algorithm("RS256"), Some(Algorithm::RS256));
+    assert_eq!(parse_signing_algorithm("ES256"), Some(Algorithm::ES256));
+    assert_eq!(parse_signing_algorithm("EdDSA"), Some(Algorithm::EdDSA));
+    assert_eq!(parse_signing_algorithm("PS512"), None);
+  }
 }
@@ -0,0 +1,221 @@
+use anyhow::Context;
+use argon2::password_hash::rand_core::OsRng;
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{
+  DateTime,
+  Utc,
+};
+use jsonwebtoken::Algorithm;
+use rand::RngCore;
+use rsa::pkcs8::{
+  EncodePrivateKey as _,
+  LineEnding,
+};
+use serde::Serialize;
+use sqlx::{
+  FromRow,
+  PgPool,
+};
+use uuid::Uuid;
+
+use crate::auth::{
+  jwt,
+  mfa,
+};
+use crate::state::AppState;
+
+pub const KEY_STATUS_NEXT: &str = "next";
+pub const KEY_STATUS_ACTIVE: &str = "active";
+pub const KEY_STATUS_RETIRING: &str = "retiring";
+pub const KEY_STATUS_REVOKED: &str = "revoked";
+
+const RSA_KEY_BITS: usize = 2048;
+const HMAC_SECRET_BYTES: usize = 48;
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct SigningKeyRow {
+  pub id: Uuid,
+  pub kid: String,
+  pub algorithm: String,
+  pub status: String,
+  #[serde(skip)]
+  pub private_key: String,
+  pub created_at: DateTime<Utc>,
+  pub activated_at: Option<DateTime<Utc>>,
+  pub retired_at: Option<DateTime<Utc>>,
+  pub revoked_at: Option<DateTime<Utc>>,
+  pub updated_at: DateTime<Utc>,
+}
+
+/// Builds the runtime keyring from `auth.signing_keys`.
+///
+/// The active database key signs new tokens. Keys from `JWT_SECRET` or
+/// `JWT_SIGNING_KEY_PATH` stay accepted on decode and take over signing when
+/// no database key is active. Revoked keys are never loaded.
+pub async fn load_keyring(
+  db: &PgPool,
+  mfa_keys: &mfa::EncryptionKeys,
+  configured: &jwt::JwtKeyring,
+) -> anyhow::Result<jwt::JwtKeyring> {
+  let rows: Vec<SigningKeyRow> = sqlx::query_as::<_, SigningKeyRow>(
+    "SELECT id, kid, algorithm, status, private_key, created_at, activated_at, retired_at, revoked_at, updated_at FROM auth.signing_keys WHERE status <> 'revoked' ORDER BY created_at DESC",
+  )
+  .fetch_all(db)
+  .await?;
+
+  let mut active = None;
+  let mut others = Vec::new();
+  for row in rows {
+    let key =
+      decode_row(&row, mfa_keys).with_context(|| format!("failed to load signing key {}", row.kid))?;
+    if row.status == KEY_STATUS_ACTIVE {
+      active = Some(key);
+    } else {
+      others.push(key);
+    }
+  }
+
+  Ok(assemble_keyring(configured, active, others))
+}
+
+fn assemble_keyring(
+  configured: &jwt::JwtKeyring,
+  active: Option<jwt::JwtKey>,
+  others: Vec<jwt::JwtKey>,
+) -> jwt::JwtKeyring {
+  let keyring = match active {
+    Some(active) => configured.keys().cloned().fold(
+      jwt::JwtKeyring::new(active),
+      jwt::JwtKeyring::with_verification_key,
+    ),
+    None => configured.clone(),
+  };
+  others
+    .into_iter()
+    .fold(keyring, jwt::JwtKeyring::with_verification_key)
+}
+
+pub async fn reload(state: &AppState) -> anyhow::Result<()> {
+  let keyring = load_keyring(&state.db, &state.mfa_keys, &state.jwt_configured_keys).await?;
+  *state.jwt_keys.write().await = keyring;
+  Ok(())
+}
+
+fn decode_row(row: &SigningKeyRow, mfa_keys: &mfa::EncryptionKeys) -> anyhow::Result<jwt::JwtKey> {
+  let algorithm = jwt::parse_signing_algorithm(&row.algorithm)
+    .with_context(|| format!("unsupported signing algorithm {}", row.algorithm))?;
+  let material = String::from_utf8(mfa_keys.decrypt(&row.private_key)?)
+    .context("signing key material is not valid UTF-8")?;
+  if algorithm == Algorithm::HS256 {
+    return Ok(jwt::JwtKey::hmac(&material).with_kid(row.kid.clone()));
+  }
+  jwt::JwtKey::from_pem(algorithm, &material, Some(row.kid.clone()))
+}
+
+/// Generates fresh key material: a PKCS#8 PEM for asymmetric algorithms or a
+/// random secret for HS256.
+pub fn generate_key_material(algorithm: Algorithm) -> anyhow::Result<String> {
+  let material = match algorithm {
+    Algorithm::HS256 => {
+      let mut bytes = [0u8; HMAC_SECRET_BYTES];
+      rand::rng().fill_bytes(&mut bytes);
+      URL_SAFE_NO_PAD.encode(bytes)
+    },
+    Algorithm::RS256 => rsa::RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS)?
+      .to_pkcs8_pem(LineEnding::LF)?
+      .to_string(),
+    Algorithm::ES256 => p256::SecretKey::random(&mut OsRng)
+      .to_pkcs8_pem(LineEnding::LF)?
+      .to_string(),
+    Algorithm::EdDSA => ed25519_dalek::SigningKey::generate(&mut OsRng)
+      .to_pkcs8_pem(LineEnding::LF)?
+      .to_string(),
+    other => anyhow::bail!("unsupported signing algorithm {other:?}"),
+  };
+  Ok(material)
+}
+
+/// Returns the `kid` a freshly generated key is stored under.
+pub fn key_id_for_material(algorithm: Algorithm, material: &str) -> anyhow::Result<String> {
+  if algorithm == Algorithm::HS256 {
+    return Ok(Uuid::new_v4().simple().to_string());
+  }
+  jwt::JwtKey::from_pem(algorithm, material, None)?
+    .kid
+    .context("asymmetric keys always carry a kid")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn generated_material_loads_for_every_algorithm() {
+    for algorithm in [
+      Algorithm::HS256,
+      Algorithm::RS256,
+      Algorithm::ES256,
+      Algorithm::EdDSA,
+    ] {
+      let material = generate_key_material(algorithm).unwrap();
+      let kid = key_id_for_material(algorithm, &material).unwrap();
+      let keys = mfa::EncryptionKeys::legacy([7u8; 32]);
+      let row = SigningKeyRow {
+        id: Uuid::new_v4(),
+        kid: kid.clone(),
+        algorithm: format!("{algorithm:?}"),
+        status: KEY_STATUS_NEXT.to_string(),
+        private_key: keys.encrypt(material.as_bytes()).unwrap(),
+        created_at: Utc::now(),
+        activated_at: None,
+        retired_at: None,
+        revoked_at: None,
+        updated_at: Utc::now(),
+      };
+
+      let key = decode_row(&row, &keys).unwrap();
+      assert_eq!(key.algorithm, algorithm);
+      assert_eq!(key.kid.as_deref(), Some(kid.as_str()));
+    }
+  }
+
+  fn es256_key(kid: &str) -> jwt::JwtKey {
+    let material = generate_key_material(Algorithm::ES256).unwrap();
+    jwt::JwtKey::from_pem(Algorithm::ES256, &material, Some(kid.to_string())).unwrap()
+  }
+
+  #[test]
+  fn active_database_key_signs_and_configured_keys_keep_verifying() {
+    let configured = jwt::JwtKeyring::new(jwt::JwtKey::hmac("configured-secret-with-at-least-32-chars"));
+
+    let keyring = assemble_keyring(
+      &configured,
+      Some(es256_key("active")),
+      vec![es256_key("next"), es256_key("retiring")],
+    );
+
+    assert_eq!(keyring.signing_key().kid.as_deref(), Some("active"));
+    let kids: Vec<_> = keyring.keys().map(|key| key.kid.clone()).collect();
+    assert_eq!(
+      kids,
+      vec![
+        Some("active".to_string()),
+        None,
+        Some("next".to_string()),
+        Some("retiring".to_string()),
+      ]
+    );
+    assert_eq!(keyring.jwks().keys.len(), 3);
+  }
+
+  #[test]
+  fn configured_key_signs_when_no_database_key_is_active() {
+    let configured = jwt::JwtKeyring::new(jwt::JwtKey::hmac("configured-secret-with-at-least-32-chars"));
+
+    let keyring = assemble_keyring(&configured, None, vec![es256_key("retiring")]);
+
+    assert_eq!(keyring.signing_key().algorithm, Algorithm::HS256);
+    assert_eq!(keyring.keys().count(), 2);
+  }
+}
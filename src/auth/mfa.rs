@@ -1,3 +1,5 @@
+use std::str::FromStr;
+
 use aes_gcm::aead::{
   Aead,
   KeyInit,
@@ -13,23 +15,199 @@ use hmac::{
   Hmac,
   Mac,
 };
-use rand::RngCore;
+use rand::{
+  Rng as _,
+  RngCore,
+};
 use sha1::Sha1;
-use sha2::Sha256;
+use sha2::{
+  Sha256,
+  Sha512,
+};
 use url::Url;
 
 use crate::error::AuthError;
 
 const MFA_ENCRYPTION_KDF_SALT: &[u8] = b"haya-mfa-encryption-key-salt";
 const MFA_ENCRYPTION_KDF_INFO: &[u8] = b"haya-mfa-encryption-key/v1";
-const MFA_SECRET_BYTES: usize = 20;
-const TOTP_PERIOD_SECS: i64 = 30;
-const TOTP_DIGITS: u32 = 6;
-const TOTP_SKEW_STEPS: i64 = 1;
-const ENCRYPTION_VERSION: &str = "v1";
+const TOTP_DEFAULT_PERIOD_SECS: i64 = 30;
+const TOTP_DEFAULT_DIGITS: u32 = 6;
+const TOTP_DEFAULT_SKEW_STEPS: i64 = 1;
+const TOTP_MIN_DIGITS: u32 = 6;
+const TOTP_MAX_DIGITS: u32 = 8;
+const TOTP_MIN_PERIOD_SECS: i64 = 15;
+const TOTP_MAX_PERIOD_SECS: i64 = 300;
+const TOTP_MAX_SKEW_STEPS: i64 = 10;
+const LEGACY_KEY_VERSION: &str = "v1";
+const LABELED_KEY_VERSION: &str = "v2";
+const MAX_KEY_ID_LENGTH: usize = 32;
+const PHONE_CODE_DIGITS: u32 = 6;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_HALF_LENGTH: usize = 5;
+// Lowercase letters and digits without the easily confused 0, 1, i, l and o.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
 
 type HmacSha1 = Hmac<Sha1>;
 type HmacSha256 = Hmac<Sha256>;
+type HmacSha512 = Hmac<Sha512>;
+
+/// HMAC algorithm a TOTP factor derives its codes with, weakest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TotpAlgorithm {
+  Sha1,
+  Sha256,
+  Sha512,
+}
+
+impl TotpAlgorithm {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      TotpAlgorithm::Sha1 => "SHA1",
+      TotpAlgorithm::Sha256 => "SHA256",
+      TotpAlgorithm::Sha512 => "SHA512",
+    }
+  }
+
+  /// Secret size matching the HMAC output, as in the RFC 6238 test vectors.
+  fn secret_len(self) -> usize {
+    match self {
+      TotpAlgorithm::Sha1 => 20,
+      TotpAlgorithm::Sha256 => 32,
+      TotpAlgorithm::Sha512 => 64,
+    }
+  }
+}
+
+impl FromStr for TotpAlgorithm {
+  type Err = String;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value.trim().to_ascii_uppercase().as_str() {
+      "SHA1" => Ok(TotpAlgorithm::Sha1),
+      "SHA256" => Ok(TotpAlgorithm::Sha256),
+      "SHA512" => Ok(TotpAlgorithm::Sha512),
+      other => Err(format!(
+        "unsupported TOTP algorithm {other:?}; expected SHA1, SHA256 or SHA512"
+      )),
+    }
+  }
+}
+
+/// How a TOTP factor's codes are computed. Fixed when the factor is enrolled,
+/// since the authenticator app keeps whatever it was given then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TotpParams {
+  pub algorithm: TotpAlgorithm,
+  pub digits: u32,
+  pub period: i64,
+}
+
+impl Default for TotpParams {
+  fn default() -> Self {
+    Self {
+      algorithm: TotpAlgorithm::Sha1,
+      digits: TOTP_DEFAULT_DIGITS,
+      period: TOTP_DEFAULT_PERIOD_SECS,
+    }
+  }
+}
+
+impl TotpParams {
+  pub fn new(algorithm: TotpAlgorithm, digits: u32, period: i64) -> Result<Self, String> {
+    if !(TOTP_MIN_DIGITS..=TOTP_MAX_DIGITS).contains(&digits) {
+      return Err(format!(
+        "TOTP digits must be between {TOTP_MIN_DIGITS} and {TOTP_MAX_DIGITS}, got {digits}"
+      ));
+    }
+    if !(TOTP_MIN_PERIOD_SECS..=TOTP_MAX_PERIOD_SECS).contains(&period) {
+      return Err(format!(
+        "TOTP period must be between {TOTP_MIN_PERIOD_SECS} and {TOTP_MAX_PERIOD_SECS} seconds, got {period}"
+      ));
+    }
+    Ok(Self {
+      algorithm,
+      digits,
+      period,
+    })
+  }
+
+  /// These parameters with any of the given ones swapped in, for a factor
+  /// whose enrollment request asked for them. Users may only strengthen the
+  /// configured parameters: a stronger hash, more digits or a shorter period.
+  pub fn with_overrides(
+    self,
+    algorithm: Option<&str>,
+    digits: Option<u32>,
+    period: Option<i64>,
+  ) -> Result<Self, String> {
+    let algorithm = match algorithm {
+      Some(algorithm) => algorithm.parse::<TotpAlgorithm>()?,
+      None => self.algorithm,
+    };
+    let params = Self::new(
+      algorithm,
+      digits.unwrap_or(self.digits),
+      period.unwrap_or(self.period),
+    )?;
+    if params.algorithm < self.algorithm {
+      return Err(format!(
+        "TOTP algorithm must be {} or stronger",
+        self.algorithm.as_str()
+      ));
+    }
+    if params.digits < self.digits {
+      return Err(format!("TOTP codes must have at least {} digits", self.digits));
+    }
+    if params.period > self.period {
+      return Err(format!("TOTP period must be at most {} seconds", self.period));
+    }
+    Ok(params)
+  }
+
+  /// Reads the parameters stored on a factor row.
+  pub fn from_stored(algorithm: &str, digits: i32, period: i32) -> Result<Self, AuthError> {
+    let algorithm = algorithm
+      .parse::<TotpAlgorithm>()
+      .map_err(AuthError::InternalError)?;
+    let digits =
+      u32::try_from(digits).map_err(|_| AuthError::InternalError(format!("invalid TOTP digits {digits}")))?;
+    Self::new(algorithm, digits, i64::from(period)).map_err(AuthError::InternalError)
+  }
+}
+
+/// Parameters for new TOTP factors and how many steps of clock drift to
+/// accept either side of the current one (env: `MFA_TOTP_ALGORITHM`,
+/// `MFA_TOTP_DIGITS`, `MFA_TOTP_PERIOD`, `MFA_TOTP_SKEW_STEPS`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TotpPolicy {
+  pub enrollment: TotpParams,
+  pub skew_steps: i64,
+}
+
+impl Default for TotpPolicy {
+  fn default() -> Self {
+    Self {
+      enrollment: TotpParams::default(),
+      skew_steps: TOTP_DEFAULT_SKEW_STEPS,
+    }
+  }
+}
+
+impl TotpPolicy {
+  pub fn new(algorithm: &str, digits: u32, period: i64, skew_steps: i64) -> Result<Self, String> {
+    let algorithm = algorithm.parse::<TotpAlgorithm>()?;
+    let enrollment = TotpParams::new(algorithm, digits, period)?;
+    if !(0..=TOTP_MAX_SKEW_STEPS).contains(&skew_steps) {
+      return Err(format!(
+        "TOTP skew must be between 0 and {TOTP_MAX_SKEW_STEPS} steps, got {skew_steps}"
+      ));
+    }
+    Ok(Self {
+      enrollment,
+      skew_steps,
+    })
+  }
+}
 
 pub fn derive_encryption_key(material: &str) -> [u8; 32] {
   let mut extract = <HmacSha256 as Mac>::new_from_slice(MFA_ENCRYPTION_KDF_SALT)
@@ -47,8 +225,8 @@ pub fn derive_encryption_key(material: &str) -> [u8; 32] {
   key
 }
 
-pub fn generate_totp_secret() -> Vec<u8> {
-  let mut secret = vec![0u8; MFA_SECRET_BYTES];
+pub fn generate_totp_secret(algorithm: TotpAlgorithm) -> Vec<u8> {
+  let mut secret = vec![0u8; algorithm.secret_len()];
   rand::rng().fill_bytes(&mut secret);
   secret
 }
@@ -57,47 +235,205 @@ pub fn encode_secret(secret: &[u8]) -> String {
   base32::encode(Alphabet::Rfc4648 { padding: false }, secret)
 }
 
-pub fn encrypt_secret(secret: &[u8], key_material: &[u8; 32]) -> Result<String, AuthError> {
-  let cipher = Aes256Gcm::new_from_slice(key_material)
-    .map_err(|e| AuthError::InternalError(format!("failed to initialize MFA cipher: {e}")))?;
-  let mut nonce_bytes = [0u8; 12];
-  rand::rng().fill_bytes(&mut nonce_bytes);
-  let ciphertext = cipher
-    .encrypt(Nonce::from_slice(&nonce_bytes), secret)
-    .map_err(|e| AuthError::InternalError(format!("failed to encrypt MFA secret: {e}")))?;
+/// The keys that encrypt MFA secrets and other values at rest.
+///
+/// The unlabeled `MFA_ENCRYPTION_KEY` writes `v1.<nonce>.<ciphertext>`. Each
+/// labeled key from `MFA_ENCRYPTION_KEYS` writes
+/// `v2:<key id>.<nonce>.<ciphertext>`. A value is decrypted with the key its
+/// prefix names, and new values always use the newest key.
+#[derive(Clone)]
+pub struct EncryptionKeys {
+  legacy: Option<[u8; 32]>,
+  /// Oldest first; the last key encrypts.
+  labeled: Vec<(String, [u8; 32])>,
+}
 
-  Ok(format!(
-    "{ENCRYPTION_VERSION}.{}.{}",
-    URL_SAFE_NO_PAD.encode(nonce_bytes),
-    URL_SAFE_NO_PAD.encode(ciphertext)
-  ))
+impl std::fmt::Debug for EncryptionKeys {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("EncryptionKeys")
+      .field("legacy", &self.legacy.is_some())
+      .field(
+        "labeled",
+        &self.labeled.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(),
+      )
+      .finish()
+  }
 }
 
-pub fn decrypt_secret(secret: &str, key_material: &[u8; 32]) -> Result<Vec<u8>, AuthError> {
-  let mut parts = secret.split('.');
-  let version = parts.next().ok_or_else(|| AuthError::InvalidToken)?;
-  let nonce_b64 = parts.next().ok_or_else(|| AuthError::InvalidToken)?;
-  let ciphertext_b64 = parts.next().ok_or_else(|| AuthError::InvalidToken)?;
+impl EncryptionKeys {
+  /// A key set with only an unlabeled key.
+  pub fn legacy(key: [u8; 32]) -> Self {
+    Self {
+      legacy: Some(key),
+      labeled: Vec::new(),
+    }
+  }
+
+  /// Builds the key set from `MFA_ENCRYPTION_KEY` and `MFA_ENCRYPTION_KEYS`,
+  /// the latter a comma-separated list of `id:secret` pairs, oldest first.
+  pub fn from_config(legacy: Option<&str>, labeled: Option<&str>) -> Result<Self, String> {
+    let legacy = legacy
+      .filter(|value| !value.trim().is_empty())
+      .map(derive_encryption_key);
+    let mut keys = Vec::new();
+    for entry in labeled.unwrap_or_default().split(',').map(str::trim) {
+      if entry.is_empty() {
+        continue;
+      }
+      let Some((id, secret)) = entry.split_once(':') else {
+        return Err("MFA_ENCRYPTION_KEYS entries must look like id:secret".to_string());
+      };
+      let id = id.trim();
+      if id.is_empty()
+        || id.len() > MAX_KEY_ID_LENGTH
+        || !id
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+      {
+        return Err(format!(
+          "MFA_ENCRYPTION_KEYS key ids must be 1 to {MAX_KEY_ID_LENGTH} letters, digits, '-' or '_', got {id:?}"
+        ));
+      }
+      if secret.trim().is_empty() {
+        return Err(format!("MFA_ENCRYPTION_KEYS key {id} has an empty secret"));
+      }
+      if keys.iter().any(|(existing, _)| existing == id) {
+        return Err(format!("MFA_ENCRYPTION_KEYS lists key {id} more than once"));
+      }
+      keys.push((id.to_string(), derive_encryption_key(secret)));
+    }
+    if legacy.is_none() && keys.is_empty() {
+      return Err("MFA_ENCRYPTION_KEY or MFA_ENCRYPTION_KEYS must be set".to_string());
+    }
+
+    Ok(Self {
+      legacy,
+      labeled: keys,
+    })
+  }
+
+  /// The prefix new values are written with, such as `v1` or `v2:2026-10`.
+  pub fn current_version(&self) -> String {
+    match self.labeled.last() {
+      Some((id, _)) => format!("{LABELED_KEY_VERSION}:{id}"),
+      None => LEGACY_KEY_VERSION.to_string(),
+    }
+  }
+
+  /// Raw bytes of the newest key, for keyed hashes of values that are not
+  /// stored encrypted.
+  pub fn current_key(&self) -> &[u8; 32] {
+    match (self.labeled.last(), self.legacy.as_ref()) {
+      (Some((_, key)), _) => key,
+      (None, Some(key)) => key,
+      (None, None) => unreachable!("an encryption key set always has a key"),
+    }
+  }
+
+  pub fn encrypt(&self, secret: &[u8]) -> Result<String, AuthError> {
+    let cipher = Aes256Gcm::new_from_slice(self.current_key())
+      .map_err(|e| AuthError::InternalError(format!("failed to initialize MFA cipher: {e}")))?;
+    let mut nonce_bytes = [0u8; 12];
+    rand::rng().fill_bytes(&mut nonce_bytes);
+    let ciphertext = cipher
+      .encrypt(Nonce::from_slice(&nonce_bytes), secret)
+      .map_err(|e| AuthError::InternalError(format!("failed to encrypt MFA secret: {e}")))?;
+
+    Ok(format!(
+      "{}.{}.{}",
+      self.current_version(),
+      URL_SAFE_NO_PAD.encode(nonce_bytes),
+      URL_SAFE_NO_PAD.encode(ciphertext)
+    ))
+  }
+
+  pub fn decrypt(&self, value: &str) -> Result<Vec<u8>, AuthError> {
+    let mut parts = value.split('.');
+    let version = parts.next().ok_or(AuthError::InvalidToken)?;
+    let nonce_b64 = parts.next().ok_or(AuthError::InvalidToken)?;
+    let ciphertext_b64 = parts.next().ok_or(AuthError::InvalidToken)?;
+    if parts.next().is_some() {
+      return Err(AuthError::InvalidToken);
+    }
+    let key = self.key_for_version(version).ok_or(AuthError::InvalidToken)?;
+
+    let nonce_bytes = URL_SAFE_NO_PAD
+      .decode(nonce_b64)
+      .map_err(|_| AuthError::InvalidToken)?;
+    let ciphertext = URL_SAFE_NO_PAD
+      .decode(ciphertext_b64)
+      .map_err(|_| AuthError::InvalidToken)?;
+    let cipher = Aes256Gcm::new_from_slice(key)
+      .map_err(|e| AuthError::InternalError(format!("failed to initialize MFA cipher: {e}")))?;
 
-  if version != ENCRYPTION_VERSION || parts.next().is_some() {
-    return Err(AuthError::InvalidToken);
+    cipher
+      .decrypt(Nonce::from_slice(&nonce_bytes), ciphertext.as_ref())
+      .map_err(|_| AuthError::InvalidToken)
   }
 
-  let nonce_bytes = URL_SAFE_NO_PAD
-    .decode(nonce_b64)
-    .map_err(|_| AuthError::InvalidToken)?;
-  let ciphertext = URL_SAFE_NO_PAD
-    .decode(ciphertext_b64)
-    .map_err(|_| AuthError::InvalidToken)?;
-  let cipher = Aes256Gcm::new_from_slice(key_material)
-    .map_err(|e| AuthError::InternalError(format!("failed to initialize MFA cipher: {e}")))?;
+  fn key_for_version(&self, version: &str) -> Option<&[u8; 32]> {
+    if version == LEGACY_KEY_VERSION {
+      return self.legacy.as_ref();
+    }
+    let id = version.strip_prefix(LABELED_KEY_VERSION)?.strip_prefix(':')?;
+    self
+      .labeled
+      .iter()
+      .find(|(label, _)| label == id)
+      .map(|(_, key)| key)
+  }
+}
+
+/// Generates the numeric code sent to a phone factor.
+pub fn generate_phone_code() -> String {
+  let code = rand::rng().random_range(0..10u32.pow(PHONE_CODE_DIGITS));
+  format!("{code:0width$}", width = PHONE_CODE_DIGITS as usize)
+}
+
+/// Hash stored for a phone code. The challenge ID is mixed in so equal codes
+/// on different challenges hash differently.
+pub fn hash_phone_code(challenge_id: uuid::Uuid, code: &str) -> String {
+  crate::utils::sha256_hex(&format!("{challenge_id}:{}", code.trim()))
+}
+
+pub fn phone_code_message(site_name: &str, code: &str) -> String {
+  format!("Your {site_name} verification code is {code}")
+}
+
+/// Generates a set of recovery codes formatted as `xxxxx-xxxxx`.
+pub fn generate_recovery_codes() -> Vec<String> {
+  let mut rng = rand::rng();
+  (0..RECOVERY_CODE_COUNT)
+    .map(|_| {
+      let mut code = String::with_capacity(RECOVERY_CODE_HALF_LENGTH * 2 + 1);
+      for index in 0..RECOVERY_CODE_HALF_LENGTH * 2 {
+        if index == RECOVERY_CODE_HALF_LENGTH {
+          code.push('-');
+        }
+        code.push(RECOVERY_CODE_ALPHABET[rng.random_range(0..RECOVERY_CODE_ALPHABET.len())] as char);
+      }
+      code
+    })
+    .collect()
+}
 
-  cipher
-    .decrypt(Nonce::from_slice(&nonce_bytes), ciphertext.as_ref())
-    .map_err(|_| AuthError::InvalidToken)
+/// Hash stored for a recovery code. Case, dashes and whitespace are ignored
+/// so a code copied with different formatting still matches.
+pub fn hash_recovery_code(code: &str) -> String {
+  let normalized: String = code
+    .chars()
+    .filter(|c| *c != '-' && !c.is_whitespace())
+    .map(|c| c.to_ascii_lowercase())
+    .collect();
+  crate::utils::sha256_hex(&normalized)
 }
 
-pub fn build_otpauth_url(issuer: &str, account_name: &str, secret_b32: &str) -> Result<String, AuthError> {
+pub fn build_otpauth_url(
+  issuer: &str,
+  account_name: &str,
+  secret_b32: &str,
+  params: &TotpParams,
+) -> Result<String, AuthError> {
   let label = format!("{issuer}:{account_name}");
   let mut url = Url::parse(&format!("otpauth://totp/{label}"))
     .map_err(|e| AuthError::InternalError(format!("failed to build otpauth url: {e}")))?;
@@ -105,19 +441,25 @@ pub fn build_otpauth_url(issuer: &str, account_name: &str, secret_b32: &str) ->
     .query_pairs_mut()
     .append_pair("secret", secret_b32)
     .append_pair("issuer", issuer)
-    .append_pair("algorithm", "SHA1")
-    .append_pair("digits", &TOTP_DIGITS.to_string())
-    .append_pair("period", &TOTP_PERIOD_SECS.to_string());
+    .append_pair("algorithm", params.algorithm.as_str())
+    .append_pair("digits", &params.digits.to_string())
+    .append_pair("period", &params.period.to_string());
   Ok(url.to_string())
 }
 
-pub fn matching_code_step(secret: &[u8], code: &str, now: i64) -> Result<Option<i64>, AuthError> {
-  let normalized = normalize_code(code)?;
-  let counter = now.div_euclid(TOTP_PERIOD_SECS);
+pub fn matching_code_step(
+  secret: &[u8],
+  code: &str,
+  now: i64,
+  params: &TotpParams,
+  skew_steps: i64,
+) -> Result<Option<i64>, AuthError> {
+  let normalized = normalize_code(code, params.digits)?;
+  let counter = now.div_euclid(params.period);
 
-  for step in -TOTP_SKEW_STEPS..=TOTP_SKEW_STEPS {
+  for step in -skew_steps..=skew_steps {
     let candidate = counter + step;
-    if generate_totp(secret, candidate)? == normalized {
+    if generate_totp(secret, candidate, params)? == normalized {
       return Ok(Some(candidate));
     }
   }
@@ -125,12 +467,12 @@ pub fn matching_code_step(secret: &[u8], code: &str, now: i64) -> Result<Option<
   Ok(None)
 }
 
-fn normalize_code(code: &str) -> Result<u32, AuthError> {
+fn normalize_code(code: &str, digits: u32) -> Result<u32, AuthError> {
   let trimmed = code.trim();
-  if trimmed.len() != TOTP_DIGITS as usize || !trimmed.chars().all(|c| c.is_ascii_digit()) {
-    return Err(AuthError::ValidationFailed(
-      "TOTP code must be a 6-digit number".to_string(),
-    ));
+  if trimmed.len() != digits as usize || !trimmed.chars().all(|c| c.is_ascii_digit()) {
+    return Err(AuthError::ValidationFailed(format!(
+      "TOTP code must be {digits} digits"
+    )));
   }
 
   trimmed
@@ -138,21 +480,28 @@ fn normalize_code(code: &str) -> Result<u32, AuthError> {
     .map_err(|_| AuthError::ValidationFailed("TOTP code must be numeric".to_string()))
 }
 
-fn generate_totp(secret: &[u8], counter: i64) -> Result<u32, AuthError> {
-  let mut mac = <HmacSha1 as Mac>::new_from_slice(secret)
-    .map_err(|e| AuthError::InternalError(format!("failed to initialize TOTP HMAC: {e}")))?;
-  let mut msg = [0u8; 8];
-  msg.copy_from_slice(&(counter as u64).to_be_bytes());
-  mac.update(&msg);
-  let result = mac.finalize().into_bytes();
+fn generate_totp(secret: &[u8], counter: i64, params: &TotpParams) -> Result<u32, AuthError> {
+  let msg = (counter as u64).to_be_bytes();
+  let result = match params.algorithm {
+    TotpAlgorithm::Sha1 => totp_hmac::<HmacSha1>(secret, &msg)?,
+    TotpAlgorithm::Sha256 => totp_hmac::<HmacSha256>(secret, &msg)?,
+    TotpAlgorithm::Sha512 => totp_hmac::<HmacSha512>(secret, &msg)?,
+  };
 
-  let offset = (result[19] & 0x0f) as usize;
+  let offset = (result[result.len() - 1] & 0x0f) as usize;
   let binary = ((u32::from(result[offset]) & 0x7f) << 24)
     | (u32::from(result[offset + 1]) << 16)
     | (u32::from(result[offset + 2]) << 8)
     | u32::from(result[offset + 3]);
 
-  Ok(binary % 10u32.pow(TOTP_DIGITS))
+  Ok(binary % 10u32.pow(params.digits))
+}
+
+fn totp_hmac<M: Mac + hmac::digest::KeyInit>(secret: &[u8], msg: &[u8]) -> Result<Vec<u8>, AuthError> {
+  let mut mac = <M as Mac>::new_from_slice(secret)
+    .map_err(|e| AuthError::InternalError(format!("failed to initialize TOTP HMAC: {e}")))?;
+  mac.update(msg);
+  Ok(mac.finalize().into_bytes().to_vec())
 }
 
 #[cfg(test)]
@@ -161,28 +510,221 @@ mod tests {
 
   #[test]
   fn encryption_round_trip_preserves_secret() {
-    let key = derive_encryption_key("test-material");
-    let secret = generate_totp_secret();
-    let encrypted = encrypt_secret(&secret, &key).unwrap();
-    let decrypted = decrypt_secret(&encrypted, &key).unwrap();
+    let keys = EncryptionKeys::legacy(derive_encryption_key("test-material"));
+    let secret = generate_totp_secret(TotpAlgorithm::Sha1);
+    let encrypted = keys.encrypt(&secret).unwrap();
+    let decrypted = keys.decrypt(&encrypted).unwrap();
 
+    assert!(encrypted.starts_with("v1."));
     assert_eq!(secret, decrypted);
   }
 
+  #[test]
+  fn rotated_keys_decrypt_older_values_and_encrypt_with_the_newest() {
+    let before = EncryptionKeys::from_config(Some("legacy-material"), Some("2025:old-material")).unwrap();
+    let legacy_value = EncryptionKeys::legacy(derive_encryption_key("legacy-material"))
+      .encrypt(b"legacy")
+      .unwrap();
+    let old_value = before.encrypt(b"old").unwrap();
+    assert!(old_value.starts_with("v2:2025."));
+
+    let after = EncryptionKeys::from_config(
+      Some("legacy-material"),
+      Some("2025:old-material, 2026:new-material"),
+    )
+    .unwrap();
+    assert_eq!(after.decrypt(&legacy_value).unwrap(), b"legacy");
+    assert_eq!(after.decrypt(&old_value).unwrap(), b"old");
+
+    let new_value = after.encrypt(b"new").unwrap();
+    assert_eq!(after.current_version(), "v2:2026");
+    assert!(new_value.starts_with("v2:2026."));
+    assert!(before.decrypt(&new_value).is_err());
+  }
+
+  #[test]
+  fn encryption_keys_config_is_validated() {
+    assert!(EncryptionKeys::from_config(None, None).is_err());
+    assert!(EncryptionKeys::from_config(None, Some("no-secret")).is_err());
+    assert!(EncryptionKeys::from_config(None, Some("a.b:secret")).is_err());
+    assert!(EncryptionKeys::from_config(None, Some("a:one,a:two")).is_err());
+    assert!(EncryptionKeys::from_config(None, Some("a:")).is_err());
+    assert!(EncryptionKeys::from_config(None, Some("a:one,")).is_ok());
+  }
+
   #[test]
   fn rfc_totp_vector_is_accepted() {
     let secret = b"12345678901234567890";
-    assert!(matching_code_step(secret, "287082", 59).unwrap().is_some());
-    assert_eq!(matching_code_step(secret, "287082", 59).unwrap(), Some(1));
+    let params = TotpParams::default();
+    assert!(
+      matching_code_step(secret, "287082", 59, &params, 1)
+        .unwrap()
+        .is_some()
+    );
+    assert_eq!(
+      matching_code_step(secret, "287082", 59, &params, 1).unwrap(),
+      Some(1)
+    );
+  }
+
+  #[test]
+  fn rfc_totp_vectors_match_each_algorithm() {
+    let sha1 = b"12345678901234567890".as_slice();
+    let sha256 = b"12345678901234567890123456789012".as_slice();
+    let sha512 = b"1234567890123456789012345678901234567890123456789012345678901234".as_slice();
+    let cases = [
+      (TotpAlgorithm::Sha1, sha1, 59, "94287082"),
+      (TotpAlgorithm::Sha256, sha256, 59, "46119246"),
+      (TotpAlgorithm::Sha512, sha512, 59, "90693936"),
+      (TotpAlgorithm::Sha256, sha256, 1111111109, "68084774"),
+      (TotpAlgorithm::Sha512, sha512, 1111111109, "25091201"),
+    ];
+    for (algorithm, secret, now, code) in cases {
+      let params = TotpParams::new(algorithm, 8, 30).unwrap();
+      assert_eq!(
+        matching_code_step(secret, code, now, &params, 0).unwrap(),
+        Some(now / 30),
+        "{algorithm:?} at {now}"
+      );
+    }
+  }
+
+  #[test]
+  fn code_length_and_skew_follow_parameters() {
+    let secret = b"12345678901234567890";
+    let params = TotpParams::new(TotpAlgorithm::Sha1, 8, 30).unwrap();
+    assert!(matching_code_step(secret, "287082", 59, &params, 1).is_err());
+    assert_eq!(
+      matching_code_step(secret, "94287082", 89, &params, 1).unwrap(),
+      Some(1)
+    );
+    assert_eq!(
+      matching_code_step(secret, "94287082", 89, &params, 0).unwrap(),
+      None
+    );
+  }
+
+  #[test]
+  fn totp_policy_is_validated() {
+    assert_eq!(
+      TotpPolicy::new("sha256", 8, 30, 1).unwrap().enrollment.algorithm,
+      TotpAlgorithm::Sha256
+    );
+    assert!(TotpPolicy::new("md5", 6, 30, 1).is_err());
+    assert!(TotpPolicy::new("SHA1", 5, 30, 1).is_err());
+    assert!(TotpPolicy::new("SHA1", 6, 5, 1).is_err());
+    assert!(TotpPolicy::new("SHA1", 6, 30, -1).is_err());
+    assert_eq!(
+      TotpParams::from_stored("SHA1", 6, 30).unwrap(),
+      TotpParams::default()
+    );
+    assert!(TotpParams::from_stored("SHA1", -6, 30).is_err());
+  }
+
+  #[test]
+  fn enrollment_overrides_stay_within_the_allowed_set() {
+    let defaults = TotpParams::default();
+    assert_eq!(defaults.with_overrides(None, None, None).unwrap(), defaults);
+    assert_eq!(
+      defaults.with_overrides(Some("sha512"), Some(8), None).unwrap(),
+      TotpParams::new(TotpAlgorithm::Sha512, 8, defaults.period).unwrap()
+    );
+    assert!(defaults.with_overrides(Some("md5"), None, None).is_err());
+    assert!(defaults.with_overrides(None, Some(10), None).is_err());
+    assert!(defaults.with_overrides(None, None, Some(15)).is_ok());
+
+    let strict = TotpParams::new(TotpAlgorithm::Sha256, 8, 30).unwrap();
+    assert!(strict.with_overrides(Some("SHA1"), None, None).is_err());
+    assert!(strict.with_overrides(None, Some(6), None).is_err());
+    assert!(strict.with_overrides(None, None, Some(60)).is_err());
+    assert_eq!(
+      strict
+        .with_overrides(Some("SHA512"), None, None)
+        .unwrap()
+        .algorithm,
+      TotpAlgorithm::Sha512
+    );
+  }
+
+  #[test]
+  fn phone_codes_are_six_digits() {
+    for _ in 0..100 {
+      let code = generate_phone_code();
+      assert_eq!(code.len(), 6);
+      assert!(code.chars().all(|c| c.is_ascii_digit()));
+    }
+  }
+
+  #[test]
+  fn phone_code_hash_depends_on_challenge() {
+    let first = uuid::Uuid::new_v4();
+    let second = uuid::Uuid::new_v4();
+
+    assert_eq!(
+      hash_phone_code(first, "123456"),
+      hash_phone_code(first, " 123456 ")
+    );
+    assert_ne!(
+      hash_phone_code(first, "123456"),
+      hash_phone_code(second, "123456")
+    );
+  }
+
+  #[test]
+  fn recovery_codes_are_distinct_and_formatted() {
+    let codes = generate_recovery_codes();
+    let unique: std::collections::HashSet<_> = codes.iter().collect();
+
+    assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+    assert_eq!(unique.len(), codes.len());
+    for code in &codes {
+      assert_eq!(code.len(), 11);
+      assert_eq!(code.as_bytes()[5], b'-');
+    }
+  }
+
+  #[test]
+  fn recovery_code_hash_ignores_formatting() {
+    assert_eq!(
+      hash_recovery_code("abcde-fghjk"),
+      hash_recovery_code(" ABCDE FGHJK ")
+    );
+    assert_eq!(
+      hash_recovery_code("abcde-fghjk"),
+      hash_recovery_code("abcdefghjk")
+    );
+    assert_ne!(
+      hash_recovery_code("abcde-fghjk"),
+      hash_recovery_code("abcde-fghjm")
+    );
   }
 
   #[test]
   fn otpauth_url_contains_expected_fields() {
-    let url = build_otpauth_url("Haya", "user@example.com", "JBSWY3DPEHPK3PXP").unwrap();
+    let url = build_otpauth_url(
+      "Haya",
+      "user@example.com",
+      "JBSWY3DPEHPK3PXP",
+      &TotpParams::default(),
+    )
+    .unwrap();
 
     assert!(url.starts_with("otpauth://totp/Haya:user@example.com?"));
     assert!(url.contains("secret=JBSWY3DPEHPK3PXP"));
     assert!(url.contains("issuer=Haya"));
     assert!(url.contains("algorithm=SHA1"));
+    assert!(url.contains("digits=6"));
+    assert!(url.contains("period=30"));
+
+    let params = TotpParams::new(TotpAlgorithm::Sha256, 8, 60).unwrap();
+    let url = build_otpauth_url("Haya", "user@example.com", "JBSWY3DPEHPK3PXP", &params).unwrap();
+    assert!(url.contains("algorithm=SHA256&digits=8&period=60"));
+  }
+
+  #[test]
+  fn totp_secret_length_follows_algorithm() {
+    assert_eq!(generate_totp_secret(TotpAlgorithm::Sha1).len(), 20);
+    assert_eq!(generate_totp_secret(TotpAlgorithm::Sha256).len(), 32);
+    assert_eq!(generate_totp_secret(TotpAlgorithm::Sha512).len(), 64);
   }
 }
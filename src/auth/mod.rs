@@ -1,7 +1,18 @@
 pub mod audit;
+pub mod binding;
+pub mod breached_password;
+pub mod captcha;
+pub mod hook;
 pub mod jwt;
+pub mod keyring;
 pub mod mfa;
+pub mod oauth;
 pub mod oidc;
 pub mod password;
+pub mod password_policy;
+pub mod pkce;
 pub mod rate_limit;
 pub mod session;
+pub mod session_limits;
+pub mod trusted_device;
+pub mod webauthn;
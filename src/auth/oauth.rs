@@ -0,0 +1,713 @@
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{
+  DateTime,
+  Utc,
+};
+use serde::Serialize;
+use serde_json::{
+  Map,
+  Value,
+};
+use sqlx::{
+  FromRow,
+  PgPool,
+};
+use uuid::Uuid;
+
+use crate::auth::binding::SessionBindingPolicy;
+use crate::auth::jwt;
+use crate::error::{
+  AuthError,
+  Result,
+};
+use crate::model::{
+  OAuthTokenResponse,
+  User,
+};
+use crate::state::AppState;
+use crate::utils::{
+  constant_time_eq,
+  sha256_hex,
+};
+
+pub const AUTH_METHOD_CLIENT_SECRET_BASIC: &str = "client_secret_basic";
+pub const AUTH_METHOD_CLIENT_SECRET_POST: &str = "client_secret_post";
+pub const AUTH_METHOD_NONE: &str = "none";
+pub const TOKEN_ENDPOINT_AUTH_METHODS: &[&str] = &[
+  AUTH_METHOD_CLIENT_SECRET_BASIC,
+  AUTH_METHOD_CLIENT_SECRET_POST,
+  AUTH_METHOD_NONE,
+];
+
+pub const GRANT_AUTHORIZATION_CODE: &str = "authorization_code";
+pub const GRANT_REFRESH_TOKEN: &str = "refresh_token";
+pub const GRANT_CLIENT_CREDENTIALS: &str = "client_credentials";
+pub const GRANT_DEVICE_CODE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+pub const GRANT_TYPES: &[&str] = &[
+  GRANT_AUTHORIZATION_CODE,
+  GRANT_REFRESH_TOKEN,
+  GRANT_CLIENT_CREDENTIALS,
+  GRANT_DEVICE_CODE,
+];
+
+/// RFC 8693 token exchange. Served on `/token` for admin impersonation, not to
+/// OAuth clients, so it is not part of `GRANT_TYPES`.
+pub const GRANT_TOKEN_EXCHANGE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+pub const TOKEN_TYPE_ACCESS_TOKEN: &str = "urn:ietf:params:oauth:token-type:access_token";
+/// Haya-specific subject token type whose value is the target user's ID
+pub const TOKEN_TYPE_USER_ID: &str = "urn:haya:params:oauth:token-type:user_id";
+
+pub const SCOPE_OPENID: &str = "openid";
+pub const SUPPORTED_SCOPES: &[&str] = &[SCOPE_OPENID, "email", "profile", "phone"];
+pub const SUPPORTED_CLAIMS: &[&str] = &[
+  "sub",
+  "email",
+  "email_verified",
+  "phone_number",
+  "phone_number_verified",
+  "name",
+  "picture",
+  "preferred_username",
+  "updated_at",
+];
+
+const OAUTH_CLIENT_SELECT_SQL: &str = "SELECT id, client_id, client_secret_hash, name, redirect_uris, grant_types, scopes, token_endpoint_auth_method, role, session_binding, disabled_at, created_at, updated_at FROM auth.oauth_clients";
+
+/// A client application registered to sign users in through Haya.
+#[derive(Debug, Clone, Serialize)]
+pub struct OAuthClient {
+  pub id: Uuid,
+  pub client_id: String,
+  #[serde(skip)]
+  pub client_secret_hash: Option<String>,
+  pub name: String,
+  pub redirect_uris: Vec<String>,
+  pub grant_types: Vec<String>,
+  pub scopes: Vec<String>,
+  pub token_endpoint_auth_method: String,
+  /// Role claim of `client_credentials` tokens
+  pub role: Option<String>,
+  /// Overrides `SESSION_BINDING_POLICY` for this client's sessions
+  pub session_binding: Option<SessionBindingPolicy>,
+  pub disabled_at: Option<DateTime<Utc>>,
+  pub created_at: Option<DateTime<Utc>>,
+  pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, FromRow)]
+struct OAuthClientRow {
+  id: Uuid,
+  client_id: String,
+  client_secret_hash: Option<String>,
+  name: String,
+  redirect_uris: Value,
+  grant_types: Value,
+  scopes: Value,
+  token_endpoint_auth_method: String,
+  role: Option<String>,
+  session_binding: Option<String>,
+  disabled_at: Option<DateTime<Utc>>,
+  created_at: Option<DateTime<Utc>>,
+  updated_at: Option<DateTime<Utc>>,
+}
+
+impl OAuthClientRow {
+  fn into_client(self) -> Result<OAuthClient> {
+    let strings = |value: Value, field: &str| {
+      serde_json::from_value::<Vec<String>>(value)
+        .map_err(|e| AuthError::InternalError(format!("invalid OAuth client field {field}: {e}")))
+    };
+
+    Ok(OAuthClient {
+      id: self.id,
+      client_id: self.client_id,
+      client_secret_hash: self.client_secret_hash,
+      name: self.name,
+      redirect_uris: strings(self.redirect_uris, "redirect_uris")?,
+      grant_types: strings(self.grant_types, "grant_types")?,
+      scopes: strings(self.scopes, "scopes")?,
+      token_endpoint_auth_method: self.token_endpoint_auth_method,
+      role: self.role,
+      session_binding: self
+        .session_binding
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(|e| AuthError::InternalError(format!("invalid OAuth client field session_binding: {e}")))?,
+      disabled_at: self.disabled_at,
+      created_at: self.created_at,
+      updated_at: self.updated_at,
+    })
+  }
+}
+
+pub async fn find_client(db: &PgPool, client_id: &str) -> Result<Option<OAuthClient>> {
+  sqlx::query_as::<_, OAuthClientRow>(&format!("{OAUTH_CLIENT_SELECT_SQL} WHERE client_id = $1"))
+    .bind(client_id)
+    .fetch_optional(db)
+    .await?
+    .map(OAuthClientRow::into_client)
+    .transpose()
+}
+
+pub async fn list_clients(db: &PgPool) -> Result<Vec<OAuthClient>> {
+  sqlx::query_as::<_, OAuthClientRow>(&format!("{OAUTH_CLIENT_SELECT_SQL} ORDER BY name ASC"))
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(OAuthClientRow::into_client)
+    .collect()
+}
+
+impl OAuthClient {
+  pub fn is_public(&self) -> bool {
+    self.token_endpoint_auth_method == AUTH_METHOD_NONE
+  }
+
+  pub fn is_disabled(&self) -> bool {
+    self.disabled_at.is_some()
+  }
+
+  pub fn allows_grant(&self, grant_type: &str) -> bool {
+    self.grant_types.iter().any(|grant| grant == grant_type)
+  }
+
+  /// Redirect URIs must match a registered value exactly (RFC 6749 section
+  /// 3.1.2.3). An omitted `redirect_uri` resolves to the only registered one.
+  pub fn resolve_redirect_uri(&self, requested: Option<&str>) -> Option<String> {
+    match requested {
+      Some(uri) => self
+        .redirect_uris
+        .iter()
+        .find(|registered| *registered == uri)
+        .cloned(),
+      None if self.redirect_uris.len() == 1 => self.redirect_uris.first().cloned(),
+      None => None,
+    }
+  }
+
+  /// Returns the granted scope string, defaulting to every scope the client
+  /// is registered for.
+  pub fn resolve_scope(&self, requested: Option<&str>) -> Result<String> {
+    let requested: Vec<&str> = requested
+      .map(|value| value.split_whitespace().collect())
+      .unwrap_or_default();
+    if requested.is_empty() {
+      return Ok(self.scopes.join(" "));
+    }
+
+    if let Some(scope) = requested
+      .iter()
+      .find(|scope| !self.scopes.iter().any(|allowed| allowed == *scope))
+    {
+      return Err(AuthError::oauth(
+        "invalid_scope",
+        format!("scope {scope} is not allowed for this client"),
+      ));
+    }
+    let mut granted: Vec<&str> = Vec::new();
+    for scope in requested {
+      if !granted.contains(&scope) {
+        granted.push(scope);
+      }
+    }
+    Ok(granted.join(" "))
+  }
+
+  /// Whether this client may introspect or revoke a token. Tokens issued to a
+  /// client belong to it alone; first-party Haya tokens (`owner` is `None`)
+  /// are open to confidential clients such as an API gateway.
+  pub fn may_access_token(&self, owner: Option<&str>) -> bool {
+    match owner {
+      Some(owner) => owner == self.client_id,
+      None => !self.is_public(),
+    }
+  }
+
+  fn verify_secret(&self, secret: &str) -> bool {
+    self
+      .client_secret_hash
+      .as_deref()
+      .is_some_and(|hash| constant_time_eq(hash, &hash_client_secret(secret)))
+  }
+}
+
+pub fn hash_client_secret(secret: &str) -> String {
+  sha256_hex(secret)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCredentials {
+  pub client_id: String,
+  pub client_secret: Option<String>,
+  pub method: &'static str,
+}
+
+/// Reads client credentials from an HTTP Basic `Authorization` header or,
+/// failing that, from the `client_id` / `client_secret` form fields.
+pub fn client_credentials(
+  authorization: Option<&str>,
+  client_id: Option<&str>,
+  client_secret: Option<&str>,
+) -> Result<ClientCredentials> {
+  if let Some(header) = authorization {
+    let (scheme, encoded) = header
+      .split_once(' ')
+      .ok_or_else(|| AuthError::oauth("invalid_client", "malformed Authorization header"))?;
+    if scheme.eq_ignore_ascii_case("basic") {
+      let decoded = STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| AuthError::oauth("invalid_client", "malformed Authorization header"))?;
+      let (id, secret) = decoded
+        .split_once(':')
+        .ok_or_else(|| AuthError::oauth("invalid_client", "malformed Authorization header"))?;
+      return Ok(ClientCredentials {
+        client_id: form_decode(id),
+        client_secret: Some(form_decode(secret)),
+        method: AUTH_METHOD_CLIENT_SECRET_BASIC,
+      });
+    }
+  }
+
+  let client_id = client_id
+    .filter(|value| !value.is_empty())
+    .ok_or_else(|| AuthError::oauth("invalid_client", "client authentication is required"))?;
+  Ok(ClientCredentials {
+    client_id: client_id.to_string(),
+    client_secret: client_secret.map(str::to_string),
+    method: if client_secret.is_some() {
+      AUTH_METHOD_CLIENT_SECRET_POST
+    } else {
+      AUTH_METHOD_NONE
+    },
+  })
+}
+
+/// Basic credentials are form-urlencoded before base64 (RFC 6749 section 2.3.1).
+fn form_decode(value: &str) -> String {
+  url::form_urlencoded::parse(value.as_bytes())
+    .map(|(key, _)| key.into_owned())
+    .next()
+    .unwrap_or_default()
+}
+
+pub async fn authenticate_client(db: &PgPool, credentials: &ClientCredentials) -> Result<OAuthClient> {
+  let client = find_client(db, &credentials.client_id)
+    .await?
+    .filter(|client| !client.is_disabled())
+    .ok_or_else(|| AuthError::oauth("invalid_client", "client authentication failed"))?;
+
+  if credentials.method != client.token_endpoint_auth_method {
+    return Err(AuthError::oauth("invalid_client", "client authentication failed"));
+  }
+  if !client.is_public()
+    && !credentials
+      .client_secret
+      .as_deref()
+      .is_some_and(|secret| client.verify_secret(secret))
+  {
+    return Err(AuthError::oauth("invalid_client", "client authentication failed"));
+  }
+  Ok(client)
+}
+
+pub fn scope_contains(scope: &str, value: &str) -> bool {
+  scope.split_whitespace().any(|granted| granted == value)
+}
+
+/// Refuses the `openid` scope unless an asymmetric key can sign the id_token.
+pub fn ensure_openid_supported(keys: &jwt::JwtKeyring, scope: &str) -> Result<()> {
+  if scope_contains(scope, SCOPE_OPENID) && keys.id_token_signing_key().is_none() {
+    return Err(AuthError::oauth(
+      "invalid_scope",
+      "the openid scope needs an asymmetric JWT_ALGORITHM",
+    ));
+  }
+  Ok(())
+}
+
+/// Standard OpenID Connect claims released for `scope`, shared by the
+/// `id_token` and `/oauth/userinfo`.
+pub fn user_claims(user: &User, scope: &str) -> Map<String, Value> {
+  let mut claims = Map::new();
+  claims.insert("sub".to_string(), Value::String(user.id.to_string()));
+
+  if scope_contains(scope, "email")
+    && let Some(email) = user.email.as_deref()
+  {
+    claims.insert("email".to_string(), Value::String(email.to_string()));
+    claims.insert(
+      "email_verified".to_string(),
+      Value::Bool(user.email_confirmed_at.is_some()),
+    );
+  }
+
+  if scope_contains(scope, "phone")
+    && let Some(phone) = user.phone.as_deref()
+  {
+    claims.insert("phone_number".to_string(), Value::String(phone.to_string()));
+    claims.insert(
+      "phone_number_verified".to_string(),
+      Value::Bool(user.phone_confirmed_at.is_some()),
+    );
+  }
+
+  if scope_contains(scope, "profile") {
+    let metadata = user.raw_user_meta_data.as_ref();
+    let metadata_string = |keys: &[&str]| {
+      keys.iter().find_map(|key| {
+        metadata
+          .and_then(|value| value.get(*key))
+          .and_then(Value::as_str)
+          .filter(|value| !value.is_empty())
+          .map(|value| Value::String(value.to_string()))
+      })
+    };
+    if let Some(name) = metadata_string(&["name", "full_name"]) {
+      claims.insert("name".to_string(), name);
+    }
+    if let Some(picture) = metadata_string(&["avatar_url", "picture"]) {
+      claims.insert("picture".to_string(), picture);
+    }
+    if let Some(username) = metadata_string(&["preferred_username", "user_name"]) {
+      claims.insert("preferred_username".to_string(), username);
+    }
+    if let Some(updated_at) = user.updated_at {
+      claims.insert("updated_at".to_string(), Value::from(updated_at.timestamp()));
+    }
+  }
+
+  claims
+}
+
+#[derive(Debug)]
+pub struct IdTokenParams<'a> {
+  pub issuer: &'a str,
+  pub client_id: &'a str,
+  pub user: &'a User,
+  pub scope: &'a str,
+  pub nonce: Option<&'a str>,
+  pub auth_time: i64,
+  pub aal: &'a str,
+  pub amr: &'a [jwt::AmrEntry],
+  pub signing_key: &'a jwt::JwtKey,
+  pub jwt_exp: i64,
+}
+
+pub fn encode_id_token(params: IdTokenParams<'_>) -> Result<String> {
+  if params.signing_key.public_jwk().is_none() {
+    return Err(AuthError::InternalError(
+      "id_tokens must not be signed with a shared secret".to_string(),
+    ));
+  }
+  let now = Utc::now().timestamp();
+  let mut claims = user_claims(params.user, params.scope);
+  claims.insert("iss".to_string(), Value::String(params.issuer.to_string()));
+  claims.insert("aud".to_string(), Value::String(params.client_id.to_string()));
+  claims.insert("iat".to_string(), Value::from(now));
+  claims.insert("exp".to_string(), Value::from(now + params.jwt_exp));
+  claims.insert("auth_time".to_string(), Value::from(params.auth_time));
+  claims.insert("acr".to_string(), Value::String(params.aal.to_string()));
+  claims.insert(
+    "amr".to_string(),
+    Value::from(
+      params
+        .amr
+        .iter()
+        .map(|entry| entry.method.clone())
+        .collect::<Vec<_>>(),
+    ),
+  );
+  if let Some(nonce) = params.nonce {
+    claims.insert("nonce".to_string(), Value::String(nonce.to_string()));
+  }
+  jwt::encode_claims(params.signing_key, &claims)
+}
+
+/// Issues a short-lived `client_credentials` access token. It carries the
+/// client's role but no user or session, so Haya's own user endpoints reject it.
+pub async fn issue_client_credentials_token(
+  state: &AppState,
+  client: &OAuthClient,
+  requested_scope: Option<&str>,
+) -> Result<OAuthTokenResponse> {
+  let scope = client_credentials_scope(client, requested_scope)?;
+  let role = client.role.as_deref().ok_or_else(|| {
+    AuthError::oauth(
+      "unauthorized_client",
+      "client has no role for the client_credentials grant",
+    )
+  })?;
+
+  let now = Utc::now().timestamp();
+  let claims = jwt::ClientClaims {
+    sub: client.client_id.clone(),
+    aud: jwt::JWT_AUDIENCE.to_string(),
+    exp: now + state.client_credentials_jwt_exp,
+    iat: now,
+    iss: state.issuer.clone(),
+    role: role.to_string(),
+    client_id: client.client_id.clone(),
+    scope: (!scope.is_empty()).then(|| scope.clone()),
+  };
+  let signing_key = state.jwt_keys.read().await.signing_key().clone();
+
+  Ok(OAuthTokenResponse {
+    access_token: jwt::encode_claims(&signing_key, &claims)?,
+    token_type: "Bearer".to_string(),
+    expires_in: state.client_credentials_jwt_exp,
+    refresh_token: None,
+    id_token: None,
+    scope: claims.scope,
+  })
+}
+
+fn client_credentials_scope(client: &OAuthClient, requested_scope: Option<&str>) -> Result<String> {
+  if !client.allows_grant(GRANT_CLIENT_CREDENTIALS) {
+    return Err(AuthError::oauth(
+      "unauthorized_client",
+      "client is not allowed to use the client_credentials grant",
+    ));
+  }
+  if client.is_public() {
+    return Err(AuthError::oauth(
+      "unauthorized_client",
+      "public clients cannot use the client_credentials grant",
+    ));
+  }
+  client.resolve_scope(requested_scope)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample_client(method: &str) -> OAuthClient {
+    OAuthClient {
+      id: Uuid::nil(),
+      client_id: "grafana".to_string(),
+      client_secret_hash: (method != AUTH_METHOD_NONE).then(|| hash_client_secret("s3cret")),
+      name: "Grafana".to_string(),
+      redirect_uris: vec!["https://grafana.example.com/login/generic_oauth".to_string()],
+      grant_types: GRANT_TYPES.iter().map(|grant| grant.to_string()).collect(),
+      scopes: vec!["openid".to_string(), "email".to_string(), "profile".to_string()],
+      token_endpoint_auth_method: method.to_string(),
+      role: Some("service_worker".to_string()),
+      session_binding: None,
+      disabled_at: None,
+      created_at: None,
+      updated_at: None,
+    }
+  }
+
+  fn sample_user() -> User {
+    User {
+      id: Uuid::nil(),
+      instance_id: None,
+      aud: None,
+      role: None,
+      email: Some("user@example.com".to_string()),
+      encrypted_password: None,
+      email_confirmed_at: Some(Utc::now()),
+      phone: Some("+15555550123".to_string()),
+      phone_confirmed_at: None,
+      confirmed_at: None,
+      last_sign_in_at: None,
+      raw_app_meta_data: None,
+      raw_user_meta_data: Some(serde_json::json!({"full_name": "Jo Example"})),
+      is_super_admin: None,
+      is_sso_user: false,
+      is_anonymous: false,
+      banned_until: None,
+      deleted_at: None,
+      created_at: None,
+      updated_at: None,
+    }
+  }
+
+  #[test]
+  fn redirect_uris_must_match_exactly() {
+    let client = sample_client(AUTH_METHOD_CLIENT_SECRET_BASIC);
+
+    assert_eq!(
+      client.resolve_redirect_uri(None).as_deref(),
+      Some("https://grafana.example.com/login/generic_oauth")
+    );
+    assert!(
+      client
+        .resolve_redirect_uri(Some("https://grafana.example.com/login/generic_oauth/"))
+        .is_none()
+    );
+    assert!(
+      client
+        .resolve_redirect_uri(Some("https://evil.example.com/login/generic_oauth"))
+        .is_none()
+    );
+  }
+
+  #[test]
+  fn scope_defaults_to_registered_scopes_and_rejects_others() {
+    let client = sample_client(AUTH_METHOD_CLIENT_SECRET_BASIC);
+
+    assert_eq!(client.resolve_scope(None).unwrap(), "openid email profile");
+    assert_eq!(
+      client.resolve_scope(Some("openid  email openid")).unwrap(),
+      "openid email"
+    );
+    assert!(matches!(
+      client.resolve_scope(Some("openid phone")),
+      Err(AuthError::OAuth {
+        error: "invalid_scope",
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn clients_only_reach_their_own_or_first_party_tokens() {
+    let confidential = sample_client(AUTH_METHOD_CLIENT_SECRET_BASIC);
+    let public = sample_client(AUTH_METHOD_NONE);
+
+    assert!(confidential.may_access_token(Some(&confidential.client_id)));
+    assert!(confidential.may_access_token(None));
+    assert!(!confidential.may_access_token(Some("another-client")));
+    assert!(public.may_access_token(Some(&public.client_id)));
+    assert!(!public.may_access_token(None));
+  }
+
+  #[test]
+  fn client_credentials_need_a_confidential_client_with_the_grant() {
+    let mut client = sample_client(AUTH_METHOD_CLIENT_SECRET_BASIC);
+    client.scopes = vec!["jobs:read".to_string(), "jobs:write".to_string()];
+
+    assert_eq!(
+      client_credentials_scope(&client, None).unwrap(),
+      "jobs:read jobs:write"
+    );
+    assert_eq!(
+      client_credentials_scope(&client, Some("jobs:read")).unwrap(),
+      "jobs:read"
+    );
+    assert!(client_credentials_scope(&client, Some("admin")).is_err());
+
+    client.grant_types = vec![GRANT_AUTHORIZATION_CODE.to_string()];
+    assert!(matches!(
+      client_credentials_scope(&client, None),
+      Err(AuthError::OAuth {
+        error: "unauthorized_client",
+        ..
+      })
+    ));
+
+    let public = sample_client(AUTH_METHOD_NONE);
+    assert!(client_credentials_scope(&public, None).is_err());
+  }
+
+  #[test]
+  fn reads_basic_and_form_client_credentials() {
+    let header = format!("Basic {}", STANDARD.encode("my%3Aclient:pa%2Bss"));
+    let basic = client_credentials(Some(&header), None, None).unwrap();
+    assert_eq!(basic.client_id, "my:client");
+    assert_eq!(basic.client_secret.as_deref(), Some("pa+ss"));
+    assert_eq!(basic.method, AUTH_METHOD_CLIENT_SECRET_BASIC);
+
+    let post = client_credentials(None, Some("grafana"), Some("s3cret")).unwrap();
+    assert_eq!(post.method, AUTH_METHOD_CLIENT_SECRET_POST);
+
+    let public = client_credentials(None, Some("cli"), None).unwrap();
+    assert_eq!(public.method, AUTH_METHOD_NONE);
+
+    assert!(client_credentials(None, None, None).is_err());
+  }
+
+  #[test]
+  fn releases_claims_by_scope() {
+    let user = sample_user();
+
+    let openid = user_claims(&user, "openid");
+    assert_eq!(openid.len(), 1);
+
+    let claims = user_claims(&user, "openid email profile");
+    assert_eq!(claims["email"], "user@example.com");
+    assert_eq!(claims["email_verified"], true);
+    assert_eq!(claims["name"], "Jo Example");
+    assert!(!claims.contains_key("phone_number"));
+  }
+
+  fn es256_key() -> jwt::JwtKey {
+    let material = crate::auth::keyring::generate_key_material(jsonwebtoken::Algorithm::ES256).unwrap();
+    jwt::JwtKey::from_pem(jsonwebtoken::Algorithm::ES256, &material, None).unwrap()
+  }
+
+  #[test]
+  fn openid_needs_an_asymmetric_key() {
+    let hmac = jwt::JwtKeyring::new(jwt::JwtKey::hmac("test-secret-key"));
+    assert!(ensure_openid_supported(&hmac, "email profile").is_ok());
+    assert!(matches!(
+      ensure_openid_supported(&hmac, "openid email"),
+      Err(AuthError::OAuth {
+        error: "invalid_scope",
+        ..
+      })
+    ));
+    assert!(ensure_openid_supported(&jwt::JwtKeyring::new(es256_key()), "openid").is_ok());
+  }
+
+  #[test]
+  fn id_token_is_addressed_to_the_client() {
+    let key = es256_key();
+    let user = sample_user();
+    let token = encode_id_token(IdTokenParams {
+      issuer: "https://auth.example.com",
+      client_id: "grafana",
+      user: &user,
+      scope: "openid email",
+      nonce: Some("n-0S6_WzA2Mj"),
+      auth_time: 1_700_000_000,
+      aal: "aal1",
+      amr: &[jwt::AmrEntry {
+        method: "password".to_string(),
+        timestamp: 1_700_000_000,
+      }],
+      signing_key: &key,
+      jwt_exp: 3600,
+    })
+    .unwrap();
+
+    let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::ES256);
+    validation.set_audience(&["grafana"]);
+    validation.set_issuer(&["https://auth.example.com"]);
+    let claims = jsonwebtoken::decode::<Value>(
+      &token,
+      &jsonwebtoken::DecodingKey::from_jwk(key.public_jwk().unwrap()).unwrap(),
+      &validation,
+    )
+    .unwrap()
+    .claims;
+    assert_eq!(claims["nonce"], "n-0S6_WzA2Mj");
+    assert_eq!(claims["amr"], serde_json::json!(["password"]));
+    assert_eq!(claims["email"], "user@example.com");
+  }
+
+  #[test]
+  fn id_token_is_never_signed_with_a_shared_secret() {
+    let key = jwt::JwtKey::hmac("test-secret-key");
+    let user = sample_user();
+    let result = encode_id_token(IdTokenParams {
+      issuer: "https://auth.example.com",
+      client_id: "grafana",
+      user: &user,
+      scope: "openid",
+      nonce: None,
+      auth_time: 1_700_000_000,
+      aal: "aal1",
+      amr: &[],
+      signing_key: &key,
+      jwt_exp: 3600,
+    });
+    assert!(matches!(result, Err(AuthError::InternalError(_))));
+  }
+}
@@ -59,6 +59,10 @@ pub struct OidcProviderConfig {
   pub pkce: bool,
   #[serde(default)]
   pub allowed_email_domains: Vec<String>,
+  /// Audiences accepted on ID tokens besides `client_id`, such as the bundle
+  /// or client IDs of native apps using the `id_token` grant
+  #[serde(default)]
+  pub additional_audiences: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -132,6 +136,7 @@ struct OidcProviderRow {
   scopes: Value,
   pkce: bool,
   allowed_email_domains: Value,
+  additional_audiences: Value,
 }
 
 fn default_scopes() -> Vec<String> {
@@ -144,7 +149,7 @@ fn default_true() -> bool {
 
 pub async fn load_providers_from_db(db: &PgPool) -> anyhow::Result<HashMap<String, OidcProviderConfig>> {
   let rows: Vec<OidcProviderRow> = sqlx::query_as::<_, OidcProviderRow>(
-    "SELECT id, name, issuer, client_id, client_secret, redirect_uri, scopes, pkce, allowed_email_domains FROM auth.oidc_providers ORDER BY created_at ASC NULLS LAST, name ASC",
+    "SELECT id, name, issuer, client_id, client_secret, redirect_uri, scopes, pkce, allowed_email_domains, additional_audiences FROM auth.oidc_providers ORDER BY created_at ASC NULLS LAST, name ASC",
   )
   .fetch_all(db)
   .await?;
@@ -160,6 +165,13 @@ pub async fn load_providers_from_db(db: &PgPool) -> anyhow::Result<HashMap<Strin
 }
 
 impl OidcProviderConfig {
+  /// Audiences an ID token from this provider may be addressed to.
+  pub fn accepted_audiences(&self) -> Vec<&str> {
+    std::iter::once(self.client_id.as_str())
+      .chain(self.additional_audiences.iter().map(String::as_str))
+      .collect()
+  }
+
   fn validate(&self) -> anyhow::Result<()> {
     if self.name.trim().is_empty() {
       anyhow::bail!("OIDC provider name must not be empty");
@@ -180,6 +192,7 @@ impl OidcProviderRow {
   fn into_config(self) -> anyhow::Result<OidcProviderConfig> {
     let scopes = json_array_to_strings(self.scopes, "scopes")?;
     let allowed_email_domains = json_array_to_strings(self.allowed_email_domains, "allowed_email_domains")?;
+    let additional_audiences = json_array_to_strings(self.additional_audiences, "additional_audiences")?;
 
     Ok(OidcProviderConfig {
       name: self.name,
@@ -194,6 +207,7 @@ impl OidcProviderRow {
       },
       pkce: self.pkce,
       allowed_email_domains,
+      additional_audiences,
     })
   }
 }
@@ -408,6 +422,23 @@ pub async fn validate_id_token(
   config: &OidcProviderConfig,
   id_token: &str,
   expected_nonce: &str,
+) -> Result<ValidatedIdToken, AuthError> {
+  let validated = verify_id_token(state, discovery, config, id_token).await?;
+  if validated.claims.nonce.as_deref() != Some(expected_nonce) {
+    return Err(AuthError::ValidationFailed(
+      "OIDC nonce validation failed".to_string(),
+    ));
+  }
+  Ok(validated)
+}
+
+/// Checks the signature, issuer and audience of an ID token but leaves the
+/// nonce to the caller.
+pub async fn verify_id_token(
+  state: &crate::state::AppState,
+  discovery: &OidcDiscoveryDocument,
+  config: &OidcProviderConfig,
+  id_token: &str,
 ) -> Result<ValidatedIdToken, AuthError> {
   let header = decode_header(id_token).map_err(|_| AuthError::InvalidToken)?;
   let jwks = get_cached_jwks(state, &discovery.jwks_uri).await?;
@@ -425,7 +456,7 @@ pub async fn validate_id_token(
 
   let key = DecodingKey::from_jwk(jwk).map_err(|e| AuthError::InternalError(e.to_string()))?;
   let mut validation = Validation::new(header.alg);
-  validation.set_audience(std::slice::from_ref(&config.client_id));
+  validation.set_audience(&config.accepted_audiences());
   validation.set_issuer(std::slice::from_ref(&discovery.issuer));
   validation.leeway = JWT_LEEWAY_SECONDS;
 
@@ -435,12 +466,6 @@ pub async fn validate_id_token(
   let claims: OidcIdTokenClaims = serde_json::from_value(raw_claims.clone())
     .map_err(|e| AuthError::InternalError(format!("invalid OIDC id_token claims: {e}")))?;
 
-  if claims.nonce.as_deref() != Some(expected_nonce) {
-    return Err(AuthError::ValidationFailed(
-      "OIDC nonce validation failed".to_string(),
-    ));
-  }
-
   Ok(ValidatedIdToken { claims, raw_claims })
 }
 
@@ -648,12 +673,14 @@ mod tests {
       scopes: serde_json::json!(["openid", "email", "profile"]),
       pkce: true,
       allowed_email_domains: serde_json::json!(["example.com"]),
+      additional_audiences: serde_json::json!(["com.example.ios"]),
     };
 
     let provider = row.into_config().unwrap();
     assert_eq!(provider.scopes, default_scopes());
     assert!(provider.pkce);
     assert_eq!(provider.allowed_email_domains, vec!["example.com".to_string()]);
+    assert_eq!(provider.accepted_audiences(), vec!["abc", "com.example.ios"]);
   }
 
   #[test]
@@ -674,6 +701,7 @@ mod tests {
       scopes: default_scopes(),
       pkce: true,
       allowed_email_domains: vec![],
+      additional_audiences: vec![],
     };
     let flow = OidcFlowTokens {
       state: "state".to_string(),
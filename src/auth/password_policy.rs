@@ -0,0 +1,492 @@
+//! Rules new passwords must follow (env: `PASSWORD_MIN_LENGTH`,
+//! `PASSWORD_MAX_LENGTH`, `PASSWORD_REQUIRED_CHARACTERS`,
+//! `PASSWORD_BANNED_WORDS`, `PASSWORD_MIN_SCORE`).
+//!
+//! The strength score follows zxcvbn's 0-4 scale: it estimates how many
+//! guesses the password takes, charging little for common words, repeats and
+//! sequences, and maps the estimate onto zxcvbn's thresholds.
+
+use serde::Serialize;
+use std::str::FromStr;
+
+use crate::auth::breached_password::BreachedPasswords;
+use crate::defaults::{
+  PASSWORD_MAX_LENGTH,
+  PASSWORD_MIN_LENGTH,
+};
+use crate::error::AuthError;
+
+/// Highest strength score, as in zxcvbn.
+pub const MAX_SCORE: u8 = 4;
+
+/// Parts of the site name and email local part shorter than this are not
+/// banned, so that short names do not rule out most passwords.
+const MIN_CONTEXT_WORD_LEN: usize = 4;
+
+/// Upper bound for `PASSWORD_MAX_LENGTH`; hashing cost grows with length.
+const MAX_LENGTH_LIMIT: usize = 1024;
+
+/// Words attackers try first, keyboard walks included.
+const COMMON_WORDS: &[&str] = &[
+  "password",
+  "passw0rd",
+  "qwerty",
+  "qwertyuiop",
+  "asdf",
+  "asdfgh",
+  "zxcvbn",
+  "letmein",
+  "welcome",
+  "admin",
+  "administrator",
+  "login",
+  "iloveyou",
+  "monkey",
+  "dragon",
+  "master",
+  "sunshine",
+  "princess",
+  "football",
+  "baseball",
+  "shadow",
+  "superman",
+  "batman",
+  "trustno1",
+  "secret",
+  "hello",
+  "freedom",
+  "whatever",
+  "starwars",
+  "computer",
+  "summer",
+  "winter",
+  "spring",
+  "autumn",
+];
+
+/// Estimated guesses, as a power of ten, for a word from a list.
+const WORD_GUESSES_LOG10: f64 = 2.0;
+/// Estimated guesses, as a power of ten, for a character that repeats or
+/// continues a sequence.
+const PATTERN_GUESSES_LOG10: f64 = 0.3;
+
+/// A kind of character a password can be required to include.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CharacterClass {
+  Lowercase,
+  Uppercase,
+  Digit,
+  Symbol,
+  /// Anything but a letter: a digit, symbol or space
+  NonLetter,
+}
+
+impl CharacterClass {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      CharacterClass::Lowercase => "lowercase",
+      CharacterClass::Uppercase => "uppercase",
+      CharacterClass::Digit => "digit",
+      CharacterClass::Symbol => "symbol",
+      CharacterClass::NonLetter => "non_letter",
+    }
+  }
+
+  pub fn matches(self, c: char) -> bool {
+    match self {
+      CharacterClass::Lowercase => c.is_lowercase(),
+      CharacterClass::Uppercase => c.is_uppercase(),
+      CharacterClass::Digit => c.is_numeric(),
+      CharacterClass::Symbol => !c.is_alphanumeric(),
+      CharacterClass::NonLetter => !c.is_alphabetic(),
+    }
+  }
+
+  fn requirement(self) -> &'static str {
+    match self {
+      CharacterClass::Lowercase => "a lowercase letter",
+      CharacterClass::Uppercase => "an uppercase letter",
+      CharacterClass::Digit => "a digit",
+      CharacterClass::Symbol => "a symbol",
+      CharacterClass::NonLetter => "a non-letter character",
+    }
+  }
+}
+
+impl FromStr for CharacterClass {
+  type Err = String;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value.trim().to_ascii_lowercase().as_str() {
+      "lowercase" => Ok(CharacterClass::Lowercase),
+      "uppercase" => Ok(CharacterClass::Uppercase),
+      "digit" => Ok(CharacterClass::Digit),
+      "symbol" => Ok(CharacterClass::Symbol),
+      "non_letter" => Ok(CharacterClass::NonLetter),
+      other => Err(format!(
+        "unsupported character class {other:?}; expected lowercase, uppercase, digit, symbol or non_letter"
+      )),
+    }
+  }
+}
+
+/// What new passwords are checked against. `/settings` exposes it so clients
+/// can check passwords before submitting them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordPolicy {
+  pub min_length: usize,
+  pub max_length: usize,
+  pub required_characters: Vec<CharacterClass>,
+  /// Lowercased words passwords must not contain: `PASSWORD_BANNED_WORDS`
+  /// and the parts of `SITE_NAME`. The user's email local part is added per
+  /// check.
+  pub banned_words: Vec<String>,
+  /// Lowest accepted strength score, `0` to `4`; `0` skips the check
+  pub min_score: u8,
+}
+
+impl Default for PasswordPolicy {
+  fn default() -> Self {
+    Self {
+      min_length: PASSWORD_MIN_LENGTH,
+      max_length: PASSWORD_MAX_LENGTH,
+      required_characters: vec![CharacterClass::NonLetter],
+      banned_words: Vec::new(),
+      min_score: 0,
+    }
+  }
+}
+
+impl PasswordPolicy {
+  /// Builds a policy. `required_characters` and `banned_words` are
+  /// comma-separated; `required_characters` may be `none`.
+  pub fn new(
+    min_length: usize,
+    max_length: usize,
+    required_characters: &str,
+    banned_words: &str,
+    min_score: u8,
+    site_name: &str,
+  ) -> Result<Self, String> {
+    if min_length == 0 {
+      return Err("minimum password length must be at least 1".to_string());
+    }
+    if max_length < min_length || max_length > MAX_LENGTH_LIMIT {
+      return Err(format!(
+        "maximum password length must be between {min_length} and {MAX_LENGTH_LIMIT}, got {max_length}"
+      ));
+    }
+    if min_score > MAX_SCORE {
+      return Err(format!(
+        "minimum password score must be between 0 and {MAX_SCORE}, got {min_score}"
+      ));
+    }
+    let mut classes = Vec::new();
+    if !required_characters.trim().eq_ignore_ascii_case("none") {
+      for name in required_characters
+        .split(',')
+        .filter(|name| !name.trim().is_empty())
+      {
+        let class = name.parse::<CharacterClass>()?;
+        if !classes.contains(&class) {
+          classes.push(class);
+        }
+      }
+    }
+    let mut words: Vec<String> = banned_words
+      .split(',')
+      .map(|word| word.trim().to_lowercase())
+      .filter(|word| !word.is_empty())
+      .collect();
+    words.extend(context_words(site_name));
+    words.sort();
+    words.dedup();
+
+    Ok(Self {
+      min_length,
+      max_length,
+      required_characters: classes,
+      banned_words: words,
+      min_score,
+    })
+  }
+
+  /// Checks a new password for the user with `email`. Like GoTrue, the
+  /// breached-password corpus is only consulted once every other rule
+  /// passes. A password over the maximum length is a plain validation error.
+  pub fn check(
+    &self,
+    password: &str,
+    email: Option<&str>,
+    breached: Option<&BreachedPasswords>,
+  ) -> Result<(), AuthError> {
+    let length = password.chars().count();
+    if length > self.max_length {
+      return Err(AuthError::ValidationFailed(format!(
+        "Password must not exceed {} characters.",
+        self.max_length
+      )));
+    }
+    let mut messages = Vec::new();
+    let mut reasons = Vec::new();
+    if length < self.min_length {
+      messages.push(format!(
+        "Password must be at least {} characters.",
+        self.min_length
+      ));
+      reasons.push("length");
+    }
+    let missing: Vec<&str> = self
+      .required_characters
+      .iter()
+      .filter(|class| !password.chars().any(|c| class.matches(c)))
+      .map(|class| class.requirement())
+      .collect();
+    if !missing.is_empty() {
+      let list = match missing.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} and {last}", rest.join(", ")),
+        None => String::new(),
+      };
+      messages.push(format!("Password must include {list}."));
+      reasons.push("characters");
+    }
+
+    let lowered = password.to_lowercase();
+    let email_words = email
+      .and_then(|email| email.split_once('@'))
+      .map(|(local, _)| context_words(local.split('+').next().unwrap_or(local)))
+      .unwrap_or_default();
+    if self
+      .banned_words
+      .iter()
+      .chain(&email_words)
+      .any(|word| lowered.contains(word.as_str()))
+    {
+      messages.push(
+        "Password must not contain your email address, the site name or other banned words.".to_string(),
+      );
+      reasons.push("banned_words");
+    }
+    if self.min_score > 0 && strength_score(password) < self.min_score {
+      messages.push("Password is too easy to guess, try a longer or less predictable one.".to_string());
+      reasons.push("strength");
+    }
+    if reasons.is_empty() && breached.is_some_and(|corpus| corpus.contains(password)) {
+      messages
+        .push("Password is known to be weak and easy to guess, please choose a different one.".to_string());
+      reasons.push("pwned");
+    }
+    if reasons.is_empty() {
+      return Ok(());
+    }
+    Err(AuthError::WeakPassword {
+      message: messages.join(" "),
+      reasons,
+    })
+  }
+}
+
+/// Lowercased alphanumeric parts of `value` long enough to ban.
+fn context_words(value: &str) -> Vec<String> {
+  let lowered = value.to_lowercase();
+  let mut words: Vec<String> = lowered
+    .split(|c: char| !c.is_alphanumeric())
+    .filter(|part| part.chars().count() >= MIN_CONTEXT_WORD_LEN)
+    .map(str::to_string)
+    .collect();
+  let whole: String = lowered.chars().filter(|c| c.is_alphanumeric()).collect();
+  if whole.chars().count() >= MIN_CONTEXT_WORD_LEN && !words.contains(&whole) {
+    words.push(whole);
+  }
+  words
+}
+
+/// Estimates how hard `password` is to guess on zxcvbn's scale: `0` is too
+/// guessable and `4` is very unguessable.
+pub fn strength_score(password: &str) -> u8 {
+  match guesses_log10(password) {
+    g if g < 3.0 => 0,
+    g if g < 6.0 => 1,
+    g if g < 8.0 => 2,
+    g if g < 10.0 => 3,
+    _ => 4,
+  }
+}
+
+/// Estimated guesses for `password` as a power of ten. Common words cost a
+/// fixed amount whatever their length, characters that repeat or continue a
+/// sequence (`aaa`, `abc`, `321`) cost little, and any other character costs
+/// the size of its character set.
+fn guesses_log10(password: &str) -> f64 {
+  let original: Vec<char> = password.chars().collect();
+  let lowered: Vec<char> = original
+    .iter()
+    .map(|c| c.to_lowercase().next().unwrap_or(*c))
+    .collect();
+  let mut guesses = 0.0;
+  let mut i = 0;
+  while i < lowered.len() {
+    let word_len = COMMON_WORDS
+      .iter()
+      .filter(|word| {
+        let word: Vec<char> = word.chars().collect();
+        lowered[i..].starts_with(&word)
+      })
+      .map(|word| word.chars().count())
+      .max();
+    if let Some(len) = word_len {
+      guesses += WORD_GUESSES_LOG10;
+      if original[i..i + len].iter().any(|c| c.is_uppercase()) {
+        guesses += PATTERN_GUESSES_LOG10;
+      }
+      i += len;
+      continue;
+    }
+
+    let c = lowered[i];
+    let continues_pattern = i > 0 && {
+      let step = c as i64 - lowered[i - 1] as i64;
+      (-1..=1).contains(&step)
+    };
+    guesses += if continues_pattern {
+      PATTERN_GUESSES_LOG10
+    } else {
+      (charset_size(original[i]) as f64).log10()
+    };
+    i += 1;
+  }
+  guesses
+}
+
+fn charset_size(c: char) -> u32 {
+  if c.is_ascii_lowercase() || c.is_ascii_uppercase() {
+    26
+  } else if c.is_ascii_digit() {
+    10
+  } else if c.is_ascii() {
+    33
+  } else {
+    100
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use sha1::Digest as _;
+
+  use super::*;
+
+  fn reasons(policy: &PasswordPolicy, password: &str, email: Option<&str>) -> Vec<&'static str> {
+    match policy.check(password, email, None) {
+      Err(AuthError::WeakPassword { reasons, .. }) => reasons,
+      other => panic!("expected a weak password, got {other:?}"),
+    }
+  }
+
+  #[test]
+  fn default_policy_requires_length_and_a_non_letter() {
+    let policy = PasswordPolicy::default();
+    assert_eq!(reasons(&policy, "short123", None), vec!["length"]);
+    assert_eq!(reasons(&policy, "LettersOnlyPw", None), vec!["characters"]);
+    assert_eq!(reasons(&policy, "short", None), vec!["length", "characters"]);
+    assert!(policy.check("long-enough1", None, None).is_ok());
+    assert!(matches!(
+      policy.check(&"x1".repeat(65), None, None),
+      Err(AuthError::ValidationFailed(_))
+    ));
+  }
+
+  #[test]
+  fn configured_policy_is_parsed_and_validated() {
+    let policy =
+      PasswordPolicy::new(8, 64, "lowercase, Uppercase,digit,digit", "Acme, ", 3, "My Shop").unwrap();
+    assert_eq!(
+      policy.required_characters,
+      vec![
+        CharacterClass::Lowercase,
+        CharacterClass::Uppercase,
+        CharacterClass::Digit
+      ]
+    );
+    assert_eq!(policy.banned_words, vec!["acme", "myshop", "shop"]);
+    assert!(
+      PasswordPolicy::new(8, 64, "none", "", 0, "")
+        .unwrap()
+        .required_characters
+        .is_empty()
+    );
+    assert!(PasswordPolicy::new(0, 64, "none", "", 0, "").is_err());
+    assert!(PasswordPolicy::new(12, 8, "none", "", 0, "").is_err());
+    assert!(PasswordPolicy::new(8, 64, "emoji", "", 0, "").is_err());
+    assert!(PasswordPolicy::new(8, 64, "none", "", 5, "").is_err());
+  }
+
+  #[test]
+  fn required_character_classes_are_reported_together() {
+    let policy = PasswordPolicy::new(8, 64, "uppercase,digit,symbol", "", 0, "").unwrap();
+    match policy.check("lowercaseonly", None, None) {
+      Err(AuthError::WeakPassword { message, reasons }) => {
+        assert_eq!(reasons, vec!["characters"]);
+        assert_eq!(
+          message,
+          "Password must include an uppercase letter, a digit and a symbol."
+        );
+      },
+      other => panic!("expected a weak password, got {other:?}"),
+    }
+    assert!(policy.check("Upper-case1", None, None).is_ok());
+  }
+
+  #[test]
+  fn banned_words_include_site_name_and_email_local_part() {
+    let policy = PasswordPolicy::new(8, 64, "none", "hunter", 0, "Haya").unwrap();
+    assert_eq!(reasons(&policy, "my-HUNTER-2026", None), vec!["banned_words"]);
+    assert_eq!(reasons(&policy, "haya-rocks-99", None), vec!["banned_words"]);
+    assert_eq!(
+      reasons(&policy, "JaneDoe#2026", Some("jane.doe+work@example.com")),
+      vec!["banned_words"]
+    );
+    assert!(
+      policy
+        .check("x-doe-x-2026", Some("jane.doe@example.com"), None)
+        .is_ok()
+    );
+    assert!(
+      policy
+        .check("kiwi-fruit-77", Some("al@example.com"), None)
+        .is_ok()
+    );
+  }
+
+  #[test]
+  fn strength_score_follows_guessability() {
+    assert_eq!(strength_score("password"), 0);
+    assert_eq!(strength_score("aaaaaaaaaaaa"), 1);
+    assert!(strength_score("Password1234") <= 1);
+    assert!(strength_score("qwerty123456") <= 1);
+    assert_eq!(strength_score("correct-horse-battery"), 4);
+    assert_eq!(strength_score("Tr0ub4dor&3"), 4);
+
+    let policy = PasswordPolicy::new(8, 64, "none", "", 3, "").unwrap();
+    assert_eq!(reasons(&policy, "password1234", None), vec!["strength"]);
+    assert!(policy.check("correct-horse-battery", None, None).is_ok());
+  }
+
+  #[test]
+  fn breached_passwords_are_only_checked_once_other_rules_pass() {
+    let hash: [u8; 20] = sha1::Sha1::digest(b"password1234").into();
+    let corpus = BreachedPasswords::Exact(vec![hash]);
+    let policy = PasswordPolicy::default();
+    match policy.check("password1234", None, Some(&corpus)) {
+      Err(AuthError::WeakPassword { reasons, .. }) => assert_eq!(reasons, vec!["pwned"]),
+      other => panic!("expected a weak password, got {other:?}"),
+    }
+    match policy.check("password", None, Some(&corpus)) {
+      Err(AuthError::WeakPassword { reasons, .. }) => assert_eq!(reasons, vec!["length", "characters"]),
+      other => panic!("expected a weak password, got {other:?}"),
+    }
+    assert!(policy.check("long-enough1", None, Some(&corpus)).is_ok());
+  }
+}
@@ -0,0 +1,341 @@
+use chrono::{
+  DateTime,
+  Duration,
+  Utc,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::auth::{
+  oidc,
+  session,
+};
+use crate::defaults::AUTHORIZATION_CODE_LIFETIME;
+use crate::error::{
+  AuthError,
+  Result,
+};
+use crate::utils::sha256_hex;
+
+/// `auth.flow_state.provider_type` of GoTrue PKCE flows.
+pub const PROVIDER_TYPE: &str = "pkce";
+/// Email link tokens that belong to a PKCE flow carry this prefix, so
+/// `GET /verify` knows to answer with an auth code instead of a session.
+pub const TOKEN_PREFIX: &str = "pkce_";
+
+pub const METHOD_SIGNUP: &str = "signup";
+pub const METHOD_RECOVERY: &str = "recovery";
+pub const METHOD_MAGICLINK: &str = "magiclink";
+pub const METHOD_OIDC: &str = "oidc";
+
+/// How long an email flow waits for its link, matching the longest email token.
+const EMAIL_FLOW_LIFETIME_HOURS: i64 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeMethod {
+  S256,
+  Plain,
+}
+
+impl ChallengeMethod {
+  /// The `auth.code_challenge_method` enum value.
+  pub fn as_str(self) -> &'static str {
+    match self {
+      ChallengeMethod::S256 => "s256",
+      ChallengeMethod::Plain => "plain",
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeChallenge {
+  pub challenge: String,
+  pub method: ChallengeMethod,
+}
+
+impl CodeChallenge {
+  /// Reads the `code_challenge` and `code_challenge_method` a client sent.
+  /// `None` means the client uses the implicit flow.
+  pub fn from_request(challenge: Option<&str>, method: Option<&str>) -> Result<Option<Self>> {
+    let (challenge, method) = match (challenge, method) {
+      (None, None) => return Ok(None),
+      (Some(challenge), Some(method)) => (challenge, method),
+      _ => {
+        return Err(AuthError::ValidationFailed(
+          "PKCE flow requires both code_challenge and code_challenge_method".to_string(),
+        ));
+      },
+    };
+    let method = if method.eq_ignore_ascii_case("s256") {
+      ChallengeMethod::S256
+    } else if method.eq_ignore_ascii_case("plain") {
+      ChallengeMethod::Plain
+    } else {
+      return Err(AuthError::ValidationFailed(format!(
+        "Unsupported code_challenge_method: {method}"
+      )));
+    };
+    if !is_valid_pkce_value(challenge) {
+      return Err(AuthError::ValidationFailed(
+        "code_challenge must be 43 to 128 unreserved characters".to_string(),
+      ));
+    }
+    Ok(Some(Self {
+      challenge: challenge.to_string(),
+      method,
+    }))
+  }
+
+  pub fn verify(&self, verifier: &str) -> bool {
+    if !is_valid_pkce_value(verifier) {
+      return false;
+    }
+    match self.method {
+      ChallengeMethod::S256 => oidc::pkce_challenge(verifier) == self.challenge,
+      ChallengeMethod::Plain => verifier == self.challenge,
+    }
+  }
+}
+
+// RFC 7636 section 4.1: 43-128 characters from the unreserved set.
+fn is_valid_pkce_value(value: &str) -> bool {
+  (43..=128).contains(&value.len())
+    && value
+      .bytes()
+      .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~'))
+}
+
+/// A new email link token, prefixed when it belongs to a PKCE flow.
+pub fn email_token(pkce: bool) -> String {
+  let token = session::generate_refresh_token();
+  if pkce {
+    format!("{TOKEN_PREFIX}{token}")
+  } else {
+    token
+  }
+}
+
+pub fn is_pkce_token(token: &str) -> bool {
+  token.starts_with(TOKEN_PREFIX)
+}
+
+/// A PKCE flow requested alongside an email link.
+#[derive(Debug, Clone)]
+pub struct EmailFlow {
+  pub challenge: CodeChallenge,
+  pub redirect_to: String,
+}
+
+impl EmailFlow {
+  pub async fn start(&self, db: &PgPool, user_id: Uuid, authentication_method: &str) -> Result<()> {
+    start_email_flow(
+      db,
+      user_id,
+      authentication_method,
+      &self.challenge,
+      &self.redirect_to,
+    )
+    .await
+  }
+}
+
+/// The user and sign-in method behind an exchanged auth code.
+#[derive(Debug, Clone)]
+pub struct PkceGrant {
+  pub user_id: Uuid,
+  pub authentication_method: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct FlowRow {
+  user_id: Option<Uuid>,
+  code_challenge: String,
+  code_challenge_method: String,
+  authentication_method: String,
+  expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct PendingFlowRow {
+  redirect_to: Option<String>,
+}
+
+/// Records an email flow that waits for its link to be opened. A newer
+/// request for the same user and method replaces an unfinished one.
+async fn start_email_flow(
+  db: &PgPool,
+  user_id: Uuid,
+  authentication_method: &str,
+  challenge: &CodeChallenge,
+  redirect_to: &str,
+) -> Result<()> {
+  let now = Utc::now();
+  let mut tx = db.begin().await?;
+  sqlx::query(
+    "DELETE FROM auth.flow_state WHERE user_id = $1 AND authentication_method = $2 AND provider_type = $3 AND auth_code_issued_at IS NULL",
+  )
+  .bind(user_id)
+  .bind(authentication_method)
+  .bind(PROVIDER_TYPE)
+  .execute(&mut *tx)
+  .await?;
+  sqlx::query(
+    "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, authentication_method, created_at, updated_at, redirect_to, expires_at) VALUES ($1, $2, $3, $4::auth.code_challenge_method, $5, $6, $7, $8, $9, $10, $11)",
+  )
+  .bind(Uuid::new_v4())
+  .bind(user_id)
+  .bind(sha256_hex(&session::generate_refresh_token()))
+  .bind(challenge.method.as_str())
+  .bind(&challenge.challenge)
+  .bind(PROVIDER_TYPE)
+  .bind(authentication_method)
+  .bind(now)
+  .bind(now)
+  .bind(redirect_to)
+  .bind(now + Duration::hours(EMAIL_FLOW_LIFETIME_HOURS))
+  .execute(&mut *tx)
+  .await?;
+  tx.commit().await?;
+  Ok(())
+}
+
+/// Whether a resent email should keep the PKCE flow of the original one.
+pub async fn has_pending_email_flow(db: &PgPool, user_id: Uuid, authentication_method: &str) -> Result<bool> {
+  let pending: Option<(Uuid,)> = sqlx::query_as(
+    "SELECT id FROM auth.flow_state WHERE user_id = $1 AND authentication_method = $2 AND provider_type = $3 AND auth_code_issued_at IS NULL AND expires_at > now()",
+  )
+  .bind(user_id)
+  .bind(authentication_method)
+  .bind(PROVIDER_TYPE)
+  .fetch_optional(db)
+  .await?;
+  Ok(pending.is_some())
+}
+
+/// Called once the email link has been verified. Returns the auth code and
+/// the `redirect_to` the client asked for.
+pub async fn issue_email_auth_code(
+  db: &PgPool,
+  user_id: Uuid,
+  authentication_method: &str,
+) -> Result<(String, Option<String>)> {
+  let now = Utc::now();
+  let code = session::generate_refresh_token();
+  let flow = sqlx::query_as::<_, PendingFlowRow>(
+    "UPDATE auth.flow_state SET auth_code = $1, auth_code_issued_at = $2, expires_at = $3, updated_at = $2 WHERE id = (SELECT id FROM auth.flow_state WHERE user_id = $4 AND authentication_method = $5 AND provider_type = $6 AND auth_code_issued_at IS NULL AND expires_at > $2 ORDER BY created_at DESC LIMIT 1) RETURNING redirect_to",
+  )
+  .bind(sha256_hex(&code))
+  .bind(now)
+  .bind(now + Duration::seconds(AUTHORIZATION_CODE_LIFETIME))
+  .bind(user_id)
+  .bind(authentication_method)
+  .bind(PROVIDER_TYPE)
+  .fetch_optional(db)
+  .await?
+  .ok_or(AuthError::TokenExpired)?;
+  Ok((code, flow.redirect_to))
+}
+
+/// Records a finished sign-in, such as an OIDC callback, and returns the
+/// auth code the client exchanges for it.
+pub async fn issue_auth_code(
+  db: &PgPool,
+  user_id: Uuid,
+  authentication_method: &str,
+  challenge: &CodeChallenge,
+) -> Result<String> {
+  let now = Utc::now();
+  let code = session::generate_refresh_token();
+  sqlx::query(
+    "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, authentication_method, created_at, updated_at, auth_code_issued_at, expires_at) VALUES ($1, $2, $3, $4::auth.code_challenge_method, $5, $6, $7, $8, $8, $8, $9)",
+  )
+  .bind(Uuid::new_v4())
+  .bind(user_id)
+  .bind(sha256_hex(&code))
+  .bind(challenge.method.as_str())
+  .bind(&challenge.challenge)
+  .bind(PROVIDER_TYPE)
+  .bind(authentication_method)
+  .bind(now)
+  .bind(now + Duration::seconds(AUTHORIZATION_CODE_LIFETIME))
+  .execute(db)
+  .await?;
+  Ok(code)
+}
+
+/// Consumes an auth code. The code is gone after the first attempt, even
+/// when the verifier is wrong.
+pub async fn exchange_auth_code(db: &PgPool, auth_code: &str, code_verifier: &str) -> Result<PkceGrant> {
+  let flow = sqlx::query_as::<_, FlowRow>(
+    "DELETE FROM auth.flow_state WHERE auth_code = $1 AND provider_type = $2 AND auth_code_issued_at IS NOT NULL RETURNING user_id, code_challenge, code_challenge_method::text AS code_challenge_method, authentication_method, expires_at",
+  )
+  .bind(sha256_hex(auth_code))
+  .bind(PROVIDER_TYPE)
+  .fetch_optional(db)
+  .await?
+  .ok_or(AuthError::InvalidToken)?;
+
+  if flow.expires_at.map(|value| value < Utc::now()).unwrap_or(true) {
+    return Err(AuthError::TokenExpired);
+  }
+  let challenge = CodeChallenge::from_request(Some(&flow.code_challenge), Some(&flow.code_challenge_method))?
+    .ok_or(AuthError::InvalidToken)?;
+  if !challenge.verify(code_verifier) {
+    return Err(AuthError::InvalidToken);
+  }
+  let user_id = flow.user_id.ok_or(AuthError::InvalidToken)?;
+  Ok(PkceGrant {
+    user_id,
+    authentication_method: flow.authentication_method,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+  const S256_CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+  #[test]
+  fn reads_gotrue_challenge_methods() {
+    let challenge = CodeChallenge::from_request(Some(S256_CHALLENGE), Some("s256"))
+      .unwrap()
+      .unwrap();
+    assert_eq!(challenge.method, ChallengeMethod::S256);
+    assert_eq!(
+      CodeChallenge::from_request(Some(S256_CHALLENGE), Some("S256"))
+        .unwrap()
+        .unwrap()
+        .method,
+      ChallengeMethod::S256
+    );
+    assert!(CodeChallenge::from_request(None, None).unwrap().is_none());
+    assert!(CodeChallenge::from_request(Some(S256_CHALLENGE), None).is_err());
+    assert!(CodeChallenge::from_request(Some(S256_CHALLENGE), Some("md5")).is_err());
+    assert!(CodeChallenge::from_request(Some("short"), Some("plain")).is_err());
+  }
+
+  #[test]
+  fn verifies_the_code_verifier() {
+    let s256 = CodeChallenge {
+      challenge: S256_CHALLENGE.to_string(),
+      method: ChallengeMethod::S256,
+    };
+    assert!(s256.verify(VERIFIER));
+    assert!(!s256.verify(S256_CHALLENGE));
+
+    let plain = CodeChallenge {
+      challenge: VERIFIER.to_string(),
+      method: ChallengeMethod::Plain,
+    };
+    assert!(plain.verify(VERIFIER));
+    assert!(!plain.verify("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXx"));
+  }
+
+  #[test]
+  fn marks_pkce_email_tokens() {
+    assert!(is_pkce_token(&email_token(true)));
+    assert!(!is_pkce_token(&email_token(false)));
+  }
+}
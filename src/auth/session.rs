@@ -5,12 +5,24 @@ use chrono::{
   Duration,
   Utc,
 };
+use hmac::{
+  Hmac,
+  Mac,
+};
 use rand::RngCore;
+use sha2::Sha256;
 use std::net::IpAddr;
 use uuid::Uuid;
 
+use crate::auth::session_limits::{
+  SessionLimitStrategy,
+  SessionLimits,
+};
 use crate::auth::{
+  audit,
+  hook,
   jwt,
+  oauth,
   password,
   rate_limit,
 };
@@ -23,6 +35,7 @@ use crate::model::{
   MfaFactorRow,
   SessionAmrClaimRow,
   SessionRow,
+  TokenExchangeResponse,
   TokenResponse,
   User,
   UserResponse,
@@ -30,9 +43,13 @@ use crate::model::{
 use crate::state::AppState;
 use crate::utils::sha256_hex;
 
+/// `amr` method recorded on sessions issued through admin impersonation.
+pub const IMPERSONATION_METHOD: &str = "impersonation";
 const REAUTHENTICATION_TTL_MINUTES: i64 = 10;
 const REAUTHENTICATION_RATE_LIMIT_WINDOW_SECS: u64 = 900;
 const REAUTHENTICATION_RATE_LIMIT_ATTEMPTS: u32 = 10;
+const REFRESH_REUSE_KDF_SALT: &[u8] = b"haya-refresh-token-salt";
+const REFRESH_REUSE_KDF_INFO: &[u8] = b"refresh-reuse";
 
 #[derive(Debug, Clone, Default)]
 pub struct ClientContext {
@@ -40,6 +57,20 @@ pub struct ClientContext {
   pub ip: Option<IpAddr>,
 }
 
+/// The OAuth client a session was issued to and the scope it was granted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OAuthGrant {
+  pub client_id: String,
+  pub scope: String,
+}
+
+pub fn oauth_grant(session: &SessionRow) -> Option<OAuthGrant> {
+  Some(OAuthGrant {
+    client_id: session.oauth_client_id.clone()?,
+    scope: session.scope.clone().unwrap_or_default(),
+  })
+}
+
 pub async fn issue_session(state: &AppState, user: &User, method: &str) -> Result<TokenResponse> {
   issue_session_with_client_context(
     state,
@@ -86,21 +117,65 @@ pub async fn issue_session_with_client_context(
   factor_id: Option<Uuid>,
   methods: Vec<String>,
   client_context: ClientContext,
+) -> Result<TokenResponse> {
+  create_session(state, user, aal, factor_id, methods, client_context, None).await
+}
+
+/// Issues a session owned by an OAuth client. Its tokens carry `client_id`
+/// and `scope`, and can only be refreshed through `/oauth/token`.
+pub async fn issue_oauth_session(
+  state: &AppState,
+  user: &User,
+  aal: &str,
+  factor_id: Option<Uuid>,
+  methods: Vec<String>,
+  client_context: ClientContext,
+  grant: &OAuthGrant,
+) -> Result<TokenResponse> {
+  create_session(state, user, aal, factor_id, methods, client_context, Some(grant)).await
+}
+
+async fn create_session(
+  state: &AppState,
+  user: &User,
+  aal: &str,
+  factor_id: Option<Uuid>,
+  methods: Vec<String>,
+  client_context: ClientContext,
+  grant: Option<&OAuthGrant>,
 ) -> Result<TokenResponse> {
   let now = Utc::now();
   let session_id = Uuid::new_v4();
+  let limits = limits_for(state, user);
   let mut tx = state.db.begin().await?;
 
+  if let Some(max_per_user) = limits.max_per_user {
+    lock_user_sessions(&mut tx, user.id).await?;
+    let active = active_session_ids(&mut tx, state, user.id, now).await?;
+    let excess = active.len() as i64 - max_per_user + 1;
+    if excess > 0 {
+      if limits.strategy == SessionLimitStrategy::Reject {
+        return Err(AuthError::SessionLimitReached);
+      }
+      for evicted in active.iter().take(excess as usize) {
+        evict_session(&mut tx, state, user.id, *evicted, &limits, client_context.ip, now).await?;
+      }
+    }
+  }
+
   sqlx::query(
-    "INSERT INTO auth.sessions (id, user_id, factor_id, aal, user_agent, ip, refreshed_at, created_at, updated_at) VALUES ($1, $2, $3, $4::auth.aal_level, $5, $6::inet, $7, $8, $9)",
+    "INSERT INTO auth.sessions (id, user_id, factor_id, aal, not_after, user_agent, ip, refreshed_at, oauth_client_id, scope, created_at, updated_at) VALUES ($1, $2, $3, $4::auth.aal_level, $5, $6, $7::inet, $8, $9, $10, $11, $12)",
   )
   .bind(session_id)
   .bind(user.id)
   .bind(factor_id)
   .bind(aal)
+  .bind(limits.max_lifetime_secs.map(|secs| now + Duration::seconds(secs)))
   .bind(client_context.user_agent)
   .bind(client_context.ip.map(|value| value.to_string()))
   .bind(now.naive_utc())
+  .bind(grant.map(|grant| grant.client_id.as_str()))
+  .bind(grant.map(|grant| grant.scope.as_str()))
   .bind(now)
   .bind(now)
   .execute(&mut *tx)
@@ -150,7 +225,151 @@ pub async fn issue_session_with_client_context(
     })
     .collect();
 
-  build_token_response(state, user, session_id, aal, amr, refresh_token).await
+  build_token_response(state, user, session_id, aal, amr, refresh_token, grant).await
+}
+
+/// Raises the caller's session to aal2 once they have verified `factor_id`,
+/// as GoTrue's `/factors/{id}/verify` does. The session keeps its id and
+/// gains an `amr` entry for `method`. It also gets a new refresh token, and
+/// the session's earlier refresh tokens are revoked.
+pub async fn upgrade_session(
+  state: &AppState,
+  claims: &jwt::Claims,
+  user: &User,
+  factor_id: Uuid,
+  method: &str,
+) -> Result<TokenResponse> {
+  let session = ensure_active_session(state, claims).await?;
+  let now = Utc::now();
+  let mut tx = state.db.begin().await?;
+
+  sqlx::query(
+    "UPDATE auth.sessions SET aal = 'aal2'::auth.aal_level, factor_id = $1, updated_at = $2 WHERE id = $3",
+  )
+  .bind(factor_id)
+  .bind(now)
+  .bind(session.id)
+  .execute(&mut *tx)
+  .await?;
+  sqlx::query(
+    "INSERT INTO auth.mfa_amr_claims (id, session_id, created_at, updated_at, authentication_method) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (session_id, authentication_method) DO UPDATE SET created_at = EXCLUDED.created_at, updated_at = EXCLUDED.updated_at",
+  )
+  .bind(Uuid::new_v4())
+  .bind(session.id)
+  .bind(now)
+  .bind(now)
+  .bind(method)
+  .execute(&mut *tx)
+  .await?;
+
+  sqlx::query(
+    "UPDATE auth.refresh_tokens SET revoked = true, updated_at = $1 WHERE session_id = $2 AND revoked = false",
+  )
+  .bind(now)
+  .bind(session.id)
+  .execute(&mut *tx)
+  .await?;
+  let refresh_token = generate_refresh_token();
+  sqlx::query(
+    "INSERT INTO auth.refresh_tokens (instance_id, user_id, token, session_id, revoked, created_at, updated_at) VALUES ($1, $2, $3, $4, false, $5, $6)",
+  )
+  .bind(state.instance_id)
+  .bind(user.id.to_string())
+  .bind(sha256_hex(&refresh_token))
+  .bind(session.id)
+  .bind(now)
+  .bind(now)
+  .execute(&mut *tx)
+  .await?;
+  tx.commit().await?;
+
+  let (session, amr) = load_session_context(state, session.id).await?;
+  build_token_response(
+    state,
+    user,
+    session.id,
+    "aal2",
+    amr,
+    refresh_token,
+    oauth_grant(&session).as_ref(),
+  )
+  .await
+}
+
+/// Session limits that apply to `user`, based on their role.
+pub fn limits_for(state: &AppState, user: &User) -> SessionLimits {
+  state
+    .session_limits
+    .for_role(user.role.as_deref().unwrap_or("authenticated"))
+}
+
+/// Serializes session creation and limit checks for one user, so concurrent
+/// sign-ins cannot both slip under the limit.
+pub(crate) async fn lock_user_sessions(
+  tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+  user_id: Uuid,
+) -> Result<()> {
+  sqlx::query("SELECT id FROM auth.users WHERE id = $1 FOR UPDATE")
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+  Ok(())
+}
+
+/// Ids of the user's sessions that count toward `SESSION_MAX_PER_USER`,
+/// oldest first. Expired, idle and impersonation sessions do not count.
+pub(crate) async fn active_session_ids(
+  tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+  state: &AppState,
+  user_id: Uuid,
+  now: DateTime<Utc>,
+) -> Result<Vec<Uuid>> {
+  let ids = sqlx::query_scalar::<_, Uuid>(
+    "SELECT s.id FROM auth.sessions s WHERE s.user_id = $1 AND (s.not_after IS NULL OR s.not_after > $2) AND COALESCE(s.refreshed_at AT TIME ZONE 'UTC', s.created_at) > $2 - make_interval(secs => $3) AND NOT EXISTS (SELECT 1 FROM auth.mfa_amr_claims c WHERE c.session_id = s.id AND c.authentication_method = $4) ORDER BY s.created_at ASC NULLS FIRST, s.id",
+  )
+  .bind(user_id)
+  .bind(now)
+  .bind(state.session_idle_timeout_secs as f64)
+  .bind(IMPERSONATION_METHOD)
+  .fetch_all(&mut **tx)
+  .await?;
+  Ok(ids)
+}
+
+/// Ends a session to enforce the concurrent-session limit and records a
+/// `session_evicted` audit event.
+pub(crate) async fn evict_session(
+  tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+  state: &AppState,
+  user_id: Uuid,
+  session_id: Uuid,
+  limits: &SessionLimits,
+  client_ip: Option<IpAddr>,
+  now: DateTime<Utc>,
+) -> Result<()> {
+  sqlx::query("UPDATE auth.refresh_tokens SET revoked = true, updated_at = $1 WHERE session_id = $2")
+    .bind(now)
+    .bind(session_id)
+    .execute(&mut **tx)
+    .await?;
+  sqlx::query("DELETE FROM auth.sessions WHERE id = $1")
+    .bind(session_id)
+    .execute(&mut **tx)
+    .await?;
+  audit::log_event_tx(
+    tx.as_mut(),
+    state.instance_id,
+    client_ip,
+    "session_evicted",
+    serde_json::json!({
+      "user_id": user_id,
+      "session_id": session_id,
+      "reason": "session_limit",
+      "max_per_user": limits.max_per_user,
+      "strategy": limits.strategy.as_str(),
+    }),
+  )
+  .await
 }
 
 pub fn generate_refresh_token() -> String {
@@ -159,6 +378,34 @@ pub fn generate_refresh_token() -> String {
   URL_SAFE_NO_PAD.encode(bytes)
 }
 
+/// Derives the key [`derive_child_refresh_token`] uses from
+/// `REFRESH_TOKEN_SECRET` with HKDF-SHA256 under a `refresh-reuse` label. Rotating the MFA encryption
+/// keys therefore leaves the reuse window intact.
+pub fn derive_refresh_reuse_key(secret: &str) -> [u8; 32] {
+  let mut extract = <Hmac<Sha256> as Mac>::new_from_slice(REFRESH_REUSE_KDF_SALT)
+    .expect("static HKDF salt is a valid HMAC-SHA256 key");
+  extract.update(secret.as_bytes());
+  let prk = extract.finalize().into_bytes();
+
+  let mut expand =
+    <Hmac<Sha256> as Mac>::new_from_slice(&prk).expect("HKDF extract output is a valid HMAC-SHA256 key");
+  expand.update(REFRESH_REUSE_KDF_INFO);
+  expand.update(&[1]);
+  let mut key = [0u8; 32];
+  key.copy_from_slice(&expand.finalize().into_bytes());
+  key
+}
+
+/// Derives the refresh token that replaces `parent` on rotation. Because the
+/// child follows from the parent, a client that lost the race to rotate
+/// `parent` can be handed the same child without Haya storing it in plain.
+pub fn derive_child_refresh_token(key: &[u8; 32], parent: &str) -> String {
+  let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+  mac.update(b"haya-refresh-token-child:");
+  mac.update(parent.as_bytes());
+  URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
 pub async fn build_token_response(
   state: &AppState,
   user: &User,
@@ -166,12 +413,14 @@ pub async fn build_token_response(
   aal: &str,
   amr: Vec<jwt::AmrEntry>,
   refresh_token: String,
+  grant: Option<&OAuthGrant>,
 ) -> Result<TokenResponse> {
   let now = Utc::now();
   let app_meta = user.raw_app_meta_data.clone().unwrap_or(serde_json::json!({}));
   let user_meta = user.raw_user_meta_data.clone().unwrap_or(serde_json::json!({}));
   let role = user.role.as_deref().unwrap_or("authenticated");
-  let access_token = jwt::encode_token(jwt::EncodeTokenParams {
+  let signing_key = state.jwt_keys.read().await.signing_key().clone();
+  let params = jwt::EncodeTokenParams {
     user_id: user.id,
     email: user.email.clone(),
     phone: user.phone.clone(),
@@ -182,13 +431,17 @@ pub async fn build_token_response(
     amr,
     user_metadata: user_meta,
     app_metadata: app_meta,
-    jwt_secret: &state.jwt_secret,
+    client_id: grant.map(|grant| grant.client_id.as_str()),
+    scope: grant.map(|grant| grant.scope.as_str()),
+    act: None,
+    signing_key: &signing_key,
     jwt_exp: state.jwt_exp,
     issuer: &state.issuer,
-  })?;
+  };
 
   let expires_at = now.timestamp() + state.jwt_exp;
   let user_response = UserResponse::from_user(&state.db, user.clone()).await?;
+  let access_token = sign_access_token(state, &user_response, params).await?;
   Ok(TokenResponse {
     access_token,
     token_type: "bearer".to_string(),
@@ -196,15 +449,133 @@ pub async fn build_token_response(
     expires_at,
     refresh_token,
     user: user_response,
+    trusted_device_token: None,
+    trusted_device_expires_at: None,
+  })
+}
+
+/// Signs an access token, running the custom access-token hook first when
+/// one is configured.
+async fn sign_access_token(
+  state: &AppState,
+  user: &UserResponse,
+  params: jwt::EncodeTokenParams<'_>,
+) -> Result<String> {
+  let Some(access_token_hook) = state.access_token_hook.as_deref() else {
+    return jwt::encode_token(params);
+  };
+  let signing_key = params.signing_key;
+  let user_id = params.user_id;
+  let session_id = params.session_id;
+  let authentication_method = params.amr.last().map(|entry| entry.method.clone());
+  let proposed = hook::claims_map(&jwt::build_claims(params))?;
+  let claims = hook::customize_access_token(
+    state,
+    access_token_hook,
+    &hook::AccessTokenHookEvent {
+      user_id,
+      session_id,
+      authentication_method: authentication_method.as_deref(),
+      claims: &proposed,
+      user,
+    },
+  )
+  .await?;
+  jwt::encode_claims(signing_key, &claims)
+}
+
+/// Issues a short-lived session for `user` on behalf of an admin. The access
+/// token carries an RFC 8693 `act` claim naming the admin and comes without a
+/// refresh token, so the session ends when it expires.
+pub async fn issue_impersonation_session(
+  state: &AppState,
+  user: &User,
+  actor: jwt::Actor,
+  lifetime_secs: i64,
+  client_context: ClientContext,
+) -> Result<TokenExchangeResponse> {
+  let now = Utc::now();
+  let session_id = Uuid::new_v4();
+  let mut tx = state.db.begin().await?;
+
+  sqlx::query(
+    "INSERT INTO auth.sessions (id, user_id, aal, not_after, user_agent, ip, refreshed_at, created_at, updated_at) VALUES ($1, $2, 'aal1'::auth.aal_level, $3, $4, $5::inet, $6, $7, $8)",
+  )
+  .bind(session_id)
+  .bind(user.id)
+  .bind(now + Duration::seconds(lifetime_secs))
+  .bind(client_context.user_agent)
+  .bind(client_context.ip.map(|value| value.to_string()))
+  .bind(now.naive_utc())
+  .bind(now)
+  .bind(now)
+  .execute(&mut *tx)
+  .await?;
+  sqlx::query(
+    "INSERT INTO auth.mfa_amr_claims (id, session_id, created_at, updated_at, authentication_method) VALUES ($1, $2, $3, $4, $5)",
+  )
+  .bind(Uuid::new_v4())
+  .bind(session_id)
+  .bind(now)
+  .bind(now)
+  .bind(IMPERSONATION_METHOD)
+  .execute(&mut *tx)
+  .await?;
+
+  tx.commit().await?;
+
+  let role = user.role.as_deref().unwrap_or("authenticated");
+  let signing_key = state.jwt_keys.read().await.signing_key().clone();
+  let params = jwt::EncodeTokenParams {
+    user_id: user.id,
+    email: user.email.clone(),
+    phone: user.phone.clone(),
+    role,
+    session_id,
+    is_anonymous: user.is_anonymous,
+    aal: "aal1",
+    amr: vec![jwt::AmrEntry {
+      method: IMPERSONATION_METHOD.to_string(),
+      timestamp: now.timestamp(),
+    }],
+    user_metadata: user.raw_user_meta_data.clone().unwrap_or(serde_json::json!({})),
+    app_metadata: user.raw_app_meta_data.clone().unwrap_or(serde_json::json!({})),
+    client_id: None,
+    scope: None,
+    act: Some(actor),
+    signing_key: &signing_key,
+    jwt_exp: lifetime_secs,
+    issuer: &state.issuer,
+  };
+  let user_response = UserResponse::from_user(&state.db, user.clone()).await?;
+  let access_token = sign_access_token(state, &user_response, params).await?;
+
+  Ok(TokenExchangeResponse {
+    access_token,
+    issued_token_type: oauth::TOKEN_TYPE_ACCESS_TOKEN.to_string(),
+    token_type: "bearer".to_string(),
+    expires_in: lifetime_secs,
+    expires_at: now.timestamp() + lifetime_secs,
+    user: user_response,
   })
 }
 
+/// Rejects requests made with an impersonation token. Used by endpoints that
+/// change credentials, MFA factors or grants, which support staff must never
+/// do on a user's behalf.
+pub fn forbid_impersonation(claims: &jwt::Claims) -> Result<()> {
+  if claims.act.is_some() {
+    return Err(AuthError::ImpersonationForbidden);
+  }
+  Ok(())
+}
+
 pub async fn load_session_context(
   state: &AppState,
   session_id: Uuid,
 ) -> Result<(SessionRow, Vec<jwt::AmrEntry>)> {
   let session: SessionRow = sqlx::query_as::<_, SessionRow>(
-    "SELECT id, user_id, factor_id, aal::text as aal, not_after, user_agent, host(ip) as ip, refreshed_at, created_at, updated_at FROM auth.sessions WHERE id = $1",
+    "SELECT id, user_id, factor_id, aal::text as aal, not_after, user_agent, host(ip) as ip, refreshed_at, oauth_client_id, scope, created_at, updated_at FROM auth.sessions WHERE id = $1",
   )
   .bind(session_id)
   .fetch_one(&state.db)
@@ -235,7 +606,7 @@ pub async fn ensure_active_session(state: &AppState, claims: &jwt::Claims) -> Re
     .map_err(|_| AuthError::NotAuthorized)?;
   let user_id = claims.sub.parse::<Uuid>().map_err(|_| AuthError::NotAuthorized)?;
   let session: SessionRow = sqlx::query_as::<_, SessionRow>(
-    "SELECT id, user_id, factor_id, aal::text as aal, not_after, user_agent, host(ip) as ip, refreshed_at, created_at, updated_at FROM auth.sessions WHERE id = $1",
+    "SELECT id, user_id, factor_id, aal::text as aal, not_after, user_agent, host(ip) as ip, refreshed_at, oauth_client_id, scope, created_at, updated_at FROM auth.sessions WHERE id = $1",
   )
   .bind(session_id)
   .fetch_optional(&state.db)
@@ -253,6 +624,10 @@ pub async fn ensure_active_session(state: &AppState, claims: &jwt::Claims) -> Re
 
 pub async fn load_current_user(state: &AppState, claims: &jwt::Claims) -> Result<User> {
   let user_id = claims.sub.parse::<Uuid>().map_err(|_| AuthError::NotAuthorized)?;
+  load_active_user(state, user_id).await
+}
+
+pub async fn load_active_user(state: &AppState, user_id: Uuid) -> Result<User> {
   let user: User = sqlx::query_as::<_, User>(
     "SELECT id, instance_id, aud, role, email, encrypted_password, email_confirmed_at, phone, phone_confirmed_at, confirmed_at, last_sign_in_at, raw_app_meta_data, raw_user_meta_data, is_super_admin, is_sso_user, is_anonymous, banned_until, deleted_at, created_at, updated_at FROM auth.users WHERE id = $1",
   )
@@ -305,7 +680,7 @@ pub async fn require_reauthentication(
   reauthentication_token: Option<&str>,
 ) -> Result<()> {
   let has_verified_mfa = sqlx::query_as::<_, MfaFactorRow>(
-    "SELECT id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, created_at, updated_at, last_challenged_at FROM auth.mfa_factors WHERE user_id = $1 AND status = 'verified'::auth.factor_status LIMIT 1",
+    "SELECT id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, phone, created_at, updated_at, last_challenged_at FROM auth.mfa_factors WHERE user_id = $1 AND status = 'verified'::auth.factor_status LIMIT 1",
   )
   .bind(user.id)
   .fetch_optional(&state.db)
@@ -452,7 +827,7 @@ async fn consume_reauthentication_token(
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
   use std::collections::HashMap;
   use std::sync::Arc;
 
@@ -460,22 +835,45 @@ mod tests {
   use tokio::sync::RwLock;
 
   use super::*;
+  use crate::auth::binding::SessionBindingPolicy;
+  use crate::auth::session_limits::SessionLimitPolicy;
+  use crate::auth::webauthn::RelyingParty;
 
-  fn sample_state(session_idle_timeout_secs: i64) -> AppState {
+  pub(crate) fn sample_state(session_idle_timeout_secs: i64) -> AppState {
     AppState {
       db: PgPoolOptions::new()
         .connect_lazy("postgres://localhost:5432/haya")
         .expect("lazy pool"),
       http_client: reqwest::Client::new(),
-      jwt_secret: "a-very-long-test-secret-with-at-least-32-chars".to_string(),
-      mfa_encryption_key: [0; 32],
+      jwt_configured_keys: Arc::new(jwt::JwtKeyring::new(jwt::JwtKey::hmac(
+        "a-very-long-test-secret-with-at-least-32-chars",
+      ))),
+      jwt_keys: Arc::new(RwLock::new(jwt::JwtKeyring::new(jwt::JwtKey::hmac(
+        "a-very-long-test-secret-with-at-least-32-chars",
+      )))),
+      mfa_keys: Arc::new(crate::auth::mfa::EncryptionKeys::legacy([0; 32])),
+      refresh_token_reuse_key: [0; 32],
+      totp: crate::auth::mfa::TotpPolicy::default(),
+      trusted_device_max_lifetime_secs: 0,
+      password_policy: Arc::new(crate::auth::password_policy::PasswordPolicy::default()),
+      breached_passwords: None,
       jwt_exp: 3600,
+      client_credentials_jwt_exp: 900,
       refresh_token_exp: 3600,
       session_idle_timeout_secs,
+      refresh_token_reuse_interval: 10,
+      session_binding: SessionBindingPolicy::Strict,
+      session_limits: Arc::new(SessionLimitPolicy::default()),
+      webauthn: Arc::new(RelyingParty::new("http://localhost:9999", "Haya", None, &[]).unwrap()),
+      access_token_hook: None,
+      anonymous_sign_ins: false,
+      captcha: None,
       site_url: "http://localhost:9999".to_string(),
       allowed_redirect_origins: vec![],
       allowed_redirect_path_prefixes: vec![],
       oidc_form_post: false,
+      oauth_consent_url: "http://localhost:9999/oauth/consent".to_string(),
+      oauth_device_verification_url: "http://localhost:9999/device".to_string(),
       site_name: "Haya".to_string(),
       issuer: "http://localhost:9999".to_string(),
       instance_id: Uuid::nil(),
@@ -483,6 +881,7 @@ mod tests {
       oidc_jwks_cache: Arc::new(RwLock::new(HashMap::new())),
       mailer_autoconfirm: false,
       mailer: None,
+      sms: None,
     }
   }
 
@@ -496,6 +895,8 @@ mod tests {
       user_agent: None,
       ip: None,
       refreshed_at: Some(last_active_at.naive_utc()),
+      oauth_client_id: None,
+      scope: None,
       created_at: Some(last_active_at),
       updated_at: Some(last_active_at),
     }
@@ -519,4 +920,26 @@ mod tests {
       Err(AuthError::NotAuthorized)
     ));
   }
+
+  #[test]
+  fn child_refresh_token_is_stable_per_parent_and_key() {
+    let key = [7; 32];
+    let child = derive_child_refresh_token(&key, "parent");
+    assert_eq!(child, derive_child_refresh_token(&key, "parent"));
+    assert_ne!(child, derive_child_refresh_token(&key, "other-parent"));
+    assert_ne!(child, derive_child_refresh_token(&[8; 32], "parent"));
+    assert_eq!(child.len(), generate_refresh_token().len());
+  }
+
+  #[test]
+  fn refresh_reuse_key_is_separate_from_the_mfa_key() {
+    let secret = "a-very-long-test-secret-with-at-least-32-chars";
+    let key = derive_refresh_reuse_key(secret);
+    assert_eq!(key, derive_refresh_reuse_key(secret));
+    assert_ne!(
+      key,
+      derive_refresh_reuse_key("another-test-secret-with-at-least-32-chars")
+    );
+    assert_ne!(key, crate::auth::mfa::derive_encryption_key(secret));
+  }
 }
@@ -0,0 +1,181 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// What happens when a user already has the maximum number of sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionLimitStrategy {
+  /// End the user's oldest sessions to make room
+  EvictOldest,
+  /// Refuse the new sign-in
+  Reject,
+}
+
+impl SessionLimitStrategy {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      SessionLimitStrategy::EvictOldest => "evict_oldest",
+      SessionLimitStrategy::Reject => "reject",
+    }
+  }
+}
+
+impl FromStr for SessionLimitStrategy {
+  type Err = String;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value.trim().to_ascii_lowercase().as_str() {
+      "evict_oldest" => Ok(SessionLimitStrategy::EvictOldest),
+      "reject" => Ok(SessionLimitStrategy::Reject),
+      other => Err(format!(
+        "unsupported session limit strategy {other:?}; expected evict_oldest or reject"
+      )),
+    }
+  }
+}
+
+/// Effective limits for one user. `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionLimits {
+  pub max_lifetime_secs: Option<i64>,
+  pub max_per_user: Option<i64>,
+  pub strategy: SessionLimitStrategy,
+}
+
+/// Per-role entry of `SESSION_ROLE_LIMITS`; unset fields fall back to the
+/// global settings and `0` lifts a limit for the role.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoleSessionLimits {
+  pub max_lifetime_secs: Option<i64>,
+  pub max_per_user: Option<i64>,
+  pub strategy: Option<SessionLimitStrategy>,
+}
+
+/// Session age and concurrency limits (env: `SESSION_MAX_LIFETIME_SECS`,
+/// `SESSION_MAX_PER_USER`, `SESSION_LIMIT_STRATEGY`, `SESSION_ROLE_LIMITS`).
+#[derive(Debug, Clone)]
+pub struct SessionLimitPolicy {
+  pub default: SessionLimits,
+  pub roles: HashMap<String, RoleSessionLimits>,
+}
+
+impl Default for SessionLimitPolicy {
+  fn default() -> Self {
+    Self {
+      default: SessionLimits {
+        max_lifetime_secs: None,
+        max_per_user: None,
+        strategy: SessionLimitStrategy::EvictOldest,
+      },
+      roles: HashMap::new(),
+    }
+  }
+}
+
+impl SessionLimitPolicy {
+  pub fn new(
+    max_lifetime_secs: i64,
+    max_per_user: i64,
+    strategy: &str,
+    role_limits: Option<&str>,
+  ) -> Result<Self, String> {
+    if max_lifetime_secs < 0 {
+      return Err("SESSION_MAX_LIFETIME_SECS must not be negative".to_string());
+    }
+    if max_per_user < 0 {
+      return Err("SESSION_MAX_PER_USER must not be negative".to_string());
+    }
+    let strategy = strategy
+      .parse::<SessionLimitStrategy>()
+      .map_err(|e| format!("SESSION_LIMIT_STRATEGY: {e}"))?;
+    let roles = match role_limits {
+      Some(value) => serde_json::from_str::<HashMap<String, RoleSessionLimits>>(value)
+        .map_err(|e| format!("SESSION_ROLE_LIMITS must be a JSON object of role limits: {e}"))?,
+      None => HashMap::new(),
+    };
+    for (role, limits) in &roles {
+      if limits.max_lifetime_secs.is_some_and(|value| value < 0)
+        || limits.max_per_user.is_some_and(|value| value < 0)
+      {
+        return Err(format!(
+          "SESSION_ROLE_LIMITS: limits for role {role} must not be negative"
+        ));
+      }
+    }
+
+    Ok(Self {
+      default: SessionLimits {
+        max_lifetime_secs: positive(max_lifetime_secs),
+        max_per_user: positive(max_per_user),
+        strategy,
+      },
+      roles,
+    })
+  }
+
+  pub fn for_role(&self, role: &str) -> SessionLimits {
+    let Some(role_limits) = self.roles.get(role) else {
+      return self.default;
+    };
+    SessionLimits {
+      max_lifetime_secs: role_limits
+        .max_lifetime_secs
+        .map_or(self.default.max_lifetime_secs, positive),
+      max_per_user: role_limits
+        .max_per_user
+        .map_or(self.default.max_per_user, positive),
+      strategy: role_limits.strategy.unwrap_or(self.default.strategy),
+    }
+  }
+}
+
+fn positive(value: i64) -> Option<i64> {
+  (value > 0).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn zero_means_unlimited() {
+    let policy = SessionLimitPolicy::new(0, 0, "evict_oldest", None).unwrap();
+    assert_eq!(
+      policy.for_role("authenticated"),
+      SessionLimitPolicy::default().default
+    );
+  }
+
+  #[test]
+  fn role_limits_override_the_defaults() {
+    let policy = SessionLimitPolicy::new(
+      43_200,
+      5,
+      "evict_oldest",
+      Some(r#"{"support": {"max_per_user": 1, "strategy": "reject"}, "kiosk": {"max_lifetime_secs": 0}}"#),
+    )
+    .unwrap();
+
+    assert_eq!(
+      policy.for_role("support"),
+      SessionLimits {
+        max_lifetime_secs: Some(43_200),
+        max_per_user: Some(1),
+        strategy: SessionLimitStrategy::Reject,
+      }
+    );
+    assert_eq!(policy.for_role("kiosk").max_lifetime_secs, None);
+    assert_eq!(policy.for_role("kiosk").max_per_user, Some(5));
+    assert_eq!(policy.for_role("authenticated"), policy.default);
+  }
+
+  #[test]
+  fn rejects_invalid_settings() {
+    assert!(SessionLimitPolicy::new(-1, 0, "evict_oldest", None).is_err());
+    assert!(SessionLimitPolicy::new(0, 0, "newest_wins", None).is_err());
+    assert!(SessionLimitPolicy::new(0, 0, "reject", Some("[]")).is_err());
+    assert!(SessionLimitPolicy::new(0, 0, "reject", Some(r#"{"a": {"max_sessions": 1}}"#)).is_err());
+  }
+}
@@ -0,0 +1,137 @@
+//! "Remember this device" tokens. A device that completed TOTP can be handed
+//! a token that lets later password sign-ins from it skip the MFA step.
+
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{
+  DateTime,
+  Utc,
+};
+use rand::RngCore;
+use sqlx::PgPool;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+use crate::error::{
+  AuthError,
+  Result,
+};
+
+/// AMR method recorded for sessions that skipped MFA on a trusted device.
+pub const METHOD: &str = "trusted_device";
+
+/// A trusted device a presented token belongs to.
+#[derive(Debug, Clone, Copy, sqlx::FromRow)]
+pub struct TrustedDevice {
+  pub id: Uuid,
+  pub factor_id: Uuid,
+}
+
+/// A newly trusted device, with the token to hand back to it.
+#[derive(Debug, Clone)]
+pub struct IssuedTrustedDevice {
+  pub id: Uuid,
+  pub token: String,
+  pub expires_at: DateTime<Utc>,
+}
+
+/// How long a device asking for `requested` seconds is trusted for: at most
+/// `max_lifetime_secs`, where `0` means trusted devices are disabled.
+pub fn lifetime_secs(requested: i64, max_lifetime_secs: i64) -> Result<i64> {
+  if max_lifetime_secs <= 0 {
+    return Err(AuthError::ValidationFailed(
+      "Trusted devices are disabled".to_string(),
+    ));
+  }
+  if requested <= 0 {
+    return Err(AuthError::ValidationFailed(
+      "remember_device_for must be a positive number of seconds".to_string(),
+    ));
+  }
+  Ok(requested.min(max_lifetime_secs))
+}
+
+fn generate_token() -> String {
+  let mut bytes = [0u8; 32];
+  rand::rng().fill_bytes(&mut bytes);
+  URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Trusts the device `user_agent` / `ip` signed in from until `lifetime_secs`
+/// from now. Only the token's hash is stored.
+pub async fn issue(
+  db: &PgPool,
+  user_id: Uuid,
+  factor_id: Uuid,
+  lifetime_secs: i64,
+  user_agent: Option<&str>,
+  ip: IpAddr,
+) -> Result<IssuedTrustedDevice> {
+  let id = Uuid::new_v4();
+  let token = generate_token();
+  let now = Utc::now();
+  let expires_at = now + chrono::Duration::seconds(lifetime_secs);
+  sqlx::query(
+    "INSERT INTO auth.mfa_trusted_devices (id, user_id, factor_id, token_hash, user_agent, ip, created_at, last_used_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6::inet, $7, NULL, $8)",
+  )
+  .bind(id)
+  .bind(user_id)
+  .bind(factor_id)
+  .bind(crate::utils::sha256_hex(&token))
+  .bind(user_agent)
+  .bind(ip.to_string())
+  .bind(now)
+  .bind(expires_at)
+  .execute(db)
+  .await?;
+
+  Ok(IssuedTrustedDevice {
+    id,
+    token,
+    expires_at,
+  })
+}
+
+/// Looks up the unexpired trusted device of `user_id` that `token` belongs
+/// to, recording that it was used.
+pub async fn redeem(db: &PgPool, user_id: Uuid, token: &str) -> Result<Option<TrustedDevice>> {
+  let device = sqlx::query_as::<_, TrustedDevice>(
+    "UPDATE auth.mfa_trusted_devices SET last_used_at = now() WHERE user_id = $1 AND token_hash = $2 AND expires_at > now() RETURNING id, factor_id",
+  )
+  .bind(user_id)
+  .bind(crate::utils::sha256_hex(token.trim()))
+  .fetch_optional(db)
+  .await?;
+  Ok(device)
+}
+
+/// Forgets every device the user trusted, as when their password changes.
+pub async fn revoke_all(db: &mut sqlx::PgConnection, user_id: Uuid) -> Result<u64> {
+  let removed = sqlx::query("DELETE FROM auth.mfa_trusted_devices WHERE user_id = $1")
+    .bind(user_id)
+    .execute(db)
+    .await?
+    .rows_affected();
+  Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lifetime_is_capped_and_validated() {
+    assert_eq!(lifetime_secs(3600, 86_400).unwrap(), 3600);
+    assert_eq!(lifetime_secs(10_000_000, 86_400).unwrap(), 86_400);
+    assert!(lifetime_secs(0, 86_400).is_err());
+    assert!(lifetime_secs(-1, 86_400).is_err());
+    assert!(lifetime_secs(3600, 0).is_err());
+  }
+
+  #[test]
+  fn tokens_are_unique() {
+    let token = generate_token();
+    assert_eq!(URL_SAFE_NO_PAD.decode(&token).unwrap().len(), 32);
+    assert_ne!(token, generate_token());
+  }
+}
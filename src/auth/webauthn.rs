@@ -0,0 +1,825 @@
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ciborium::Value;
+use p256::ecdsa::signature::Verifier;
+use rand::RngCore;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use sha2::{
+  Digest,
+  Sha256,
+};
+use url::Url;
+use uuid::Uuid;
+
+use crate::error::AuthError;
+
+/// `amr` method recorded for sessions raised to aal2 with a WebAuthn factor.
+pub const METHOD: &str = "webauthn";
+/// How long the browser may take to complete a ceremony.
+pub const CEREMONY_TIMEOUT_MS: u64 = 300_000;
+const CHALLENGE_BYTES: usize = 32;
+const PUBLIC_KEY_TYPE: &str = "public-key";
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_BACKUP_ELIGIBLE: u8 = 0x08;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+const COSE_ALG_ES256: i64 = -7;
+const COSE_ALG_EDDSA: i64 = -8;
+const COSE_ALG_RS256: i64 = -257;
+
+/// The relying party credentials are scoped to (env: `WEBAUTHN_RP_ID`,
+/// `WEBAUTHN_RP_NAME`, `WEBAUTHN_ORIGINS`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelyingParty {
+  pub id: String,
+  pub name: String,
+  pub origins: Vec<String>,
+}
+
+impl RelyingParty {
+  /// Defaults the RP ID to the host of `site_url` and the allowed origins to
+  /// the origin of `site_url`.
+  pub fn new(site_url: &str, name: &str, id: Option<&str>, origins: &[String]) -> Result<Self, String> {
+    let site = Url::parse(site_url).map_err(|e| format!("SITE_URL is not a valid URL: {e}"))?;
+    let id = match id {
+      Some(id) => id.trim().to_ascii_lowercase(),
+      None => site
+        .host_str()
+        .ok_or_else(|| "SITE_URL has no host to use as the WebAuthn RP ID".to_string())?
+        .to_ascii_lowercase(),
+    };
+    let origins = if origins.is_empty() {
+      vec![site.origin().ascii_serialization()]
+    } else {
+      origins
+        .iter()
+        .map(|origin| {
+          Url::parse(origin)
+            .map(|url| url.origin().ascii_serialization())
+            .map_err(|e| format!("WEBAUTHN_ORIGINS entry {origin:?} is not a valid URL: {e}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?
+    };
+    for origin in &origins {
+      let host = Url::parse(origin)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+      if host != id && !host.ends_with(&format!(".{id}")) {
+        return Err(format!(
+          "WebAuthn origin {origin} is not on the RP ID {id} or one of its subdomains"
+        ));
+      }
+    }
+
+    Ok(Self {
+      id,
+      name: name.to_string(),
+      origins,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelyingPartyEntity {
+  pub id: String,
+  pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+  /// Base64url of the user's UUID bytes; passkeys return it as the user handle
+  pub id: String,
+  pub name: String,
+  pub display_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CredentialParameter {
+  #[serde(rename = "type")]
+  pub type_: String,
+  pub alg: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CredentialDescriptor {
+  #[serde(rename = "type")]
+  pub type_: String,
+  pub id: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub transports: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+  pub resident_key: String,
+  pub user_verification: String,
+}
+
+/// `PublicKeyCredentialCreationOptions`, in the JSON form accepted by
+/// `PublicKeyCredential.parseCreationOptionsFromJSON`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialCreationOptions {
+  pub challenge: String,
+  pub rp: RelyingPartyEntity,
+  pub user: UserEntity,
+  pub pub_key_cred_params: Vec<CredentialParameter>,
+  pub timeout: u64,
+  pub exclude_credentials: Vec<CredentialDescriptor>,
+  pub authenticator_selection: AuthenticatorSelection,
+  pub attestation: String,
+}
+
+/// `PublicKeyCredentialRequestOptions`, in the JSON form accepted by
+/// `PublicKeyCredential.parseRequestOptionsFromJSON`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialRequestOptions {
+  pub challenge: String,
+  pub rp_id: String,
+  pub allow_credentials: Vec<CredentialDescriptor>,
+  pub timeout: u64,
+  pub user_verification: String,
+}
+
+/// A `PublicKeyCredential` returned by `navigator.credentials.create()`,
+/// serialized with `toJSON()`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegistrationCredential {
+  pub id: String,
+  #[serde(rename = "type")]
+  pub type_: String,
+  pub response: AttestationResponse,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+  #[serde(rename = "clientDataJSON")]
+  pub client_data_json: String,
+  pub attestation_object: String,
+  #[serde(default)]
+  pub transports: Vec<String>,
+}
+
+/// A `PublicKeyCredential` returned by `navigator.credentials.get()`,
+/// serialized with `toJSON()`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AssertionCredential {
+  pub id: String,
+  #[serde(rename = "type")]
+  pub type_: String,
+  pub response: AssertionResponse,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+  #[serde(rename = "clientDataJSON")]
+  pub client_data_json: String,
+  pub authenticator_data: String,
+  pub signature: String,
+  #[serde(default)]
+  pub user_handle: Option<String>,
+}
+
+/// What is kept in `auth.mfa_factors.web_authn_credential` for a verified
+/// credential. `id` and `public_key` (a COSE key) are base64url encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredCredential {
+  pub id: String,
+  pub public_key: String,
+  pub sign_count: u32,
+  #[serde(default)]
+  pub transports: Vec<String>,
+  #[serde(default)]
+  pub backup_eligible: bool,
+}
+
+impl StoredCredential {
+  pub fn descriptor(&self) -> CredentialDescriptor {
+    CredentialDescriptor {
+      type_: PUBLIC_KEY_TYPE.to_string(),
+      id: self.id.clone(),
+      transports: self.transports.clone(),
+    }
+  }
+}
+
+/// A credential accepted by [`verify_registration`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+  pub credential: StoredCredential,
+  pub aaguid: Uuid,
+}
+
+pub fn generate_challenge() -> String {
+  let mut bytes = [0u8; CHALLENGE_BYTES];
+  rand::rng().fill_bytes(&mut bytes);
+  URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn user_handle(user_id: Uuid) -> String {
+  URL_SAFE_NO_PAD.encode(user_id.as_bytes())
+}
+
+/// The user a passkey belongs to, read from the assertion's user handle.
+pub fn user_id_from_handle(credential: &AssertionCredential) -> Result<Uuid, AuthError> {
+  let handle = credential
+    .response
+    .user_handle
+    .as_deref()
+    .ok_or_else(|| invalid("assertion has no user handle"))?;
+  Uuid::from_slice(&decode(handle, "userHandle")?).map_err(|_| invalid("unknown user handle"))
+}
+
+/// The challenge an assertion claims to answer. It still has to be checked
+/// by [`verify_assertion`].
+pub fn assertion_challenge(credential: &AssertionCredential) -> Result<String, AuthError> {
+  let client_data = decode(&credential.response.client_data_json, "clientDataJSON")?;
+  serde_json::from_slice::<ClientData>(&client_data)
+    .map(|client_data| client_data.challenge)
+    .map_err(|_| invalid("clientDataJSON is not valid JSON"))
+}
+
+pub fn creation_options(
+  rp: &RelyingParty,
+  challenge: &str,
+  user_id: Uuid,
+  user_name: &str,
+  exclude_credentials: Vec<CredentialDescriptor>,
+) -> CredentialCreationOptions {
+  CredentialCreationOptions {
+    challenge: challenge.to_string(),
+    rp: RelyingPartyEntity {
+      id: rp.id.clone(),
+      name: rp.name.clone(),
+    },
+    user: UserEntity {
+      id: user_handle(user_id),
+      name: user_name.to_string(),
+      display_name: user_name.to_string(),
+    },
+    pub_key_cred_params: [COSE_ALG_ES256, COSE_ALG_EDDSA, COSE_ALG_RS256]
+      .into_iter()
+      .map(|alg| CredentialParameter {
+        type_: PUBLIC_KEY_TYPE.to_string(),
+        alg,
+      })
+      .collect(),
+    timeout: CEREMONY_TIMEOUT_MS,
+    exclude_credentials,
+    authenticator_selection: AuthenticatorSelection {
+      resident_key: "preferred".to_string(),
+      user_verification: "preferred".to_string(),
+    },
+    attestation: "none".to_string(),
+  }
+}
+
+pub fn request_options(
+  rp: &RelyingParty,
+  challenge: &str,
+  allow_credentials: Vec<CredentialDescriptor>,
+) -> CredentialRequestOptions {
+  CredentialRequestOptions {
+    challenge: challenge.to_string(),
+    rp_id: rp.id.clone(),
+    allow_credentials,
+    timeout: CEREMONY_TIMEOUT_MS,
+    user_verification: "preferred".to_string(),
+  }
+}
+
+/// Options for signing in with a passkey alone. `allowCredentials` is empty
+/// so the browser offers every discoverable credential for the RP, and user
+/// verification is required because the passkey is the only factor checked.
+pub fn passkey_request_options(rp: &RelyingParty, challenge: &str) -> CredentialRequestOptions {
+  CredentialRequestOptions {
+    user_verification: "required".to_string(),
+    ..request_options(rp, challenge, Vec::new())
+  }
+}
+
+/// Checks a new credential against the challenge it was created for.
+/// Attestation statements are not verified, since registration asks for
+/// `attestation: "none"`.
+pub fn verify_registration(
+  rp: &RelyingParty,
+  challenge: &str,
+  credential: &RegistrationCredential,
+) -> Result<Registration, AuthError> {
+  ensure_public_key_type(&credential.type_)?;
+  let client_data = decode(&credential.response.client_data_json, "clientDataJSON")?;
+  check_client_data(rp, &client_data, "webauthn.create", challenge)?;
+
+  let attestation_object = decode(&credential.response.attestation_object, "attestationObject")?;
+  let attestation: Value = ciborium::from_reader(attestation_object.as_slice())
+    .map_err(|_| invalid("attestationObject is not valid CBOR"))?;
+  let auth_data = map_entry(&attestation, &Value::Text("authData".to_string()))
+    .and_then(Value::as_bytes)
+    .ok_or_else(|| invalid("attestationObject has no authData"))?;
+  let auth_data = parse_authenticator_data(auth_data)?;
+  check_authenticator_data(rp, &auth_data)?;
+  let attested = auth_data
+    .attested
+    .ok_or_else(|| invalid("authData has no attested credential"))?;
+
+  let id = URL_SAFE_NO_PAD.encode(&attested.credential_id);
+  if id != credential.id {
+    return Err(invalid("credential id does not match the attested credential"));
+  }
+  PublicKey::from_cose(&attested.public_key)?;
+
+  Ok(Registration {
+    credential: StoredCredential {
+      id,
+      public_key: URL_SAFE_NO_PAD.encode(&attested.public_key),
+      sign_count: auth_data.sign_count,
+      transports: credential.response.transports.clone(),
+      backup_eligible: auth_data.flags & FLAG_BACKUP_ELIGIBLE != 0,
+    },
+    aaguid: attested.aaguid,
+  })
+}
+
+/// Checks an assertion made with `stored` and returns the authenticator's new
+/// signature counter. A counter that did not advance means the credential
+/// may have been cloned and is rejected.
+pub fn verify_assertion(
+  rp: &RelyingParty,
+  challenge: &str,
+  stored: &StoredCredential,
+  credential: &AssertionCredential,
+  require_user_verification: bool,
+) -> Result<u32, AuthError> {
+  ensure_public_key_type(&credential.type_)?;
+  if credential.id != stored.id {
+    return Err(invalid("assertion was made with a different credential"));
+  }
+  let client_data = decode(&credential.response.client_data_json, "clientDataJSON")?;
+  check_client_data(rp, &client_data, "webauthn.get", challenge)?;
+
+  let raw_auth_data = decode(&credential.response.authenticator_data, "authenticatorData")?;
+  let auth_data = parse_authenticator_data(&raw_auth_data)?;
+  check_authenticator_data(rp, &auth_data)?;
+  if require_user_verification && auth_data.flags & FLAG_USER_VERIFIED == 0 {
+    return Err(invalid("user verification is required"));
+  }
+
+  let public_key = decode(&stored.public_key, "stored public key")?;
+  let signature = decode(&credential.response.signature, "signature")?;
+  let mut signed = raw_auth_data.clone();
+  signed.extend_from_slice(&Sha256::digest(&client_data));
+  PublicKey::from_cose(&public_key)?.verify(&signed, &signature)?;
+
+  if (auth_data.sign_count != 0 || stored.sign_count != 0) && auth_data.sign_count <= stored.sign_count {
+    return Err(invalid("signature counter did not increase"));
+  }
+
+  Ok(auth_data.sign_count)
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+  #[serde(rename = "type")]
+  type_: String,
+  challenge: String,
+  origin: String,
+  #[serde(rename = "crossOrigin", default)]
+  cross_origin: bool,
+}
+
+fn check_client_data(
+  rp: &RelyingParty,
+  raw: &[u8],
+  ceremony: &str,
+  challenge: &str,
+) -> Result<(), AuthError> {
+  let client_data: ClientData =
+    serde_json::from_slice(raw).map_err(|_| invalid("clientDataJSON is not valid JSON"))?;
+  if client_data.type_ != ceremony {
+    return Err(invalid("clientDataJSON is for a different ceremony"));
+  }
+  if client_data.challenge != challenge {
+    return Err(invalid("challenge does not match"));
+  }
+  if !rp.origins.contains(&client_data.origin) {
+    return Err(invalid("origin is not allowed"));
+  }
+  if client_data.cross_origin {
+    return Err(invalid("cross-origin ceremonies are not allowed"));
+  }
+  Ok(())
+}
+
+struct AuthenticatorData {
+  rp_id_hash: [u8; 32],
+  flags: u8,
+  sign_count: u32,
+  attested: Option<AttestedCredential>,
+}
+
+struct AttestedCredential {
+  aaguid: Uuid,
+  credential_id: Vec<u8>,
+  public_key: Vec<u8>,
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, AuthError> {
+  if data.len() < 37 {
+    return Err(invalid("authData is too short"));
+  }
+  let mut rp_id_hash = [0u8; 32];
+  rp_id_hash.copy_from_slice(&data[..32]);
+  let flags = data[32];
+  let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+  let attested = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+    let rest = &data[37..];
+    if rest.len() < 18 {
+      return Err(invalid("attested credential data is too short"));
+    }
+    let aaguid = Uuid::from_slice(&rest[..16]).map_err(|_| invalid("invalid AAGUID"))?;
+    let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+    let rest = &rest[18..];
+    if rest.len() < id_len {
+      return Err(invalid("credential id is truncated"));
+    }
+    let (credential_id, mut key_bytes) = rest.split_at(id_len);
+    let before = key_bytes.len();
+    let _: Value = ciborium::from_reader(&mut key_bytes)
+      .map_err(|_| invalid("credential public key is not valid CBOR"))?;
+    let key_len = before - key_bytes.len();
+    Some(AttestedCredential {
+      aaguid,
+      credential_id: credential_id.to_vec(),
+      public_key: rest[id_len..id_len + key_len].to_vec(),
+    })
+  } else {
+    None
+  };
+
+  Ok(AuthenticatorData {
+    rp_id_hash,
+    flags,
+    sign_count,
+    attested,
+  })
+}
+
+fn check_authenticator_data(rp: &RelyingParty, auth_data: &AuthenticatorData) -> Result<(), AuthError> {
+  if auth_data.rp_id_hash[..] != Sha256::digest(rp.id.as_bytes())[..] {
+    return Err(invalid("credential is for a different RP ID"));
+  }
+  if auth_data.flags & FLAG_USER_PRESENT == 0 {
+    return Err(invalid("user presence was not confirmed"));
+  }
+  Ok(())
+}
+
+enum PublicKey {
+  Es256(p256::ecdsa::VerifyingKey),
+  EdDsa(ed25519_dalek::VerifyingKey),
+  Rs256(rsa::pkcs1v15::VerifyingKey<Sha256>),
+}
+
+impl PublicKey {
+  fn from_cose(bytes: &[u8]) -> Result<Self, AuthError> {
+    let key: Value = ciborium::from_reader(bytes).map_err(|_| invalid("public key is not valid CBOR"))?;
+    let param = |label: i64| map_entry(&key, &Value::Integer(label.into()));
+    let bytes_param = |label: i64| {
+      param(label)
+        .and_then(Value::as_bytes)
+        .ok_or_else(|| invalid("public key is missing a parameter"))
+    };
+    let alg = param(3)
+      .and_then(Value::as_integer)
+      .and_then(|alg| i64::try_from(alg).ok())
+      .ok_or_else(|| invalid("public key has no algorithm"))?;
+
+    match alg {
+      COSE_ALG_ES256 => {
+        let (x, y) = (bytes_param(-2)?, bytes_param(-3)?);
+        if x.len() != 32 || y.len() != 32 {
+          return Err(invalid("invalid P-256 public key"));
+        }
+        let point = p256::EncodedPoint::from_affine_coordinates(
+          p256::FieldBytes::from_slice(x),
+          p256::FieldBytes::from_slice(y),
+          false,
+        );
+        p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+          .map(PublicKey::Es256)
+          .map_err(|_| invalid("invalid P-256 public key"))
+      },
+      COSE_ALG_EDDSA => {
+        let x: [u8; 32] = bytes_param(-2)?
+          .as_slice()
+          .try_into()
+          .map_err(|_| invalid("invalid Ed25519 public key"))?;
+        ed25519_dalek::VerifyingKey::from_bytes(&x)
+          .map(PublicKey::EdDsa)
+          .map_err(|_| invalid("invalid Ed25519 public key"))
+      },
+      COSE_ALG_RS256 => {
+        let n = rsa::BigUint::from_bytes_be(bytes_param(-1)?);
+        let e = rsa::BigUint::from_bytes_be(bytes_param(-2)?);
+        rsa::RsaPublicKey::new(n, e)
+          .map(|key| PublicKey::Rs256(rsa::pkcs1v15::VerifyingKey::new(key)))
+          .map_err(|_| invalid("invalid RSA public key"))
+      },
+      _ => Err(invalid("unsupported public key algorithm")),
+    }
+  }
+
+  fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), AuthError> {
+    let verified = match self {
+      PublicKey::Es256(key) => p256::ecdsa::Signature::from_der(signature)
+        .ok()
+        .is_some_and(|signature| key.verify(message, &signature).is_ok()),
+      PublicKey::EdDsa(key) => ed25519_dalek::Signature::from_slice(signature)
+        .ok()
+        .is_some_and(|signature| key.verify(message, &signature).is_ok()),
+      PublicKey::Rs256(key) => rsa::pkcs1v15::Signature::try_from(signature)
+        .ok()
+        .is_some_and(|signature| key.verify(message, &signature).is_ok()),
+    };
+    if verified {
+      Ok(())
+    } else {
+      Err(invalid("signature is not valid"))
+    }
+  }
+}
+
+fn map_entry<'a>(map: &'a Value, key: &Value) -> Option<&'a Value> {
+  map
+    .as_map()?
+    .iter()
+    .find(|(entry_key, _)| entry_key == key)
+    .map(|(_, value)| value)
+}
+
+fn ensure_public_key_type(type_: &str) -> Result<(), AuthError> {
+  if type_ != PUBLIC_KEY_TYPE {
+    return Err(invalid("credential type must be public-key"));
+  }
+  Ok(())
+}
+
+fn decode(value: &str, field: &str) -> Result<Vec<u8>, AuthError> {
+  URL_SAFE_NO_PAD
+    .decode(value.trim_end_matches('='))
+    .map_err(|_| AuthError::ValidationFailed(format!("{field} must be base64url encoded")))
+}
+
+fn invalid(message: &str) -> AuthError {
+  AuthError::ValidationFailed(format!("Invalid WebAuthn credential: {message}"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use argon2::password_hash::rand_core::OsRng;
+  use p256::ecdsa::signature::Signer;
+
+  /// A software authenticator holding one P-256 credential, so ceremonies
+  /// can be exercised without hardware.
+  struct SoftAuthenticator {
+    credential_id: Vec<u8>,
+    key: p256::ecdsa::SigningKey,
+    sign_count: u32,
+    user_verified: bool,
+  }
+
+  impl SoftAuthenticator {
+    fn new() -> Self {
+      Self {
+        credential_id: Uuid::new_v4().as_bytes().to_vec(),
+        key: p256::ecdsa::SigningKey::random(&mut OsRng),
+        sign_count: 0,
+        user_verified: true,
+      }
+    }
+
+    fn cose_key(&self) -> Vec<u8> {
+      let point = self.key.verifying_key().to_encoded_point(false);
+      let key = Value::Map(vec![
+        (Value::Integer(1.into()), Value::Integer(2.into())),
+        (Value::Integer(3.into()), Value::Integer(COSE_ALG_ES256.into())),
+        (Value::Integer((-1).into()), Value::Integer(1.into())),
+        (
+          Value::Integer((-2).into()),
+          Value::Bytes(point.x().unwrap().to_vec()),
+        ),
+        (
+          Value::Integer((-3).into()),
+          Value::Bytes(point.y().unwrap().to_vec()),
+        ),
+      ]);
+      let mut bytes = Vec::new();
+      ciborium::into_writer(&key, &mut bytes).unwrap();
+      bytes
+    }
+
+    fn auth_data(&self, rp_id: &str, attested: bool) -> Vec<u8> {
+      let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+      let mut flags = FLAG_USER_PRESENT;
+      if self.user_verified {
+        flags |= FLAG_USER_VERIFIED;
+      }
+      if attested {
+        flags |= FLAG_ATTESTED_CREDENTIAL_DATA;
+      }
+      data.push(flags);
+      data.extend_from_slice(&self.sign_count.to_be_bytes());
+      if attested {
+        data.extend_from_slice(&[0u8; 16]);
+        data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.credential_id);
+        data.extend_from_slice(&self.cose_key());
+      }
+      data
+    }
+
+    fn client_data(ceremony: &str, challenge: &str, origin: &str) -> Vec<u8> {
+      serde_json::to_vec(&serde_json::json!({
+        "type": ceremony,
+        "challenge": challenge,
+        "origin": origin,
+        "crossOrigin": false,
+      }))
+      .unwrap()
+    }
+
+    fn register(&self, rp_id: &str, challenge: &str, origin: &str) -> RegistrationCredential {
+      let attestation = Value::Map(vec![
+        (Value::Text("fmt".to_string()), Value::Text("none".to_string())),
+        (Value::Text("attStmt".to_string()), Value::Map(Vec::new())),
+        (
+          Value::Text("authData".to_string()),
+          Value::Bytes(self.auth_data(rp_id, true)),
+        ),
+      ]);
+      let mut attestation_object = Vec::new();
+      ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+      RegistrationCredential {
+        id: URL_SAFE_NO_PAD.encode(&self.credential_id),
+        type_: PUBLIC_KEY_TYPE.to_string(),
+        response: AttestationResponse {
+          client_data_json: URL_SAFE_NO_PAD.encode(Self::client_data("webauthn.create", challenge, origin)),
+          attestation_object: URL_SAFE_NO_PAD.encode(attestation_object),
+          transports: vec!["internal".to_string()],
+        },
+      }
+    }
+
+    fn assert(
+      &mut self,
+      rp_id: &str,
+      challenge: &str,
+      origin: &str,
+      user_handle: Option<String>,
+    ) -> AssertionCredential {
+      self.sign_count += 1;
+      let auth_data = self.auth_data(rp_id, false);
+      let client_data = Self::client_data("webauthn.get", challenge, origin);
+      let mut signed = auth_data.clone();
+      signed.extend_from_slice(&Sha256::digest(&client_data));
+      let signature: p256::ecdsa::Signature = self.key.sign(&signed);
+      AssertionCredential {
+        id: URL_SAFE_NO_PAD.encode(&self.credential_id),
+        type_: PUBLIC_KEY_TYPE.to_string(),
+        response: AssertionResponse {
+          client_data_json: URL_SAFE_NO_PAD.encode(client_data),
+          authenticator_data: URL_SAFE_NO_PAD.encode(auth_data),
+          signature: URL_SAFE_NO_PAD.encode(signature.to_der()),
+          user_handle,
+        },
+      }
+    }
+  }
+
+  fn rp() -> RelyingParty {
+    RelyingParty::new(
+      "https://auth.example.com",
+      "Haya",
+      Some("example.com"),
+      &["https://app.example.com".to_string()],
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn registers_and_authenticates_with_a_software_authenticator() {
+    let rp = rp();
+    let mut authenticator = SoftAuthenticator::new();
+    let challenge = generate_challenge();
+    let registration = verify_registration(
+      &rp,
+      &challenge,
+      &authenticator.register("example.com", &challenge, "https://app.example.com"),
+    )
+    .unwrap();
+    assert_eq!(registration.credential.sign_count, 0);
+    assert_eq!(registration.credential.transports, ["internal"]);
+
+    let challenge = generate_challenge();
+    let assertion = authenticator.assert("example.com", &challenge, "https://app.example.com", None);
+    let sign_count = verify_assertion(&rp, &challenge, &registration.credential, &assertion, false).unwrap();
+    assert_eq!(sign_count, 1);
+
+    let stale = StoredCredential {
+      sign_count: 5,
+      ..registration.credential
+    };
+    assert!(verify_assertion(&rp, &challenge, &stale, &assertion, false).is_err());
+  }
+
+  #[test]
+  fn rejects_wrong_challenge_origin_and_rp_id() {
+    let rp = rp();
+    let authenticator = SoftAuthenticator::new();
+    let challenge = generate_challenge();
+
+    let other_challenge =
+      authenticator.register("example.com", &generate_challenge(), "https://app.example.com");
+    assert!(verify_registration(&rp, &challenge, &other_challenge).is_err());
+    let other_origin = authenticator.register("example.com", &challenge, "https://evil.test");
+    assert!(verify_registration(&rp, &challenge, &other_origin).is_err());
+    let other_rp = authenticator.register("evil.test", &challenge, "https://app.example.com");
+    assert!(verify_registration(&rp, &challenge, &other_rp).is_err());
+  }
+
+  #[test]
+  fn rejects_a_signature_from_another_key() {
+    let rp = rp();
+    let authenticator = SoftAuthenticator::new();
+    let challenge = generate_challenge();
+    let registration = verify_registration(
+      &rp,
+      &challenge,
+      &authenticator.register("example.com", &challenge, "https://app.example.com"),
+    )
+    .unwrap();
+
+    let mut impostor = SoftAuthenticator::new();
+    impostor.credential_id = authenticator.credential_id.clone();
+    let assertion = impostor.assert("example.com", &challenge, "https://app.example.com", None);
+    assert!(verify_assertion(&rp, &challenge, &registration.credential, &assertion, false).is_err());
+  }
+
+  #[test]
+  fn passkey_sign_in_requires_user_verification() {
+    let rp = rp();
+    let mut authenticator = SoftAuthenticator::new();
+    let challenge = generate_challenge();
+    let registration = verify_registration(
+      &rp,
+      &challenge,
+      &authenticator.register("example.com", &challenge, "https://app.example.com"),
+    )
+    .unwrap();
+    let user_id = Uuid::new_v4();
+
+    let challenge = generate_challenge();
+    let assertion = authenticator.assert(
+      "example.com",
+      &challenge,
+      "https://app.example.com",
+      Some(user_handle(user_id)),
+    );
+    assert_eq!(user_id_from_handle(&assertion).unwrap(), user_id);
+    assert_eq!(assertion_challenge(&assertion).unwrap(), challenge);
+    assert!(verify_assertion(&rp, &challenge, &registration.credential, &assertion, true).is_ok());
+
+    authenticator.user_verified = false;
+    let assertion = authenticator.assert("example.com", &challenge, "https://app.example.com", None);
+    assert!(user_id_from_handle(&assertion).is_err());
+    assert!(verify_assertion(&rp, &challenge, &registration.credential, &assertion, true).is_err());
+    assert!(verify_assertion(&rp, &challenge, &registration.credential, &assertion, false).is_ok());
+  }
+
+  #[test]
+  fn relying_party_origins_must_match_the_rp_id() {
+    let rp = RelyingParty::new("https://auth.example.com/path", "Haya", None, &[]).unwrap();
+    assert_eq!(rp.id, "auth.example.com");
+    assert_eq!(rp.origins, ["https://auth.example.com"]);
+    assert!(
+      RelyingParty::new(
+        "https://auth.example.com",
+        "Haya",
+        Some("example.com"),
+        &["https://example.org".to_string()]
+      )
+      .is_err()
+    );
+  }
+}
@@ -1,6 +1,14 @@
 pub const AUTHORIZATION_CODE_LIFETIME: i64 = 300;
 pub const ACCESS_TOKEN_LIFETIME: i64 = 3600;
+pub const CLIENT_CREDENTIALS_TOKEN_LIFETIME: i64 = 900;
 pub const REFRESH_TOKEN_LIFETIME: i64 = 1_209_600;
 pub const SESSION_IDLE_TIMEOUT_SECS: i64 = 86_400;
+pub const REFRESH_TOKEN_REUSE_INTERVAL: i64 = 10;
+pub const SESSION_MAX_LIFETIME_SECS: i64 = 0;
+pub const SESSION_MAX_PER_USER: i64 = 0;
+pub const MFA_TRUSTED_DEVICE_MAX_LIFETIME_SECS: i64 = 2_592_000;
+pub const PASSWORD_MIN_LENGTH: usize = 12;
+pub const PASSWORD_MAX_LENGTH: usize = 128;
 pub const DEFAULT_DATABASE_URL: &str = "postgres://localhost:0/haya";
 pub const DEFAULT_PORT: u16 = 9999;
+pub const ACCESS_TOKEN_HOOK_TIMEOUT_MS: u64 = 2_000;
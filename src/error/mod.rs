@@ -20,10 +20,20 @@ pub enum AuthError {
   UserAlreadyExists,
   #[error("Validation failed: {0}")]
   ValidationFailed(String),
+  /// A new password fails the password policy. `reasons` uses GoTrue's
+  /// names `length`, `characters` and `pwned`, plus `banned_words` and
+  /// `strength`.
+  #[error("{message}")]
+  WeakPassword {
+    message: String,
+    reasons: Vec<&'static str>,
+  },
   #[error("User not found")]
   UserNotFound,
   #[error("Session not found")]
   SessionNotFound,
+  #[error("Trusted device not found")]
+  TrustedDeviceNotFound,
   #[error("Invalid token")]
   InvalidToken,
   #[error("Token expired")]
@@ -34,8 +44,28 @@ pub enum AuthError {
   NotAdmin,
   #[error("User banned")]
   UserBanned,
+  /// The caller holds an impersonation token, which cannot perform this action
+  #[error("Not allowed while impersonating a user")]
+  ImpersonationForbidden,
+  /// The user already has the maximum number of sessions and new sign-ins are rejected
+  #[error("Too many active sessions")]
+  SessionLimitReached,
   #[error("Too many requests")]
   TooManyRequests,
+  #[error("CAPTCHA verification failed")]
+  CaptchaFailed,
+  /// An RFC 6749 error from the OAuth endpoints, rendered as `{error, error_description}`
+  #[error("{description}")]
+  OAuth {
+    error: &'static str,
+    description: String,
+  },
+  /// The custom access-token hook refused to issue a token
+  #[error("{message}")]
+  HookRejected { status: StatusCode, message: String },
+  /// The custom access-token hook timed out, failed, or returned unusable claims
+  #[error("Access token hook failed: {0}")]
+  HookFailed(String),
   #[error("Database error: {0}")]
   DatabaseError(#[from] sqlx::Error),
   #[error("Internal error: {0}")]
@@ -43,20 +73,39 @@ pub enum AuthError {
 }
 
 impl AuthError {
+  pub fn oauth(error: &'static str, description: impl Into<String>) -> Self {
+    AuthError::OAuth {
+      error,
+      description: description.into(),
+    }
+  }
+
   fn status_code(&self) -> StatusCode {
     match self {
       AuthError::InvalidCredentials => StatusCode::UNAUTHORIZED,
       AuthError::EmailNotConfirmed => StatusCode::UNAUTHORIZED,
       AuthError::UserAlreadyExists => StatusCode::UNPROCESSABLE_ENTITY,
       AuthError::ValidationFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+      AuthError::WeakPassword { .. } => StatusCode::UNPROCESSABLE_ENTITY,
       AuthError::UserNotFound => StatusCode::NOT_FOUND,
       AuthError::SessionNotFound => StatusCode::NOT_FOUND,
+      AuthError::TrustedDeviceNotFound => StatusCode::NOT_FOUND,
       AuthError::InvalidToken => StatusCode::UNAUTHORIZED,
       AuthError::TokenExpired => StatusCode::UNAUTHORIZED,
       AuthError::NotAuthorized => StatusCode::UNAUTHORIZED,
       AuthError::NotAdmin => StatusCode::FORBIDDEN,
       AuthError::UserBanned => StatusCode::FORBIDDEN,
+      AuthError::ImpersonationForbidden => StatusCode::FORBIDDEN,
+      AuthError::SessionLimitReached => StatusCode::FORBIDDEN,
       AuthError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+      AuthError::CaptchaFailed => StatusCode::BAD_REQUEST,
+      AuthError::OAuth { error, .. } => match *error {
+        "invalid_client" => StatusCode::UNAUTHORIZED,
+        "server_error" => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+      },
+      AuthError::HookRejected { status, .. } => *status,
+      AuthError::HookFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
       AuthError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
       AuthError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
     }
@@ -68,14 +117,22 @@ impl AuthError {
       AuthError::EmailNotConfirmed => "email_not_confirmed",
       AuthError::UserAlreadyExists => "user_already_exists",
       AuthError::ValidationFailed(_) => "validation_failed",
+      AuthError::WeakPassword { .. } => "weak_password",
       AuthError::UserNotFound => "user_not_found",
       AuthError::SessionNotFound => "session_not_found",
+      AuthError::TrustedDeviceNotFound => "trusted_device_not_found",
       AuthError::InvalidToken => "bad_jwt",
       AuthError::TokenExpired => "bad_jwt",
       AuthError::NotAuthorized => "no_authorization",
       AuthError::NotAdmin => "not_admin",
       AuthError::UserBanned => "user_banned",
+      AuthError::ImpersonationForbidden => "impersonation_forbidden",
+      AuthError::SessionLimitReached => "session_limit_reached",
       AuthError::TooManyRequests => "too_many_requests",
+      AuthError::CaptchaFailed => "captcha_failed",
+      AuthError::OAuth { error, .. } => error,
+      AuthError::HookRejected { .. } => "hook_rejected",
+      AuthError::HookFailed(_) => "hook_failed",
       AuthError::DatabaseError(_) => "unexpected_failure",
       AuthError::InternalError(_) => "unexpected_failure",
     }
@@ -85,6 +142,13 @@ impl AuthError {
 impl IntoResponse for AuthError {
   fn into_response(self) -> Response {
     let status = self.status_code();
+    if let AuthError::OAuth { error, description } = &self {
+      let body = json!({
+          "error": error,
+          "error_description": description,
+      });
+      return (status, Json(body)).into_response();
+    }
     // Never send internal implementation details or SQL errors to clients.
     // Log them server-side instead.
     let msg = match &self {
@@ -96,13 +160,20 @@ impl IntoResponse for AuthError {
         tracing::error!("Internal error: {e}");
         "An unexpected error occurred".to_string()
       },
+      AuthError::HookFailed(e) => {
+        tracing::error!("Access token hook failed: {e}");
+        "Access token hook failed".to_string()
+      },
       _ => self.to_string(),
     };
-    let body = json!({
+    let mut body = json!({
         "code": status.as_u16(),
         "error_code": self.error_code(),
         "msg": msg,
     });
+    if let AuthError::WeakPassword { reasons, .. } = &self {
+      body["weak_password"] = json!({ "reasons": reasons });
+    }
     (status, Json(body)).into_response()
   }
 }
@@ -120,14 +191,51 @@ mod tests {
       AuthError::ValidationFailed("bad input".into()).error_code(),
       "validation_failed"
     );
+    assert_eq!(
+      AuthError::WeakPassword {
+        message: "weak".into(),
+        reasons: vec!["pwned"],
+      }
+      .error_code(),
+      "weak_password"
+    );
     assert_eq!(AuthError::UserNotFound.error_code(), "user_not_found");
     assert_eq!(AuthError::SessionNotFound.error_code(), "session_not_found");
+    assert_eq!(
+      AuthError::TrustedDeviceNotFound.error_code(),
+      "trusted_device_not_found"
+    );
     assert_eq!(AuthError::InvalidToken.error_code(), "bad_jwt");
     assert_eq!(AuthError::TokenExpired.error_code(), "bad_jwt");
     assert_eq!(AuthError::NotAuthorized.error_code(), "no_authorization");
     assert_eq!(AuthError::NotAdmin.error_code(), "not_admin");
     assert_eq!(AuthError::UserBanned.error_code(), "user_banned");
+    assert_eq!(
+      AuthError::ImpersonationForbidden.error_code(),
+      "impersonation_forbidden"
+    );
+    assert_eq!(
+      AuthError::SessionLimitReached.error_code(),
+      "session_limit_reached"
+    );
     assert_eq!(AuthError::TooManyRequests.error_code(), "too_many_requests");
+    assert_eq!(AuthError::CaptchaFailed.error_code(), "captcha_failed");
+    assert_eq!(
+      AuthError::oauth("invalid_grant", "code expired").error_code(),
+      "invalid_grant"
+    );
+    assert_eq!(
+      AuthError::HookRejected {
+        status: StatusCode::FORBIDDEN,
+        message: "no".into()
+      }
+      .error_code(),
+      "hook_rejected"
+    );
+    assert_eq!(
+      AuthError::HookFailed("timeout".into()).error_code(),
+      "hook_failed"
+    );
     assert_eq!(
       AuthError::InternalError("oops".into()).error_code(),
       "unexpected_failure"
@@ -154,15 +262,48 @@ mod tests {
     );
     assert_eq!(AuthError::UserNotFound.status_code(), StatusCode::NOT_FOUND);
     assert_eq!(AuthError::SessionNotFound.status_code(), StatusCode::NOT_FOUND);
+    assert_eq!(
+      AuthError::TrustedDeviceNotFound.status_code(),
+      StatusCode::NOT_FOUND
+    );
     assert_eq!(AuthError::InvalidToken.status_code(), StatusCode::UNAUTHORIZED);
     assert_eq!(AuthError::TokenExpired.status_code(), StatusCode::UNAUTHORIZED);
     assert_eq!(AuthError::NotAuthorized.status_code(), StatusCode::UNAUTHORIZED);
     assert_eq!(AuthError::NotAdmin.status_code(), StatusCode::FORBIDDEN);
     assert_eq!(AuthError::UserBanned.status_code(), StatusCode::FORBIDDEN);
+    assert_eq!(
+      AuthError::ImpersonationForbidden.status_code(),
+      StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+      AuthError::SessionLimitReached.status_code(),
+      StatusCode::FORBIDDEN
+    );
     assert_eq!(
       AuthError::TooManyRequests.status_code(),
       StatusCode::TOO_MANY_REQUESTS
     );
+    assert_eq!(AuthError::CaptchaFailed.status_code(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+      AuthError::oauth("invalid_client", "").status_code(),
+      StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+      AuthError::oauth("invalid_grant", "").status_code(),
+      StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+      AuthError::HookRejected {
+        status: StatusCode::PAYMENT_REQUIRED,
+        message: "".into()
+      }
+      .status_code(),
+      StatusCode::PAYMENT_REQUIRED
+    );
+    assert_eq!(
+      AuthError::HookFailed("".into()).status_code(),
+      StatusCode::INTERNAL_SERVER_ERROR
+    );
     assert_eq!(
       AuthError::InternalError("".into()).status_code(),
       StatusCode::INTERNAL_SERVER_ERROR
@@ -182,4 +323,21 @@ mod tests {
       "Internal error: DB failure"
     );
   }
+
+  #[tokio::test]
+  async fn weak_password_response_lists_reasons() {
+    let response = AuthError::WeakPassword {
+      message: "Password is known to be weak and easy to guess, please choose a different one.".into(),
+      reasons: vec!["length", "pwned"],
+    }
+    .into_response();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+      .await
+      .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error_code"], "weak_password");
+    assert_eq!(body["weak_password"]["reasons"], json!(["length", "pwned"]));
+  }
 }
@@ -22,6 +22,7 @@
 //! | `recovery.html/txt`| `{{site_name}}`, `{{recovery_url}}`, `{{email}}`          |
 //! | `magic_link.html/txt` | `{{site_name}}`, `{{magic_link_url}}`, `{{email}}`     |
 //! | `reauthenticate.html/txt` | `{{site_name}}`, `{{reauthentication_token}}`, `{{email}}`, `{{expires_minutes}}` |
+//! | `mfa_recovery_code_used.html/txt` | `{{site_name}}`, `{{email}}`, `{{remaining_codes}}` |
 
 use std::path::Path;
 
@@ -163,6 +164,34 @@ const DEFAULT_REAUTH_HTML: &str = r#"<!DOCTYPE html>
 
 const DEFAULT_REAUTH_TXT: &str = "Confirm this sensitive action for {{site_name}}\n\nUse this token for {{email}}:\n\n{{reauthentication_token}}\n\nThis token expires in {{expires_minutes}} minutes. If you didn't request this, you can safely ignore this email.\n";
 
+const DEFAULT_RECOVERY_CODE_USED_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="UTF-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1.0">
+  <title>A recovery code was used</title>
+  <style>
+    body { font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif; background: #f9fafb; margin: 0; padding: 40px 20px; }
+    .card { background: white; border-radius: 8px; max-width: 480px; margin: 0 auto; padding: 40px; box-shadow: 0 1px 3px rgba(0,0,0,.1); }
+    h2 { margin: 0 0 16px; font-size: 22px; color: #111; }
+    p { margin: 0 0 16px; color: #555; line-height: 1.6; }
+    .footer { margin-top: 32px; font-size: 12px; color: #999; }
+  </style>
+</head>
+<body>
+  <div class="card">
+    <h2>A recovery code was used</h2>
+    <p>Someone signed in to <strong>{{email}}</strong> on <strong>{{site_name}}</strong> with one of your MFA recovery codes. You have <strong>{{remaining_codes}}</strong> unused codes left.</p>
+    <p>If you have lost your authenticator, enroll a new one and generate a fresh set of recovery codes.</p>
+    <div class="footer">
+      <p>If this wasn't you, change your password and regenerate your recovery codes right away.</p>
+    </div>
+  </div>
+</body>
+</html>"#;
+
+const DEFAULT_RECOVERY_CODE_USED_TXT: &str = "A recovery code was used on {{site_name}}\n\nSomeone signed in to {{email}} with one of your MFA recovery codes. You have {{remaining_codes}} unused codes left.\n\nIf you have lost your authenticator, enroll a new one and generate a fresh set of recovery codes.\n\nIf this wasn't you, change your password and regenerate your recovery codes right away.\n";
+
 // ── Email kind ───────────────────────────────────────────────────────────────
 
 /// Identifies which email to send.  Each variant maps to a pair of template
@@ -176,6 +205,8 @@ pub enum EmailKind {
   MagicLink,
   /// Re-authentication token for sensitive actions.
   Reauthentication,
+  /// Notice that an MFA recovery code was used to sign in.
+  RecoveryCodeUsed,
 }
 
 impl EmailKind {
@@ -186,6 +217,7 @@ impl EmailKind {
       Self::Recovery => "recovery",
       Self::MagicLink => "magic_link",
       Self::Reauthentication => "reauthenticate",
+      Self::RecoveryCodeUsed => "mfa_recovery_code_used",
     }
   }
 
@@ -196,6 +228,7 @@ impl EmailKind {
       Self::Recovery => "Reset your password",
       Self::MagicLink => "Your magic link",
       Self::Reauthentication => "Confirm this sensitive action",
+      Self::RecoveryCodeUsed => "A recovery code was used to sign in",
     }
   }
 
@@ -205,6 +238,7 @@ impl EmailKind {
       Self::Recovery => DEFAULT_RECOVERY_HTML,
       Self::MagicLink => DEFAULT_MAGIC_LINK_HTML,
       Self::Reauthentication => DEFAULT_REAUTH_HTML,
+      Self::RecoveryCodeUsed => DEFAULT_RECOVERY_CODE_USED_HTML,
     }
   }
 
@@ -214,6 +248,7 @@ impl EmailKind {
       Self::Recovery => DEFAULT_RECOVERY_TXT,
       Self::MagicLink => DEFAULT_MAGIC_LINK_TXT,
       Self::Reauthentication => DEFAULT_REAUTH_TXT,
+      Self::RecoveryCodeUsed => DEFAULT_RECOVERY_CODE_USED_TXT,
     }
   }
 }
@@ -7,33 +7,53 @@ mod mailer;
 mod middleware;
 mod model;
 mod public;
+mod sms;
 mod state;
 mod utils;
 
 use std::env;
 use std::sync::Arc;
 
+use anyhow::Context as _;
 use base64::Engine as _;
 use clap::Parser;
+use jsonwebtoken::Algorithm;
 use rand::RngCore;
 use tokio::sync::RwLock;
 
+use crate::auth::binding::SessionBindingPolicy;
+use crate::auth::breached_password::BreachedPasswords;
+use crate::auth::password_policy::PasswordPolicy;
+use crate::auth::session_limits::SessionLimitPolicy;
+use crate::auth::webauthn::RelyingParty;
 use crate::auth::{
+  captcha,
+  hook,
+  jwt,
+  keyring,
   mfa,
   oidc,
+  session,
 };
 use crate::cli::Cli;
 use crate::defaults::{
+  ACCESS_TOKEN_HOOK_TIMEOUT_MS,
   ACCESS_TOKEN_LIFETIME,
+  CLIENT_CREDENTIALS_TOKEN_LIFETIME,
   DEFAULT_DATABASE_URL,
   DEFAULT_PORT,
+  MFA_TRUSTED_DEVICE_MAX_LIFETIME_SECS,
   REFRESH_TOKEN_LIFETIME,
+  REFRESH_TOKEN_REUSE_INTERVAL,
   SESSION_IDLE_TIMEOUT_SECS,
+  SESSION_MAX_LIFETIME_SECS,
+  SESSION_MAX_PER_USER,
 };
 use crate::mailer::{
   Mailer,
   MailerConfig,
 };
+use crate::sms::SmsSender;
 use crate::state::{
   AppState,
   RuntimeConfig,
@@ -50,9 +70,20 @@ struct RuntimeBootstrap {
   config: RuntimeConfig,
   http_client: reqwest::Client,
   jwt_secret: String,
-  mfa_encryption_key: [u8; 32],
+  jwt_algorithm: Algorithm,
+  jwt_key_id: Option<String>,
+  access_token_hook: Option<Arc<hook::AccessTokenHook>>,
+  captcha: Option<Arc<captcha::Captcha>>,
+  session_binding: SessionBindingPolicy,
+  session_limits: Arc<SessionLimitPolicy>,
+  webauthn: Arc<RelyingParty>,
+  mfa_keys: Arc<mfa::EncryptionKeys>,
+  refresh_token_reuse_key: [u8; 32],
+  totp: mfa::TotpPolicy,
+  password_policy: Arc<PasswordPolicy>,
   instance_id: Uuid,
   mailer: Option<Arc<Mailer>>,
+  sms: Option<Arc<dyn SmsSender>>,
 }
 
 #[tokio::main]
@@ -153,8 +184,24 @@ fn build_runtime_bootstrap(require_database: bool) -> anyhow::Result<RuntimeBoot
   if require_database && dev_mode {
     anyhow::bail!("HAYA_DEV_MODE must not be enabled when running with a required database");
   }
+  let jwt_algorithm = match env::var("JWT_ALGORITHM") {
+    Ok(value) if !value.trim().is_empty() => jwt::parse_signing_algorithm(&value)
+      .ok_or_else(|| anyhow::anyhow!("JWT_ALGORITHM must be one of HS256, RS256, ES256, or EdDSA"))?,
+    _ => Algorithm::HS256,
+  };
+  let jwt_signing_key_path = env::var("JWT_SIGNING_KEY_PATH")
+    .ok()
+    .filter(|value| !value.trim().is_empty());
+  let jwt_key_id = env::var("JWT_KEY_ID")
+    .ok()
+    .filter(|value| !value.trim().is_empty());
+  if require_database && jwt_algorithm != Algorithm::HS256 && jwt_signing_key_path.is_none() {
+    anyhow::bail!("JWT_SIGNING_KEY_PATH is required when JWT_ALGORITHM is {jwt_algorithm:?}");
+  }
+
   let jwt_secret = match env::var("JWT_SECRET") {
     Ok(secret) if secret.len() >= 32 => secret,
+    Ok(secret) if secret.is_empty() && jwt_algorithm != Algorithm::HS256 => secret,
     Ok(secret) if secret.is_empty() && require_database => {
       anyhow::bail!("JWT_SECRET must not be empty");
     },
@@ -163,6 +210,9 @@ fn build_runtime_bootstrap(require_database: bool) -> anyhow::Result<RuntimeBoot
       anyhow::bail!("JWT_SECRET must be at least 32 characters long");
     },
     Ok(secret) => secret,
+    // Asymmetric signing does not need a shared secret; when one is set it
+    // keeps verifying HS256 tokens issued before the switch.
+    Err(_) if jwt_algorithm != Algorithm::HS256 => String::new(),
     Err(_) if dev_mode => {
       let mut bytes = [0u8; 32];
       rand::rng().fill_bytes(&mut bytes);
@@ -188,15 +238,49 @@ fn build_runtime_bootstrap(require_database: bool) -> anyhow::Result<RuntimeBoot
     .ok()
     .and_then(|v| v.parse().ok())
     .unwrap_or(ACCESS_TOKEN_LIFETIME);
+  let client_credentials_jwt_exp: i64 = env::var("CLIENT_CREDENTIALS_JWT_EXPIRY")
+    .ok()
+    .and_then(|v| v.parse().ok())
+    .unwrap_or(CLIENT_CREDENTIALS_TOKEN_LIFETIME);
 
-  let (mfa_encryption_key, mfa_key_source) = match env::var("MFA_ENCRYPTION_KEY") {
-    Ok(value) if !value.trim().is_empty() => (mfa::derive_encryption_key(&value), "env"),
-    Ok(_) if require_database => anyhow::bail!("MFA_ENCRYPTION_KEY must not be empty when set"),
-    Ok(_) => ([0; 32], "unset"),
-    Err(_) if require_database => anyhow::bail!(
-      "MFA_ENCRYPTION_KEY environment variable is required and must be set independently from JWT_SECRET"
-    ),
-    Err(_) => ([0; 32], "unset"),
+  let legacy_mfa_key = env::var("MFA_ENCRYPTION_KEY").ok();
+  let labeled_mfa_keys = env::var("MFA_ENCRYPTION_KEYS")
+    .ok()
+    .filter(|value| !value.trim().is_empty());
+  if require_database
+    && legacy_mfa_key
+      .as_deref()
+      .is_some_and(|value| value.trim().is_empty())
+  {
+    anyhow::bail!("MFA_ENCRYPTION_KEY must not be empty when set");
+  }
+  let mfa_key_configured = labeled_mfa_keys.is_some()
+    || legacy_mfa_key
+      .as_deref()
+      .is_some_and(|value| !value.trim().is_empty());
+  if require_database && !mfa_key_configured {
+    anyhow::bail!(
+      "MFA_ENCRYPTION_KEY or MFA_ENCRYPTION_KEYS is required and must be set independently from JWT_SECRET"
+    );
+  }
+  let (mfa_keys, mfa_key_source, mfa_key_error) =
+    match mfa::EncryptionKeys::from_config(legacy_mfa_key.as_deref(), labeled_mfa_keys.as_deref()) {
+      Ok(keys) => (keys, "env", None),
+      Err(e) if require_database => anyhow::bail!(e),
+      Err(e) => (
+        mfa::EncryptionKeys::legacy([0; 32]),
+        "unset",
+        mfa_key_configured.then_some(e),
+      ),
+    };
+  let trusted_device_max_lifetime_secs: i64 = env::var("MFA_TRUSTED_DEVICE_MAX_LIFETIME_SECS")
+    .ok()
+    .and_then(|v| v.parse().ok())
+    .unwrap_or(MFA_TRUSTED_DEVICE_MAX_LIFETIME_SECS);
+  let (totp, totp_error) = match load_totp_policy() {
+    Ok(policy) => (policy, None),
+    Err(e) if require_database => anyhow::bail!(e),
+    Err(e) => (mfa::TotpPolicy::default(), Some(e)),
   };
 
   let refresh_token_exp: i64 = env::var("REFRESH_TOKEN_EXPIRY")
@@ -207,6 +291,121 @@ fn build_runtime_bootstrap(require_database: bool) -> anyhow::Result<RuntimeBoot
     .ok()
     .and_then(|v| v.parse().ok())
     .unwrap_or(SESSION_IDLE_TIMEOUT_SECS);
+  let refresh_token_reuse_interval: i64 = env::var("REFRESH_TOKEN_REUSE_INTERVAL")
+    .ok()
+    .and_then(|v| v.parse().ok())
+    .unwrap_or(REFRESH_TOKEN_REUSE_INTERVAL);
+  // Rotated refresh tokens are derived rather than stored, so the key must be
+  // the same on every instance and across restarts. It must also be known
+  // only to Haya: services that verify access tokens may hold JWT_SECRET, and
+  // with it could compute a session's next refresh token.
+  let refresh_token_secret = env::var("REFRESH_TOKEN_SECRET")
+    .ok()
+    .filter(|value| !value.trim().is_empty());
+  if refresh_token_secret
+    .as_deref()
+    .is_some_and(|secret| secret == jwt_secret)
+  {
+    anyhow::bail!("REFRESH_TOKEN_SECRET must be set independently from JWT_SECRET");
+  }
+  if require_database && refresh_token_reuse_interval > 0 && refresh_token_secret.is_none() {
+    anyhow::bail!(
+      "REFRESH_TOKEN_SECRET is required when REFRESH_TOKEN_REUSE_INTERVAL is positive; \
+       set it, or set REFRESH_TOKEN_REUSE_INTERVAL=0 to turn off the reuse window"
+    );
+  }
+  let (refresh_token_reuse_key, refresh_token_secret_source) = match refresh_token_secret {
+    Some(secret) => (session::derive_refresh_reuse_key(&secret), "env"),
+    // Only reached when the reuse window is off or nothing is served, so the
+    // key never derives a token anyone receives.
+    None => ([0; 32], "unset"),
+  };
+  let session_max_lifetime_secs: i64 = env::var("SESSION_MAX_LIFETIME_SECS")
+    .ok()
+    .and_then(|v| v.parse().ok())
+    .unwrap_or(SESSION_MAX_LIFETIME_SECS);
+  let session_max_per_user: i64 = env::var("SESSION_MAX_PER_USER")
+    .ok()
+    .and_then(|v| v.parse().ok())
+    .unwrap_or(SESSION_MAX_PER_USER);
+  let session_limit_strategy = env::var("SESSION_LIMIT_STRATEGY")
+    .ok()
+    .filter(|value| !value.trim().is_empty())
+    .unwrap_or_else(|| "evict_oldest".to_string());
+  let session_role_limits = env::var("SESSION_ROLE_LIMITS")
+    .ok()
+    .filter(|value| !value.trim().is_empty());
+  let (session_limits, session_limits_error) = match SessionLimitPolicy::new(
+    session_max_lifetime_secs,
+    session_max_per_user,
+    &session_limit_strategy,
+    session_role_limits.as_deref(),
+  ) {
+    Ok(policy) => (policy, None),
+    Err(e) if require_database => anyhow::bail!(e),
+    Err(e) => (SessionLimitPolicy::default(), Some(e)),
+  };
+  let mut session_limit_roles: Vec<String> = session_limits.roles.keys().cloned().collect();
+  session_limit_roles.sort();
+  let session_binding_policy = env::var("SESSION_BINDING_POLICY")
+    .ok()
+    .filter(|value| !value.trim().is_empty())
+    .unwrap_or_else(|| "strict".to_string());
+  let (session_binding, session_binding_error) = match session_binding_policy.parse::<SessionBindingPolicy>()
+  {
+    Ok(policy) => (policy, None),
+    Err(e) if require_database => anyhow::bail!("SESSION_BINDING_POLICY: {e}"),
+    Err(e) => (
+      SessionBindingPolicy::Strict,
+      Some(format!("SESSION_BINDING_POLICY: {e}")),
+    ),
+  };
+
+  let access_token_hook_uri = env::var("ACCESS_TOKEN_HOOK_URI")
+    .ok()
+    .filter(|value| !value.trim().is_empty());
+  let access_token_hook_secret = env::var("ACCESS_TOKEN_HOOK_SECRET").ok();
+  let access_token_hook_timeout_ms: u64 = env::var("ACCESS_TOKEN_HOOK_TIMEOUT_MS")
+    .ok()
+    .and_then(|v| v.parse().ok())
+    .unwrap_or(ACCESS_TOKEN_HOOK_TIMEOUT_MS);
+  let (access_token_hook, access_token_hook_error) = match access_token_hook_uri.as_deref() {
+    Some(uri) => match hook::AccessTokenHook::new(
+      uri,
+      access_token_hook_secret.as_deref(),
+      std::time::Duration::from_millis(access_token_hook_timeout_ms),
+    ) {
+      Ok(access_token_hook) => (Some(Arc::new(access_token_hook)), None),
+      Err(e) if require_database => return Err(e),
+      Err(e) => (None, Some(format!("{e:#}"))),
+    },
+    None => (None, None),
+  };
+
+  // The corpus can be large, so it is only opened here and loaded with the
+  // application state.
+  let breached_passwords_path = env::var("BREACHED_PASSWORDS_PATH")
+    .ok()
+    .filter(|value| !value.trim().is_empty());
+  let breached_passwords_error = match breached_passwords_path.as_deref().map(std::fs::File::open) {
+    Some(Err(e)) if require_database => anyhow::bail!("BREACHED_PASSWORDS_PATH: {e}"),
+    Some(Err(e)) => Some(format!("BREACHED_PASSWORDS_PATH: {e}")),
+    _ => None,
+  };
+  let anonymous_sign_ins = env::var("ANONYMOUS_SIGN_INS_ENABLED")
+    .map(|v| v.to_lowercase() == "true" || v == "1")
+    .unwrap_or(false);
+  let captcha_provider = env::var("CAPTCHA_PROVIDER")
+    .ok()
+    .filter(|value| !value.trim().is_empty());
+  let (captcha, captcha_error) = match captcha_provider.as_deref() {
+    Some(provider) => match captcha::Captcha::new(provider, env::var("CAPTCHA_SECRET").unwrap_or_default()) {
+      Ok(captcha) => (Some(Arc::new(captcha)), None),
+      Err(e) if require_database => anyhow::bail!(e),
+      Err(e) => (None, Some(e)),
+    },
+    None => (None, None),
+  };
 
   let site_url = env::var("SITE_URL").unwrap_or_else(|_| "http://localhost:9999".to_string());
   let cors_allowed_origins = parse_origin_list_env("CORS_ALLOWED_ORIGINS");
@@ -221,7 +420,33 @@ fn build_runtime_bootstrap(require_database: bool) -> anyhow::Result<RuntimeBoot
   }
   allowed_redirect_origins.sort();
   allowed_redirect_origins.dedup();
+  let oauth_consent_url = env::var("OAUTH_CONSENT_URL")
+    .unwrap_or_else(|_| format!("{}/oauth/consent", site_url.trim_end_matches('/')));
+  let oauth_device_verification_url = env::var("OAUTH_DEVICE_VERIFICATION_URL")
+    .unwrap_or_else(|_| format!("{}/device", site_url.trim_end_matches('/')));
   let site_name = env::var("SITE_NAME").unwrap_or_else(|_| "Haya".to_string());
+  let (password_policy, password_policy_error) = match load_password_policy(&site_name) {
+    Ok(policy) => (policy, None),
+    Err(e) if require_database => anyhow::bail!(e),
+    Err(e) => (PasswordPolicy::default(), Some(e)),
+  };
+  let webauthn_rp_name = env::var("WEBAUTHN_RP_NAME").unwrap_or_else(|_| site_name.clone());
+  let (webauthn, webauthn_error) = match RelyingParty::new(
+    &site_url,
+    &webauthn_rp_name,
+    env::var("WEBAUTHN_RP_ID")
+      .ok()
+      .filter(|value| !value.trim().is_empty())
+      .as_deref(),
+    &parse_origin_list_env("WEBAUTHN_ORIGINS"),
+  ) {
+    Ok(rp) => (rp, None),
+    Err(e) if require_database => anyhow::bail!(e),
+    Err(e) => (
+      RelyingParty::new(&site_url, &webauthn_rp_name, None, &[]).map_err(anyhow::Error::msg)?,
+      Some(e),
+    ),
+  };
   let issuer = env::var("GOTRUE_JWT_ISSUER")
     .or_else(|_| env::var("JWT_ISSUER"))
     .unwrap_or_else(|_| site_url.clone());
@@ -273,6 +498,27 @@ fn build_runtime_bootstrap(require_database: bool) -> anyhow::Result<RuntimeBoot
     (None, false)
   };
 
+  let sms_provider = env::var("SMS_PROVIDER")
+    .ok()
+    .filter(|value| !value.trim().is_empty());
+  let (sms, sms_error): (Option<Arc<dyn SmsSender>>, Option<String>) = match sms_provider.as_deref() {
+    Some(provider) => match sms::from_config(
+      provider,
+      env::var("SMS_HTTP_URL").ok(),
+      env::var("SMS_HTTP_USERNAME").ok(),
+      env::var("SMS_HTTP_PASSWORD").ok(),
+      env::var("SMS_FROM").ok(),
+      env::var("SMS_FILE_PATH")
+        .ok()
+        .filter(|value| !value.trim().is_empty()),
+    ) {
+      Ok(sender) => (Some(Arc::from(sender)), None),
+      Err(e) if require_database => return Err(e),
+      Err(e) => (None, Some(e.to_string())),
+    },
+    None => (None, None),
+  };
+
   let port: u16 = env::var("PORT")
     .ok()
     .and_then(|v| v.parse().ok())
@@ -287,45 +533,183 @@ fn build_runtime_bootstrap(require_database: bool) -> anyhow::Result<RuntimeBoot
     allowed_redirect_path_prefixes,
     oidc_form_post,
     cors_allowed_origins,
+    oauth_consent_url,
+    oauth_device_verification_url,
     site_name,
     issuer,
     jwt_exp,
+    client_credentials_jwt_exp,
     refresh_token_exp,
     session_idle_timeout_secs,
+    refresh_token_reuse_interval,
+    refresh_token_secret_source,
+    session_binding_policy,
+    session_binding_error,
+    session_max_lifetime_secs,
+    session_max_per_user,
+    session_limit_strategy,
+    session_limit_roles,
+    session_limits_error,
+    webauthn_rp_id: webauthn.id.clone(),
+    webauthn_origins: webauthn.origins.clone(),
+    webauthn_error,
+    access_token_hook_uri,
+    access_token_hook_timeout_ms,
+    access_token_hook_error,
+    anonymous_sign_ins,
+    password_min_length: password_policy.min_length,
+    password_max_length: password_policy.max_length,
+    password_required_characters: password_policy
+      .required_characters
+      .iter()
+      .map(|class| class.as_str().to_string())
+      .collect(),
+    password_banned_words: password_policy.banned_words.clone(),
+    password_min_score: password_policy.min_score,
+    password_policy_error,
+    breached_passwords_path,
+    breached_passwords_error,
+    captcha_provider,
+    captcha_error,
     pid_file,
     jwt_secret_len: jwt_secret.len(),
+    jwt_algorithm: format!("{jwt_algorithm:?}"),
+    jwt_signing_key_path,
     mailer_autoconfirm,
     smtp_configured,
+    sms_provider,
+    sms_error,
     dev_mode,
     mfa_key_source,
+    mfa_key_version: mfa_keys.current_version(),
+    mfa_key_error,
+    totp_algorithm: totp.enrollment.algorithm.as_str().to_string(),
+    totp_digits: totp.enrollment.digits,
+    totp_period: totp.enrollment.period,
+    totp_skew_steps: totp.skew_steps,
+    totp_error,
+    trusted_device_max_lifetime_secs,
   };
 
   Ok(RuntimeBootstrap {
     config,
     http_client,
     jwt_secret,
-    mfa_encryption_key,
+    jwt_algorithm,
+    jwt_key_id,
+    access_token_hook,
+    captcha,
+    session_binding,
+    session_limits: Arc::new(session_limits),
+    webauthn: Arc::new(webauthn),
+    mfa_keys: Arc::new(mfa_keys),
+    refresh_token_reuse_key,
+    totp,
+    password_policy: Arc::new(password_policy),
     instance_id,
     mailer,
+    sms,
   })
 }
 
+/// Reads the `MFA_TOTP_*` settings. Unlike most numeric settings these are
+/// not silently defaulted, since a typo would enroll factors with parameters
+/// nobody asked for.
+fn load_totp_policy() -> Result<mfa::TotpPolicy, String> {
+  let defaults = mfa::TotpPolicy::default();
+  let algorithm = env::var("MFA_TOTP_ALGORITHM")
+    .ok()
+    .filter(|value| !value.trim().is_empty())
+    .unwrap_or_else(|| defaults.enrollment.algorithm.as_str().to_string());
+  mfa::TotpPolicy::new(
+    &algorithm,
+    number("MFA_TOTP_DIGITS", defaults.enrollment.digits)?,
+    number("MFA_TOTP_PERIOD", defaults.enrollment.period)?,
+    number("MFA_TOTP_SKEW_STEPS", defaults.skew_steps)?,
+  )
+  .map_err(|e| format!("MFA_TOTP_*: {e}"))
+}
+
+/// Reads the `PASSWORD_*` settings, which like `MFA_TOTP_*` are reported
+/// rather than defaulted when they do not parse.
+fn load_password_policy(site_name: &str) -> Result<PasswordPolicy, String> {
+  let defaults = PasswordPolicy::default();
+  let required_characters = env::var("PASSWORD_REQUIRED_CHARACTERS")
+    .ok()
+    .filter(|value| !value.trim().is_empty())
+    .unwrap_or_else(|| {
+      defaults
+        .required_characters
+        .iter()
+        .map(|class| class.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+    });
+  PasswordPolicy::new(
+    number("PASSWORD_MIN_LENGTH", defaults.min_length)?,
+    number("PASSWORD_MAX_LENGTH", defaults.max_length)?,
+    &required_characters,
+    &env::var("PASSWORD_BANNED_WORDS").unwrap_or_default(),
+    number("PASSWORD_MIN_SCORE", defaults.min_score)?,
+    site_name,
+  )
+  .map_err(|e| format!("PASSWORD_*: {e}"))
+}
+
+fn number<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+  match env::var(name).ok().filter(|value| !value.trim().is_empty()) {
+    Some(value) => value
+      .trim()
+      .parse()
+      .map_err(|_| format!("{name} must be a number, got {value:?}")),
+    None => Ok(default),
+  }
+}
+
 async fn build_app_state(bootstrap: &RuntimeBootstrap) -> anyhow::Result<AppState> {
   let db = db::init_pool(&bootstrap.config.database_url).await?;
   let oidc_providers = oidc::load_providers_from_db(&db).await?;
+  let jwt_configured_keys = build_jwt_keyring(bootstrap)?;
+  let jwt_keys = keyring::load_keyring(&db, &bootstrap.mfa_keys, &jwt_configured_keys).await?;
+  let breached_passwords = match bootstrap.config.breached_passwords_path.clone() {
+    Some(path) => {
+      let corpus = tokio::task::spawn_blocking(move || BreachedPasswords::load(std::path::Path::new(&path)))
+        .await?
+        .context("BREACHED_PASSWORDS_PATH")?;
+      tracing::info!(passwords = corpus.len(), "Loaded breached password corpus");
+      Some(Arc::new(corpus))
+    },
+    None => None,
+  };
 
   Ok(AppState {
     db,
     http_client: bootstrap.http_client.clone(),
-    jwt_secret: bootstrap.jwt_secret.clone(),
-    mfa_encryption_key: bootstrap.mfa_encryption_key,
+    jwt_configured_keys: Arc::new(jwt_configured_keys),
+    jwt_keys: Arc::new(RwLock::new(jwt_keys)),
+    mfa_keys: bootstrap.mfa_keys.clone(),
+    refresh_token_reuse_key: bootstrap.refresh_token_reuse_key,
+    totp: bootstrap.totp,
+    trusted_device_max_lifetime_secs: bootstrap.config.trusted_device_max_lifetime_secs,
     jwt_exp: bootstrap.config.jwt_exp,
+    client_credentials_jwt_exp: bootstrap.config.client_credentials_jwt_exp,
     refresh_token_exp: bootstrap.config.refresh_token_exp,
     session_idle_timeout_secs: bootstrap.config.session_idle_timeout_secs,
+    refresh_token_reuse_interval: bootstrap.config.refresh_token_reuse_interval,
+    session_binding: bootstrap.session_binding,
+    session_limits: bootstrap.session_limits.clone(),
+    webauthn: bootstrap.webauthn.clone(),
+    access_token_hook: bootstrap.access_token_hook.clone(),
+    anonymous_sign_ins: bootstrap.config.anonymous_sign_ins,
+    password_policy: bootstrap.password_policy.clone(),
+    breached_passwords,
+    captcha: bootstrap.captcha.clone(),
     site_url: bootstrap.config.site_url.clone(),
     allowed_redirect_origins: bootstrap.config.allowed_redirect_origins.clone(),
     allowed_redirect_path_prefixes: bootstrap.config.allowed_redirect_path_prefixes.clone(),
     oidc_form_post: bootstrap.config.oidc_form_post,
+    oauth_consent_url: bootstrap.config.oauth_consent_url.clone(),
+    oauth_device_verification_url: bootstrap.config.oauth_device_verification_url.clone(),
     site_name: bootstrap.config.site_name.clone(),
     issuer: bootstrap.config.issuer.clone(),
     instance_id: bootstrap.instance_id,
@@ -333,5 +717,29 @@ async fn build_app_state(bootstrap: &RuntimeBootstrap) -> anyhow::Result<AppStat
     oidc_jwks_cache: Arc::new(RwLock::new(std::collections::HashMap::new())),
     mailer_autoconfirm: bootstrap.config.mailer_autoconfirm,
     mailer: bootstrap.mailer.clone(),
+    sms: bootstrap.sms.clone(),
   })
 }
+
+fn build_jwt_keyring(bootstrap: &RuntimeBootstrap) -> anyhow::Result<jwt::JwtKeyring> {
+  if bootstrap.jwt_algorithm == Algorithm::HS256 {
+    return Ok(jwt::JwtKeyring::new(jwt::JwtKey::hmac(&bootstrap.jwt_secret)));
+  }
+
+  let path = bootstrap
+    .config
+    .jwt_signing_key_path
+    .as_deref()
+    .context("JWT_SIGNING_KEY_PATH is not set")?;
+  let pem =
+    std::fs::read_to_string(path).with_context(|| format!("failed to read JWT signing key {path}"))?;
+  let signing_key = jwt::JwtKey::from_pem(bootstrap.jwt_algorithm, &pem, bootstrap.jwt_key_id.clone())
+    .with_context(|| format!("failed to load JWT signing key {path}"))?;
+  tracing::info!(kid = ?signing_key.kid, algorithm = ?signing_key.algorithm, "Loaded JWT signing key");
+
+  let keyring = jwt::JwtKeyring::new(signing_key);
+  if bootstrap.jwt_secret.is_empty() {
+    return Ok(keyring);
+  }
+  Ok(keyring.with_verification_key(jwt::JwtKey::hmac(&bootstrap.jwt_secret)))
+}
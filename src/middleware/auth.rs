@@ -1,63 +1,143 @@
-use axum::extract::FromRequestParts;
+use axum::extract::{
+  ConnectInfo,
+  FromRequestParts,
+};
+use axum::http::HeaderMap;
 use axum::http::request::Parts;
+use std::net::SocketAddr;
 
 use crate::auth::{
+  audit,
   jwt,
   session,
 };
 use crate::error::AuthError;
-use crate::model::User;
+use crate::model::{
+  SessionRow,
+  User,
+};
 use crate::state::AppState;
 
+/// A user signed in through a first-party session. Tokens issued to OAuth
+/// clients are rejected, since they only grant the scopes the user consented
+/// to.
 pub struct AuthUser {
   pub claims: jwt::Claims,
   pub user: User,
 }
+/// A user behind any access token, including ones issued to OAuth clients.
+/// Only for endpoints that limit what they do to the token's `scope`, such as
+/// `/oauth/userinfo`.
+pub struct ScopedUser {
+  pub claims: jwt::Claims,
+  pub user: User,
+}
 pub struct AdminUser(pub jwt::Claims);
 
 impl FromRequestParts<AppState> for AuthUser {
   type Rejection = AuthError;
 
   async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
-    let token = extract_bearer_token(parts)?;
-    let claims = jwt::decode_token(&token, &state.jwt_secret, &state.issuer)
-      .map_err(|_| AuthError::NotAuthorized)?
-      .claims;
-    session::ensure_active_session(state, &claims)
-      .await
-      .map_err(|_| AuthError::NotAuthorized)?;
-    let user = session::load_current_user(state, &claims)
-      .await
-      .map_err(|_| AuthError::NotAuthorized)?;
+    let (claims, user, session) = authenticate(parts, state).await?;
+    if issued_to_oauth_client(&claims, &session) {
+      return Err(AuthError::NotAuthorized);
+    }
     Ok(AuthUser { claims, user })
   }
 }
 
+impl FromRequestParts<AppState> for ScopedUser {
+  type Rejection = AuthError;
+
+  async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+    let (claims, user, _) = authenticate(parts, state).await?;
+    Ok(ScopedUser { claims, user })
+  }
+}
+
+async fn authenticate(parts: &Parts, state: &AppState) -> Result<(jwt::Claims, User, SessionRow), AuthError> {
+  let token = extract_bearer_token(&parts.headers)?;
+  let claims = decode_claims(state, &token).await?;
+  let session = session::ensure_active_session(state, &claims)
+    .await
+    .map_err(|_| AuthError::NotAuthorized)?;
+  let user = session::load_current_user(state, &claims)
+    .await
+    .map_err(|_| AuthError::NotAuthorized)?;
+  if let Some(actor) = &claims.act {
+    let client_ip = parts
+      .extensions
+      .get::<ConnectInfo<SocketAddr>>()
+      .map(|ConnectInfo(addr)| addr.ip());
+    audit::log_event(
+      &state.db,
+      state.instance_id,
+      client_ip,
+      "impersonated_request",
+      serde_json::json!({
+        "user_id": user.id,
+        "admin_id": actor.sub,
+        "session_id": claims.session_id,
+        "method": parts.method.as_str(),
+        "path": parts.uri.path(),
+      }),
+    )
+    .await?;
+  }
+  Ok((claims, user, session))
+}
+
+/// Whether the token belongs to a session an OAuth client obtained through
+/// `/oauth/token` rather than a first-party sign-in.
+fn issued_to_oauth_client(claims: &jwt::Claims, session: &SessionRow) -> bool {
+  claims.client_id.is_some() || session.oauth_client_id.is_some()
+}
+
 impl FromRequestParts<AppState> for AdminUser {
   type Rejection = AuthError;
 
   async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
-    let token = extract_bearer_token(parts)?;
-    let claims = jwt::decode_token(&token, &state.jwt_secret, &state.issuer)
-      .map_err(|_| AuthError::NotAuthorized)?
-      .claims;
-    session::ensure_active_session(state, &claims)
-      .await
-      .map_err(|_| AuthError::NotAuthorized)?;
-    let user: User = session::load_current_user(state, &claims)
+    authenticate_admin(state, &parts.headers)
       .await
-      .map_err(|_| AuthError::NotAuthorized)?;
-    let role = user.role.as_deref().unwrap_or("authenticated");
-    if role != "service_role" && role != "supabase_admin" {
-      return Err(AuthError::NotAdmin);
-    }
-    Ok(AdminUser(claims))
+      .map(|(claims, _)| AdminUser(claims))
   }
 }
 
-fn extract_bearer_token(parts: &Parts) -> Result<String, AuthError> {
-  let auth_header = parts
-    .headers
+/// Resolves the admin behind the request's bearer token. Impersonation tokens
+/// and tokens issued to OAuth clients never count as admin, whatever role the
+/// user has.
+pub(crate) async fn authenticate_admin(
+  state: &AppState,
+  headers: &HeaderMap,
+) -> Result<(jwt::Claims, User), AuthError> {
+  let token = extract_bearer_token(headers)?;
+  let claims = decode_claims(state, &token).await?;
+  let session = session::ensure_active_session(state, &claims)
+    .await
+    .map_err(|_| AuthError::NotAuthorized)?;
+  let user: User = session::load_current_user(state, &claims)
+    .await
+    .map_err(|_| AuthError::NotAuthorized)?;
+  if claims.act.is_some() || issued_to_oauth_client(&claims, &session) || !is_admin_role(&user) {
+    return Err(AuthError::NotAdmin);
+  }
+  Ok((claims, user))
+}
+
+pub(crate) fn is_admin_role(user: &User) -> bool {
+  let role = user.role.as_deref().unwrap_or("authenticated");
+  role == "service_role" || role == "supabase_admin"
+}
+
+async fn decode_claims(state: &AppState, token: &str) -> Result<jwt::Claims, AuthError> {
+  let keys = state.jwt_keys.read().await;
+  jwt::decode_token(token, &keys, &state.issuer)
+    .map(|data| data.claims)
+    .map_err(|_| AuthError::NotAuthorized)
+}
+
+fn extract_bearer_token(headers: &HeaderMap) -> Result<String, AuthError> {
+  let auth_header = headers
     .get(axum::http::header::AUTHORIZATION)
     .and_then(|v| v.to_str().ok())
     .ok_or(AuthError::NotAuthorized)?;
@@ -15,6 +15,10 @@ use sqlx::{
 };
 use uuid::Uuid;
 
+use crate::auth::webauthn::{
+  CredentialCreationOptions,
+  CredentialRequestOptions,
+};
 use crate::error::Result;
 
 #[derive(Debug, Clone, Deserialize, FromRow)]
@@ -218,6 +222,25 @@ mod tests {
     assert_eq!(response.app_metadata, serde_json::json!({}));
     assert_eq!(response.user_metadata, serde_json::json!({}));
   }
+
+  #[test]
+  fn challenge_response_matches_gotrue_shape() {
+    let response = MfaChallengeResponse {
+      id: Uuid::nil(),
+      factor_type: "totp".to_string(),
+      expires_at: 1_700_000_000,
+      webauthn: None,
+    };
+
+    assert_eq!(
+      serde_json::to_value(&response).unwrap(),
+      serde_json::json!({
+        "id": Uuid::nil(),
+        "type": "totp",
+        "expires_at": 1_700_000_000,
+      })
+    );
+  }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -228,6 +251,64 @@ pub struct TokenResponse {
   pub expires_at: i64,
   pub refresh_token: String,
   pub user: UserResponse,
+  /// Token a device that asked to be remembered presents on later sign-ins
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub trusted_device_token: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub trusted_device_expires_at: Option<i64>,
+}
+
+/// RFC 8693 token exchange response for an impersonation token. There is no
+/// refresh token; the admin exchanges again once it expires.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenExchangeResponse {
+  pub access_token: String,
+  pub issued_token_type: String,
+  pub token_type: String,
+  pub expires_in: i64,
+  pub expires_at: i64,
+  pub user: UserResponse,
+}
+
+/// Token endpoint response for OAuth clients, shaped per RFC 6749 section 5.1.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthTokenResponse {
+  pub access_token: String,
+  pub token_type: String,
+  pub expires_in: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub refresh_token: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id_token: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub scope: Option<String>,
+}
+
+/// Token introspection response, shaped per RFC 7662 section 2.2. Inactive
+/// tokens serialize as `{"active": false}` alone.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IntrospectionResponse {
+  pub active: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub token_type: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub client_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub scope: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sub: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub aud: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub iss: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub exp: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub iat: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub session_id: Option<Uuid>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub aal: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -238,6 +319,7 @@ pub struct MfaFactorRow {
   pub factor_type: String,
   pub status: String,
   pub secret: Option<String>,
+  pub phone: Option<String>,
   pub created_at: DateTime<Utc>,
   pub updated_at: DateTime<Utc>,
   pub last_challenged_at: Option<DateTime<Utc>>,
@@ -249,6 +331,8 @@ pub struct MfaFactorResponse {
   pub friendly_name: Option<String>,
   pub factor_type: String,
   pub status: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub phone: Option<String>,
   pub created_at: DateTime<Utc>,
   pub updated_at: DateTime<Utc>,
   pub last_challenged_at: Option<DateTime<Utc>>,
@@ -261,6 +345,7 @@ impl From<MfaFactorRow> for MfaFactorResponse {
       friendly_name: value.friendly_name,
       factor_type: value.factor_type,
       status: value.status,
+      phone: value.phone,
       created_at: value.created_at,
       updated_at: value.updated_at,
       last_challenged_at: value.last_challenged_at,
@@ -268,13 +353,24 @@ impl From<MfaFactorRow> for MfaFactorResponse {
   }
 }
 
+/// A new set of recovery codes. They are only ever shown here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryCodesResponse {
+  pub codes: Vec<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MfaEnrollResponse {
   pub id: Uuid,
   pub friendly_name: Option<String>,
   pub factor_type: String,
   pub status: String,
-  pub totp: TotpEnrollment,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub totp: Option<TotpEnrollment>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub webauthn: Option<WebAuthnEnrollment>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub phone: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -283,11 +379,33 @@ pub struct TotpEnrollment {
   pub uri: String,
 }
 
+/// Options to pass to `navigator.credentials.create()` to register a
+/// security key or passkey as a factor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebAuthnEnrollment {
+  pub options: CredentialCreationOptions,
+}
+
+/// An open challenge on a factor. `expires_at` is a Unix timestamp.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaChallengeResponse {
+  pub id: Uuid,
+  #[serde(rename = "type")]
+  pub factor_type: String,
+  pub expires_at: i64,
+  /// Options for `navigator.credentials.get()` when the factor is WebAuthn
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub webauthn: Option<CredentialRequestOptions>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PendingMfaResponse {
   pub mfa_required: bool,
   pub mfa_token: String,
   pub factors: Vec<MfaFactorResponse>,
+  /// Options for `navigator.credentials.get()` when the user has a WebAuthn factor
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub webauthn: Option<CredentialRequestOptions>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -295,6 +413,15 @@ pub struct PendingMfaResponse {
 pub enum TokenGrantResponse {
   Token(Box<TokenResponse>),
   PendingMfa(PendingMfaResponse),
+  Client(OAuthTokenResponse),
+  Exchange(Box<TokenExchangeResponse>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum VerifyFactorResponse {
+  Token(Box<TokenResponse>),
+  Factor(MfaFactorResponse),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -326,6 +453,8 @@ pub struct SessionRow {
   pub user_agent: Option<String>,
   pub ip: Option<String>,
   pub refreshed_at: Option<chrono::NaiveDateTime>,
+  pub oauth_client_id: Option<String>,
+  pub scope: Option<String>,
   pub created_at: Option<DateTime<Utc>>,
   pub updated_at: Option<DateTime<Utc>>,
 }
@@ -15,6 +15,7 @@ use uuid::Uuid;
 use crate::auth::{
   audit,
   password,
+  trusted_device,
 };
 use crate::error::{
   AuthError,
@@ -39,6 +40,7 @@ const RESERVED_APP_METADATA_KEYS: &[&str] = &["provider", "providers", "role"];
 pub struct PaginationQuery {
   pub page: Option<i64>,
   pub per_page: Option<i64>,
+  pub is_anonymous: Option<bool>,
 }
 
 pub async fn admin_list_users(
@@ -51,16 +53,20 @@ pub async fn admin_list_users(
   let offset = (page - 1) * per_page;
 
   let users: Vec<User> = sqlx::query_as::<_, User>(
-        "SELECT id, instance_id, aud, role, email, encrypted_password, email_confirmed_at, phone, phone_confirmed_at, confirmed_at, last_sign_in_at, raw_app_meta_data, raw_user_meta_data, is_super_admin, is_sso_user, is_anonymous, banned_until, deleted_at, created_at, updated_at FROM auth.users ORDER BY created_at DESC LIMIT $1 OFFSET $2"
+        "SELECT id, instance_id, aud, role, email, encrypted_password, email_confirmed_at, phone, phone_confirmed_at, confirmed_at, last_sign_in_at, raw_app_meta_data, raw_user_meta_data, is_super_admin, is_sso_user, is_anonymous, banned_until, deleted_at, created_at, updated_at FROM auth.users WHERE ($3::bool IS NULL OR is_anonymous = $3) ORDER BY created_at DESC LIMIT $1 OFFSET $2"
     )
     .bind(per_page)
     .bind(offset)
+    .bind(query.is_anonymous)
     .fetch_all(&state.db)
     .await?;
 
-  let total: (i64,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM auth.users")
-    .fetch_one(&state.db)
-    .await?;
+  let total: (i64,) = sqlx::query_as::<_, (i64,)>(
+    "SELECT COUNT(*) FROM auth.users WHERE ($1::bool IS NULL OR is_anonymous = $1)",
+  )
+  .bind(query.is_anonymous)
+  .fetch_one(&state.db)
+  .await?;
 
   let user_ids = users.iter().map(|user| user.id).collect::<Vec<_>>();
   let identity_map = identity_values_by_user_id(&state.db, &user_ids).await?;
@@ -133,7 +139,7 @@ pub async fn admin_create_user(
   let user_id = Uuid::new_v4();
   let now = Utc::now();
   let hashed = if let Some(ref pw) = req.password {
-    validate_password_policy(pw)?;
+    validate_password_policy(&state, pw, req.email.as_deref())?;
     Some(password::hash_password(pw)?)
   } else {
     None
@@ -241,7 +247,15 @@ pub async fn admin_update_user(
   let mut password_changed = false;
 
   if let Some(ref pw) = req.password {
-    validate_password_policy(pw)?;
+    let email = match req.email.clone() {
+      Some(email) => Some(email),
+      None => sqlx::query_scalar::<_, Option<String>>("SELECT email FROM auth.users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .flatten(),
+    };
+    validate_password_policy(&state, pw, email.as_deref())?;
     let hashed = password::hash_password(pw)?;
     sqlx::query("UPDATE auth.users SET encrypted_password = $1, updated_at = $2 WHERE id = $3")
       .bind(hashed)
@@ -258,6 +272,7 @@ pub async fn admin_update_user(
       .bind(user_id)
       .execute(&mut *tx)
       .await?;
+    trusted_device::revoke_all(tx.as_mut(), user_id).await?;
     password_changed = true;
   }
 
@@ -445,23 +460,16 @@ pub(crate) fn parse_ban_duration(duration: &str) -> Result<i64, AuthError> {
   }
 }
 
-pub(crate) fn validate_password_policy(password: &str) -> Result<(), AuthError> {
-  if password.len() < 12 {
-    return Err(AuthError::ValidationFailed(
-      "Password must be at least 12 characters.".to_string(),
-    ));
-  }
-  if password.len() > 128 {
-    return Err(AuthError::ValidationFailed(
-      "Password must not exceed 128 characters.".to_string(),
-    ));
-  }
-  if password.chars().all(char::is_alphabetic) {
-    return Err(AuthError::ValidationFailed(
-      "Password must include at least one non-letter character.".to_string(),
-    ));
-  }
-  Ok(())
+/// Checks a new password for the user with `email` against the configured
+/// policy and breached-password corpus.
+pub(crate) fn validate_password_policy(
+  state: &AppState,
+  password: &str,
+  email: Option<&str>,
+) -> Result<(), AuthError> {
+  state
+    .password_policy
+    .check(password, email, state.breached_passwords.as_deref())
 }
 
 pub(crate) fn validate_role(role: &str) -> Result<(), AuthError> {
@@ -518,16 +526,4 @@ mod tests {
     assert!(parse_ban_duration("").is_err());
     assert!(parse_ban_duration("abch").is_err());
   }
-
-  #[test]
-  fn test_password_policy_requires_minimum_length() {
-    assert!(validate_password_policy("short123").is_err());
-    assert!(validate_password_policy("long-enough1").is_ok());
-  }
-
-  #[test]
-  fn test_password_policy_requires_non_letter_character() {
-    assert!(validate_password_policy("LettersOnlyPw").is_err());
-    assert!(validate_password_policy("LettersOnly1").is_ok());
-  }
 }
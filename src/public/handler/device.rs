@@ -0,0 +1,446 @@
+use axum::Json;
+use axum::extract::{
+  ConnectInfo,
+  Form,
+  Query,
+  State,
+};
+use axum::http::HeaderMap;
+use axum::http::header::{
+  CACHE_CONTROL,
+  PRAGMA,
+};
+use axum::response::IntoResponse;
+use chrono::{
+  Duration,
+  Utc,
+};
+use rand::Rng;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::auth::{
+  audit,
+  oauth,
+  rate_limit,
+  session,
+};
+use crate::error::{
+  AuthError,
+  Result,
+};
+use crate::middleware::auth::AuthUser;
+use crate::model::OAuthTokenResponse;
+use crate::public::handler::oauth::{
+  ApprovedRequest,
+  authenticate_request,
+  issue_approved_grant,
+};
+use crate::state::AppState;
+use crate::utils::sha256_hex;
+
+const DEVICE_AUTHORIZATION_PROVIDER_TYPE: &str = "device_authorization";
+/// How long the user has to approve a device before its codes expire.
+const DEVICE_CODE_TTL_SECS: i64 = 600;
+/// Minimum seconds between polls; devices polling faster get `slow_down`.
+const DEVICE_POLL_INTERVAL_SECS: i64 = 5;
+/// Consonants only, so codes cannot spell words or mix up 0/O and 1/I (RFC 8628 section 6.1).
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_HALF_LENGTH: usize = 4;
+const USER_CODE_RATE_LIMIT_ATTEMPTS: u32 = 10;
+const USER_CODE_RATE_LIMIT_WINDOW_SECS: i64 = 300;
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceAuthorizationForm {
+  pub client_id: Option<String>,
+  pub client_secret: Option<String>,
+  pub scope: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceAuthorizationResponse {
+  pub device_code: String,
+  pub user_code: String,
+  pub verification_uri: String,
+  pub verification_uri_complete: String,
+  pub expires_in: i64,
+  pub interval: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserCodeQuery {
+  pub user_code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceDecision {
+  pub user_code: String,
+  pub approve: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceAuthorizationDetails {
+  pub client_id: String,
+  pub client_name: String,
+  pub scope: String,
+  pub expires_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct PendingDeviceRow {
+  oauth_client_id: Option<String>,
+  scope: Option<String>,
+  expires_at: Option<chrono::DateTime<Utc>>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DevicePollRow {
+  id: Uuid,
+  user_id: Option<Uuid>,
+  oauth_client_id: Option<String>,
+  expires_at: Option<chrono::DateTime<Utc>>,
+  last_polled_at: Option<chrono::DateTime<Utc>>,
+  denied_at: Option<chrono::DateTime<Utc>>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ApprovedDeviceRow {
+  user_id: Option<Uuid>,
+  session_id: Option<Uuid>,
+  scope: Option<String>,
+}
+
+/// Starts a device authorization request (RFC 8628 section 3.1). The device
+/// shows `user_code` and polls the token endpoint with `device_code`.
+pub async fn authorize(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  headers: HeaderMap,
+  Form(form): Form<DeviceAuthorizationForm>,
+) -> Result<impl IntoResponse> {
+  let client = authenticate_request(
+    &state,
+    &headers,
+    form.client_id.as_deref(),
+    form.client_secret.as_deref(),
+  )
+  .await?;
+  if !client.allows_grant(oauth::GRANT_DEVICE_CODE) {
+    return Err(AuthError::oauth(
+      "unauthorized_client",
+      "client is not allowed to use the device_code grant",
+    ));
+  }
+  let scope = client.resolve_scope(form.scope.as_deref())?;
+  oauth::ensure_openid_supported(&*state.jwt_keys.read().await, &scope)?;
+  let device_code = session::generate_refresh_token();
+  let user_code = generate_user_code();
+  let now = Utc::now();
+
+  sqlx::query(
+    "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, provider_access_token, provider_refresh_token, authentication_method, created_at, updated_at, expires_at, oauth_client_id, scope, user_code) VALUES ($1, NULL, $2, 'plain'::auth.code_challenge_method, '', $3, NULL, NULL, $4, $5, $6, $7, $8, $9, $10)",
+  )
+  .bind(Uuid::new_v4())
+  .bind(sha256_hex(&device_code))
+  .bind(DEVICE_AUTHORIZATION_PROVIDER_TYPE)
+  .bind(oauth::GRANT_DEVICE_CODE)
+  .bind(now)
+  .bind(now)
+  .bind(now + Duration::seconds(DEVICE_CODE_TTL_SECS))
+  .bind(&client.client_id)
+  .bind(&scope)
+  .bind(&user_code)
+  .execute(&state.db)
+  .await?;
+
+  let mut verification_uri_complete = url::Url::parse(&state.oauth_device_verification_url)
+    .map_err(|e| AuthError::InternalError(format!("invalid OAUTH_DEVICE_VERIFICATION_URL: {e}")))?;
+  verification_uri_complete
+    .query_pairs_mut()
+    .append_pair("user_code", &user_code);
+
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_addr.ip()),
+    "device_authorization_started",
+    serde_json::json!({
+      "client_id": client.client_id,
+      "scope": scope,
+    }),
+  )
+  .await?;
+
+  Ok((
+    [(CACHE_CONTROL, "no-store"), (PRAGMA, "no-cache")],
+    Json(DeviceAuthorizationResponse {
+      device_code,
+      user_code,
+      verification_uri: state.oauth_device_verification_url.clone(),
+      verification_uri_complete: verification_uri_complete.to_string(),
+      expires_in: DEVICE_CODE_TTL_SECS,
+      interval: DEVICE_POLL_INTERVAL_SECS,
+    }),
+  ))
+}
+
+/// Lets the verification page show which client is asking before the
+/// signed-in user approves a `user_code`.
+pub async fn get_device_authorization(
+  State(state): State<AppState>,
+  AuthUser { user, .. }: AuthUser,
+  Query(query): Query<UserCodeQuery>,
+) -> Result<Json<DeviceAuthorizationDetails>> {
+  let rate_limit_key = user_code_rate_limit_key(user.id);
+  if rate_limit::is_limited(&state.db, &rate_limit_key, USER_CODE_RATE_LIMIT_ATTEMPTS).await? {
+    return Err(AuthError::TooManyRequests);
+  }
+  let row = sqlx::query_as::<_, PendingDeviceRow>(
+    "SELECT oauth_client_id, scope, expires_at FROM auth.flow_state WHERE user_code = $1 AND provider_type = $2 AND user_id IS NULL AND denied_at IS NULL AND expires_at > now()",
+  )
+  .bind(normalize_user_code(&query.user_code))
+  .bind(DEVICE_AUTHORIZATION_PROVIDER_TYPE)
+  .fetch_optional(&state.db)
+  .await?;
+  let Some(row) = row else {
+    rate_limit::record_failure(&state.db, &rate_limit_key, USER_CODE_RATE_LIMIT_WINDOW_SECS).await?;
+    return Err(AuthError::InvalidToken);
+  };
+  let client_id = row
+    .oauth_client_id
+    .ok_or_else(|| AuthError::InternalError("device authorization is missing its client".to_string()))?;
+  let client = oauth::find_client(&state.db, &client_id)
+    .await?
+    .ok_or(AuthError::InvalidToken)?;
+
+  Ok(Json(DeviceAuthorizationDetails {
+    client_id: client.client_id,
+    client_name: client.name,
+    scope: row.scope.unwrap_or_default(),
+    expires_at: row.expires_at.unwrap_or_else(Utc::now),
+  }))
+}
+
+/// Approves or denies a device on behalf of the signed-in user. The device
+/// picks up the result on its next poll.
+pub async fn decide_device_authorization(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  AuthUser { claims, user }: AuthUser,
+  Json(decision): Json<DeviceDecision>,
+) -> Result<Json<serde_json::Value>> {
+  session::forbid_impersonation(&claims)?;
+  let session_id: Uuid = claims
+    .session_id
+    .parse()
+    .map_err(|_| AuthError::InternalError("Invalid session_id".to_string()))?;
+  let rate_limit_key = user_code_rate_limit_key(user.id);
+  if rate_limit::is_limited(&state.db, &rate_limit_key, USER_CODE_RATE_LIMIT_ATTEMPTS).await? {
+    return Err(AuthError::TooManyRequests);
+  }
+  let now = Utc::now();
+  let user_code = normalize_user_code(&decision.user_code);
+
+  let client_id: Option<(Option<String>,)> = if decision.approve {
+    sqlx::query_as(
+      "UPDATE auth.flow_state SET user_id = $1, session_id = $2, updated_at = $3 WHERE user_code = $4 AND provider_type = $5 AND user_id IS NULL AND denied_at IS NULL AND expires_at > $3 RETURNING oauth_client_id",
+    )
+    .bind(user.id)
+    .bind(session_id)
+    .bind(now)
+    .bind(&user_code)
+    .bind(DEVICE_AUTHORIZATION_PROVIDER_TYPE)
+    .fetch_optional(&state.db)
+    .await?
+  } else {
+    sqlx::query_as(
+      "UPDATE auth.flow_state SET denied_at = $1, updated_at = $1 WHERE user_code = $2 AND provider_type = $3 AND user_id IS NULL AND denied_at IS NULL AND expires_at > $1 RETURNING oauth_client_id",
+    )
+    .bind(now)
+    .bind(&user_code)
+    .bind(DEVICE_AUTHORIZATION_PROVIDER_TYPE)
+    .fetch_optional(&state.db)
+    .await?
+  };
+  let Some((client_id,)) = client_id else {
+    rate_limit::record_failure(&state.db, &rate_limit_key, USER_CODE_RATE_LIMIT_WINDOW_SECS).await?;
+    return Err(AuthError::InvalidToken);
+  };
+
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_addr.ip()),
+    if decision.approve {
+      "device_authorization_approved"
+    } else {
+      "device_authorization_denied"
+    },
+    serde_json::json!({
+      "user_id": user.id,
+      "client_id": client_id,
+    }),
+  )
+  .await?;
+
+  Ok(Json(serde_json::json!({ "approved": decision.approve })))
+}
+
+/// Polls a device authorization (RFC 8628 section 3.4). Shared by `/token`
+/// and `/oauth/token`.
+pub(crate) async fn device_code_grant(
+  state: &AppState,
+  client: &oauth::OAuthClient,
+  device_code: Option<&str>,
+  context: session::ClientContext,
+) -> Result<OAuthTokenResponse> {
+  let device_code =
+    device_code.ok_or_else(|| AuthError::oauth("invalid_request", "device_code is required"))?;
+  if !client.allows_grant(oauth::GRANT_DEVICE_CODE) {
+    return Err(AuthError::oauth(
+      "unauthorized_client",
+      "client is not allowed to use the device_code grant",
+    ));
+  }
+  let row = sqlx::query_as::<_, DevicePollRow>(
+    "SELECT id, user_id, oauth_client_id, expires_at, last_polled_at, denied_at FROM auth.flow_state WHERE auth_code = $1 AND provider_type = $2",
+  )
+  .bind(sha256_hex(device_code))
+  .bind(DEVICE_AUTHORIZATION_PROVIDER_TYPE)
+  .fetch_optional(&state.db)
+  .await?
+  .ok_or_else(|| AuthError::oauth("invalid_grant", "device code is invalid"))?;
+
+  if row.oauth_client_id.as_deref() != Some(client.client_id.as_str()) {
+    return Err(AuthError::oauth(
+      "invalid_grant",
+      "device code was issued to another client",
+    ));
+  }
+  let now = Utc::now();
+  if row.expires_at.map(|value| value < now).unwrap_or(true) || row.denied_at.is_some() {
+    delete_flow(state, row.id).await?;
+    return Err(if row.denied_at.is_some() {
+      AuthError::oauth("access_denied", "the user denied the request")
+    } else {
+      AuthError::oauth("expired_token", "device code has expired")
+    });
+  }
+  if row.user_id.is_none() {
+    sqlx::query("UPDATE auth.flow_state SET last_polled_at = $1 WHERE id = $2")
+      .bind(now)
+      .bind(row.id)
+      .execute(&state.db)
+      .await?;
+    return Err(
+      if row
+        .last_polled_at
+        .is_some_and(|polled_at| now - polled_at < Duration::seconds(DEVICE_POLL_INTERVAL_SECS))
+      {
+        AuthError::oauth("slow_down", "polling too frequently")
+      } else {
+        AuthError::oauth(
+          "authorization_pending",
+          "the user has not approved the request yet",
+        )
+      },
+    );
+  }
+
+  // Deleting the approved flow makes the device code single-use.
+  let approved = sqlx::query_as::<_, ApprovedDeviceRow>(
+    "DELETE FROM auth.flow_state WHERE id = $1 AND user_id IS NOT NULL RETURNING user_id, session_id, scope",
+  )
+  .bind(row.id)
+  .fetch_optional(&state.db)
+  .await?
+  .ok_or_else(|| AuthError::oauth("invalid_grant", "device code is invalid"))?;
+  let (Some(user_id), Some(session_id)) = (approved.user_id, approved.session_id) else {
+    return Err(AuthError::oauth("invalid_grant", "device code is invalid"));
+  };
+
+  issue_approved_grant(
+    state,
+    client,
+    ApprovedRequest {
+      user_id,
+      session_id,
+      scope: approved.scope.unwrap_or_default(),
+      nonce: None,
+      grant_type: oauth::GRANT_DEVICE_CODE,
+    },
+    context,
+  )
+  .await
+}
+
+async fn delete_flow(state: &AppState, flow_id: Uuid) -> Result<()> {
+  sqlx::query("DELETE FROM auth.flow_state WHERE id = $1")
+    .bind(flow_id)
+    .execute(&state.db)
+    .await?;
+  Ok(())
+}
+
+/// Formats a user code as `XXXX-XXXX`.
+fn generate_user_code() -> String {
+  let mut rng = rand::rng();
+  let mut code = String::with_capacity(USER_CODE_HALF_LENGTH * 2 + 1);
+  for index in 0..USER_CODE_HALF_LENGTH * 2 {
+    if index == USER_CODE_HALF_LENGTH {
+      code.push('-');
+    }
+    code.push(USER_CODE_ALPHABET[rng.random_range(0..USER_CODE_ALPHABET.len())] as char);
+  }
+  code
+}
+
+/// Users may type the code in lower case, without the dash, or with spaces.
+fn normalize_user_code(input: &str) -> String {
+  let mut code: String = input
+    .chars()
+    .filter(char::is_ascii_alphanumeric)
+    .map(|ch| ch.to_ascii_uppercase())
+    .collect();
+  if code.len() == USER_CODE_HALF_LENGTH * 2 {
+    code.insert(USER_CODE_HALF_LENGTH, '-');
+  }
+  code
+}
+
+fn user_code_rate_limit_key(user_id: Uuid) -> String {
+  format!("device-user-code:{user_id}")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn generated_user_codes_survive_normalization() {
+    let code = generate_user_code();
+
+    assert_eq!(code.len(), 9);
+    assert_eq!(code.as_bytes()[4], b'-');
+    assert!(
+      code
+        .bytes()
+        .filter(|byte| *byte != b'-')
+        .all(|byte| USER_CODE_ALPHABET.contains(&byte))
+    );
+    assert_eq!(normalize_user_code(&code), code);
+    assert_eq!(
+      normalize_user_code(&code.replace('-', " ").to_ascii_lowercase()),
+      code
+    );
+  }
+
+  #[test]
+  fn normalization_leaves_wrong_lengths_unmatched() {
+    assert_eq!(normalize_user_code("bcdf-ghj"), "BCDFGHJ");
+  }
+}
@@ -8,6 +8,7 @@ use chrono::Utc;
 use serde::Deserialize;
 use uuid::Uuid;
 
+use crate::auth::session;
 use crate::error::Result;
 use crate::middleware::auth::AuthUser;
 use crate::state::AppState;
@@ -28,6 +29,10 @@ pub async fn logout(
     .map_err(|_| crate::error::AuthError::InternalError("Invalid session_id".to_string()))?;
   let user_id = user.id;
   let now = Utc::now();
+  // An impersonating admin may end their own session, not the user's others.
+  if matches!(query.scope.as_deref(), Some("global" | "others")) {
+    session::forbid_impersonation(&claims)?;
+  }
 
   match query.scope.as_deref() {
     Some("global") => {
@@ -9,7 +9,11 @@ use axum::extract::{
   Path,
   State,
 };
-use axum::http::HeaderMap;
+use axum::http::{
+  HeaderMap,
+  HeaderName,
+};
+use axum::response::IntoResponse;
 use chrono::{
   Duration,
   Utc,
@@ -18,47 +22,101 @@ use serde::Deserialize;
 use sqlx::PgPool;
 use uuid::Uuid;
 
+use crate::auth::jwt::Claims;
+use crate::auth::webauthn::{
+  self,
+  AssertionCredential,
+  RegistrationCredential,
+  StoredCredential,
+};
 use crate::auth::{
   audit,
   mfa,
+  rate_limit,
   session,
+  trusted_device,
 };
 use crate::error::{
   AuthError,
   Result,
 };
+use crate::mailer::EmailKind;
 use crate::middleware::auth::{
   AuthUser,
   extract_bearer_token_value,
 };
 use crate::model::{
+  MfaChallengeResponse,
   MfaEnrollResponse,
   MfaFactorResponse,
   MfaFactorRow,
   PendingMfaResponse,
+  RecoveryCodesResponse,
   TokenResponse,
   User,
+  VerifyFactorResponse,
+  WebAuthnEnrollment,
 };
+use crate::public::handler::signup::is_valid_e164_phone;
+use crate::sms::SmsSender;
 use crate::state::AppState;
 use crate::utils::sha256_hex;
 
+/// Response header of `GET /factors` carrying the number of unused recovery codes
+pub const RECOVERY_CODES_REMAINING_HEADER: &str = "x-recovery-codes-remaining";
 const MFA_PENDING_TTL_MINUTES: i64 = 5;
 const MFA_MAX_VERIFY_ATTEMPTS: i32 = 10;
 const MFA_ENROLL_MAX_VERIFY_ATTEMPTS: i32 = 10;
 const MFA_ENROLL_VERIFY_WINDOW_MINUTES: i64 = 5;
 const MFA_MAX_UNVERIFIED_FACTORS_PER_USER: i64 = 10;
+const PHONE_METHOD: &str = "phone";
+const RECOVERY_CODE_METHOD: &str = "recovery_code";
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+const CHALLENGE_MAX_VERIFY_ATTEMPTS: i32 = 5;
+const CHALLENGE_RATE_LIMIT_WINDOW_SECS: i64 = 300;
+const CHALLENGE_RATE_LIMIT_ATTEMPTS: u32 = 30;
+const TOTP_FAILURE_RATE_LIMIT_WINDOW_SECS: i64 = 900;
+const TOTP_FACTOR_FAILURE_RATE_LIMIT_ATTEMPTS: u32 = 10;
+const TOTP_USER_FAILURE_RATE_LIMIT_ATTEMPTS: u32 = 20;
+const PHONE_CODE_RESEND_INTERVAL_SECS: i64 = 60;
+const PHONE_CODE_RATE_LIMIT_WINDOW_SECS: i64 = 3600;
+const PHONE_CODE_RATE_LIMIT_ATTEMPTS: u32 = 5;
+const PHONE_CODE_USER_RATE_LIMIT_ATTEMPTS: u32 = 10;
+const PHONE_CODE_IP_RATE_LIMIT_ATTEMPTS: u32 = 30;
 
 #[derive(Debug, Deserialize)]
-pub struct CreateTotpFactorRequest {
+pub struct CreateFactorRequest {
+  /// `totp` (the default), `webauthn` or `phone`
+  pub factor_type: Option<String>,
   pub friendly_name: Option<String>,
+  /// E.164 number of a `phone` factor
+  pub phone: Option<String>,
   pub issuer: Option<String>,
+  /// Parameters of a `totp` factor, each defaulting to `MFA_TOTP_*` and no
+  /// weaker than it
+  pub totp: Option<TotpEnrollmentRequest>,
   pub current_password: Option<String>,
   pub reauthentication_token: Option<String>,
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct TotpEnrollmentRequest {
+  /// `SHA1`, `SHA256` or `SHA512`
+  pub algorithm: Option<String>,
+  pub digits: Option<u32>,
+  pub period: Option<i64>,
+}
+
 #[derive(Debug, Deserialize)]
-pub struct VerifyTotpCodeRequest {
-  pub code: String,
+pub struct VerifyFactorRequest {
+  /// Current code of a `totp` factor, or the code sent to a `phone` factor
+  pub code: Option<String>,
+  /// Challenge from `POST /factors/{id}/challenge` being answered. When set,
+  /// the current session is upgraded to aal2 and returned.
+  pub challenge_id: Option<Uuid>,
+  /// Registration response of a `webauthn` factor being enrolled, or an
+  /// assertion answering a challenge of an enrolled one
+  pub credential: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -66,12 +124,39 @@ pub struct PendingMfaFactorsRequest {
   pub mfa_token: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PendingMfaChallengeRequest {
+  pub factor_id: Uuid,
+}
+
 #[derive(Debug, sqlx::FromRow)]
 struct PendingFlowRow {
   id: Uuid,
   user_id: Option<Uuid>,
   authentication_method: String,
   expires_at: chrono::DateTime<Utc>,
+  webauthn_challenge: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct WebAuthnEnrollmentRow {
+  status: String,
+  web_authn_session_data: Option<serde_json::Value>,
+  created_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct WebAuthnCredentialRow {
+  id: Uuid,
+  web_authn_credential: sqlx::types::Json<StoredCredential>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ChallengeRow {
+  id: Uuid,
+  ip_address: Option<String>,
+  otp_code: Option<String>,
+  web_authn_session_data: Option<serde_json::Value>,
 }
 
 #[derive(Debug, sqlx::FromRow)]
@@ -81,22 +166,92 @@ struct TotpFactorVerifyStateRow {
   last_verified_totp_step: Option<i64>,
   enrollment_verify_attempts: i32,
   last_enrollment_verify_attempt_at: Option<chrono::DateTime<Utc>>,
+  totp_algorithm: String,
+  totp_digits: i32,
+  totp_period: i32,
 }
 
+impl TotpFactorVerifyStateRow {
+  fn totp_params(&self) -> Result<mfa::TotpParams> {
+    mfa::TotpParams::from_stored(&self.totp_algorithm, self.totp_digits, self.totp_period)
+  }
+}
+
+/// Lists the user's factors. The body stays the array GoTrue clients expect,
+/// so the number of unused recovery codes goes in a header.
 pub async fn list_factors(
   State(state): State<AppState>,
   AuthUser { user, .. }: AuthUser,
-) -> Result<Json<Vec<MfaFactorResponse>>> {
+) -> Result<impl IntoResponse> {
   let factors = factors_by_user_id(&state.db, user.id).await?;
-  Ok(Json(factors.into_iter().map(Into::into).collect()))
+  let recovery_codes_remaining = remaining_recovery_codes(&state.db, user.id).await?;
+  Ok((
+    [(
+      HeaderName::from_static(RECOVERY_CODES_REMAINING_HEADER),
+      recovery_codes_remaining.to_string(),
+    )],
+    Json(
+      factors
+        .into_iter()
+        .map(Into::into)
+        .collect::<Vec<MfaFactorResponse>>(),
+    ),
+  ))
+}
+
+/// Replaces the user's recovery codes with a new set. Codes from earlier sets
+/// stop working at once.
+pub async fn generate_recovery_codes(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  AuthUser { claims, user }: AuthUser,
+) -> Result<Json<RecoveryCodesResponse>> {
+  session::forbid_impersonation(&claims)?;
+  if claims.aal != "aal2" {
+    return Err(AuthError::NotAuthorized);
+  }
+
+  let codes = mfa::generate_recovery_codes();
+  let now = Utc::now();
+  let mut tx = state.db.begin().await?;
+  sqlx::query("DELETE FROM auth.mfa_recovery_codes WHERE user_id = $1")
+    .bind(user.id)
+    .execute(tx.as_mut())
+    .await?;
+  for code in &codes {
+    sqlx::query(
+      "INSERT INTO auth.mfa_recovery_codes (id, user_id, code_hash, created_at, used_at) VALUES ($1, $2, $3, $4, NULL)",
+    )
+    .bind(Uuid::new_v4())
+    .bind(user.id)
+    .bind(mfa::hash_recovery_code(code))
+    .bind(now)
+    .execute(tx.as_mut())
+    .await?;
+  }
+  audit::log_event_tx(
+    tx.as_mut(),
+    state.instance_id,
+    Some(client_addr.ip()),
+    "mfa_recovery_codes_generated",
+    serde_json::json!({
+      "user_id": user.id,
+      "count": codes.len(),
+    }),
+  )
+  .await?;
+  tx.commit().await?;
+
+  Ok(Json(RecoveryCodesResponse { codes }))
 }
 
-pub async fn create_totp_factor(
+pub async fn create_factor(
   State(state): State<AppState>,
   ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
   AuthUser { claims, user }: AuthUser,
-  Json(req): Json<CreateTotpFactorRequest>,
+  Json(req): Json<CreateFactorRequest>,
 ) -> Result<Json<MfaEnrollResponse>> {
+  session::forbid_impersonation(&claims)?;
   let user_id = user.id;
   session::require_reauthentication(
     &state,
@@ -112,57 +267,756 @@ pub async fn create_totp_factor(
   ensure_friendly_name_available(&state.db, user_id, friendly_name.as_deref()).await?;
   ensure_unverified_factor_limit_not_reached(&state.db, user_id).await?;
 
-  let raw_secret = mfa::generate_totp_secret();
+  let factor_type = req.factor_type.as_deref().unwrap_or("totp");
+  if req.totp.is_some() && factor_type != "totp" {
+    return Err(AuthError::ValidationFailed(
+      "totp parameters only apply to totp factors".to_string(),
+    ));
+  }
+  let response = match factor_type {
+    "totp" => {
+      let totp = req.totp.unwrap_or_default();
+      let params = state
+        .totp
+        .enrollment
+        .with_overrides(totp.algorithm.as_deref(), totp.digits, totp.period)
+        .map_err(AuthError::ValidationFailed)?;
+      enroll_totp_factor(&state, &user, friendly_name, req.issuer, params).await?
+    },
+    "webauthn" => enroll_webauthn_factor(&state, &user, friendly_name).await?,
+    PHONE_METHOD => enroll_phone_factor(&state, &user, friendly_name, req.phone).await?,
+    _ => {
+      return Err(AuthError::ValidationFailed(
+        "factor_type must be totp, webauthn or phone".to_string(),
+      ));
+    },
+  };
+
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_addr.ip()),
+    "mfa_enrollment_created",
+    serde_json::json!({
+      "user_id": user_id,
+      "factor_id": response.id,
+      "factor_type": response.factor_type,
+    }),
+  )
+  .await?;
+
+  Ok(Json(response))
+}
+
+async fn enroll_totp_factor(
+  state: &AppState,
+  user: &User,
+  friendly_name: Option<String>,
+  issuer: Option<String>,
+  params: mfa::TotpParams,
+) -> Result<MfaEnrollResponse> {
+  let raw_secret = mfa::generate_totp_secret(params.algorithm);
   let secret = mfa::encode_secret(&raw_secret);
-  let encrypted_secret = mfa::encrypt_secret(&raw_secret, &state.mfa_encryption_key)?;
+  let encrypted_secret = state.mfa_keys.encrypt(&raw_secret)?;
   let account_name = user.email.clone().unwrap_or_else(|| user.id.to_string());
-  let issuer = req.issuer.unwrap_or_else(|| state.site_name.clone());
-  let uri = mfa::build_otpauth_url(&issuer, &account_name, &secret)?;
+  let issuer = issuer.unwrap_or_else(|| state.site_name.clone());
+  let uri = mfa::build_otpauth_url(&issuer, &account_name, &secret, &params)?;
   let now = Utc::now();
   let factor_id = Uuid::new_v4();
 
   sqlx::query(
-    "INSERT INTO auth.mfa_factors (id, user_id, friendly_name, factor_type, status, secret, created_at, updated_at) VALUES ($1, $2, $3, 'totp'::auth.factor_type, 'unverified'::auth.factor_status, $4, $5, $6)",
+    "INSERT INTO auth.mfa_factors (id, user_id, friendly_name, factor_type, status, secret, totp_algorithm, totp_digits, totp_period, created_at, updated_at) VALUES ($1, $2, $3, 'totp'::auth.factor_type, 'unverified'::auth.factor_status, $4, $5, $6, $7, $8, $9)",
   )
   .bind(factor_id)
-  .bind(user_id)
+  .bind(user.id)
   .bind(&friendly_name)
   .bind(&encrypted_secret)
+  .bind(params.algorithm.as_str())
+  .bind(params.digits as i32)
+  .bind(params.period as i32)
+  .bind(now)
+  .bind(now)
+  .execute(&state.db)
+  .await?;
+
+  Ok(MfaEnrollResponse {
+    id: factor_id,
+    friendly_name,
+    factor_type: "totp".to_string(),
+    status: "unverified".to_string(),
+    totp: Some(crate::model::TotpEnrollment { secret, uri }),
+    webauthn: None,
+    phone: None,
+  })
+}
+
+/// Creates an unverified WebAuthn factor holding a registration challenge.
+/// The factor is verified with the authenticator's response to the returned
+/// options.
+async fn enroll_webauthn_factor(
+  state: &AppState,
+  user: &User,
+  friendly_name: Option<String>,
+) -> Result<MfaEnrollResponse> {
+  let challenge = webauthn::generate_challenge();
+  let exclude_credentials = verified_webauthn_credentials(&state.db, user.id)
+    .await?
+    .iter()
+    .map(|row| row.web_authn_credential.descriptor())
+    .collect();
+  let account_name = user.email.clone().unwrap_or_else(|| user.id.to_string());
+  let options = webauthn::creation_options(
+    &state.webauthn,
+    &challenge,
+    user.id,
+    &account_name,
+    exclude_credentials,
+  );
+  let now = Utc::now();
+  let factor_id = Uuid::new_v4();
+
+  sqlx::query(
+    "INSERT INTO auth.mfa_factors (id, user_id, friendly_name, factor_type, status, web_authn_session_data, created_at, updated_at) VALUES ($1, $2, $3, 'webauthn'::auth.factor_type, 'unverified'::auth.factor_status, $4, $5, $6)",
+  )
+  .bind(factor_id)
+  .bind(user.id)
+  .bind(&friendly_name)
+  .bind(serde_json::json!({ "challenge": challenge }))
+  .bind(now)
+  .bind(now)
+  .execute(&state.db)
+  .await?;
+
+  Ok(MfaEnrollResponse {
+    id: factor_id,
+    friendly_name,
+    factor_type: webauthn::METHOD.to_string(),
+    status: "unverified".to_string(),
+    totp: None,
+    webauthn: Some(WebAuthnEnrollment { options }),
+    phone: None,
+  })
+}
+
+/// Creates an unverified phone factor. No code is sent until the factor is
+/// challenged, and it is verified with the code from that challenge.
+async fn enroll_phone_factor(
+  state: &AppState,
+  user: &User,
+  friendly_name: Option<String>,
+  phone: Option<String>,
+) -> Result<MfaEnrollResponse> {
+  if state.sms.is_none() {
+    return Err(AuthError::ValidationFailed(
+      "Phone MFA is not enabled".to_string(),
+    ));
+  }
+  let phone = phone
+    .map(|value| value.trim().to_string())
+    .ok_or_else(|| AuthError::ValidationFailed("phone is required".to_string()))?;
+  if !is_valid_e164_phone(&phone) {
+    return Err(AuthError::ValidationFailed(
+      "phone must be an E.164 number such as +15555550100".to_string(),
+    ));
+  }
+  // Only the user's own factors are checked, since checking every account
+  // would reveal which numbers are registered elsewhere.
+  let phone_in_use =
+    sqlx::query_scalar::<_, Uuid>("SELECT id FROM auth.mfa_factors WHERE phone = $1 AND user_id = $2")
+      .bind(&phone)
+      .bind(user.id)
+      .fetch_optional(&state.db)
+      .await?
+      .is_some();
+  if phone_in_use {
+    return Err(AuthError::ValidationFailed(
+      "You have already registered this phone number as a factor".to_string(),
+    ));
+  }
+  let now = Utc::now();
+  let factor_id = Uuid::new_v4();
+
+  sqlx::query(
+    "INSERT INTO auth.mfa_factors (id, user_id, friendly_name, factor_type, status, phone, created_at, updated_at) VALUES ($1, $2, $3, 'phone'::auth.factor_type, 'unverified'::auth.factor_status, $4, $5, $6)",
+  )
+  .bind(factor_id)
+  .bind(user.id)
+  .bind(&friendly_name)
+  .bind(&phone)
   .bind(now)
   .bind(now)
   .execute(&state.db)
   .await?;
 
+  Ok(MfaEnrollResponse {
+    id: factor_id,
+    friendly_name,
+    factor_type: PHONE_METHOD.to_string(),
+    status: "unverified".to_string(),
+    totp: None,
+    webauthn: None,
+    phone: Some(phone),
+  })
+}
+
+/// Opens a challenge on one of the user's factors, answered by passing its
+/// id to [`verify_factor`]. Phone factors are sent a code, and WebAuthn
+/// factors get options for `navigator.credentials.get()`.
+pub async fn challenge_factor(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  AuthUser { claims, user }: AuthUser,
+  Path(factor_id): Path<Uuid>,
+) -> Result<Json<MfaChallengeResponse>> {
+  session::forbid_impersonation(&claims)?;
+  let factor = factor_by_id(&state.db, factor_id, user.id).await?;
+  let response = if factor.factor_type == PHONE_METHOD {
+    let phone = factor
+      .phone
+      .as_deref()
+      .ok_or_else(|| AuthError::InternalError("phone factor missing number".to_string()))?;
+    send_phone_challenge(&state, user.id, factor_id, phone, client_addr.ip()).await?
+  } else {
+    open_challenge(&state, user.id, &factor, client_addr.ip()).await?
+  };
+
+  Ok(Json(response))
+}
+
+/// Records a challenge on a TOTP or WebAuthn factor. A WebAuthn factor must
+/// be verified first; its registration is finished through [`verify_factor`]
+/// with the options returned when it was created.
+async fn open_challenge(
+  state: &AppState,
+  user_id: Uuid,
+  factor: &MfaFactorRow,
+  client_ip: IpAddr,
+) -> Result<MfaChallengeResponse> {
+  let rate_limit_key = challenge_rate_limit_key(factor.id);
+  if rate_limit::is_limited(&state.db, &rate_limit_key, CHALLENGE_RATE_LIMIT_ATTEMPTS).await? {
+    return Err(AuthError::TooManyRequests);
+  }
+  rate_limit::record_attempt(&state.db, &rate_limit_key, CHALLENGE_RATE_LIMIT_WINDOW_SECS).await?;
+
+  let request_options = if factor.factor_type == webauthn::METHOD {
+    if factor.status != "verified" {
+      return Err(AuthError::ValidationFailed(
+        "Finish registering this security key before challenging it".to_string(),
+      ));
+    }
+    let sqlx::types::Json(stored) = sqlx::query_scalar::<_, sqlx::types::Json<StoredCredential>>(
+      "SELECT web_authn_credential FROM auth.mfa_factors WHERE id = $1 AND web_authn_credential IS NOT NULL",
+    )
+    .bind(factor.id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AuthError::InternalError("missing WebAuthn credential".to_string()))?;
+    Some(webauthn::request_options(
+      &state.webauthn,
+      &webauthn::generate_challenge(),
+      vec![stored.descriptor()],
+    ))
+  } else {
+    None
+  };
+
+  let challenge_id = Uuid::new_v4();
+  let now = Utc::now();
+  let expires_at = now + Duration::minutes(CHALLENGE_TTL_MINUTES);
+  sqlx::query(
+    "INSERT INTO auth.mfa_challenges (id, factor_id, created_at, verified_at, ip_address, otp_code, expires_at, web_authn_session_data) VALUES ($1, $2, $3, NULL, $4::inet, NULL, $5, $6)",
+  )
+  .bind(challenge_id)
+  .bind(factor.id)
+  .bind(now)
+  .bind(client_ip.to_string())
+  .bind(expires_at)
+  .bind(
+    request_options
+      .as_ref()
+      .map(|options| serde_json::json!({ "challenge": options.challenge })),
+  )
+  .execute(&state.db)
+  .await?;
   audit::log_event(
     &state.db,
     state.instance_id,
-    Some(client_addr.ip()),
-    "mfa_enrollment_created",
+    Some(client_ip),
+    "mfa_challenge_created",
+    serde_json::json!({
+      "user_id": user_id,
+      "factor_id": factor.id,
+      "challenge_id": challenge_id,
+      "factor_type": factor.factor_type,
+    }),
+  )
+  .await?;
+
+  Ok(MfaChallengeResponse {
+    id: challenge_id,
+    factor_type: factor.factor_type.clone(),
+    expires_at: expires_at.timestamp(),
+    webauthn: request_options,
+  })
+}
+
+/// Sends a code to a verified phone factor during a pending MFA login.
+pub async fn challenge_pending_factor(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  headers: HeaderMap,
+  Json(req): Json<PendingMfaChallengeRequest>,
+) -> Result<Json<MfaChallengeResponse>> {
+  let mfa_token = headers
+    .get(axum::http::header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(extract_bearer_token_value)
+    .ok_or_else(|| {
+      AuthError::ValidationFailed("Authorization header with Bearer MFA token is required".to_string())
+    })?;
+  let flow = pending_flow_by_token(&state.db, mfa_token).await?;
+  ensure_pending_flow_valid(&flow)?;
+  let user_id = flow
+    .user_id
+    .ok_or_else(|| AuthError::InternalError("pending MFA flow missing user".to_string()))?;
+  let phone = verified_phone_by_factor_id(&state.db, req.factor_id, user_id).await?;
+
+  Ok(Json(
+    send_phone_challenge(&state, user_id, req.factor_id, &phone, client_addr.ip()).await?,
+  ))
+}
+
+async fn send_phone_challenge(
+  state: &AppState,
+  user_id: Uuid,
+  factor_id: Uuid,
+  phone: &str,
+  client_ip: IpAddr,
+) -> Result<MfaChallengeResponse> {
+  let sender = state
+    .sms
+    .as_ref()
+    .ok_or_else(|| AuthError::ValidationFailed("Phone MFA is not enabled".to_string()))?;
+  let now = Utc::now();
+  let recently_sent = sqlx::query_scalar::<_, Uuid>(
+    "SELECT id FROM auth.mfa_challenges WHERE factor_id = $1 AND otp_code IS NOT NULL AND created_at > $2 LIMIT 1",
+  )
+  .bind(factor_id)
+  .bind(now - Duration::seconds(PHONE_CODE_RESEND_INTERVAL_SECS))
+  .fetch_optional(&state.db)
+  .await?
+  .is_some();
+  // A number shared by several accounts, or one user or client cycling
+  // through numbers, could otherwise be sent codes without bound.
+  let rate_limits = [
+    (phone_code_rate_limit_key(phone), PHONE_CODE_RATE_LIMIT_ATTEMPTS),
+    (
+      phone_code_user_rate_limit_key(user_id),
+      PHONE_CODE_USER_RATE_LIMIT_ATTEMPTS,
+    ),
+    (
+      phone_code_ip_rate_limit_key(client_ip),
+      PHONE_CODE_IP_RATE_LIMIT_ATTEMPTS,
+    ),
+  ];
+  if recently_sent {
+    return Err(AuthError::TooManyRequests);
+  }
+  for (key, max_attempts) in &rate_limits {
+    if rate_limit::is_limited(&state.db, key, *max_attempts).await? {
+      return Err(AuthError::TooManyRequests);
+    }
+  }
+  for (key, _) in &rate_limits {
+    rate_limit::record_attempt(&state.db, key, PHONE_CODE_RATE_LIMIT_WINDOW_SECS).await?;
+  }
+
+  let challenge_id = Uuid::new_v4();
+  let code = mfa::generate_phone_code();
+  let expires_at = now + Duration::minutes(CHALLENGE_TTL_MINUTES);
+  sqlx::query(
+    "INSERT INTO auth.mfa_challenges (id, factor_id, created_at, verified_at, ip_address, otp_code, expires_at) VALUES ($1, $2, $3, NULL, $4::inet, $5, $6)",
+  )
+  .bind(challenge_id)
+  .bind(factor_id)
+  .bind(now)
+  .bind(client_ip.to_string())
+  .bind(mfa::hash_phone_code(challenge_id, &code))
+  .bind(expires_at)
+  .execute(&state.db)
+  .await?;
+
+  if let Err(e) = deliver_phone_code(sender.as_ref(), &state.site_name, phone, &code).await {
+    tracing::error!(error = %e, "Failed to send phone MFA code");
+    sqlx::query("DELETE FROM auth.mfa_challenges WHERE id = $1")
+      .bind(challenge_id)
+      .execute(&state.db)
+      .await?;
+    return Err(AuthError::InternalError(
+      "failed to send phone MFA code".to_string(),
+    ));
+  }
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_ip),
+    "mfa_phone_code_sent",
     serde_json::json!({
       "user_id": user_id,
       "factor_id": factor_id,
-      "factor_type": "totp",
+      "challenge_id": challenge_id,
     }),
   )
   .await?;
 
-  Ok(Json(MfaEnrollResponse {
-    id: factor_id,
-    friendly_name,
-    factor_type: "totp".to_string(),
-    status: "unverified".to_string(),
-    totp: crate::model::TotpEnrollment { secret, uri },
-  }))
+  Ok(MfaChallengeResponse {
+    id: challenge_id,
+    factor_type: PHONE_METHOD.to_string(),
+    expires_at: expires_at.timestamp(),
+    webauthn: None,
+  })
+}
+
+async fn deliver_phone_code(
+  sender: &dyn SmsSender,
+  site_name: &str,
+  phone: &str,
+  code: &str,
+) -> anyhow::Result<()> {
+  sender
+    .send(phone, &mfa::phone_code_message(site_name, code))
+    .await
+}
+
+/// Checks a code against an open challenge of a phone factor and marks the
+/// challenge verified. Every check counts towards the challenge's attempt
+/// limit, so a code cannot be guessed by retrying.
+async fn verify_phone_code(
+  db: &PgPool,
+  factor_id: Uuid,
+  challenge_id: Option<Uuid>,
+  code: &str,
+  now: chrono::DateTime<Utc>,
+) -> Result<Uuid> {
+  let challenge = attempt_challenge(db, factor_id, challenge_id, now).await?;
+  check_phone_code(&challenge, code)?;
+  mark_challenge_verified(db, challenge.id, now).await?;
+
+  Ok(challenge.id)
+}
+
+fn check_phone_code(challenge: &ChallengeRow, code: &str) -> Result<()> {
+  if challenge.otp_code.as_deref() != Some(mfa::hash_phone_code(challenge.id, code).as_str()) {
+    return Err(AuthError::ValidationFailed("Invalid phone code".to_string()));
+  }
+
+  Ok(())
+}
+
+/// Counts an attempt against an open challenge of the factor, the latest one
+/// when `challenge_id` is not given, and returns it. Fails once the
+/// challenge has expired or used up its attempts.
+async fn attempt_challenge(
+  db: &PgPool,
+  factor_id: Uuid,
+  challenge_id: Option<Uuid>,
+  now: chrono::DateTime<Utc>,
+) -> Result<ChallengeRow> {
+  sqlx::query_as::<_, ChallengeRow>(
+    "UPDATE auth.mfa_challenges SET attempts = attempts + 1 WHERE id = (SELECT id FROM auth.mfa_challenges WHERE factor_id = $1 AND ($2::uuid IS NULL OR id = $2) AND verified_at IS NULL AND expires_at IS NOT NULL ORDER BY created_at DESC LIMIT 1) AND expires_at > $3 AND attempts < $4 RETURNING id, host(ip_address) as ip_address, otp_code, web_authn_session_data",
+  )
+  .bind(factor_id)
+  .bind(challenge_id)
+  .bind(now)
+  .bind(CHALLENGE_MAX_VERIFY_ATTEMPTS)
+  .fetch_optional(db)
+  .await?
+  .ok_or_else(|| {
+    AuthError::ValidationFailed(
+      "Challenge has expired or was tried too many times. Request a new one.".to_string(),
+    )
+  })
+}
+
+async fn mark_challenge_verified(db: &PgPool, challenge_id: Uuid, now: chrono::DateTime<Utc>) -> Result<()> {
+  let consumed =
+    sqlx::query("UPDATE auth.mfa_challenges SET verified_at = $1 WHERE id = $2 AND verified_at IS NULL")
+      .bind(now)
+      .bind(challenge_id)
+      .execute(db)
+      .await?
+      .rows_affected();
+  if consumed == 0 {
+    return Err(AuthError::ValidationFailed(
+      "Challenge has already been used".to_string(),
+    ));
+  }
+
+  Ok(())
+}
+
+/// Verifies a factor. With a `challenge_id` from [`challenge_factor`] this
+/// is GoTrue's verify step: the factor is verified if it was not already,
+/// and the caller's session is upgraded to aal2 and returned with new
+/// tokens. Without one, it only finishes enrolling an unverified factor.
+pub async fn verify_factor(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  AuthUser { claims, user }: AuthUser,
+  Path(factor_id): Path<Uuid>,
+  Json(req): Json<VerifyFactorRequest>,
+) -> Result<Json<VerifyFactorResponse>> {
+  session::forbid_impersonation(&claims)?;
+  let factor = factor_by_id(&state.db, factor_id, user.id).await?;
+  if let Some(challenge_id) = req.challenge_id {
+    let response = verify_challenge(
+      &state,
+      client_addr.ip(),
+      &claims,
+      &user,
+      &factor,
+      challenge_id,
+      &req,
+    )
+    .await?;
+    return Ok(Json(VerifyFactorResponse::Token(Box::new(response))));
+  }
+
+  let verified = if factor.factor_type == webauthn::METHOD {
+    let credential = serde_json::from_value::<RegistrationCredential>(required_credential(&req)?)
+      .map_err(|e| AuthError::ValidationFailed(format!("credential is not a WebAuthn registration: {e}")))?;
+    verify_webauthn_enrollment(&state, user.id, factor_id, &credential).await?
+  } else if factor.factor_type == PHONE_METHOD {
+    verify_phone_enrollment(&state, &factor, required_code(&req)?).await?
+  } else {
+    verify_totp_enrollment(&state, user.id, factor_id, required_code(&req)?).await?
+  };
+
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_addr.ip()),
+    "mfa_enrollment_verified",
+    serde_json::json!({
+      "user_id": user.id,
+      "factor_id": factor_id,
+      "factor_type": verified.factor_type,
+    }),
+  )
+  .await?;
+
+  Ok(Json(VerifyFactorResponse::Factor(verified.into())))
+}
+
+/// Answers a challenge opened by [`challenge_factor`] and upgrades the
+/// caller's session. The answer must come from the IP address the challenge
+/// was opened from.
+async fn verify_challenge(
+  state: &AppState,
+  client_ip: IpAddr,
+  claims: &Claims,
+  user: &User,
+  factor: &MfaFactorRow,
+  challenge_id: Uuid,
+  req: &VerifyFactorRequest,
+) -> Result<TokenResponse> {
+  let now = Utc::now();
+  let challenge = attempt_challenge(&state.db, factor.id, Some(challenge_id), now).await?;
+  if challenge.ip_address.as_deref() != Some(client_ip.to_string().as_str()) {
+    return Err(AuthError::ValidationFailed(
+      "Challenge was opened from a different IP address".to_string(),
+    ));
+  }
+  let enrolled = factor.status == "verified";
+  if factor.factor_type == PHONE_METHOD {
+    check_phone_code(&challenge, required_code(req)?)?;
+  } else if factor.factor_type == webauthn::METHOD {
+    let expected = challenge
+      .web_authn_session_data
+      .as_ref()
+      .and_then(|data| data.get("challenge"))
+      .and_then(|challenge| challenge.as_str())
+      .ok_or_else(|| AuthError::InternalError("missing WebAuthn challenge".to_string()))?;
+    let credential = serde_json::from_value::<AssertionCredential>(required_credential(req)?)
+      .map_err(|e| AuthError::ValidationFailed(format!("credential is not a WebAuthn assertion: {e}")))?;
+    verify_webauthn_assertion(state, factor.id, expected, &credential, now).await?;
+  } else if enrolled {
+    verify_totp_code(state, factor.id, user.id, required_code(req)?, now).await?;
+  } else {
+    verify_totp_enrollment(state, user.id, factor.id, required_code(req)?).await?;
+  }
+  mark_challenge_verified(&state.db, challenge.id, now).await?;
+
+  sqlx::query(
+    "UPDATE auth.mfa_factors SET status = 'verified'::auth.factor_status, last_challenged_at = $1, updated_at = $2 WHERE id = $3",
+  )
+  .bind(now)
+  .bind(now)
+  .bind(factor.id)
+  .execute(&state.db)
+  .await?;
+  if !enrolled {
+    audit::log_event(
+      &state.db,
+      state.instance_id,
+      Some(client_ip),
+      "mfa_enrollment_verified",
+      serde_json::json!({
+        "user_id": user.id,
+        "factor_id": factor.id,
+        "factor_type": factor.factor_type,
+      }),
+    )
+    .await?;
+  }
+
+  let response = session::upgrade_session(state, claims, user, factor.id, &factor.factor_type).await?;
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_ip),
+    "mfa_challenge_verified",
+    serde_json::json!({
+      "user_id": user.id,
+      "factor_id": factor.id,
+      "challenge_id": challenge.id,
+      "session_id": claims.session_id,
+    }),
+  )
+  .await?;
+
+  Ok(response)
+}
+
+fn required_code(req: &VerifyFactorRequest) -> Result<&str> {
+  req
+    .code
+    .as_deref()
+    .ok_or_else(|| AuthError::ValidationFailed("code is required".to_string()))
+}
+
+fn required_credential(req: &VerifyFactorRequest) -> Result<serde_json::Value> {
+  req
+    .credential
+    .clone()
+    .ok_or_else(|| AuthError::ValidationFailed("credential is required".to_string()))
+}
+
+/// Checks a code from a verified TOTP factor and records its time step, so
+/// the same code cannot be used twice. Wrong codes count against the factor
+/// and the user whichever challenge or pending login they were sent to, so
+/// opening new challenges does not buy more guesses.
+async fn verify_totp_code(
+  state: &AppState,
+  factor_id: Uuid,
+  user_id: Uuid,
+  code: &str,
+  now: chrono::DateTime<Utc>,
+) -> Result<()> {
+  let rate_limits = [
+    (
+      totp_factor_failure_rate_limit_key(factor_id),
+      TOTP_FACTOR_FAILURE_RATE_LIMIT_ATTEMPTS,
+    ),
+    (
+      totp_user_failure_rate_limit_key(user_id),
+      TOTP_USER_FAILURE_RATE_LIMIT_ATTEMPTS,
+    ),
+  ];
+  for (key, max_attempts) in &rate_limits {
+    if rate_limit::is_limited(&state.db, key, *max_attempts).await? {
+      return Err(AuthError::TooManyRequests);
+    }
+  }
+
+  let mut tx = state.db.begin().await?;
+  let factor = verified_factor_verify_state_by_id(tx.as_mut(), factor_id, user_id).await?;
+  let encrypted_secret = factor
+    .secret
+    .as_deref()
+    .ok_or_else(|| AuthError::InternalError("missing TOTP secret".to_string()))?;
+  let decrypted_secret = state.mfa_keys.decrypt(encrypted_secret)?;
+  let matched_step = mfa::matching_code_step(
+    &decrypted_secret,
+    code,
+    now.timestamp(),
+    &factor.totp_params()?,
+    state.totp.skew_steps,
+  )?;
+  let error = match matched_step {
+    None => Some("Invalid TOTP code"),
+    Some(step) if factor.last_verified_totp_step == Some(step) => {
+      Some("TOTP code has already been used for this factor")
+    },
+    Some(_) => None,
+  };
+  if let Some(error) = error {
+    tx.rollback().await?;
+    for (key, _) in &rate_limits {
+      rate_limit::record_failure(&state.db, key, TOTP_FAILURE_RATE_LIMIT_WINDOW_SECS).await?;
+    }
+    return Err(AuthError::ValidationFailed(error.to_string()));
+  }
+
+  sqlx::query(
+    "UPDATE auth.mfa_factors SET last_challenged_at = $1, last_verified_totp_step = $2, updated_at = $3 WHERE id = $4",
+  )
+  .bind(now)
+  .bind(matched_step)
+  .bind(now)
+  .bind(factor_id)
+  .execute(tx.as_mut())
+  .await?;
+  tx.commit().await?;
+  for (key, _) in &rate_limits {
+    rate_limit::clear(&state.db, key).await?;
+  }
+
+  Ok(())
+}
+
+/// Checks an assertion from a verified WebAuthn factor against `challenge`
+/// and stores the credential's new signature count.
+async fn verify_webauthn_assertion(
+  state: &AppState,
+  factor_id: Uuid,
+  challenge: &str,
+  credential: &AssertionCredential,
+  now: chrono::DateTime<Utc>,
+) -> Result<()> {
+  let mut tx = state.db.begin().await?;
+  let sqlx::types::Json(mut stored) = sqlx::query_scalar::<_, sqlx::types::Json<StoredCredential>>(
+    "SELECT web_authn_credential FROM auth.mfa_factors WHERE id = $1 AND status = 'verified'::auth.factor_status FOR UPDATE",
+  )
+  .bind(factor_id)
+  .fetch_optional(tx.as_mut())
+  .await?
+  .ok_or(AuthError::NotAuthorized)?;
+  if stored.id != credential.id {
+    return Err(AuthError::NotAuthorized);
+  }
+  stored.sign_count = webauthn::verify_assertion(&state.webauthn, challenge, &stored, credential, false)?;
+
+  sqlx::query(
+    "UPDATE auth.mfa_factors SET web_authn_credential = $1, last_challenged_at = $2, updated_at = $3 WHERE id = $4",
+  )
+  .bind(sqlx::types::Json(&stored))
+  .bind(now)
+  .bind(now)
+  .bind(factor_id)
+  .execute(tx.as_mut())
+  .await?;
+  tx.commit().await?;
+
+  Ok(())
 }
 
-pub async fn verify_totp_factor(
-  State(state): State<AppState>,
-  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
-  AuthUser { user, .. }: AuthUser,
-  Path(factor_id): Path<Uuid>,
-  Json(req): Json<VerifyTotpCodeRequest>,
-) -> Result<Json<MfaFactorResponse>> {
-  let user_id = user.id;
+async fn verify_totp_enrollment(
+  state: &AppState,
+  user_id: Uuid,
+  factor_id: Uuid,
+  code: &str,
+) -> Result<MfaFactorRow> {
   let now = Utc::now();
   let mut tx = state.db.begin().await?;
   let factor = factor_verify_state_by_id(tx.as_mut(), factor_id, user_id).await?;
@@ -171,8 +1025,14 @@ pub async fn verify_totp_factor(
     .secret
     .as_deref()
     .ok_or_else(|| AuthError::InternalError("missing TOTP secret".to_string()))?;
-  let decrypted = mfa::decrypt_secret(secret, &state.mfa_encryption_key)?;
-  let matched_step = mfa::matching_code_step(&decrypted, &req.code, now.timestamp())?;
+  let decrypted = state.mfa_keys.decrypt(secret)?;
+  let matched_step = mfa::matching_code_step(
+    &decrypted,
+    code,
+    now.timestamp(),
+    &factor.totp_params()?,
+    state.totp.skew_steps,
+  )?;
   let Some(matched_step) = matched_step else {
     record_failed_enrollment_attempt(tx.as_mut(), factor.id, &factor, now).await?;
     tx.commit().await?;
@@ -187,7 +1047,7 @@ pub async fn verify_totp_factor(
   }
 
   let verified: MfaFactorRow = sqlx::query_as::<_, MfaFactorRow>(
-    "UPDATE auth.mfa_factors SET status = 'verified'::auth.factor_status, last_challenged_at = $1, last_verified_totp_step = $2, enrollment_verify_attempts = 0, last_enrollment_verify_attempt_at = NULL, updated_at = $3 WHERE id = $4 AND user_id = $5 RETURNING id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, created_at, updated_at, last_challenged_at",
+    "UPDATE auth.mfa_factors SET status = 'verified'::auth.factor_status, last_challenged_at = $1, last_verified_totp_step = $2, enrollment_verify_attempts = 0, last_enrollment_verify_attempt_at = NULL, updated_at = $3 WHERE id = $4 AND user_id = $5 RETURNING id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, phone, created_at, updated_at, last_challenged_at",
   )
   .bind(now)
   .bind(matched_step)
@@ -198,20 +1058,93 @@ pub async fn verify_totp_factor(
   .await?;
   tx.commit().await?;
 
-  audit::log_event(
-    &state.db,
-    state.instance_id,
-    Some(client_addr.ip()),
-    "mfa_enrollment_verified",
-    serde_json::json!({
-      "user_id": user_id,
-      "factor_id": factor_id,
-      "factor_type": "totp",
-    }),
+  Ok(verified)
+}
+
+async fn verify_webauthn_enrollment(
+  state: &AppState,
+  user_id: Uuid,
+  factor_id: Uuid,
+  credential: &RegistrationCredential,
+) -> Result<MfaFactorRow> {
+  let now = Utc::now();
+  let mut tx = state.db.begin().await?;
+  let factor = sqlx::query_as::<_, WebAuthnEnrollmentRow>(
+    "SELECT status::text as status, web_authn_session_data, created_at FROM auth.mfa_factors WHERE id = $1 AND user_id = $2 AND factor_type = 'webauthn'::auth.factor_type FOR UPDATE",
+  )
+  .bind(factor_id)
+  .bind(user_id)
+  .fetch_optional(tx.as_mut())
+  .await?
+  .ok_or(AuthError::UserNotFound)?;
+  if factor.status == "verified" {
+    return Err(AuthError::ValidationFailed(
+      "Factor is already verified".to_string(),
+    ));
+  }
+  let challenge = factor
+    .web_authn_session_data
+    .as_ref()
+    .and_then(|data| data.get("challenge"))
+    .and_then(|challenge| challenge.as_str())
+    .ok_or_else(|| AuthError::InternalError("missing WebAuthn registration challenge".to_string()))?;
+  if factor.created_at + Duration::milliseconds(webauthn::CEREMONY_TIMEOUT_MS as i64) < now {
+    return Err(AuthError::ValidationFailed(
+      "WebAuthn registration has expired. Remove this factor and create a new one.".to_string(),
+    ));
+  }
+
+  let registration = webauthn::verify_registration(&state.webauthn, challenge, credential)?;
+  let already_registered =
+    sqlx::query_scalar::<_, Uuid>("SELECT id FROM auth.mfa_factors WHERE web_authn_credential ->> 'id' = $1")
+      .bind(&registration.credential.id)
+      .fetch_optional(tx.as_mut())
+      .await?
+      .is_some();
+  if already_registered {
+    return Err(AuthError::ValidationFailed(
+      "This security key is already registered".to_string(),
+    ));
+  }
+
+  let verified: MfaFactorRow = sqlx::query_as::<_, MfaFactorRow>(
+    "UPDATE auth.mfa_factors SET status = 'verified'::auth.factor_status, web_authn_credential = $1, web_authn_aaguid = $2, web_authn_session_data = NULL, updated_at = $3 WHERE id = $4 AND user_id = $5 RETURNING id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, phone, created_at, updated_at, last_challenged_at",
   )
+  .bind(sqlx::types::Json(&registration.credential))
+  .bind(registration.aaguid)
+  .bind(now)
+  .bind(factor_id)
+  .bind(user_id)
+  .fetch_one(tx.as_mut())
   .await?;
+  tx.commit().await?;
+
+  Ok(verified)
+}
+
+async fn verify_phone_enrollment(
+  state: &AppState,
+  factor: &MfaFactorRow,
+  code: &str,
+) -> Result<MfaFactorRow> {
+  if factor.status == "verified" {
+    return Err(AuthError::ValidationFailed(
+      "Factor is already verified".to_string(),
+    ));
+  }
+  let now = Utc::now();
+  verify_phone_code(&state.db, factor.id, None, code, now).await?;
 
-  Ok(Json(verified.into()))
+  sqlx::query_as::<_, MfaFactorRow>(
+    "UPDATE auth.mfa_factors SET status = 'verified'::auth.factor_status, last_challenged_at = $1, updated_at = $2 WHERE id = $3 AND user_id = $4 AND status = 'unverified'::auth.factor_status RETURNING id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, phone, created_at, updated_at, last_challenged_at",
+  )
+  .bind(now)
+  .bind(now)
+  .bind(factor.id)
+  .bind(factor.user_id)
+  .fetch_optional(&state.db)
+  .await?
+  .ok_or_else(|| AuthError::ValidationFailed("Factor is already verified".to_string()))
 }
 
 pub async fn delete_factor(
@@ -219,6 +1152,7 @@ pub async fn delete_factor(
   AuthUser { claims, user }: AuthUser,
   Path(factor_id): Path<Uuid>,
 ) -> Result<Json<serde_json::Value>> {
+  session::forbid_impersonation(&claims)?;
   let user_id = user.id;
   let factor = factor_by_id(&state.db, factor_id, user_id).await?;
 
@@ -238,6 +1172,7 @@ pub async fn delete_factor(
     .bind(user_id)
     .execute(&mut *tx)
     .await?;
+  clear_orphaned_recovery_codes(&mut tx, user_id).await?;
   sqlx::query("UPDATE auth.users SET updated_at = $1 WHERE id = $2")
     .bind(now)
     .bind(user_id)
@@ -273,9 +1208,11 @@ pub async fn create_pending_login(
   let factors = verified_factors_by_user_id(&state.db, user_id).await?;
   if factors.is_empty() {
     return Err(AuthError::InternalError(
-      "no verified TOTP factor available".to_string(),
+      "no verified MFA factor available".to_string(),
     ));
   }
+  let credentials = verified_webauthn_credentials(&state.db, user_id).await?;
+  let webauthn_challenge = (!credentials.is_empty()).then(webauthn::generate_challenge);
 
   let now = Utc::now();
   let flow_id = Uuid::new_v4();
@@ -288,7 +1225,7 @@ pub async fn create_pending_login(
     .execute(&state.db)
     .await?;
   sqlx::query(
-    "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, provider_access_token, provider_refresh_token, authentication_method, created_at, updated_at, factor_id, expires_at, attempts) VALUES ($1, $2, $3, 'plain'::auth.code_challenge_method, $4, 'totp', NULL, NULL, $5, $6, $7, NULL, $8, 0)",
+    "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, provider_access_token, provider_refresh_token, authentication_method, created_at, updated_at, factor_id, expires_at, attempts, webauthn_challenge) VALUES ($1, $2, $3, 'plain'::auth.code_challenge_method, $4, 'totp', NULL, NULL, $5, $6, $7, NULL, $8, 0, $9)",
   )
   .bind(flow_id)
   .bind(user_id)
@@ -298,6 +1235,7 @@ pub async fn create_pending_login(
   .bind(now)
   .bind(now)
   .bind(expires_at)
+  .bind(&webauthn_challenge)
   .execute(&state.db)
   .await?;
 
@@ -305,9 +1243,22 @@ pub async fn create_pending_login(
     mfa_required: true,
     mfa_token,
     factors: factors.into_iter().map(Into::into).collect(),
+    webauthn: webauthn_challenge.map(|challenge| {
+      webauthn::request_options(
+        &state.webauthn,
+        &challenge,
+        credentials
+          .iter()
+          .map(|row| row.web_authn_credential.descriptor())
+          .collect(),
+      )
+    }),
   })
 }
 
+/// Completes a pending MFA login with a TOTP code. With `remember_device_for`
+/// the device is also trusted for that many seconds, capped by
+/// `MFA_TRUSTED_DEVICE_MAX_LIFETIME_SECS`.
 pub async fn verify_pending_totp(
   state: &AppState,
   client_ip: IpAddr,
@@ -315,29 +1266,19 @@ pub async fn verify_pending_totp(
   mfa_token: &str,
   factor_id: Uuid,
   code: &str,
+  remember_device_for: Option<i64>,
 ) -> Result<TokenResponse> {
+  let trust_lifetime_secs = remember_device_for
+    .map(|requested| trusted_device::lifetime_secs(requested, state.trusted_device_max_lifetime_secs))
+    .transpose()?;
   let now = Utc::now();
-  let flow = pending_flow_attempt(&state.db, mfa_token, factor_id, now).await?;
+  let flow = pending_flow_attempt(&state.db, mfa_token, Some(factor_id), now).await?;
   ensure_pending_flow_valid(&flow)?;
 
   let user_id = flow
     .user_id
     .ok_or_else(|| AuthError::InternalError("pending MFA flow missing user".to_string()))?;
-  let factor = verified_factor_verify_state_by_id(&state.db, factor_id, user_id).await?;
-  let encrypted_secret = factor
-    .secret
-    .as_deref()
-    .ok_or_else(|| AuthError::InternalError("missing TOTP secret".to_string()))?;
-  let decrypted_secret = mfa::decrypt_secret(encrypted_secret, &state.mfa_encryption_key)?;
-  let matched_step = mfa::matching_code_step(&decrypted_secret, code, now.timestamp())?;
-  let Some(matched_step) = matched_step else {
-    return Err(AuthError::ValidationFailed("Invalid TOTP code".to_string()));
-  };
-  if factor.last_verified_totp_step == Some(matched_step) {
-    return Err(AuthError::ValidationFailed(
-      "TOTP code has already been used for this factor".to_string(),
-    ));
-  }
+  verify_totp_code(state, factor_id, user_id, code, now).await?;
   let challenge_id = Uuid::new_v4();
   let ip_address = client_ip;
 
@@ -356,11 +1297,146 @@ pub async fn verify_pending_totp(
     .bind(challenge_id)
     .execute(&state.db)
     .await?;
+
+  let user = fetch_user(&state.db, user_id).await?;
+  let device_user_agent = user_agent.clone();
+  let mut response = session::issue_session_with_client_context(
+    state,
+    &user,
+    "aal2",
+    Some(factor_id),
+    vec![flow.authentication_method, "totp".to_string()],
+    session::ClientContext {
+      user_agent,
+      ip: Some(client_ip),
+    },
+  )
+  .await?;
+  if let Some(lifetime_secs) = trust_lifetime_secs {
+    let device = trusted_device::issue(
+      &state.db,
+      user_id,
+      factor_id,
+      lifetime_secs,
+      device_user_agent.as_deref(),
+      client_ip,
+    )
+    .await?;
+    audit::log_event(
+      &state.db,
+      state.instance_id,
+      Some(client_ip),
+      "trusted_device_created",
+      serde_json::json!({
+        "user_id": user_id,
+        "factor_id": factor_id,
+        "trusted_device_id": device.id,
+        "expires_at": device.expires_at,
+      }),
+    )
+    .await?;
+    response.trusted_device_token = Some(device.token);
+    response.trusted_device_expires_at = Some(device.expires_at.timestamp());
+  }
+
+  sqlx::query("DELETE FROM auth.flow_state WHERE id = $1")
+    .bind(flow.id)
+    .execute(&state.db)
+    .await?;
+
+  Ok(response)
+}
+
+/// Completes a pending MFA login with an assertion from one of the user's
+/// WebAuthn factors, signed over the challenge issued with the login.
+pub async fn verify_pending_webauthn(
+  state: &AppState,
+  client_ip: IpAddr,
+  user_agent: Option<String>,
+  mfa_token: &str,
+  credential: &AssertionCredential,
+) -> Result<TokenResponse> {
+  let now = Utc::now();
+  let flow = pending_flow_by_token(&state.db, mfa_token).await?;
+  ensure_pending_flow_valid(&flow)?;
+  let user_id = flow
+    .user_id
+    .ok_or_else(|| AuthError::InternalError("pending MFA flow missing user".to_string()))?;
+  if credential
+    .response
+    .user_handle
+    .as_deref()
+    .is_some_and(|handle| handle != webauthn::user_handle(user_id))
+  {
+    return Err(AuthError::NotAuthorized);
+  }
+  let factor_id = verified_webauthn_credentials(&state.db, user_id)
+    .await?
+    .into_iter()
+    .find(|row| row.web_authn_credential.id == credential.id)
+    .map(|row| row.id)
+    .ok_or(AuthError::NotAuthorized)?;
+  let flow = pending_flow_attempt(&state.db, mfa_token, Some(factor_id), now).await?;
+  let challenge = flow
+    .webauthn_challenge
+    .as_deref()
+    .ok_or_else(|| AuthError::ValidationFailed("This login has no WebAuthn challenge".to_string()))?;
+
+  verify_webauthn_assertion(state, factor_id, challenge, credential, now).await?;
   sqlx::query(
-    "UPDATE auth.mfa_factors SET last_challenged_at = $1, last_verified_totp_step = $2, updated_at = $3 WHERE id = $4",
+    "INSERT INTO auth.mfa_challenges (id, factor_id, created_at, verified_at, ip_address, otp_code) VALUES ($1, $2, $3, $4, $5::inet, NULL)",
+  )
+  .bind(Uuid::new_v4())
+  .bind(factor_id)
+  .bind(now)
+  .bind(now)
+  .bind(client_ip.to_string())
+  .execute(&state.db)
+  .await?;
+
+  let user = fetch_user(&state.db, user_id).await?;
+  let response = session::issue_session_with_client_context(
+    state,
+    &user,
+    "aal2",
+    Some(factor_id),
+    vec![flow.authentication_method, webauthn::METHOD.to_string()],
+    session::ClientContext {
+      user_agent,
+      ip: Some(client_ip),
+    },
   )
+  .await?;
+
+  sqlx::query("DELETE FROM auth.flow_state WHERE id = $1")
+    .bind(flow.id)
+    .execute(&state.db)
+    .await?;
+
+  Ok(response)
+}
+
+/// Completes a pending MFA login with a code sent to one of the user's phone
+/// factors through [`challenge_pending_factor`].
+pub async fn verify_pending_phone(
+  state: &AppState,
+  client_ip: IpAddr,
+  user_agent: Option<String>,
+  mfa_token: &str,
+  factor_id: Uuid,
+  challenge_id: Option<Uuid>,
+  code: &str,
+) -> Result<TokenResponse> {
+  let now = Utc::now();
+  let flow = pending_flow_attempt(&state.db, mfa_token, Some(factor_id), now).await?;
+  ensure_pending_flow_valid(&flow)?;
+  let user_id = flow
+    .user_id
+    .ok_or_else(|| AuthError::InternalError("pending MFA flow missing user".to_string()))?;
+  verified_phone_by_factor_id(&state.db, factor_id, user_id).await?;
+  verify_phone_code(&state.db, factor_id, challenge_id, code, now).await?;
+  sqlx::query("UPDATE auth.mfa_factors SET last_challenged_at = $1, updated_at = $2 WHERE id = $3")
     .bind(now)
-    .bind(matched_step)
     .bind(now)
     .bind(factor_id)
     .execute(&state.db)
@@ -372,7 +1448,66 @@ pub async fn verify_pending_totp(
     &user,
     "aal2",
     Some(factor_id),
-    vec![flow.authentication_method, "totp".to_string()],
+    vec![flow.authentication_method, PHONE_METHOD.to_string()],
+    session::ClientContext {
+      user_agent,
+      ip: Some(client_ip),
+    },
+  )
+  .await?;
+
+  sqlx::query("DELETE FROM auth.flow_state WHERE id = $1")
+    .bind(flow.id)
+    .execute(&state.db)
+    .await?;
+
+  Ok(response)
+}
+
+/// Completes a pending MFA login with one of the user's recovery codes in
+/// place of a TOTP code. The code is spent, and the user is emailed that it
+/// was used.
+pub async fn verify_pending_recovery_code(
+  state: &AppState,
+  client_ip: IpAddr,
+  user_agent: Option<String>,
+  mfa_token: &str,
+  code: &str,
+) -> Result<TokenResponse> {
+  let now = Utc::now();
+  let flow = pending_flow_attempt(&state.db, mfa_token, None, now).await?;
+  ensure_pending_flow_valid(&flow)?;
+  let user_id = flow
+    .user_id
+    .ok_or_else(|| AuthError::InternalError("pending MFA flow missing user".to_string()))?;
+  let used = sqlx::query_scalar::<_, Uuid>(
+    "UPDATE auth.mfa_recovery_codes SET used_at = $1 WHERE user_id = $2 AND code_hash = $3 AND used_at IS NULL RETURNING id",
+  )
+  .bind(now)
+  .bind(user_id)
+  .bind(mfa::hash_recovery_code(code))
+  .fetch_optional(&state.db)
+  .await?;
+  let Some(recovery_code_id) = used else {
+    audit::log_event(
+      &state.db,
+      state.instance_id,
+      Some(client_ip),
+      "mfa_recovery_code_failed",
+      serde_json::json!({ "user_id": user_id }),
+    )
+    .await?;
+    return Err(AuthError::ValidationFailed("Invalid recovery code".to_string()));
+  };
+  let remaining = remaining_recovery_codes(&state.db, user_id).await?;
+
+  let user = fetch_user(&state.db, user_id).await?;
+  let response = session::issue_session_with_client_context(
+    state,
+    &user,
+    "aal2",
+    None,
+    vec![flow.authentication_method, RECOVERY_CODE_METHOD.to_string()],
     session::ClientContext {
       user_agent,
       ip: Some(client_ip),
@@ -384,13 +1519,93 @@ pub async fn verify_pending_totp(
     .bind(flow.id)
     .execute(&state.db)
     .await?;
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_ip),
+    "mfa_recovery_code_used",
+    serde_json::json!({
+      "user_id": user_id,
+      "recovery_code_id": recovery_code_id,
+      "remaining": remaining,
+    }),
+  )
+  .await?;
+  notify_recovery_code_used(state, &user, remaining).await;
 
   Ok(response)
 }
 
+async fn notify_recovery_code_used(state: &AppState, user: &User, remaining: i64) {
+  let (Some(mailer), Some(email)) = (state.mailer.as_ref(), user.email.as_deref()) else {
+    return;
+  };
+  let remaining = remaining.to_string();
+  if let Err(e) = mailer
+    .send(
+      EmailKind::RecoveryCodeUsed,
+      email,
+      &[
+        ("site_name", state.site_name.as_str()),
+        ("email", email),
+        ("remaining_codes", remaining.as_str()),
+      ],
+    )
+    .await
+  {
+    tracing::warn!(error = %e, "Failed to send recovery code notification email");
+  }
+}
+
+async fn remaining_recovery_codes(db: &PgPool, user_id: Uuid) -> Result<i64> {
+  let remaining = sqlx::query_scalar::<_, i64>(
+    "SELECT COUNT(*) FROM auth.mfa_recovery_codes WHERE user_id = $1 AND used_at IS NULL",
+  )
+  .bind(user_id)
+  .fetch_one(db)
+  .await?;
+
+  Ok(remaining)
+}
+
+/// Recovery codes only stand in for a verified factor, so they go once the
+/// user has none left.
+pub async fn clear_orphaned_recovery_codes(db: &mut sqlx::PgConnection, user_id: Uuid) -> Result<()> {
+  sqlx::query(
+    "DELETE FROM auth.mfa_recovery_codes WHERE user_id = $1 AND NOT EXISTS (SELECT 1 FROM auth.mfa_factors WHERE user_id = $1 AND status = 'verified'::auth.factor_status)",
+  )
+  .bind(user_id)
+  .execute(db)
+  .await?;
+
+  Ok(())
+}
+
+async fn verified_phone_by_factor_id(db: &PgPool, factor_id: Uuid, user_id: Uuid) -> Result<String> {
+  sqlx::query_scalar::<_, String>(
+    "SELECT phone FROM auth.mfa_factors WHERE id = $1 AND user_id = $2 AND factor_type = 'phone'::auth.factor_type AND status = 'verified'::auth.factor_status AND phone IS NOT NULL",
+  )
+  .bind(factor_id)
+  .bind(user_id)
+  .fetch_optional(db)
+  .await?
+  .ok_or(AuthError::NotAuthorized)
+}
+
+async fn verified_webauthn_credentials(db: &PgPool, user_id: Uuid) -> Result<Vec<WebAuthnCredentialRow>> {
+  let credentials = sqlx::query_as::<_, WebAuthnCredentialRow>(
+    "SELECT id, web_authn_credential FROM auth.mfa_factors WHERE user_id = $1 AND factor_type = 'webauthn'::auth.factor_type AND status = 'verified'::auth.factor_status AND web_authn_credential IS NOT NULL ORDER BY created_at ASC",
+  )
+  .bind(user_id)
+  .fetch_all(db)
+  .await?;
+
+  Ok(credentials)
+}
+
 pub async fn verified_factors_by_user_id(db: &PgPool, user_id: Uuid) -> Result<Vec<MfaFactorRow>> {
   let factors = sqlx::query_as::<_, MfaFactorRow>(
-    "SELECT id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, created_at, updated_at, last_challenged_at FROM auth.mfa_factors WHERE user_id = $1 AND factor_type = 'totp'::auth.factor_type AND status = 'verified'::auth.factor_status ORDER BY created_at ASC",
+    "SELECT id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, phone, created_at, updated_at, last_challenged_at FROM auth.mfa_factors WHERE user_id = $1 AND status = 'verified'::auth.factor_status ORDER BY created_at ASC",
   )
   .bind(user_id)
   .fetch_all(db)
@@ -401,7 +1616,7 @@ pub async fn verified_factors_by_user_id(db: &PgPool, user_id: Uuid) -> Result<V
 
 async fn factors_by_user_id(db: &PgPool, user_id: Uuid) -> Result<Vec<MfaFactorRow>> {
   let factors = sqlx::query_as::<_, MfaFactorRow>(
-    "SELECT id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, created_at, updated_at, last_challenged_at FROM auth.mfa_factors WHERE user_id = $1 AND factor_type = 'totp'::auth.factor_type ORDER BY created_at ASC",
+    "SELECT id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, phone, created_at, updated_at, last_challenged_at FROM auth.mfa_factors WHERE user_id = $1 ORDER BY created_at ASC",
   )
   .bind(user_id)
   .fetch_all(db)
@@ -412,7 +1627,7 @@ async fn factors_by_user_id(db: &PgPool, user_id: Uuid) -> Result<Vec<MfaFactorR
 
 async fn ensure_unverified_factor_limit_not_reached(db: &PgPool, user_id: Uuid) -> Result<()> {
   let (count,): (i64,) = sqlx::query_as(
-    "SELECT COUNT(*) FROM auth.mfa_factors WHERE user_id = $1 AND factor_type = 'totp'::auth.factor_type AND status = 'unverified'::auth.factor_status",
+    "SELECT COUNT(*) FROM auth.mfa_factors WHERE user_id = $1 AND status = 'unverified'::auth.factor_status",
   )
   .bind(user_id)
   .fetch_one(db)
@@ -420,7 +1635,7 @@ async fn ensure_unverified_factor_limit_not_reached(db: &PgPool, user_id: Uuid)
 
   if count >= MFA_MAX_UNVERIFIED_FACTORS_PER_USER {
     return Err(AuthError::ValidationFailed(format!(
-      "Too many unverified TOTP factors. Remove an existing unverified factor before creating another (limit: {MFA_MAX_UNVERIFIED_FACTORS_PER_USER})."
+      "Too many unverified MFA factors. Remove an existing unverified factor before creating another (limit: {MFA_MAX_UNVERIFIED_FACTORS_PER_USER})."
     )));
   }
 
@@ -429,7 +1644,7 @@ async fn ensure_unverified_factor_limit_not_reached(db: &PgPool, user_id: Uuid)
 
 async fn factor_by_id(db: &PgPool, factor_id: Uuid, user_id: Uuid) -> Result<MfaFactorRow> {
   sqlx::query_as::<_, MfaFactorRow>(
-    "SELECT id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, created_at, updated_at, last_challenged_at FROM auth.mfa_factors WHERE id = $1 AND user_id = $2 AND factor_type = 'totp'::auth.factor_type",
+    "SELECT id, user_id, friendly_name, factor_type::text as factor_type, status::text as status, secret, phone, created_at, updated_at, last_challenged_at FROM auth.mfa_factors WHERE id = $1 AND user_id = $2",
   )
   .bind(factor_id)
   .bind(user_id)
@@ -439,12 +1654,12 @@ async fn factor_by_id(db: &PgPool, factor_id: Uuid, user_id: Uuid) -> Result<Mfa
 }
 
 async fn verified_factor_verify_state_by_id(
-  db: &PgPool,
+  db: &mut sqlx::PgConnection,
   factor_id: Uuid,
   user_id: Uuid,
 ) -> Result<TotpFactorVerifyStateRow> {
   sqlx::query_as::<_, TotpFactorVerifyStateRow>(
-    "SELECT id, secret, last_verified_totp_step, enrollment_verify_attempts, last_enrollment_verify_attempt_at FROM auth.mfa_factors WHERE id = $1 AND user_id = $2 AND factor_type = 'totp'::auth.factor_type AND status = 'verified'::auth.factor_status",
+    "SELECT id, secret, last_verified_totp_step, enrollment_verify_attempts, last_enrollment_verify_attempt_at, totp_algorithm, totp_digits, totp_period FROM auth.mfa_factors WHERE id = $1 AND user_id = $2 AND factor_type = 'totp'::auth.factor_type AND status = 'verified'::auth.factor_status FOR UPDATE",
   )
   .bind(factor_id)
   .bind(user_id)
@@ -459,7 +1674,7 @@ async fn factor_verify_state_by_id(
   user_id: Uuid,
 ) -> Result<TotpFactorVerifyStateRow> {
   sqlx::query_as::<_, TotpFactorVerifyStateRow>(
-    "SELECT id, secret, last_verified_totp_step, enrollment_verify_attempts, last_enrollment_verify_attempt_at FROM auth.mfa_factors WHERE id = $1 AND user_id = $2 AND factor_type = 'totp'::auth.factor_type FOR UPDATE",
+    "SELECT id, secret, last_verified_totp_step, enrollment_verify_attempts, last_enrollment_verify_attempt_at, totp_algorithm, totp_digits, totp_period FROM auth.mfa_factors WHERE id = $1 AND user_id = $2 AND factor_type = 'totp'::auth.factor_type FOR UPDATE",
   )
   .bind(factor_id)
   .bind(user_id)
@@ -480,7 +1695,7 @@ async fn fetch_user(db: &PgPool, user_id: Uuid) -> Result<User> {
 
 async fn pending_flow_by_token(db: &PgPool, token: &str) -> Result<PendingFlowRow> {
   sqlx::query_as::<_, PendingFlowRow>(
-    "SELECT id, user_id, authentication_method, expires_at FROM auth.flow_state WHERE auth_code = $1 AND provider_type = 'totp'",
+    "SELECT id, user_id, authentication_method, expires_at, webauthn_challenge FROM auth.flow_state WHERE auth_code = $1 AND provider_type = 'totp'",
   )
   .bind(sha256_hex(token))
   .fetch_optional(db)
@@ -491,11 +1706,11 @@ async fn pending_flow_by_token(db: &PgPool, token: &str) -> Result<PendingFlowRo
 async fn pending_flow_attempt(
   db: &PgPool,
   token: &str,
-  factor_id: Uuid,
+  factor_id: Option<Uuid>,
   now: chrono::DateTime<Utc>,
 ) -> Result<PendingFlowRow> {
   if let Some(flow) = sqlx::query_as::<_, PendingFlowRow>(
-    "UPDATE auth.flow_state SET factor_id = $1, attempts = attempts + 1, updated_at = $2 WHERE auth_code = $3 AND provider_type = 'totp' AND expires_at >= $2 AND attempts < $4 RETURNING id, user_id, authentication_method, expires_at",
+    "UPDATE auth.flow_state SET factor_id = $1, attempts = attempts + 1, updated_at = $2 WHERE auth_code = $3 AND provider_type = 'totp' AND expires_at >= $2 AND attempts < $4 RETURNING id, user_id, authentication_method, expires_at, webauthn_challenge",
   )
   .bind(factor_id)
   .bind(now)
@@ -611,6 +1826,30 @@ async fn record_failed_enrollment_attempt(
   Ok(())
 }
 
+fn challenge_rate_limit_key(factor_id: Uuid) -> String {
+  format!("mfa-challenge:{factor_id}")
+}
+
+fn totp_factor_failure_rate_limit_key(factor_id: Uuid) -> String {
+  format!("mfa-totp-factor-failures:{factor_id}")
+}
+
+fn totp_user_failure_rate_limit_key(user_id: Uuid) -> String {
+  format!("mfa-totp-user-failures:{user_id}")
+}
+
+fn phone_code_rate_limit_key(phone: &str) -> String {
+  format!("mfa-phone:{phone}")
+}
+
+fn phone_code_user_rate_limit_key(user_id: Uuid) -> String {
+  format!("mfa-phone-user:{user_id}")
+}
+
+fn phone_code_ip_rate_limit_key(client_ip: IpAddr) -> String {
+  format!("mfa-phone-ip:{client_ip}")
+}
+
 fn extract_mfa_token<'a>(headers: &'a HeaderMap, req: &'a PendingMfaFactorsRequest) -> Result<&'a str> {
   if let Some(value) = headers
     .get(axum::http::header::AUTHORIZATION)
@@ -646,6 +1885,9 @@ mod tests {
       last_verified_totp_step: None,
       enrollment_verify_attempts: 0,
       last_enrollment_verify_attempt_at: None,
+      totp_algorithm: "SHA1".to_string(),
+      totp_digits: 6,
+      totp_period: 30,
     }
   }
 
@@ -660,6 +1902,20 @@ mod tests {
     assert_eq!(current_enrollment_attempts(&factor, now), 0);
   }
 
+  #[tokio::test]
+  async fn phone_code_is_sent_to_the_factor_number() {
+    let sender = crate::sms::MockSmsSender::default();
+    deliver_phone_code(&sender, "Haya", "+15555550100", "042917")
+      .await
+      .unwrap();
+
+    let sent = sender.sent.lock().unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].0, "+15555550100");
+    assert!(sent[0].1.contains("042917"));
+    assert!(sent[0].1.contains("Haya"));
+  }
+
   #[test]
   fn enrollment_attempts_block_within_window() {
     let mut factor = sample_factor_state();
@@ -1,17 +1,23 @@
 pub mod admin;
+pub mod device;
 pub mod health;
 pub mod logout;
 pub mod mfa;
+pub mod oauth;
 pub mod otp;
+pub mod passkey;
 pub mod reauthenticate;
 pub mod recover;
 pub mod resend;
+pub mod session;
 pub mod settings;
 pub mod signup;
 pub mod sso;
 pub mod token;
+pub mod trusted_device;
 pub mod user;
 pub mod verify;
+pub mod well_known;
 
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
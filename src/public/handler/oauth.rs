@@ -0,0 +1,1032 @@
+use axum::Json;
+use axum::extract::{
+  ConnectInfo,
+  Form,
+  Path,
+  Query,
+  State,
+};
+use axum::http::header::{
+  AUTHORIZATION,
+  CACHE_CONTROL,
+  PRAGMA,
+};
+use axum::http::{
+  HeaderMap,
+  StatusCode,
+};
+use axum::response::{
+  IntoResponse,
+  Redirect,
+  Response,
+};
+use chrono::{
+  Duration,
+  Utc,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use std::net::{
+  IpAddr,
+  SocketAddr,
+};
+use uuid::Uuid;
+
+use crate::auth::{
+  audit,
+  jwt,
+  oauth,
+  oidc,
+  session,
+};
+use crate::defaults::AUTHORIZATION_CODE_LIFETIME;
+use crate::error::{
+  AuthError,
+  Result,
+};
+use crate::middleware::auth::{
+  AuthUser,
+  ScopedUser,
+};
+use crate::model::{
+  IntrospectionResponse,
+  OAuthTokenResponse,
+  RefreshToken,
+  User,
+};
+use crate::public::handler::{
+  device,
+  token,
+};
+use crate::state::AppState;
+use crate::utils::sha256_hex;
+
+const OAUTH_AUTHORIZATION_PROVIDER_TYPE: &str = "oauth_authorization";
+/// How long the user has to sign in and approve a pending authorization request.
+const OAUTH_AUTHORIZATION_TTL_MINUTES: i64 = 10;
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizeQuery {
+  pub response_type: Option<String>,
+  pub client_id: Option<String>,
+  pub redirect_uri: Option<String>,
+  pub scope: Option<String>,
+  pub state: Option<String>,
+  pub nonce: Option<String>,
+  pub code_challenge: Option<String>,
+  pub code_challenge_method: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizationDecision {
+  pub approve: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthorizationDetails {
+  pub authorization_id: Uuid,
+  pub client_id: String,
+  pub client_name: String,
+  pub scope: String,
+  pub redirect_uri: String,
+  pub expires_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OAuthTokenForm {
+  pub grant_type: String,
+  pub code: Option<String>,
+  pub redirect_uri: Option<String>,
+  pub code_verifier: Option<String>,
+  pub refresh_token: Option<String>,
+  pub scope: Option<String>,
+  pub client_id: Option<String>,
+  pub client_secret: Option<String>,
+  pub device_code: Option<String>,
+}
+
+/// Body of `/oauth/introspect` and `/oauth/revoke`.
+#[derive(Debug, Deserialize)]
+pub struct TokenManagementForm {
+  pub token: String,
+  pub client_id: Option<String>,
+  pub client_secret: Option<String>,
+  /// `/oauth/revoke` only: also end the session and every refresh token issued in it
+  #[serde(default)]
+  pub revoke_family: bool,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct PendingAuthorizationRow {
+  id: Uuid,
+  oauth_client_id: Option<String>,
+  scope: Option<String>,
+  redirect_to: Option<String>,
+  expires_at: Option<chrono::DateTime<Utc>>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DecidedAuthorizationRow {
+  redirect_to: Option<String>,
+  client_state: Option<String>,
+  oauth_client_id: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct AuthorizationCodeRow {
+  user_id: Option<Uuid>,
+  session_id: Option<Uuid>,
+  oauth_client_id: Option<String>,
+  scope: Option<String>,
+  nonce: Option<String>,
+  redirect_to: Option<String>,
+  redirect_uri_provided: bool,
+  code_challenge: String,
+  expires_at: Option<chrono::DateTime<Utc>>,
+}
+
+/// Starts an authorization-code flow for a registered client. Errors about the
+/// client or redirect URI are shown directly; anything else is reported back to
+/// the client's redirect URI as RFC 6749 section 4.1.2.1 requires.
+pub async fn authorize(
+  State(state): State<AppState>,
+  Query(query): Query<AuthorizeQuery>,
+) -> Result<Response> {
+  let client_id = query
+    .client_id
+    .as_deref()
+    .ok_or_else(|| AuthError::oauth("invalid_request", "client_id is required"))?;
+  let client = oauth::find_client(&state.db, client_id)
+    .await?
+    .filter(|client| !client.is_disabled())
+    .ok_or_else(|| AuthError::oauth("invalid_client", "unknown client_id"))?;
+  let redirect_uri = client
+    .resolve_redirect_uri(query.redirect_uri.as_deref())
+    .ok_or_else(|| {
+      AuthError::oauth(
+        "invalid_request",
+        "redirect_uri is not registered for this client",
+      )
+    })?;
+
+  let validated = match validate_authorization_request(&client, &query) {
+    Ok(scope) => oauth::ensure_openid_supported(&*state.jwt_keys.read().await, &scope).map(|()| scope),
+    Err(error) => Err(error),
+  };
+  let scope = match validated {
+    Ok(scope) => scope,
+    Err(AuthError::OAuth { error, description }) => {
+      let url = error_redirect_url(&redirect_uri, error, &description, query.state.as_deref())?;
+      return Ok(Redirect::to(&url).into_response());
+    },
+    Err(error) => return Err(error),
+  };
+
+  let authorization_id = Uuid::new_v4();
+  let now = Utc::now();
+  let expires_at = now + Duration::minutes(OAUTH_AUTHORIZATION_TTL_MINUTES);
+  sqlx::query(
+    "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, authentication_method, created_at, updated_at, nonce, redirect_to, expires_at, oauth_client_id, scope, client_state, redirect_uri_provided) VALUES ($1, NULL, $2, $3::auth.code_challenge_method, $4, $5, 'oauth', $6, $7, $8, $9, $10, $11, $12, $13, $14)",
+  )
+  .bind(authorization_id)
+  // Placeholder until the request is approved; the real code is only ever stored hashed.
+  .bind(sha256_hex(&session::generate_refresh_token()))
+  .bind(if query.code_challenge.is_some() {
+    "s256"
+  } else {
+    "plain"
+  })
+  .bind(query.code_challenge.as_deref().unwrap_or_default())
+  .bind(OAUTH_AUTHORIZATION_PROVIDER_TYPE)
+  .bind(now)
+  .bind(now)
+  .bind(query.nonce.as_deref())
+  .bind(&redirect_uri)
+  .bind(expires_at)
+  .bind(&client.client_id)
+  .bind(&scope)
+  .bind(query.state.as_deref())
+  .bind(query.redirect_uri.is_some())
+  .execute(&state.db)
+  .await?;
+
+  let mut consent_url = url::Url::parse(&state.oauth_consent_url)
+    .map_err(|e| AuthError::InternalError(format!("invalid OAUTH_CONSENT_URL: {e}")))?;
+  consent_url
+    .query_pairs_mut()
+    .append_pair("authorization_id", &authorization_id.to_string());
+  Ok(Redirect::to(consent_url.as_str()).into_response())
+}
+
+fn validate_authorization_request(client: &oauth::OAuthClient, query: &AuthorizeQuery) -> Result<String> {
+  if query.response_type.as_deref() != Some("code") {
+    return Err(AuthError::oauth(
+      "unsupported_response_type",
+      "only response_type=code is supported",
+    ));
+  }
+  if !client.allows_grant(oauth::GRANT_AUTHORIZATION_CODE) {
+    return Err(AuthError::oauth(
+      "unauthorized_client",
+      "client is not allowed to use the authorization code grant",
+    ));
+  }
+  let scope = client.resolve_scope(query.scope.as_deref())?;
+
+  match query.code_challenge.as_deref() {
+    Some(_) if query.code_challenge_method.as_deref() != Some("S256") => Err(AuthError::oauth(
+      "invalid_request",
+      "code_challenge_method must be S256",
+    )),
+    None if client.is_public() => Err(AuthError::oauth(
+      "invalid_request",
+      "public clients must send a PKCE code_challenge",
+    )),
+    _ => Ok(scope),
+  }
+}
+
+/// Describes a pending authorization request so the consent page can show
+/// which application is asking for access.
+pub async fn get_authorization(
+  State(state): State<AppState>,
+  AuthUser { .. }: AuthUser,
+  Path(authorization_id): Path<Uuid>,
+) -> Result<Json<AuthorizationDetails>> {
+  let row = sqlx::query_as::<_, PendingAuthorizationRow>(
+    "SELECT id, oauth_client_id, scope, redirect_to, expires_at FROM auth.flow_state WHERE id = $1 AND provider_type = $2 AND user_id IS NULL AND expires_at > now()",
+  )
+  .bind(authorization_id)
+  .bind(OAUTH_AUTHORIZATION_PROVIDER_TYPE)
+  .fetch_optional(&state.db)
+  .await?
+  .ok_or(AuthError::InvalidToken)?;
+  let client_id = row
+    .oauth_client_id
+    .ok_or_else(|| AuthError::InternalError("authorization request is missing its client".to_string()))?;
+  let client = oauth::find_client(&state.db, &client_id)
+    .await?
+    .ok_or(AuthError::InvalidToken)?;
+
+  Ok(Json(AuthorizationDetails {
+    authorization_id: row.id,
+    client_id: client.client_id,
+    client_name: client.name,
+    scope: row.scope.unwrap_or_default(),
+    redirect_uri: row.redirect_to.unwrap_or_default(),
+    expires_at: row.expires_at.unwrap_or_else(Utc::now),
+  }))
+}
+
+/// Approves or denies a pending authorization request on behalf of the signed-in
+/// user and returns the client redirect the consent page should follow.
+pub async fn decide_authorization(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  AuthUser { claims, user }: AuthUser,
+  Path(authorization_id): Path<Uuid>,
+  Json(decision): Json<AuthorizationDecision>,
+) -> Result<Json<serde_json::Value>> {
+  session::forbid_impersonation(&claims)?;
+  let session_id: Uuid = claims
+    .session_id
+    .parse()
+    .map_err(|_| AuthError::InternalError("Invalid session_id".to_string()))?;
+  let now = Utc::now();
+
+  let (row, code) = if decision.approve {
+    let code = session::generate_refresh_token();
+    let row = sqlx::query_as::<_, DecidedAuthorizationRow>(
+      "UPDATE auth.flow_state SET user_id = $1, session_id = $2, auth_code = $3, expires_at = $4, updated_at = $5 WHERE id = $6 AND provider_type = $7 AND user_id IS NULL AND expires_at > $5 RETURNING redirect_to, client_state, oauth_client_id",
+    )
+    .bind(user.id)
+    .bind(session_id)
+    .bind(sha256_hex(&code))
+    .bind(now + Duration::seconds(AUTHORIZATION_CODE_LIFETIME))
+    .bind(now)
+    .bind(authorization_id)
+    .bind(OAUTH_AUTHORIZATION_PROVIDER_TYPE)
+    .fetch_optional(&state.db)
+    .await?;
+    (row, Some(code))
+  } else {
+    let row = sqlx::query_as::<_, DecidedAuthorizationRow>(
+      "DELETE FROM auth.flow_state WHERE id = $1 AND provider_type = $2 AND user_id IS NULL RETURNING redirect_to, client_state, oauth_client_id",
+    )
+    .bind(authorization_id)
+    .bind(OAUTH_AUTHORIZATION_PROVIDER_TYPE)
+    .fetch_optional(&state.db)
+    .await?;
+    (row, None)
+  };
+  let row = row.ok_or(AuthError::InvalidToken)?;
+  let redirect_uri = row.redirect_to.ok_or_else(|| {
+    AuthError::InternalError("authorization request is missing its redirect_uri".to_string())
+  })?;
+
+  let redirect_to = match code {
+    Some(ref code) => {
+      let mut url = url::Url::parse(&redirect_uri)
+        .map_err(|e| AuthError::InternalError(format!("invalid redirect_uri: {e}")))?;
+      {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("code", code);
+        if let Some(client_state) = row.client_state.as_deref() {
+          query.append_pair("state", client_state);
+        }
+      }
+      url.to_string()
+    },
+    None => error_redirect_url(
+      &redirect_uri,
+      "access_denied",
+      "the user denied the request",
+      row.client_state.as_deref(),
+    )?,
+  };
+
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_addr.ip()),
+    if code.is_some() {
+      "oauth_authorization_approved"
+    } else {
+      "oauth_authorization_denied"
+    },
+    serde_json::json!({
+      "user_id": user.id,
+      "client_id": row.oauth_client_id,
+    }),
+  )
+  .await?;
+
+  Ok(Json(serde_json::json!({ "redirect_to": redirect_to })))
+}
+
+fn error_redirect_url(
+  redirect_uri: &str,
+  error: &str,
+  description: &str,
+  state: Option<&str>,
+) -> Result<String> {
+  let mut url = url::Url::parse(redirect_uri)
+    .map_err(|e| AuthError::InternalError(format!("invalid redirect_uri: {e}")))?;
+  {
+    let mut query = url.query_pairs_mut();
+    query.append_pair("error", error);
+    query.append_pair("error_description", description);
+    if let Some(state) = state {
+      query.append_pair("state", state);
+    }
+  }
+  Ok(url.to_string())
+}
+
+pub async fn token(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  headers: HeaderMap,
+  Form(form): Form<OAuthTokenForm>,
+) -> Result<impl IntoResponse> {
+  let client = authenticate_request(
+    &state,
+    &headers,
+    form.client_id.as_deref(),
+    form.client_secret.as_deref(),
+  )
+  .await?;
+  if !oauth::GRANT_TYPES.contains(&form.grant_type.as_str()) {
+    return Err(AuthError::oauth(
+      "unsupported_grant_type",
+      format!("unsupported grant_type: {}", form.grant_type),
+    ));
+  }
+  if !client.allows_grant(&form.grant_type) {
+    return Err(AuthError::oauth(
+      "unauthorized_client",
+      format!("client is not allowed to use grant_type {}", form.grant_type),
+    ));
+  }
+
+  let client_ip = client_addr.ip();
+  let user_agent = headers
+    .get(axum::http::header::USER_AGENT)
+    .and_then(|value| value.to_str().ok())
+    .map(str::to_owned);
+  let response = match form.grant_type.as_str() {
+    oauth::GRANT_AUTHORIZATION_CODE => {
+      exchange_authorization_code(&state, &client, client_ip, user_agent, &form).await?
+    },
+    oauth::GRANT_CLIENT_CREDENTIALS => {
+      token::client_credentials_grant(&state, client_ip, &client, form.scope.as_deref()).await?
+    },
+    oauth::GRANT_DEVICE_CODE => {
+      device::device_code_grant(
+        &state,
+        &client,
+        form.device_code.as_deref(),
+        session::ClientContext {
+          user_agent,
+          ip: Some(client_ip),
+        },
+      )
+      .await?
+    },
+    _ => refresh(&state, &client, client_ip, user_agent, &form).await?,
+  };
+
+  Ok((
+    [(CACHE_CONTROL, "no-store"), (PRAGMA, "no-cache")],
+    Json(response),
+  ))
+}
+
+async fn exchange_authorization_code(
+  state: &AppState,
+  client: &oauth::OAuthClient,
+  client_ip: IpAddr,
+  user_agent: Option<String>,
+  form: &OAuthTokenForm,
+) -> Result<OAuthTokenResponse> {
+  let code = form
+    .code
+    .as_deref()
+    .ok_or_else(|| AuthError::oauth("invalid_request", "code is required"))?;
+  let row = sqlx::query_as::<_, AuthorizationCodeRow>(
+    "DELETE FROM auth.flow_state WHERE auth_code = $1 AND provider_type = $2 AND user_id IS NOT NULL RETURNING user_id, session_id, oauth_client_id, scope, nonce, redirect_to, redirect_uri_provided, code_challenge, expires_at",
+  )
+  .bind(sha256_hex(code))
+  .bind(OAUTH_AUTHORIZATION_PROVIDER_TYPE)
+  .fetch_optional(&state.db)
+  .await?
+  .ok_or_else(|| invalid_grant("authorization code is invalid"))?;
+
+  if row.oauth_client_id.as_deref() != Some(client.client_id.as_str()) {
+    return Err(invalid_grant("authorization code was issued to another client"));
+  }
+  if row.expires_at.map(|value| value < Utc::now()).unwrap_or(true) {
+    return Err(invalid_grant("authorization code has expired"));
+  }
+  check_token_redirect_uri(
+    form.redirect_uri.as_deref(),
+    row.redirect_to.as_deref(),
+    row.redirect_uri_provided,
+  )?;
+  if !row.code_challenge.is_empty() {
+    let verifier = form
+      .code_verifier
+      .as_deref()
+      .ok_or_else(|| invalid_grant("code_verifier is required"))?;
+    if oidc::pkce_challenge(verifier) != row.code_challenge {
+      return Err(invalid_grant("code_verifier does not match the code_challenge"));
+    }
+  }
+
+  let user_id = row
+    .user_id
+    .ok_or_else(|| invalid_grant("authorization code is invalid"))?;
+  let approving_session_id = row
+    .session_id
+    .ok_or_else(|| invalid_grant("authorization code is invalid"))?;
+  issue_approved_grant(
+    state,
+    client,
+    ApprovedRequest {
+      user_id,
+      session_id: approving_session_id,
+      scope: row.scope.unwrap_or_default(),
+      nonce: row.nonce,
+      grant_type: oauth::GRANT_AUTHORIZATION_CODE,
+    },
+    session::ClientContext {
+      user_agent,
+      ip: Some(client_ip),
+    },
+  )
+  .await
+}
+
+/// A request a signed-in user approved for a client, ready to turn into tokens.
+pub(crate) struct ApprovedRequest {
+  pub user_id: Uuid,
+  /// Session of the user who approved the request
+  pub session_id: Uuid,
+  pub scope: String,
+  pub nonce: Option<String>,
+  pub grant_type: &'static str,
+}
+
+/// Issues the client's tokens for an approved request. The client session
+/// inherits the assurance level of the session that approved it.
+pub(crate) async fn issue_approved_grant(
+  state: &AppState,
+  client: &oauth::OAuthClient,
+  request: ApprovedRequest,
+  context: session::ClientContext,
+) -> Result<OAuthTokenResponse> {
+  let user = sqlx::query_as::<_, User>(
+    "SELECT id, instance_id, aud, role, email, encrypted_password, email_confirmed_at, phone, phone_confirmed_at, confirmed_at, last_sign_in_at, raw_app_meta_data, raw_user_meta_data, is_super_admin, is_sso_user, is_anonymous, banned_until, deleted_at, created_at, updated_at FROM auth.users WHERE id = $1",
+  )
+  .bind(request.user_id)
+  .fetch_optional(&state.db)
+  .await?
+  .ok_or_else(|| invalid_grant("user no longer exists"))?;
+  session::ensure_user_is_active(&user).map_err(|_| invalid_grant("user is not allowed to sign in"))?;
+
+  let (approving_session, amr) = session::load_session_context(state, request.session_id)
+    .await
+    .map_err(|_| invalid_grant("the session that approved this request has ended"))?;
+  let aal = approving_session.aal.as_deref().unwrap_or("aal1");
+  let client_ip = context.ip;
+  let grant = session::OAuthGrant {
+    client_id: client.client_id.clone(),
+    scope: request.scope,
+  };
+  oauth::ensure_openid_supported(&*state.jwt_keys.read().await, &grant.scope)?;
+  let tokens = session::issue_oauth_session(
+    state,
+    &user,
+    aal,
+    approving_session.factor_id,
+    amr.iter().map(|entry| entry.method.clone()).collect(),
+    context,
+    &grant,
+  )
+  .await?;
+
+  let id_token = if oauth::scope_contains(&grant.scope, oauth::SCOPE_OPENID) {
+    let signing_key = state.jwt_keys.read().await.signing_key().clone();
+    Some(oauth::encode_id_token(oauth::IdTokenParams {
+      issuer: &state.issuer,
+      client_id: &client.client_id,
+      user: &user,
+      scope: &grant.scope,
+      nonce: request.nonce.as_deref(),
+      auth_time: approving_session.created_at.unwrap_or_else(Utc::now).timestamp(),
+      aal,
+      amr: &amr,
+      signing_key: &signing_key,
+      jwt_exp: state.jwt_exp,
+    })?)
+  } else {
+    None
+  };
+
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    client_ip,
+    "oauth_token_issued",
+    serde_json::json!({
+      "user_id": user.id,
+      "client_id": client.client_id,
+      "scope": grant.scope,
+      "grant_type": request.grant_type,
+    }),
+  )
+  .await?;
+
+  Ok(OAuthTokenResponse {
+    access_token: tokens.access_token,
+    token_type: "Bearer".to_string(),
+    expires_in: tokens.expires_in,
+    refresh_token: Some(tokens.refresh_token),
+    id_token,
+    scope: Some(grant.scope),
+  })
+}
+
+async fn refresh(
+  state: &AppState,
+  client: &oauth::OAuthClient,
+  client_ip: IpAddr,
+  user_agent: Option<String>,
+  form: &OAuthTokenForm,
+) -> Result<OAuthTokenResponse> {
+  let refresh_token = form
+    .refresh_token
+    .as_deref()
+    .ok_or_else(|| AuthError::oauth("invalid_request", "refresh_token is required"))?;
+  let tokens = token::refresh_session(
+    state,
+    client_ip,
+    user_agent,
+    refresh_token,
+    Some(&client.client_id),
+  )
+  .await
+  .map_err(|error| match error {
+    AuthError::DatabaseError(_) | AuthError::InternalError(_) => error,
+    _ => invalid_grant("refresh token is invalid"),
+  })?;
+
+  Ok(OAuthTokenResponse {
+    access_token: tokens.access_token,
+    token_type: "Bearer".to_string(),
+    expires_in: tokens.expires_in,
+    refresh_token: Some(tokens.refresh_token),
+    id_token: None,
+    scope: None,
+  })
+}
+
+/// RFC 6749 section 4.1.3: the token request repeats `redirect_uri` exactly
+/// when the authorization request sent one, and may repeat it otherwise.
+fn check_token_redirect_uri(given: Option<&str>, authorized: Option<&str>, required: bool) -> Result<()> {
+  match given {
+    None if required => Err(invalid_grant(
+      "redirect_uri is required because the authorization request included one",
+    )),
+    Some(uri) if Some(uri) != authorized => Err(invalid_grant(
+      "redirect_uri does not match the authorization request",
+    )),
+    _ => Ok(()),
+  }
+}
+
+fn invalid_grant(description: &str) -> AuthError {
+  AuthError::oauth("invalid_grant", description)
+}
+
+pub(crate) async fn authenticate_request(
+  state: &AppState,
+  headers: &HeaderMap,
+  client_id: Option<&str>,
+  client_secret: Option<&str>,
+) -> Result<oauth::OAuthClient> {
+  let credentials = oauth::client_credentials(
+    headers.get(AUTHORIZATION).and_then(|value| value.to_str().ok()),
+    client_id,
+    client_secret,
+  )?;
+  oauth::authenticate_client(&state.db, &credentials).await
+}
+
+/// Access tokens are JWTs and always contain dots; refresh tokens never do, so
+/// `token_type_hint` is not needed to tell them apart.
+fn is_access_token(token: &str) -> bool {
+  token.contains('.')
+}
+
+/// RFC 7662 token introspection for API gateways and other resource servers.
+/// Tokens are only reported active after their session has been checked too.
+pub async fn introspect(
+  State(state): State<AppState>,
+  headers: HeaderMap,
+  Form(form): Form<TokenManagementForm>,
+) -> Result<impl IntoResponse> {
+  let client = authenticate_request(
+    &state,
+    &headers,
+    form.client_id.as_deref(),
+    form.client_secret.as_deref(),
+  )
+  .await?;
+  if client.is_public() {
+    return Err(AuthError::oauth(
+      "invalid_client",
+      "public clients cannot introspect tokens",
+    ));
+  }
+
+  let response = if is_access_token(&form.token) {
+    introspect_access_token(&state, &form.token).await?
+  } else {
+    introspect_refresh_token(&state, &form.token).await?
+  };
+  // Client-credentials tokens have no session. Like first-party tokens, any
+  // confidential client may check them.
+  let response = response
+    .filter(|response| client.may_access_token(response.session_id.and(response.client_id.as_deref())))
+    .unwrap_or_default();
+
+  Ok((
+    [(CACHE_CONTROL, "no-store"), (PRAGMA, "no-cache")],
+    Json(response),
+  ))
+}
+
+async fn introspect_access_token(state: &AppState, token: &str) -> Result<Option<IntrospectionResponse>> {
+  let keys = state.jwt_keys.read().await.clone();
+  let Ok(token_data) = jwt::decode_token(token, &keys, &state.issuer) else {
+    return match jwt::decode_claims::<jwt::ClientClaims>(token, &keys, &state.issuer) {
+      Ok(token_data) => introspect_client_token(state, token_data.claims).await,
+      Err(_) => Ok(None),
+    };
+  };
+  let claims = token_data.claims;
+  let Some(session) = active_or_none(session::ensure_active_session(state, &claims).await)? else {
+    return Ok(None);
+  };
+  if active_or_none(session::load_current_user(state, &claims).await)?.is_none() {
+    return Ok(None);
+  }
+
+  Ok(Some(IntrospectionResponse {
+    active: true,
+    token_type: Some("Bearer".to_string()),
+    client_id: claims.client_id,
+    scope: claims.scope,
+    sub: Some(claims.sub),
+    aud: Some(claims.aud),
+    iss: Some(claims.iss),
+    exp: Some(claims.exp),
+    iat: Some(claims.iat),
+    session_id: Some(session.id),
+    aal: Some(claims.aal),
+  }))
+}
+
+async fn introspect_client_token(
+  state: &AppState,
+  claims: jwt::ClientClaims,
+) -> Result<Option<IntrospectionResponse>> {
+  if claims.sub != claims.client_id {
+    return Ok(None);
+  }
+  let client_active = oauth::find_client(&state.db, &claims.client_id)
+    .await?
+    .is_some_and(|client| !client.is_disabled() && client.allows_grant(oauth::GRANT_CLIENT_CREDENTIALS));
+  if !client_active {
+    return Ok(None);
+  }
+
+  Ok(Some(IntrospectionResponse {
+    active: true,
+    token_type: Some("Bearer".to_string()),
+    client_id: Some(claims.client_id),
+    scope: claims.scope,
+    sub: Some(claims.sub),
+    aud: Some(claims.aud),
+    iss: Some(claims.iss),
+    exp: Some(claims.exp),
+    iat: Some(claims.iat),
+    session_id: None,
+    aal: None,
+  }))
+}
+
+async fn introspect_refresh_token(state: &AppState, token: &str) -> Result<Option<IntrospectionResponse>> {
+  let Some(refresh_token) = sqlx::query_as::<_, RefreshToken>(
+    "SELECT id, instance_id, user_id, token, created_at, updated_at, parent, session_id, revoked FROM auth.refresh_tokens WHERE token = $1",
+  )
+  .bind(sha256_hex(token))
+  .fetch_optional(&state.db)
+  .await?
+  else {
+    return Ok(None);
+  };
+  let Some(created_at) = refresh_token.created_at else {
+    return Ok(None);
+  };
+  let mut expires_at = created_at + Duration::seconds(state.refresh_token_exp);
+  if refresh_token.revoked.unwrap_or(false) || expires_at <= Utc::now() {
+    return Ok(None);
+  }
+  let Some(session_id) = refresh_token.session_id else {
+    return Ok(None);
+  };
+  let Some((session, _)) = active_or_none(session::load_session_context(state, session_id).await)? else {
+    return Ok(None);
+  };
+  if refresh_token.user_id.as_deref() != Some(session.user_id.to_string().as_str())
+    || active_or_none(session::load_active_user(state, session.user_id).await)?.is_none()
+  {
+    return Ok(None);
+  }
+  if let Some(not_after) = session.not_after {
+    expires_at = expires_at.min(not_after);
+  }
+
+  Ok(Some(IntrospectionResponse {
+    active: true,
+    token_type: None,
+    client_id: session.oauth_client_id,
+    scope: session.scope,
+    sub: Some(session.user_id.to_string()),
+    aud: Some(jwt::JWT_AUDIENCE.to_string()),
+    iss: Some(state.issuer.clone()),
+    exp: Some(expires_at.timestamp()),
+    iat: Some(created_at.timestamp()),
+    session_id: Some(session.id),
+    aal: session.aal,
+  }))
+}
+
+/// Turns "this token is not valid" failures into `None` while still surfacing
+/// database and internal errors.
+fn active_or_none<T>(result: Result<T>) -> Result<Option<T>> {
+  match result {
+    Ok(value) => Ok(Some(value)),
+    Err(AuthError::DatabaseError(sqlx::Error::RowNotFound)) => Ok(None),
+    Err(error @ (AuthError::DatabaseError(_) | AuthError::InternalError(_))) => Err(error),
+    Err(_) => Ok(None),
+  }
+}
+
+/// RFC 7009 revocation of a refresh token. With `revoke_family=true` the whole
+/// session ends, which also invalidates its access tokens.
+pub async fn revoke(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  headers: HeaderMap,
+  Form(form): Form<TokenManagementForm>,
+) -> Result<impl IntoResponse> {
+  let client = authenticate_request(
+    &state,
+    &headers,
+    form.client_id.as_deref(),
+    form.client_secret.as_deref(),
+  )
+  .await?;
+  if is_access_token(&form.token) {
+    return Err(AuthError::oauth(
+      "unsupported_token_type",
+      "only refresh tokens can be revoked; access tokens expire with their session",
+    ));
+  }
+
+  let now = Utc::now();
+  let mut tx = state.db.begin().await?;
+  // Unknown tokens are not an error (RFC 7009 section 2.2).
+  let Some(refresh_token) = sqlx::query_as::<_, RefreshToken>(
+    "SELECT id, instance_id, user_id, token, created_at, updated_at, parent, session_id, revoked FROM auth.refresh_tokens WHERE token = $1 FOR UPDATE",
+  )
+  .bind(sha256_hex(&form.token))
+  .fetch_optional(&mut *tx)
+  .await?
+  else {
+    return Ok(StatusCode::OK);
+  };
+  let Some(session_id) = refresh_token.session_id else {
+    return Ok(StatusCode::OK);
+  };
+  let Some(owner) =
+    sqlx::query_scalar::<_, Option<String>>("SELECT oauth_client_id FROM auth.sessions WHERE id = $1")
+      .bind(session_id)
+      .fetch_optional(&mut *tx)
+      .await?
+  else {
+    return Ok(StatusCode::OK);
+  };
+  if !client.may_access_token(owner.as_deref()) {
+    return Err(AuthError::oauth(
+      "unauthorized_client",
+      "token was not issued to this client",
+    ));
+  }
+
+  if form.revoke_family {
+    token::revoke_refresh_token_family(&mut tx, session_id, now).await?;
+  } else {
+    sqlx::query("UPDATE auth.refresh_tokens SET revoked = true, updated_at = $1 WHERE id = $2")
+      .bind(now)
+      .bind(refresh_token.id)
+      .execute(&mut *tx)
+      .await?;
+  }
+  tx.commit().await?;
+
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_addr.ip()),
+    "oauth_token_revoked",
+    serde_json::json!({
+      "user_id": refresh_token.user_id,
+      "client_id": client.client_id,
+      "session_id": session_id,
+      "revoke_family": form.revoke_family,
+    }),
+  )
+  .await?;
+
+  Ok(StatusCode::OK)
+}
+
+/// OpenID Connect userinfo. First-party Haya tokens carry no scope and see
+/// every supported claim.
+pub async fn userinfo(ScopedUser { claims, user }: ScopedUser) -> Json<serde_json::Value> {
+  let scope = claims.scope.unwrap_or_else(|| oauth::SUPPORTED_SCOPES.join(" "));
+  Json(serde_json::Value::Object(oauth::user_claims(&user, &scope)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample_client(method: &str) -> oauth::OAuthClient {
+    oauth::OAuthClient {
+      id: Uuid::nil(),
+      client_id: "grafana".to_string(),
+      client_secret_hash: None,
+      name: "Grafana".to_string(),
+      redirect_uris: vec!["https://grafana.example.com/login".to_string()],
+      grant_types: vec![oauth::GRANT_AUTHORIZATION_CODE.to_string()],
+      scopes: vec!["openid".to_string(), "email".to_string()],
+      token_endpoint_auth_method: method.to_string(),
+      role: None,
+      session_binding: None,
+      disabled_at: None,
+      created_at: None,
+      updated_at: None,
+    }
+  }
+
+  fn sample_query() -> AuthorizeQuery {
+    AuthorizeQuery {
+      response_type: Some("code".to_string()),
+      client_id: Some("grafana".to_string()),
+      redirect_uri: None,
+      scope: Some("openid".to_string()),
+      state: Some("xyz".to_string()),
+      nonce: None,
+      code_challenge: None,
+      code_challenge_method: None,
+    }
+  }
+
+  #[test]
+  fn public_clients_must_use_pkce() {
+    let client = sample_client(oauth::AUTH_METHOD_NONE);
+    let mut query = sample_query();
+
+    assert!(matches!(
+      validate_authorization_request(&client, &query),
+      Err(AuthError::OAuth {
+        error: "invalid_request",
+        ..
+      })
+    ));
+
+    query.code_challenge = Some("E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM".to_string());
+    query.code_challenge_method = Some("plain".to_string());
+    assert!(validate_authorization_request(&client, &query).is_err());
+
+    query.code_challenge_method = Some("S256".to_string());
+    assert_eq!(validate_authorization_request(&client, &query).unwrap(), "openid");
+  }
+
+  #[test]
+  fn rejects_unsupported_response_types() {
+    let client = sample_client(oauth::AUTH_METHOD_CLIENT_SECRET_BASIC);
+    let mut query = sample_query();
+    query.response_type = Some("token".to_string());
+
+    assert!(matches!(
+      validate_authorization_request(&client, &query),
+      Err(AuthError::OAuth {
+        error: "unsupported_response_type",
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn inactive_introspection_reveals_nothing_else() {
+    assert_eq!(
+      serde_json::to_value(IntrospectionResponse::default()).unwrap(),
+      serde_json::json!({ "active": false })
+    );
+    assert!(is_access_token("eyJhbGciOiJFUzI1NiJ9.eyJzdWIiOiIxIn0.c2ln"));
+    assert!(!is_access_token(&session::generate_refresh_token()));
+  }
+
+  #[test]
+  fn token_request_must_repeat_an_explicit_redirect_uri() {
+    let authorized = Some("https://app.example.com/callback");
+    assert!(check_token_redirect_uri(None, authorized, false).is_ok());
+    assert!(check_token_redirect_uri(authorized, authorized, true).is_ok());
+    for result in [
+      check_token_redirect_uri(None, authorized, true),
+      check_token_redirect_uri(Some("https://evil.example.com/callback"), authorized, false),
+    ] {
+      assert!(matches!(
+        result,
+        Err(AuthError::OAuth {
+          error: "invalid_grant",
+          ..
+        })
+      ));
+    }
+  }
+
+  #[test]
+  fn error_redirect_carries_state() {
+    let url = error_redirect_url(
+      "https://grafana.example.com/login?x=1",
+      "access_denied",
+      "denied",
+      Some("xyz"),
+    )
+    .unwrap();
+
+    assert_eq!(
+      url,
+      "https://grafana.example.com/login?x=1&error=access_denied&error_description=denied&state=xyz"
+    );
+  }
+}
@@ -1,6 +1,7 @@
 use axum::Json;
 use axum::extract::{
   ConnectInfo,
+  Query,
   State,
 };
 use chrono::Utc;
@@ -12,8 +13,8 @@ use std::net::{
 use uuid::Uuid;
 
 use crate::auth::{
+  pkce,
   rate_limit,
-  session,
 };
 use crate::error::{
   AuthError,
@@ -21,7 +22,11 @@ use crate::error::{
 };
 use crate::mailer::EmailKind;
 use crate::public::handler::recover::email_token_cooldown_active;
-use crate::public::handler::signup::is_valid_email;
+use crate::public::handler::signup::{
+  RedirectToQuery,
+  email_flow,
+  is_valid_email,
+};
 use crate::state::AppState;
 use crate::utils::sha256_hex;
 
@@ -37,16 +42,21 @@ pub struct OtpRequest {
   pub create_user: Option<bool>,
   #[allow(dead_code)]
   pub data: Option<serde_json::Value>,
+  pub code_challenge: Option<String>,
+  pub code_challenge_method: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MagicLinkRequest {
   pub email: String,
+  pub code_challenge: Option<String>,
+  pub code_challenge_method: Option<String>,
 }
 
 pub async fn send_otp(
   State(state): State<AppState>,
   ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  Query(query): Query<RedirectToQuery>,
   Json(req): Json<OtpRequest>,
 ) -> Result<Json<serde_json::Value>> {
   let rate_limit_key = otp_ip_rate_limit_key(client_addr.ip());
@@ -54,6 +64,12 @@ pub async fn send_otp(
     return Err(AuthError::TooManyRequests);
   }
   rate_limit::record_attempt(&state.db, &rate_limit_key, OTP_RATE_LIMIT_WINDOW_SECS).await?;
+  let email_flow = email_flow(
+    &state,
+    &query,
+    req.code_challenge.as_deref(),
+    req.code_challenge_method.as_deref(),
+  )?;
 
   if let Some(ref email) = req.email {
     if !is_valid_email(email) {
@@ -76,7 +92,7 @@ pub async fn send_otp(
       return Ok(Json(serde_json::json!({})));
     };
 
-    let token = session::generate_refresh_token();
+    let token = pkce::email_token(email_flow.is_some());
     let token_hash = sha256_hex(&token);
     let now = Utc::now();
     if email_token_cooldown_active(magic_link_sent_at, now) {
@@ -93,6 +109,11 @@ pub async fn send_otp(
     .bind(user_id)
     .execute(&state.db)
     .await?;
+    if let Some(email_flow) = email_flow {
+      email_flow
+        .start(&state.db, user_id, pkce::METHOD_MAGICLINK)
+        .await?;
+    }
 
     // Send magic-link / OTP email.
     let magic_link_url = format!("{}/verify?token={}&type=magiclink", state.site_url, token);
@@ -123,6 +144,7 @@ pub async fn send_otp(
 pub async fn magiclink(
   State(state): State<AppState>,
   ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  query: Query<RedirectToQuery>,
   Json(req): Json<MagicLinkRequest>,
 ) -> Result<Json<serde_json::Value>> {
   let otp_req = OtpRequest {
@@ -130,8 +152,10 @@ pub async fn magiclink(
     phone: None,
     create_user: Some(false),
     data: None,
+    code_challenge: req.code_challenge,
+    code_challenge_method: req.code_challenge_method,
   };
-  send_otp(State(state), ConnectInfo(client_addr), Json(otp_req)).await
+  send_otp(State(state), ConnectInfo(client_addr), query, Json(otp_req)).await
 }
 
 fn otp_ip_rate_limit_key(client_ip: IpAddr) -> String {
@@ -0,0 +1,295 @@
+use axum::Json;
+use axum::extract::{
+  ConnectInfo,
+  State,
+};
+use chrono::{
+  Duration,
+  Utc,
+};
+use std::net::{
+  IpAddr,
+  SocketAddr,
+};
+use uuid::Uuid;
+
+use crate::auth::webauthn::{
+  self,
+  AssertionCredential,
+  CredentialRequestOptions,
+  StoredCredential,
+};
+use crate::auth::{
+  audit,
+  rate_limit,
+  session,
+};
+use crate::error::{
+  AuthError,
+  Result,
+};
+use crate::model::{
+  TokenResponse,
+  User,
+};
+use crate::state::AppState;
+use crate::utils::sha256_hex;
+
+const PASSKEY_PROVIDER_TYPE: &str = "webauthn";
+const PASSKEY_CHALLENGE_RATE_LIMIT_ATTEMPTS: u32 = 30;
+const PASSKEY_RATE_LIMIT_WINDOW_SECS: i64 = 300;
+const PASSKEY_GRANT_RATE_LIMIT_ATTEMPTS: u32 = 15;
+
+#[derive(Debug, sqlx::FromRow)]
+struct PasskeyRow {
+  id: Uuid,
+  web_authn_credential: sqlx::types::Json<StoredCredential>,
+}
+
+/// Starts a passkey sign-in. The options leave `allowCredentials` empty, so
+/// the browser offers any passkey the user has for this site.
+pub async fn challenge(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+) -> Result<Json<CredentialRequestOptions>> {
+  let rate_limit_key = challenge_rate_limit_key(client_addr.ip());
+  if rate_limit::is_limited(&state.db, &rate_limit_key, PASSKEY_CHALLENGE_RATE_LIMIT_ATTEMPTS).await? {
+    return Err(AuthError::TooManyRequests);
+  }
+  rate_limit::record_attempt(&state.db, &rate_limit_key, PASSKEY_RATE_LIMIT_WINDOW_SECS).await?;
+
+  let challenge = webauthn::generate_challenge();
+  let now = Utc::now();
+  sqlx::query(
+    "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, provider_access_token, provider_refresh_token, authentication_method, created_at, updated_at, expires_at) VALUES ($1, NULL, $2, 'plain'::auth.code_challenge_method, '', $3, NULL, NULL, $4, $5, $6, $7)",
+  )
+  .bind(Uuid::new_v4())
+  .bind(sha256_hex(&challenge))
+  .bind(PASSKEY_PROVIDER_TYPE)
+  .bind(webauthn::METHOD)
+  .bind(now)
+  .bind(now)
+  .bind(now + Duration::milliseconds(webauthn::CEREMONY_TIMEOUT_MS as i64))
+  .execute(&state.db)
+  .await?;
+
+  Ok(Json(webauthn::passkey_request_options(
+    &state.webauthn,
+    &challenge,
+  )))
+}
+
+/// Signs a user in with a passkey alone (`grant_type=webauthn`). The user is
+/// found from the credential's user handle, and the assertion must answer an
+/// unused challenge from [`challenge`] with user verification.
+pub async fn sign_in(
+  state: &AppState,
+  client_ip: IpAddr,
+  user_agent: Option<String>,
+  credential: &AssertionCredential,
+) -> Result<TokenResponse> {
+  let rate_limit_key = grant_rate_limit_key(client_ip);
+  if rate_limit::is_limited(&state.db, &rate_limit_key, PASSKEY_GRANT_RATE_LIMIT_ATTEMPTS).await? {
+    return Err(AuthError::TooManyRequests);
+  }
+  let (user, factor_id) = match verify_passkey(state, credential).await {
+    Ok(verified) => verified,
+    Err(err) => {
+      rate_limit::record_failure(&state.db, &rate_limit_key, PASSKEY_RATE_LIMIT_WINDOW_SECS).await?;
+      audit::log_event(
+        &state.db,
+        state.instance_id,
+        Some(client_ip),
+        "passkey_login_failed",
+        serde_json::json!({
+          "credential_id": credential.id,
+          "reason": "invalid_assertion",
+        }),
+      )
+      .await?;
+      return Err(err);
+    },
+  };
+
+  if let Some(reason) = sign_in_rejection(&user, state.mailer_autoconfirm) {
+    audit::log_event(
+      &state.db,
+      state.instance_id,
+      Some(client_ip),
+      "passkey_login_failed",
+      serde_json::json!({
+        "user_id": user.id,
+        "email": user.email,
+        "reason": reason,
+      }),
+    )
+    .await?;
+    if reason != "email_not_confirmed" {
+      rate_limit::record_failure(&state.db, &rate_limit_key, PASSKEY_RATE_LIMIT_WINDOW_SECS).await?;
+    }
+    return Err(AuthError::InvalidCredentials);
+  }
+
+  // A passkey used with user verification is something the user has and
+  // something they know or are, so the session starts at aal2.
+  let response = session::issue_session_with_client_context(
+    state,
+    &user,
+    "aal2",
+    Some(factor_id),
+    vec![webauthn::METHOD.to_string()],
+    session::ClientContext {
+      user_agent,
+      ip: Some(client_ip),
+    },
+  )
+  .await?;
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_ip),
+    "passkey_login_succeeded",
+    serde_json::json!({
+      "user_id": user.id,
+      "email": user.email,
+      "factor_id": factor_id,
+    }),
+  )
+  .await?;
+
+  Ok(response)
+}
+
+/// Consumes the assertion's challenge and checks the assertion against the
+/// passkey named by its credential ID and user handle.
+async fn verify_passkey(state: &AppState, credential: &AssertionCredential) -> Result<(User, Uuid)> {
+  let challenge = webauthn::assertion_challenge(credential)?;
+  let user_id = webauthn::user_id_from_handle(credential)?;
+  let now = Utc::now();
+  let mut tx = state.db.begin().await?;
+
+  sqlx::query_scalar::<_, Uuid>(
+    "DELETE FROM auth.flow_state WHERE auth_code = $1 AND provider_type = $2 AND expires_at > $3 RETURNING id",
+  )
+  .bind(sha256_hex(&challenge))
+  .bind(PASSKEY_PROVIDER_TYPE)
+  .bind(now)
+  .fetch_optional(tx.as_mut())
+  .await?
+  .ok_or(AuthError::InvalidCredentials)?;
+
+  let passkey = sqlx::query_as::<_, PasskeyRow>(
+    "SELECT id, web_authn_credential FROM auth.mfa_factors WHERE user_id = $1 AND web_authn_credential ->> 'id' = $2 AND factor_type = 'webauthn'::auth.factor_type AND status = 'verified'::auth.factor_status FOR UPDATE",
+  )
+  .bind(user_id)
+  .bind(&credential.id)
+  .fetch_optional(tx.as_mut())
+  .await?
+  .ok_or(AuthError::InvalidCredentials)?;
+  let mut stored = passkey.web_authn_credential.0;
+  stored.sign_count = webauthn::verify_assertion(&state.webauthn, &challenge, &stored, credential, true)
+    .map_err(|_| AuthError::InvalidCredentials)?;
+
+  sqlx::query(
+    "UPDATE auth.mfa_factors SET web_authn_credential = $1, last_challenged_at = $2, updated_at = $3 WHERE id = $4",
+  )
+  .bind(sqlx::types::Json(&stored))
+  .bind(now)
+  .bind(now)
+  .bind(passkey.id)
+  .execute(tx.as_mut())
+  .await?;
+
+  let user = sqlx::query_as::<_, User>(
+    "SELECT id, instance_id, aud, role, email, encrypted_password, email_confirmed_at, phone, phone_confirmed_at, confirmed_at, last_sign_in_at, raw_app_meta_data, raw_user_meta_data, is_super_admin, is_sso_user, is_anonymous, banned_until, deleted_at, created_at, updated_at FROM auth.users WHERE id = $1",
+  )
+  .bind(user_id)
+  .fetch_optional(tx.as_mut())
+  .await?
+  .ok_or(AuthError::InvalidCredentials)?;
+  tx.commit().await?;
+
+  Ok((user, passkey.id))
+}
+
+fn challenge_rate_limit_key(client_ip: IpAddr) -> String {
+  format!("passkey-challenge-ip:{client_ip}")
+}
+
+fn grant_rate_limit_key(client_ip: IpAddr) -> String {
+  format!("passkey-grant-ip:{client_ip}")
+}
+
+/// Why a user holding a valid passkey may still not sign in, as recorded in
+/// the audit log.
+fn sign_in_rejection(user: &User, mailer_autoconfirm: bool) -> Option<&'static str> {
+  if user.deleted_at.is_some() {
+    Some("user_deleted")
+  } else if user.banned_until.map(|value| value > Utc::now()).unwrap_or(false) {
+    Some("user_banned")
+  } else if !mailer_autoconfirm && user.email.is_some() && user.email_confirmed_at.is_none() {
+    Some("email_not_confirmed")
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample_user() -> User {
+    User {
+      id: Uuid::nil(),
+      instance_id: None,
+      aud: None,
+      role: None,
+      email: Some("user@example.com".to_string()),
+      encrypted_password: None,
+      email_confirmed_at: Some(Utc::now()),
+      phone: None,
+      phone_confirmed_at: None,
+      confirmed_at: None,
+      last_sign_in_at: None,
+      raw_app_meta_data: None,
+      raw_user_meta_data: None,
+      is_super_admin: None,
+      is_sso_user: false,
+      is_anonymous: false,
+      banned_until: None,
+      deleted_at: None,
+      created_at: None,
+      updated_at: None,
+    }
+  }
+
+  #[test]
+  fn active_confirmed_user_may_sign_in() {
+    assert_eq!(sign_in_rejection(&sample_user(), false), None);
+  }
+
+  #[test]
+  fn banned_user_is_rejected() {
+    let mut user = sample_user();
+    user.banned_until = Some(Utc::now() + Duration::hours(1));
+    assert_eq!(sign_in_rejection(&user, false), Some("user_banned"));
+
+    user.banned_until = Some(Utc::now() - Duration::hours(1));
+    assert_eq!(sign_in_rejection(&user, false), None);
+  }
+
+  #[test]
+  fn deleted_user_is_rejected() {
+    let mut user = sample_user();
+    user.deleted_at = Some(Utc::now());
+    assert_eq!(sign_in_rejection(&user, true), Some("user_deleted"));
+  }
+
+  #[test]
+  fn unconfirmed_user_is_rejected_unless_mail_autoconfirms() {
+    let mut user = sample_user();
+    user.email_confirmed_at = None;
+    assert_eq!(sign_in_rejection(&user, false), Some("email_not_confirmed"));
+    assert_eq!(sign_in_rejection(&user, true), None);
+  }
+}
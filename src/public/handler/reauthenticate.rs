@@ -22,8 +22,9 @@ const REAUTHENTICATE_IP_RATE_LIMIT_WINDOW_SECS: i64 = 900;
 pub async fn reauthenticate(
   State(state): State<AppState>,
   ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
-  AuthUser { user, .. }: AuthUser,
+  AuthUser { claims, user }: AuthUser,
 ) -> Result<Json<serde_json::Value>> {
+  session::forbid_impersonation(&claims)?;
   let ip_limiter_key = format!("reauthenticate-ip:{}", client_addr.ip());
   let user_limiter_key = format!("reauthenticate-user:{}", user.id);
   if rate_limit::is_limited(&state.db, &ip_limiter_key, REAUTHENTICATE_IP_RATE_LIMIT_ATTEMPTS).await?
@@ -1,6 +1,7 @@
 use axum::Json;
 use axum::extract::{
   ConnectInfo,
+  Query,
   State,
 };
 use chrono::{
@@ -14,15 +15,19 @@ use std::net::{
 };
 
 use crate::auth::{
+  pkce,
   rate_limit,
-  session,
 };
 use crate::error::{
   AuthError,
   Result,
 };
 use crate::mailer::EmailKind;
-use crate::public::handler::signup::is_valid_email;
+use crate::public::handler::signup::{
+  RedirectToQuery,
+  email_flow,
+  is_valid_email,
+};
 use crate::state::AppState;
 use crate::utils::sha256_hex;
 
@@ -39,11 +44,14 @@ type RecoveryLookup = (
 #[derive(Debug, Deserialize)]
 pub struct RecoverRequest {
   pub email: String,
+  pub code_challenge: Option<String>,
+  pub code_challenge_method: Option<String>,
 }
 
 pub async fn recover(
   State(state): State<AppState>,
   ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  Query(query): Query<RedirectToQuery>,
   Json(req): Json<RecoverRequest>,
 ) -> Result<Json<serde_json::Value>> {
   let rate_limit_key = recover_ip_rate_limit_key(client_addr.ip());
@@ -51,6 +59,12 @@ pub async fn recover(
     return Err(AuthError::TooManyRequests);
   }
   rate_limit::record_attempt(&state.db, &rate_limit_key, RECOVER_RATE_LIMIT_WINDOW_SECS).await?;
+  let email_flow = email_flow(
+    &state,
+    &query,
+    req.code_challenge.as_deref(),
+    req.code_challenge_method.as_deref(),
+  )?;
 
   if !is_valid_email(&req.email) {
     // Return 200 to avoid leaking which emails are valid
@@ -77,7 +91,7 @@ pub async fn recover(
     return Ok(Json(serde_json::json!({})));
   }
 
-  let recovery_token = session::generate_refresh_token();
+  let recovery_token = pkce::email_token(email_flow.is_some());
   let recovery_token_hash = sha256_hex(&recovery_token);
 
   sqlx::query(
@@ -89,6 +103,11 @@ pub async fn recover(
   .bind(user_id)
   .execute(&state.db)
   .await?;
+  if let Some(email_flow) = email_flow {
+    email_flow
+      .start(&state.db, user_id, pkce::METHOD_RECOVERY)
+      .await?;
+  }
 
   let recovery_url = format!("{}/verify?token={}&type=recovery", state.site_url, recovery_token);
   if let Some(ref mailer) = state.mailer {
@@ -11,8 +11,8 @@ use std::net::{
 };
 
 use crate::auth::{
+  pkce,
   rate_limit,
-  session,
 };
 use crate::error::{
   AuthError,
@@ -80,7 +80,18 @@ pub async fn resend(
     let _ = sqlx::query("SELECT 1").execute(&state.db).await?;
     return Ok(Json(serde_json::json!({})));
   };
-  let token = session::generate_refresh_token();
+  // A resent link keeps the PKCE flow the original email started.
+  let flow_method = match req.resend_type.as_str() {
+    "signup" => pkce::METHOD_SIGNUP,
+    "recovery" => pkce::METHOD_RECOVERY,
+    _ => {
+      return Err(AuthError::ValidationFailed(format!(
+        "Unsupported resend type: {}",
+        req.resend_type
+      )));
+    },
+  };
+  let token = pkce::email_token(pkce::has_pending_email_flow(&state.db, user_id, flow_method).await?);
   let token_hash = sha256_hex(&token);
   let now = Utc::now();
 
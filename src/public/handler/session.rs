@@ -0,0 +1,139 @@
+use axum::Json;
+use axum::extract::{
+  ConnectInfo,
+  Path,
+  State,
+};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use chrono::{
+  DateTime,
+  NaiveDateTime,
+  Utc,
+};
+use serde::Serialize;
+use sqlx::FromRow;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::auth::binding::{
+  UserAgentInfo,
+  parse_user_agent,
+};
+use crate::auth::{
+  audit,
+  session,
+};
+use crate::error::{
+  AuthError,
+  Result,
+};
+use crate::middleware::auth::AuthUser;
+use crate::state::AppState;
+
+#[derive(Debug, FromRow)]
+struct UserSessionRow {
+  id: Uuid,
+  aal: Option<String>,
+  ip: Option<String>,
+  user_agent: Option<String>,
+  oauth_client_id: Option<String>,
+  not_after: Option<DateTime<Utc>>,
+  refreshed_at: Option<NaiveDateTime>,
+  created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserSessionResponse {
+  pub id: Uuid,
+  pub aal: Option<String>,
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  pub device: Option<UserAgentInfo>,
+  /// OAuth client the session was issued to, if any
+  pub client_id: Option<String>,
+  pub current: bool,
+  pub created_at: Option<DateTime<Utc>>,
+  pub refreshed_at: Option<DateTime<Utc>>,
+  pub not_after: Option<DateTime<Utc>>,
+}
+
+/// Lists the caller's active sessions, newest first, marking the one the
+/// request was made with.
+pub async fn list_sessions(
+  State(state): State<AppState>,
+  AuthUser { claims, user }: AuthUser,
+) -> Result<Json<Vec<UserSessionResponse>>> {
+  let current_session_id = claims.session_id.parse::<Uuid>().ok();
+  let rows = sqlx::query_as::<_, UserSessionRow>(
+    "SELECT id, aal::text as aal, host(ip) as ip, user_agent, oauth_client_id, not_after, refreshed_at, created_at FROM auth.sessions WHERE user_id = $1 AND (not_after IS NULL OR not_after > now()) AND COALESCE(refreshed_at AT TIME ZONE 'UTC', created_at) > now() - make_interval(secs => $2) ORDER BY created_at DESC NULLS LAST",
+  )
+  .bind(user.id)
+  .bind(state.session_idle_timeout_secs as f64)
+  .fetch_all(&state.db)
+  .await?;
+
+  Ok(Json(
+    rows
+      .into_iter()
+      .map(|row| UserSessionResponse {
+        id: row.id,
+        aal: row.aal,
+        ip: row.ip,
+        device: row.user_agent.as_deref().map(parse_user_agent),
+        user_agent: row.user_agent,
+        client_id: row.oauth_client_id,
+        current: Some(row.id) == current_session_id,
+        created_at: row.created_at,
+        refreshed_at: row.refreshed_at.map(|value| value.and_utc()),
+        not_after: row.not_after,
+      })
+      .collect(),
+  ))
+}
+
+/// Signs the caller out of one of their sessions and revokes its refresh
+/// tokens. Revoking the current session works like `/logout`.
+pub async fn revoke_session(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  AuthUser { claims, user }: AuthUser,
+  Path(session_id): Path<Uuid>,
+) -> Result<impl IntoResponse> {
+  if claims.session_id.parse::<Uuid>().ok() != Some(session_id) {
+    session::forbid_impersonation(&claims)?;
+  }
+  let now = Utc::now();
+  let mut tx = state.db.begin().await?;
+  sqlx::query(
+    "UPDATE auth.refresh_tokens SET revoked = true, updated_at = $1 WHERE session_id = $2 AND user_id = $3",
+  )
+  .bind(now)
+  .bind(session_id)
+  .bind(user.id.to_string())
+  .execute(&mut *tx)
+  .await?;
+  let removed = sqlx::query("DELETE FROM auth.sessions WHERE id = $1 AND user_id = $2")
+    .bind(session_id)
+    .bind(user.id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+  if removed == 0 {
+    return Err(AuthError::SessionNotFound);
+  }
+  audit::log_event_tx(
+    tx.as_mut(),
+    state.instance_id,
+    Some(client_addr.ip()),
+    "session_revoked",
+    serde_json::json!({
+      "user_id": user.id,
+      "session_id": session_id,
+    }),
+  )
+  .await?;
+  tx.commit().await?;
+
+  Ok(StatusCode::NO_CONTENT)
+}
@@ -15,6 +15,10 @@ pub async fn get_settings(
 ) -> Result<Json<Value>> {
   let mut external = Map::new();
   external.insert("email".to_string(), Value::Bool(true));
+  external.insert(
+    "anonymous_users".to_string(),
+    Value::Bool(state.anonymous_sign_ins),
+  );
   let providers = state.oidc_providers.read().await;
   for provider in crate::auth::oidc::provider_names(&providers) {
     external.insert(provider, Value::Bool(true));
@@ -22,9 +26,20 @@ pub async fn get_settings(
 
   Ok(Json(serde_json::json!({
     "mailer_autoconfirm": state.mailer_autoconfirm,
+    "password_min_length": state.password_policy.min_length,
+    "password_policy": {
+      "min_length": state.password_policy.min_length,
+      "max_length": state.password_policy.max_length,
+      "required_characters": state.password_policy.required_characters,
+      "banned_words": state.password_policy.banned_words,
+      "min_score": state.password_policy.min_score,
+      "breached_password_check": state.breached_passwords.is_some(),
+    },
     "external": Value::Object(external),
     "mfa": {
       "totp": true,
+      "webauthn": true,
+      "phone": state.sms.is_some(),
     },
   })))
 }
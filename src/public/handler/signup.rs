@@ -1,8 +1,10 @@
 use axum::Json;
 use axum::extract::{
   ConnectInfo,
+  Query,
   State,
 };
+use axum::http::HeaderMap;
 use chrono::Utc;
 use serde::Deserialize;
 use std::net::{
@@ -11,8 +13,11 @@ use std::net::{
 };
 use uuid::Uuid;
 
+use crate::auth::captcha::MetaSecurity;
 use crate::auth::{
+  audit,
   password,
+  pkce,
   rate_limit,
   session,
 };
@@ -23,11 +28,14 @@ use crate::error::{
 use crate::mailer::EmailKind;
 use crate::model::User;
 use crate::public::handler::admin::validate_password_policy;
+use crate::public::handler::sso;
 use crate::state::AppState;
 use crate::utils::sha256_hex;
 
 const SIGNUP_RATE_LIMIT_WINDOW_SECS: i64 = 900;
 const SIGNUP_RATE_LIMIT_ATTEMPTS: u32 = 10;
+const ANONYMOUS_SIGNUP_RATE_LIMIT_WINDOW_SECS: i64 = 3600;
+const ANONYMOUS_SIGNUP_RATE_LIMIT_ATTEMPTS: u32 = 30;
 
 #[derive(Debug, Deserialize)]
 pub struct SignupRequest {
@@ -35,13 +43,48 @@ pub struct SignupRequest {
   pub password: Option<String>,
   pub phone: Option<String>,
   pub data: Option<serde_json::Value>,
+  pub code_challenge: Option<String>,
+  pub code_challenge_method: Option<String>,
+  #[serde(default)]
+  pub gotrue_meta_security: MetaSecurity,
+}
+
+/// supabase-js sends the `emailRedirectTo` / `redirectTo` option as a query
+/// parameter on the endpoints that send email links.
+#[derive(Debug, Default, Deserialize)]
+pub struct RedirectToQuery {
+  pub redirect_to: Option<String>,
+}
+
+/// The PKCE flow to start with an email link, when the client sent a
+/// `code_challenge`. Its `redirect_to` is validated up front.
+pub(crate) fn email_flow(
+  state: &AppState,
+  query: &RedirectToQuery,
+  code_challenge: Option<&str>,
+  code_challenge_method: Option<&str>,
+) -> Result<Option<pkce::EmailFlow>> {
+  pkce::CodeChallenge::from_request(code_challenge, code_challenge_method)?
+    .map(|challenge| {
+      Ok(pkce::EmailFlow {
+        challenge,
+        redirect_to: sso::validated_redirect_to(state, query.redirect_to.as_deref())?,
+      })
+    })
+    .transpose()
 }
 
 pub async fn signup(
   State(state): State<AppState>,
   ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  Query(query): Query<RedirectToQuery>,
+  headers: HeaderMap,
   Json(req): Json<SignupRequest>,
 ) -> Result<Json<serde_json::Value>> {
+  if req.email.is_none() && req.phone.is_none() && req.password.is_none() {
+    return anonymous_signup(&state, client_addr.ip(), &headers, req).await;
+  }
+
   let rate_limit_key = signup_ip_rate_limit_key(client_addr.ip());
   if rate_limit::is_limited(&state.db, &rate_limit_key, SIGNUP_RATE_LIMIT_ATTEMPTS).await? {
     return Err(AuthError::TooManyRequests);
@@ -58,7 +101,7 @@ pub async fn signup(
     return Err(AuthError::ValidationFailed("Invalid email format".to_string()));
   }
   if let Some(ref password) = req.password {
-    validate_password_policy(password)?;
+    validate_password_policy(&state, password, req.email.as_deref())?;
   }
   if req.password.is_none() && state.mailer.is_none() {
     return Err(AuthError::ValidationFailed(
@@ -72,6 +115,12 @@ pub async fn signup(
       "Phone must be a valid E.164 number".to_string(),
     ));
   }
+  let email_flow = email_flow(
+    &state,
+    &query,
+    req.code_challenge.as_deref(),
+    req.code_challenge_method.as_deref(),
+  )?;
 
   if let Some(ref email) = req.email {
     let existing: Option<User> = sqlx::query_as::<_, User>(
@@ -99,7 +148,7 @@ pub async fn signup(
 
   // Generate a confirmation token when auto-confirm is disabled.
   let (confirmation_token, confirmation_sent_at) = if !state.mailer_autoconfirm {
-    (Some(session::generate_refresh_token()), Some(now))
+    (Some(pkce::email_token(email_flow.is_some())), Some(now))
   } else {
     (None, None)
   };
@@ -134,36 +183,123 @@ pub async fn signup(
 
   // Send confirmation email when auto-confirm is disabled.
   if let Some(ref token) = confirmation_token {
-    let email = req.email.as_deref().unwrap_or_default();
-    let confirmation_url = format!("{}/verify?token={}&type=signup", state.site_url, token);
-    if let Some(ref mailer) = state.mailer {
-      if let Err(e) = mailer
-        .send(
-          EmailKind::Confirmation,
-          email,
-          &[
-            ("site_name", state.site_name.as_str()),
-            ("confirmation_url", confirmation_url.as_str()),
-            ("email", email),
-          ],
-        )
-        .await
-      {
-        tracing::error!(error = %e, %email, "Failed to send confirmation email");
-      }
-    } else {
-      tracing::warn!(%email, "SMTP not configured; confirmation email not sent");
+    if let Some(ref email_flow) = email_flow {
+      email_flow.start(&state.db, user.id, pkce::METHOD_SIGNUP).await?;
     }
+    send_confirmation_email(&state, req.email.as_deref().unwrap_or_default(), token).await;
   }
 
-  let _ = user;
   Ok(Json(serde_json::json!({})))
 }
 
+/// A signup without credentials creates an anonymous user and signs it in.
+/// The user can become permanent later through `PUT /user` or an identity link.
+async fn anonymous_signup(
+  state: &AppState,
+  client_ip: IpAddr,
+  headers: &HeaderMap,
+  req: SignupRequest,
+) -> Result<Json<serde_json::Value>> {
+  if !state.anonymous_sign_ins {
+    return Err(AuthError::ValidationFailed(
+      "Anonymous sign-ins are disabled".to_string(),
+    ));
+  }
+  let rate_limit_key = anonymous_signup_ip_rate_limit_key(client_ip);
+  if rate_limit::is_limited(&state.db, &rate_limit_key, ANONYMOUS_SIGNUP_RATE_LIMIT_ATTEMPTS).await? {
+    return Err(AuthError::TooManyRequests);
+  }
+  rate_limit::record_attempt(
+    &state.db,
+    &rate_limit_key,
+    ANONYMOUS_SIGNUP_RATE_LIMIT_WINDOW_SECS,
+  )
+  .await?;
+  if let Some(captcha) = state.captcha.as_deref() {
+    captcha
+      .verify(
+        &state.http_client,
+        req.gotrue_meta_security.captcha_token.as_deref(),
+        client_ip,
+      )
+      .await?;
+  }
+
+  let now = Utc::now();
+  let user: User = sqlx::query_as::<_, User>(
+    "INSERT INTO auth.users (id, instance_id, aud, role, raw_app_meta_data, raw_user_meta_data, is_anonymous, created_at, updated_at) VALUES ($1, $2, $3, $4, $5, $6, true, $7, $8) RETURNING id, instance_id, aud, role, email, encrypted_password, email_confirmed_at, phone, phone_confirmed_at, confirmed_at, last_sign_in_at, raw_app_meta_data, raw_user_meta_data, is_super_admin, is_sso_user, is_anonymous, banned_until, deleted_at, created_at, updated_at",
+  )
+  .bind(Uuid::new_v4())
+  .bind(state.instance_id)
+  .bind("authenticated")
+  .bind("authenticated")
+  .bind(serde_json::json!({}))
+  .bind(req.data.unwrap_or(serde_json::json!({})))
+  .bind(now)
+  .bind(now)
+  .fetch_one(&state.db)
+  .await?;
+
+  let response = session::issue_session_for_client(
+    state,
+    &user,
+    "anonymous",
+    session::ClientContext {
+      user_agent: headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned),
+      ip: Some(client_ip),
+    },
+  )
+  .await?;
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_ip),
+    "anonymous_user_created",
+    serde_json::json!({
+      "user_id": user.id,
+    }),
+  )
+  .await?;
+
+  let response = serde_json::to_value(response)
+    .map_err(|e| AuthError::InternalError(format!("failed to serialize token response: {e}")))?;
+  Ok(Json(response))
+}
+
+/// Emails the link that confirms a new address.
+pub(crate) async fn send_confirmation_email(state: &AppState, email: &str, token: &str) {
+  let confirmation_url = format!("{}/verify?token={}&type=signup", state.site_url, token);
+  if let Some(ref mailer) = state.mailer {
+    if let Err(e) = mailer
+      .send(
+        EmailKind::Confirmation,
+        email,
+        &[
+          ("site_name", state.site_name.as_str()),
+          ("confirmation_url", confirmation_url.as_str()),
+          ("email", email),
+        ],
+      )
+      .await
+    {
+      tracing::error!(error = %e, %email, "Failed to send confirmation email");
+    }
+  } else {
+    tracing::warn!(%email, "SMTP not configured; confirmation email not sent");
+  }
+}
+
 fn signup_ip_rate_limit_key(client_ip: IpAddr) -> String {
   format!("signup-ip:{client_ip}")
 }
 
+fn anonymous_signup_ip_rate_limit_key(client_ip: IpAddr) -> String {
+  format!("anonymous-signup-ip:{client_ip}")
+}
+
 pub fn is_valid_email(email: &str) -> bool {
   // Basic but reasonable email validation: local@domain.tld
   let parts: Vec<&str> = email.splitn(2, '@').collect();
@@ -1,3 +1,4 @@
+use axum::Json;
 use axum::extract::{
   ConnectInfo,
   Form,
@@ -21,7 +22,6 @@ use chrono::{
   Duration,
   Utc,
 };
-use hmac::Mac;
 use serde::Deserialize;
 use std::net::{
   IpAddr,
@@ -31,8 +31,8 @@ use uuid::Uuid;
 
 use crate::auth::{
   audit,
-  mfa as crypto_mfa,
   oidc,
+  pkce,
   rate_limit,
   session,
 };
@@ -41,22 +41,40 @@ use crate::error::{
   AuthError,
   Result,
 };
+use crate::middleware::auth::AuthUser;
 use crate::model::{
   TokenGrantResponse,
   User,
 };
 use crate::public::handler::mfa;
 use crate::state::AppState;
+use crate::utils::{
+  constant_time_eq,
+  sha256_hex,
+};
 
+const IDENTITY_LINK_METHOD: &str = "identity_link";
 const OIDC_RESULT_TTL_MINUTES: i64 = 1;
 const OIDC_STATE_COOKIE_NAME: &str = "haya_oidc_state";
 const OIDC_CALLBACK_RATE_LIMIT_ATTEMPTS: u32 = 15;
 const OIDC_CALLBACK_RATE_LIMIT_WINDOW_SECS: i64 = 300;
+const ID_TOKEN_GRANT_RATE_LIMIT_ATTEMPTS: u32 = 15;
+const ID_TOKEN_GRANT_RATE_LIMIT_WINDOW_SECS: i64 = 300;
+const ID_TOKEN_METHOD: &str = "id_token";
 
 #[derive(Debug, Deserialize)]
 pub struct AuthorizeQuery {
   pub provider: String,
   pub redirect_to: Option<String>,
+  pub code_challenge: Option<String>,
+  pub code_challenge_method: Option<String>,
+  pub link_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LinkIdentityQuery {
+  pub provider: String,
+  pub redirect_to: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -75,6 +93,7 @@ pub struct CallbackForm {
 #[derive(Debug, sqlx::FromRow)]
 struct FlowStateRow {
   id: Uuid,
+  user_id: Option<Uuid>,
   provider_type: String,
   auth_code: String,
   provider_access_token: Option<String>,
@@ -83,6 +102,19 @@ struct FlowStateRow {
   nonce: Option<String>,
   redirect_to: Option<String>,
   expires_at: Option<chrono::DateTime<Utc>>,
+  code_challenge: String,
+  code_challenge_method: String,
+}
+
+impl FlowStateRow {
+  /// The PKCE challenge of the client that started the sign-in, if any. Haya's
+  /// own PKCE with the provider only needs `pkce_verifier`.
+  fn client_challenge(&self) -> Result<Option<pkce::CodeChallenge>> {
+    if self.code_challenge.is_empty() {
+      return Ok(None);
+    }
+    pkce::CodeChallenge::from_request(Some(&self.code_challenge), Some(&self.code_challenge_method))
+  }
 }
 
 #[derive(Debug, sqlx::FromRow)]
@@ -90,6 +122,12 @@ struct IdentityLookup {
   user_id: Uuid,
 }
 
+#[derive(Debug, sqlx::FromRow)]
+struct LinkFlowRow {
+  user_id: Option<Uuid>,
+  expires_at: Option<chrono::DateTime<Utc>>,
+}
+
 #[derive(Debug, sqlx::FromRow)]
 struct OidcResultRow {
   provider_access_token: Option<String>,
@@ -101,6 +139,10 @@ pub async fn authorize(
   Query(query): Query<AuthorizeQuery>,
 ) -> Result<Response> {
   let redirect_to = validated_redirect_to(&state, query.redirect_to.as_deref())?;
+  let client_challenge = pkce::CodeChallenge::from_request(
+    query.code_challenge.as_deref(),
+    query.code_challenge_method.as_deref(),
+  )?;
   let provider = state
     .oidc_providers
     .read()
@@ -109,22 +151,30 @@ pub async fn authorize(
     .cloned()
     .ok_or_else(|| AuthError::ValidationFailed(format!("Unsupported provider: {}", query.provider)))?;
   let discovery = oidc::discover_provider(&state.http_client, &provider).await?;
+  let link_user_id = match query.link_token.as_deref() {
+    Some(link_token) => Some(consume_link_token(&state, &provider.name, link_token).await?),
+    None => None,
+  };
   let flow = oidc::generate_flow_tokens(provider.pkce);
   let flow_id = Uuid::new_v4();
   let now = Utc::now();
   let expires_at = now + Duration::seconds(AUTHORIZATION_CODE_LIFETIME);
 
   sqlx::query(
-    "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, provider_access_token, provider_refresh_token, authentication_method, created_at, updated_at, nonce, redirect_to, pkce_verifier, expires_at) VALUES ($1, NULL, $2, $3::auth.code_challenge_method, $4, $5, NULL, NULL, $6, $7, $8, $9, $10, $11, $12)",
+    "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, provider_access_token, provider_refresh_token, authentication_method, created_at, updated_at, nonce, redirect_to, pkce_verifier, expires_at) VALUES ($1, $2, $3, $4::auth.code_challenge_method, $5, $6, NULL, NULL, $7, $8, $9, $10, $11, $12, $13)",
   )
   .bind(flow_id)
+  .bind(link_user_id)
   .bind(&flow.state)
-  .bind(if provider.pkce { "s256" } else { "plain" })
   .bind(
-    flow
-      .pkce_verifier
-      .as_deref()
-      .map(oidc::pkce_challenge)
+    client_challenge
+      .as_ref()
+      .map_or(pkce::ChallengeMethod::Plain, |challenge| challenge.method)
+      .as_str(),
+  )
+  .bind(
+    client_challenge
+      .map(|challenge| challenge.challenge)
       .unwrap_or_default(),
   )
   .bind(&provider.name)
@@ -147,6 +197,71 @@ pub async fn authorize(
   Ok(response)
 }
 
+/// Starts linking an OIDC identity to the signed-in anonymous user. The
+/// returned URL goes through `/authorize` so the browser gets the state cookie.
+pub async fn link_identity(
+  State(state): State<AppState>,
+  AuthUser { claims, user }: AuthUser,
+  Query(query): Query<LinkIdentityQuery>,
+) -> Result<Json<serde_json::Value>> {
+  session::forbid_impersonation(&claims)?;
+  if !user.is_anonymous {
+    return Err(AuthError::ValidationFailed(
+      "Identity linking is only available to anonymous users".to_string(),
+    ));
+  }
+  if !state.oidc_providers.read().await.contains_key(&query.provider) {
+    return Err(AuthError::ValidationFailed(format!(
+      "Unsupported provider: {}",
+      query.provider
+    )));
+  }
+  let redirect_to = validated_redirect_to(&state, query.redirect_to.as_deref())?;
+  let link_token = session::generate_refresh_token();
+  let now = Utc::now();
+
+  sqlx::query(
+    "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, provider_access_token, provider_refresh_token, authentication_method, created_at, updated_at, expires_at) VALUES ($1, $2, $3, 'plain'::auth.code_challenge_method, '', $4, NULL, NULL, $5, $6, $7, $8)",
+  )
+  .bind(Uuid::new_v4())
+  .bind(user.id)
+  .bind(sha256_hex(&link_token))
+  .bind(&query.provider)
+  .bind(IDENTITY_LINK_METHOD)
+  .bind(now)
+  .bind(now)
+  .bind(now + Duration::seconds(AUTHORIZATION_CODE_LIFETIME))
+  .execute(&state.db)
+  .await?;
+
+  let url = url::Url::parse_with_params(
+    &format!("{}/authorize", state.site_url.trim_end_matches('/')),
+    &[
+      ("provider", query.provider.as_str()),
+      ("redirect_to", redirect_to.as_str()),
+      ("link_token", link_token.as_str()),
+    ],
+  )
+  .map_err(|e| AuthError::InternalError(format!("failed to build identity link URL: {e}")))?;
+  Ok(Json(serde_json::json!({ "url": url.to_string() })))
+}
+
+async fn consume_link_token(state: &AppState, provider_name: &str, link_token: &str) -> Result<Uuid> {
+  let row = sqlx::query_as::<_, LinkFlowRow>(
+    "DELETE FROM auth.flow_state WHERE auth_code = $1 AND provider_type = $2 AND authentication_method = $3 RETURNING user_id, expires_at",
+  )
+  .bind(sha256_hex(link_token))
+  .bind(provider_name)
+  .bind(IDENTITY_LINK_METHOD)
+  .fetch_optional(&state.db)
+  .await?
+  .ok_or(AuthError::InvalidToken)?;
+  if row.expires_at.map(|value| value < Utc::now()).unwrap_or(true) {
+    return Err(AuthError::TokenExpired);
+  }
+  row.user_id.ok_or(AuthError::InvalidToken)
+}
+
 pub async fn callback(
   State(state): State<AppState>,
   ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
@@ -180,7 +295,7 @@ async fn handle_callback(
   }
 
   let flow: FlowStateRow = sqlx::query_as::<_, FlowStateRow>(
-    "DELETE FROM auth.flow_state WHERE auth_code = $1 RETURNING id, provider_type, auth_code, provider_access_token, provider_refresh_token, pkce_verifier, nonce, redirect_to, expires_at",
+    "DELETE FROM auth.flow_state WHERE auth_code = $1 RETURNING id, user_id, provider_type, auth_code, provider_access_token, provider_refresh_token, pkce_verifier, nonce, redirect_to, expires_at, code_challenge, code_challenge_method::text AS code_challenge_method",
   )
   .bind(&state_param)
   .fetch_optional(&state.db)
@@ -225,8 +340,39 @@ async fn handle_callback(
   let userinfo = oidc::fetch_userinfo(&state.http_client, &discovery, &token_response.access_token).await?;
   let profile = oidc::normalize_profile(&validated, userinfo);
   oidc::enforce_allowed_domains(&provider, &profile)?;
-  let user = provision_sso_user(&state, &provider, &profile).await?;
+  let user = match flow.user_id {
+    Some(user_id) => link_identity_to_user(&state, client_ip, &provider, &profile, user_id).await?,
+    None => provision_sso_user(&state, &provider, &profile).await?,
+  };
   ensure_user_signin_allowed(&user)?;
+
+  // PKCE clients get an auth code for `grant_type=pkce`; MFA is checked there.
+  if let Some(challenge) = flow.client_challenge()? {
+    let auth_code = pkce::issue_auth_code(&state.db, user.id, pkce::METHOD_OIDC, &challenge).await?;
+    audit::log_event(
+      &state.db,
+      state.instance_id,
+      client_ip,
+      "oidc_login_succeeded",
+      serde_json::json!({
+        "user_id": user.id,
+        "provider": flow.provider_type,
+        "pkce": true,
+      }),
+    )
+    .await?;
+
+    let mut response = Redirect::to(&build_redirect_url(
+      validated_redirect_to(&state, flow.redirect_to.as_deref())?,
+      &auth_code,
+    )?)
+    .into_response();
+    response
+      .headers_mut()
+      .append(SET_COOKIE, clear_oidc_state_cookie(&state)?);
+    return Ok(response);
+  }
+
   let factors = mfa::verified_factors_by_user_id(&state.db, user.id).await?;
 
   if !factors.is_empty() {
@@ -415,11 +561,108 @@ async fn link_sso_user(
   fetch_user(&state.db, user.id).await
 }
 
+/// Attaches the identity to an anonymous user, making it permanent under the
+/// same id. A verified provider email fills in the user's email.
+async fn link_identity_to_user(
+  state: &AppState,
+  client_ip: Option<IpAddr>,
+  provider: &oidc::OidcProviderConfig,
+  profile: &oidc::NormalizedOidcProfile,
+  user_id: Uuid,
+) -> Result<User> {
+  let user = fetch_user(&state.db, user_id).await?;
+  ensure_user_signin_allowed(&user)?;
+  if !user.is_anonymous {
+    return Err(AuthError::ValidationFailed(
+      "Identity linking is only available to anonymous users".to_string(),
+    ));
+  }
+  if sqlx::query_as::<_, IdentityLookup>(
+    "SELECT user_id FROM auth.identities WHERE provider = $1 AND provider_id = $2",
+  )
+  .bind(&provider.name)
+  .bind(&profile.subject)
+  .fetch_optional(&state.db)
+  .await?
+  .is_some()
+  {
+    return Err(AuthError::ValidationFailed(
+      "Identity is already linked to a user".to_string(),
+    ));
+  }
+  let email = profile
+    .email
+    .as_deref()
+    .filter(|_| profile.email_verified && user.email.is_none());
+  if let Some(email) = email {
+    let existing: Option<(Uuid,)> =
+      sqlx::query_as::<_, (Uuid,)>("SELECT id FROM auth.users WHERE lower(email) = lower($1) AND id != $2")
+        .bind(email)
+        .bind(user.id)
+        .fetch_optional(&state.db)
+        .await?;
+    if existing.is_some() {
+      return Err(AuthError::ValidationFailed(
+        "Email address is already in use".to_string(),
+      ));
+    }
+  }
+
+  let now = Utc::now();
+  let mut app_metadata = merged_app_metadata(user.raw_app_meta_data.clone(), &provider.name);
+  if let Some(object) = app_metadata.as_object_mut() {
+    object
+      .entry("provider".to_string())
+      .or_insert_with(|| serde_json::json!(provider.name));
+  }
+  let mut tx = state.db.begin().await?;
+  sqlx::query(
+    "UPDATE auth.users SET email = coalesce(email, $1), email_confirmed_at = CASE WHEN $1::text IS NULL THEN email_confirmed_at ELSE $2 END, raw_app_meta_data = $3, is_sso_user = true, is_anonymous = false, updated_at = $2 WHERE id = $4",
+  )
+  .bind(email)
+  .bind(now)
+  .bind(&app_metadata)
+  .bind(user.id)
+  .execute(&mut *tx)
+  .await?;
+  sqlx::query(
+    "INSERT INTO auth.identities (id, provider_id, user_id, identity_data, provider, last_sign_in_at, created_at, updated_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+  )
+  .bind(Uuid::new_v4())
+  .bind(&profile.subject)
+  .bind(user.id)
+  .bind(&profile.raw_claims)
+  .bind(&provider.name)
+  .bind(now)
+  .bind(now)
+  .bind(now)
+  .execute(&mut *tx)
+  .await?;
+  audit::log_event_tx(
+    tx.as_mut(),
+    state.instance_id,
+    client_ip,
+    "identity_linked",
+    serde_json::json!({
+      "user_id": user.id,
+      "provider": provider.name,
+      "anonymous_upgrade": true,
+    }),
+  )
+  .await?;
+  tx.commit().await?;
+
+  fetch_user(&state.db, user.id).await
+}
+
 fn can_auto_link_sso_user(user: &User) -> bool {
   user.email_confirmed_at.is_some() && user.encrypted_password.is_none()
 }
 
-fn merged_app_metadata(existing: Option<serde_json::Value>, provider_name: &str) -> serde_json::Value {
+pub(crate) fn merged_app_metadata(
+  existing: Option<serde_json::Value>,
+  provider_name: &str,
+) -> serde_json::Value {
   let mut metadata = match existing {
     Some(serde_json::Value::Object(map)) => serde_json::Value::Object(map),
     _ => serde_json::json!({}),
@@ -493,18 +736,6 @@ fn validate_oidc_cookie_value(value: &str) -> Result<()> {
   Ok(())
 }
 
-fn constant_time_eq(left: &str, right: &str) -> bool {
-  let mut left_mac =
-    hmac::Hmac::<sha2::Sha256>::new_from_slice(b"haya-oidc-state-compare").expect("static key is valid");
-  left_mac.update(left.as_bytes());
-  let left_tag = left_mac.finalize().into_bytes();
-
-  let mut right_mac =
-    hmac::Hmac::<sha2::Sha256>::new_from_slice(b"haya-oidc-state-compare").expect("static key is valid");
-  right_mac.update(right.as_bytes());
-  right_mac.verify_slice(&left_tag).is_ok()
-}
-
 async fn fetch_user(db: &sqlx::PgPool, user_id: Uuid) -> Result<User> {
   sqlx::query_as::<_, User>(
     "SELECT id, instance_id, aud, role, email, encrypted_password, email_confirmed_at, phone, phone_confirmed_at, confirmed_at, last_sign_in_at, raw_app_meta_data, raw_user_meta_data, is_super_admin, is_sso_user, is_anonymous, banned_until, deleted_at, created_at, updated_at FROM auth.users WHERE id = $1",
@@ -564,20 +795,135 @@ pub async fn exchange_callback_code(
   let payload = row
     .provider_access_token
     .ok_or_else(|| AuthError::InternalError("OIDC callback result is missing".to_string()))?;
-  let decrypted = crypto_mfa::decrypt_secret(&payload, &state.mfa_encryption_key)?;
+  let decrypted = state.mfa_keys.decrypt(&payload)?;
   let response = serde_json::from_slice(&decrypted)
     .map_err(|e| AuthError::InternalError(format!("invalid OIDC callback result payload: {e}")))?;
   rate_limit::clear(&state.db, &rate_limit_key).await?;
   Ok(response)
 }
 
+/// `grant_type=id_token`: signs in with an ID token a native app got from the
+/// provider's SDK, without the browser redirect.
+pub async fn id_token_grant(
+  state: AppState,
+  client_ip: IpAddr,
+  user_agent: Option<String>,
+  body: serde_json::Value,
+) -> Result<TokenGrantResponse> {
+  let provider_name = body
+    .get("provider")
+    .and_then(|value| value.as_str())
+    .ok_or_else(|| AuthError::ValidationFailed("provider is required".to_string()))?;
+  let id_token = body
+    .get("id_token")
+    .and_then(|value| value.as_str())
+    .ok_or_else(|| AuthError::ValidationFailed("id_token is required".to_string()))?;
+  let nonce = body.get("nonce").and_then(|value| value.as_str());
+  let provider = state
+    .oidc_providers
+    .read()
+    .await
+    .get(provider_name)
+    .cloned()
+    .ok_or_else(|| AuthError::ValidationFailed(format!("Unsupported provider: {provider_name}")))?;
+  let rate_limit_key = format!("id-token-ip:{client_ip}");
+
+  if rate_limit::is_limited(&state.db, &rate_limit_key, ID_TOKEN_GRANT_RATE_LIMIT_ATTEMPTS).await? {
+    return Err(AuthError::TooManyRequests);
+  }
+
+  let discovery = oidc::discover_provider(&state.http_client, &provider).await?;
+  let validated = match verify_native_id_token(&state, &discovery, &provider, id_token, nonce).await {
+    Ok(validated) => validated,
+    Err(err) => {
+      rate_limit::record_failure(&state.db, &rate_limit_key, ID_TOKEN_GRANT_RATE_LIMIT_WINDOW_SECS).await?;
+      audit::log_event(
+        &state.db,
+        state.instance_id,
+        Some(client_ip),
+        "id_token_login_failed",
+        serde_json::json!({
+          "provider": provider.name,
+          "reason": "invalid_id_token",
+        }),
+      )
+      .await?;
+      return Err(err);
+    },
+  };
+  let profile = oidc::normalize_profile(&validated, None);
+  oidc::enforce_allowed_domains(&provider, &profile)?;
+  let user = provision_sso_user(&state, &provider, &profile).await?;
+  ensure_user_signin_allowed(&user)?;
+  rate_limit::clear(&state.db, &rate_limit_key).await?;
+
+  let factors = mfa::verified_factors_by_user_id(&state.db, user.id).await?;
+  if !factors.is_empty() {
+    let pending = mfa::create_pending_login(&state, user.id, ID_TOKEN_METHOD).await?;
+    return Ok(TokenGrantResponse::PendingMfa(pending));
+  }
+
+  let response = session::issue_session_for_client(
+    &state,
+    &user,
+    ID_TOKEN_METHOD,
+    session::ClientContext {
+      user_agent,
+      ip: Some(client_ip),
+    },
+  )
+  .await?;
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_ip),
+    "id_token_login_succeeded",
+    serde_json::json!({
+      "user_id": user.id,
+      "provider": provider.name,
+    }),
+  )
+  .await?;
+  Ok(TokenGrantResponse::Token(Box::new(response)))
+}
+
+/// Verifies an ID token a native SDK obtained directly from the provider,
+/// including the nonce the app passed to the provider's SDK.
+async fn verify_native_id_token(
+  state: &AppState,
+  discovery: &oidc::OidcDiscoveryDocument,
+  provider: &oidc::OidcProviderConfig,
+  id_token: &str,
+  nonce: Option<&str>,
+) -> Result<oidc::ValidatedIdToken> {
+  let validated = oidc::verify_id_token(state, discovery, provider, id_token).await?;
+  if !id_token_nonce_matches(validated.claims.nonce.as_deref(), nonce) {
+    return Err(AuthError::ValidationFailed(
+      "OIDC nonce validation failed".to_string(),
+    ));
+  }
+  Ok(validated)
+}
+
+/// Native SDKs put either the raw nonce or its SHA-256 hex digest (Apple) in
+/// the token; a token with a nonce needs the matching raw nonce and vice versa.
+fn id_token_nonce_matches(token_nonce: Option<&str>, nonce: Option<&str>) -> bool {
+  match (token_nonce, nonce) {
+    (None, None) => true,
+    (Some(token_nonce), Some(nonce)) => {
+      constant_time_eq(token_nonce, &sha256_hex(nonce)) || constant_time_eq(token_nonce, nonce)
+    },
+    _ => false,
+  }
+}
+
 async fn persist_oidc_result(state: &AppState, response: TokenGrantResponse) -> Result<String> {
   let exchange_code = session::generate_refresh_token();
   let now = Utc::now();
   let expires_at = now + Duration::minutes(OIDC_RESULT_TTL_MINUTES);
   let payload = serde_json::to_vec(&response)
     .map_err(|e| AuthError::InternalError(format!("failed to serialize OIDC callback result: {e}")))?;
-  let encrypted_payload = crypto_mfa::encrypt_secret(&payload, &state.mfa_encryption_key)?;
+  let encrypted_payload = state.mfa_keys.encrypt(&payload)?;
 
   sqlx::query(
     "INSERT INTO auth.flow_state (id, user_id, auth_code, code_challenge_method, code_challenge, provider_type, provider_access_token, provider_refresh_token, authentication_method, created_at, updated_at, expires_at) VALUES ($1, NULL, $2, 'plain'::auth.code_challenge_method, '', 'oidc_result', $3, NULL, 'oidc', $4, $5, $6)",
@@ -594,14 +940,14 @@ async fn persist_oidc_result(state: &AppState, response: TokenGrantResponse) ->
   Ok(exchange_code)
 }
 
-fn build_redirect_url(redirect_to: String, exchange_code: &str) -> Result<String> {
+pub(crate) fn build_redirect_url(redirect_to: String, exchange_code: &str) -> Result<String> {
   let mut url = url::Url::parse(&redirect_to)
     .map_err(|e| AuthError::ValidationFailed(format!("invalid redirect_to: {e}")))?;
   url.query_pairs_mut().append_pair("code", exchange_code);
   Ok(url.to_string())
 }
 
-fn validated_redirect_to(state: &AppState, redirect_to: Option<&str>) -> Result<String> {
+pub(crate) fn validated_redirect_to(state: &AppState, redirect_to: Option<&str>) -> Result<String> {
   let value = redirect_to.unwrap_or(&state.site_url);
   let url =
     url::Url::parse(value).map_err(|e| AuthError::ValidationFailed(format!("invalid redirect_to: {e}")))?;
@@ -698,6 +1044,17 @@ mod tests {
     assert!(!redirect.contains('#'));
   }
 
+  #[test]
+  fn id_token_nonce_accepts_raw_or_hashed_value() {
+    let hashed = sha256_hex("n-0S6_WzA2Mj");
+    assert!(id_token_nonce_matches(Some(&hashed), Some("n-0S6_WzA2Mj")));
+    assert!(id_token_nonce_matches(Some("n-0S6_WzA2Mj"), Some("n-0S6_WzA2Mj")));
+    assert!(id_token_nonce_matches(None, None));
+    assert!(!id_token_nonce_matches(Some(&hashed), None));
+    assert!(!id_token_nonce_matches(None, Some("n-0S6_WzA2Mj")));
+    assert!(!id_token_nonce_matches(Some(&hashed), Some("other")));
+  }
+
   #[test]
   fn constant_time_compare_accepts_equal_values() {
     assert!(constant_time_eq("state-value", "state-value"));
@@ -707,4 +1064,141 @@ mod tests {
   fn constant_time_compare_rejects_different_values() {
     assert!(!constant_time_eq("state-value", "other-state"));
   }
+
+  const NATIVE_ISSUER: &str = "https://id.example.com";
+  const NATIVE_JWKS_URI: &str = "https://id.example.com/jwks";
+
+  fn native_provider() -> oidc::OidcProviderConfig {
+    oidc::OidcProviderConfig {
+      name: "example".to_string(),
+      issuer: NATIVE_ISSUER.to_string(),
+      client_id: "web-client".to_string(),
+      client_secret: "secret".to_string(),
+      redirect_uri: "https://app.example.com/callback".to_string(),
+      scopes: vec!["openid".to_string()],
+      pkce: true,
+      allowed_email_domains: vec![],
+      additional_audiences: vec!["com.example.ios".to_string()],
+    }
+  }
+
+  fn native_discovery() -> oidc::OidcDiscoveryDocument {
+    oidc::OidcDiscoveryDocument {
+      issuer: NATIVE_ISSUER.to_string(),
+      authorization_endpoint: format!("{NATIVE_ISSUER}/authorize"),
+      token_endpoint: format!("{NATIVE_ISSUER}/token"),
+      userinfo_endpoint: None,
+      jwks_uri: NATIVE_JWKS_URI.to_string(),
+    }
+  }
+
+  /// State whose JWKS cache already holds the provider's signing key, so
+  /// verification never reaches the network.
+  async fn native_state(provider_key: &crate::auth::jwt::JwtKey) -> AppState {
+    let state = crate::auth::session::tests::sample_state(3600);
+    state.oidc_jwks_cache.write().await.insert(
+      NATIVE_JWKS_URI.to_string(),
+      oidc::CachedJwks {
+        jwks: jsonwebtoken::jwk::JwkSet {
+          keys: vec![provider_key.public_jwk().unwrap().clone()],
+        },
+        fetched_at: Utc::now(),
+      },
+    );
+    state
+  }
+
+  fn provider_key() -> crate::auth::jwt::JwtKey {
+    let material = crate::auth::keyring::generate_key_material(jsonwebtoken::Algorithm::ES256).unwrap();
+    crate::auth::jwt::JwtKey::from_pem(jsonwebtoken::Algorithm::ES256, &material, None).unwrap()
+  }
+
+  fn native_id_token(key: &crate::auth::jwt::JwtKey, aud: &str, nonce: Option<&str>) -> String {
+    let now = Utc::now().timestamp();
+    crate::auth::jwt::encode_claims(
+      key,
+      &serde_json::json!({
+        "iss": NATIVE_ISSUER,
+        "sub": "provider-user-1",
+        "aud": aud,
+        "iat": now,
+        "exp": now + 300,
+        "nonce": nonce,
+        "email": "user@example.com",
+      }),
+    )
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn id_token_grant_rejects_unknown_provider() {
+    let state = crate::auth::session::tests::sample_state(3600);
+    state
+      .oidc_providers
+      .write()
+      .await
+      .insert("example".to_string(), native_provider());
+
+    let result = id_token_grant(
+      state,
+      IpAddr::from([127, 0, 0, 1]),
+      None,
+      serde_json::json!({ "provider": "unknown", "id_token": "ey.x.y" }),
+    )
+    .await;
+
+    assert!(matches!(
+      result,
+      Err(AuthError::ValidationFailed(message)) if message == "Unsupported provider: unknown"
+    ));
+  }
+
+  #[tokio::test]
+  async fn native_id_token_accepts_client_id_and_additional_audiences() {
+    let key = provider_key();
+    let state = native_state(&key).await;
+    let hashed_nonce = sha256_hex("n-0S6_WzA2Mj");
+
+    for aud in ["web-client", "com.example.ios"] {
+      let id_token = native_id_token(&key, aud, Some(&hashed_nonce));
+      let validated = verify_native_id_token(
+        &state,
+        &native_discovery(),
+        &native_provider(),
+        &id_token,
+        Some("n-0S6_WzA2Mj"),
+      )
+      .await
+      .unwrap();
+      assert_eq!(validated.claims.sub, "provider-user-1");
+    }
+  }
+
+  #[tokio::test]
+  async fn native_id_token_rejects_foreign_audience() {
+    let key = provider_key();
+    let state = native_state(&key).await;
+    let id_token = native_id_token(&key, "someone-elses-client", None);
+
+    let result =
+      verify_native_id_token(&state, &native_discovery(), &native_provider(), &id_token, None).await;
+
+    assert!(matches!(result, Err(AuthError::InvalidToken)));
+  }
+
+  #[tokio::test]
+  async fn native_id_token_rejects_nonce_mismatch() {
+    let key = provider_key();
+    let state = native_state(&key).await;
+    let id_token = native_id_token(&key, "web-client", Some(&sha256_hex("n-0S6_WzA2Mj")));
+
+    for nonce in [Some("another-nonce"), None] {
+      let result =
+        verify_native_id_token(&state, &native_discovery(), &native_provider(), &id_token, nonce).await;
+      assert!(matches!(
+        result,
+        Err(AuthError::ValidationFailed(message)) if message == "OIDC nonce validation failed"
+      ));
+    }
+  }
 }
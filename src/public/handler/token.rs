@@ -13,25 +13,40 @@ use std::net::{
 };
 use uuid::Uuid;
 
+use crate::auth::session_limits::SessionLimitStrategy;
 use crate::auth::{
   audit,
+  binding,
+  jwt,
+  oauth,
   password,
+  pkce,
   rate_limit,
   session,
+  trusted_device,
+  webauthn,
 };
 use crate::error::{
   AuthError,
   Result,
 };
-use crate::middleware::auth::extract_bearer_token_value;
+use crate::middleware::auth::{
+  authenticate_admin,
+  extract_bearer_token_value,
+  is_admin_role,
+};
 use crate::model::{
+  OAuthTokenResponse,
   RefreshToken,
+  TokenExchangeResponse,
   TokenGrantResponse,
   TokenResponse,
   User,
 };
 use crate::public::handler::{
+  device,
   mfa,
+  passkey,
   sso,
 };
 use crate::state::AppState;
@@ -40,6 +55,9 @@ use crate::utils::sha256_hex;
 const PASSWORD_GRANT_RATE_LIMIT_ATTEMPTS: u32 = 10;
 const PASSWORD_GRANT_RATE_LIMIT_WINDOW_SECS: u64 = 300;
 const PASSWORD_GRANT_IP_RATE_LIMIT_ATTEMPTS: u32 = 15;
+/// Upper bound on an impersonation token's lifetime; `JWT_EXPIRY` still wins
+/// when it is shorter.
+const IMPERSONATION_TOKEN_LIFETIME_SECS: i64 = 900;
 
 #[derive(Debug, Deserialize)]
 pub struct TokenQuery {
@@ -64,9 +82,33 @@ pub async fn token(
     "mfa_totp" => handle_mfa_totp_grant(state, headers, client_ip, body)
       .await
       .map(|response| Json(TokenGrantResponse::Token(Box::new(response)))),
+    "mfa_phone" => handle_mfa_phone_grant(state, headers, client_ip, body)
+      .await
+      .map(|response| Json(TokenGrantResponse::Token(Box::new(response)))),
+    "mfa_webauthn" => handle_mfa_webauthn_grant(state, headers, client_ip, body)
+      .await
+      .map(|response| Json(TokenGrantResponse::Token(Box::new(response)))),
+    "webauthn" => handle_webauthn_grant(state, headers, client_ip, body)
+      .await
+      .map(|response| Json(TokenGrantResponse::Token(Box::new(response)))),
     "oidc_callback" => sso::exchange_callback_code(state, client_ip, body)
       .await
       .map(Json),
+    "pkce" => handle_pkce_grant(state, client_ip, user_agent_from_headers(&headers), body)
+      .await
+      .map(Json),
+    "id_token" => sso::id_token_grant(state, client_ip, user_agent_from_headers(&headers), body)
+      .await
+      .map(Json),
+    oauth::GRANT_CLIENT_CREDENTIALS => handle_client_credentials_grant(state, client_ip, &headers, body)
+      .await
+      .map(|response| Json(TokenGrantResponse::Client(response))),
+    oauth::GRANT_DEVICE_CODE => handle_device_code_grant(state, client_ip, &headers, body)
+      .await
+      .map(|response| Json(TokenGrantResponse::Client(response))),
+    oauth::GRANT_TOKEN_EXCHANGE => handle_token_exchange_grant(state, client_ip, &headers, body)
+      .await
+      .map(|response| Json(TokenGrantResponse::Exchange(Box::new(response)))),
     _ => Err(AuthError::ValidationFailed(format!(
       "Unsupported grant_type: {}",
       query.grant_type
@@ -262,6 +304,39 @@ async fn handle_password_grant(
   rate_limit::clear(&state.db, &ip_rate_limit_key).await?;
 
   let factors = mfa::verified_factors_by_user_id(&state.db, user.id).await?;
+  let trusted_device = match body.get("trusted_device_token").and_then(|v| v.as_str()) {
+    Some(token) if !factors.is_empty() && state.trusted_device_max_lifetime_secs > 0 => {
+      trusted_device::redeem(&state.db, user.id, token).await?
+    },
+    _ => None,
+  };
+  if let Some(device) = trusted_device {
+    let response = session::issue_session_with_client_context(
+      &state,
+      &user,
+      "aal2",
+      Some(device.factor_id),
+      vec!["password".to_string(), trusted_device::METHOD.to_string()],
+      session::ClientContext {
+        user_agent,
+        ip: Some(client_ip),
+      },
+    )
+    .await?;
+    audit::log_event(
+      &state.db,
+      state.instance_id,
+      Some(client_ip),
+      "password_login_succeeded",
+      serde_json::json!({
+        "user_id": user.id,
+        "email": user.email,
+        "trusted_device_id": device.id,
+      }),
+    )
+    .await?;
+    return Ok(TokenGrantResponse::Token(Box::new(response)));
+  }
   if !factors.is_empty() {
     let pending = mfa::create_pending_login(&state, user.id, "password").await?;
     audit::log_event(
@@ -314,6 +389,19 @@ async fn handle_refresh_grant(
     .and_then(|v| v.as_str())
     .ok_or_else(|| AuthError::ValidationFailed("refresh_token is required".to_string()))?;
 
+  refresh_session(&state, client_ip, user_agent, rt_str, None).await
+}
+
+/// Rotates a refresh token. Sessions issued to an OAuth client only refresh
+/// when `client_id` names that client, and first-party sessions only when it
+/// is `None`.
+pub(crate) async fn refresh_session(
+  state: &AppState,
+  client_ip: IpAddr,
+  user_agent: Option<String>,
+  rt_str: &str,
+  client_id: Option<&str>,
+) -> Result<TokenResponse> {
   let now = Utc::now();
   let rt_hash = sha256_hex(rt_str);
   let refresh_expires_after = chrono::Duration::seconds(state.refresh_token_exp);
@@ -330,12 +418,30 @@ async fn handle_refresh_grant(
   .await?
   .ok_or(AuthError::InvalidToken)?;
 
+  // A revoked token is normally a stolen one, but a client that lost a
+  // concurrent refresh race presents it too. Within the reuse interval that
+  // client gets the child the winner received instead of being signed out.
+  let mut reused_child = None;
   if rt.revoked.unwrap_or(false) {
-    if let Some(session_id) = rt.session_id {
-      revoke_refresh_token_family(&mut tx, session_id, now).await?;
-      tx.commit().await?;
+    reused_child = reusable_child_token(state, &mut tx, &rt, rt_str, now).await?;
+    if reused_child.is_none() {
+      if let Some(session_id) = rt.session_id {
+        revoke_refresh_token_family(&mut tx, session_id, now).await?;
+        audit::log_event_tx(
+          tx.as_mut(),
+          state.instance_id,
+          Some(client_ip),
+          "refresh_token_reuse_detected",
+          serde_json::json!({
+            "user_id": rt.user_id,
+            "session_id": session_id,
+          }),
+        )
+        .await?;
+        tx.commit().await?;
+      }
+      return Err(AuthError::InvalidToken);
     }
-    return Err(AuthError::InvalidToken);
   }
 
   if rt
@@ -346,11 +452,13 @@ async fn handle_refresh_grant(
     return Err(AuthError::InvalidToken);
   }
 
-  sqlx::query("UPDATE auth.refresh_tokens SET revoked = true, updated_at = $1 WHERE id = $2")
-    .bind(now)
-    .bind(rt.id)
-    .execute(&mut *tx)
-    .await?;
+  if reused_child.is_none() {
+    sqlx::query("UPDATE auth.refresh_tokens SET revoked = true, updated_at = $1 WHERE id = $2")
+      .bind(now)
+      .bind(rt.id)
+      .execute(&mut *tx)
+      .await?;
+  }
 
   let session_id = rt.session_id.ok_or(AuthError::SessionNotFound)?;
   let user_id_str = rt.user_id.ok_or(AuthError::UserNotFound)?;
@@ -358,28 +466,63 @@ async fn handle_refresh_grant(
     .parse()
     .map_err(|_| AuthError::InternalError("Invalid user_id in refresh token".to_string()))?;
 
-  let (session_row, amr) = session::load_session_context(&state, session_id)
+  let (session_row, amr) = session::load_session_context(state, session_id)
     .await
     .map_err(|_| AuthError::SessionNotFound)?;
-  if session_row.user_id != user_id {
+  if session_row.user_id != user_id || session_row.oauth_client_id.as_deref() != client_id {
     return Err(AuthError::InvalidToken);
   }
   if session_row.not_after.map(|value| value <= now).unwrap_or(false) {
     return Err(AuthError::SessionNotFound);
   }
-  let ip_matches = session_row
-    .ip
-    .as_deref()
-    .is_none_or(|value| value == client_ip.to_string());
-  let user_agent_matches = session_row
-    .user_agent
-    .as_deref()
-    .is_none_or(|value| user_agent.as_deref() == Some(value));
-  if !ip_matches || !user_agent_matches {
+  let policy = match session_row.oauth_client_id.as_deref() {
+    Some(client_id) => oauth::find_client(&state.db, client_id)
+      .await?
+      .and_then(|client| client.session_binding)
+      .unwrap_or(state.session_binding),
+    None => state.session_binding,
+  };
+  let binding = binding::check(
+    policy,
+    session_row.ip.as_deref(),
+    session_row.user_agent.as_deref(),
+    client_ip,
+    user_agent.as_deref(),
+  );
+  if binding.is_violation() {
     revoke_refresh_token_family(&mut tx, session_id, now).await?;
+    audit::log_event_tx(
+      tx.as_mut(),
+      state.instance_id,
+      Some(client_ip),
+      binding::VIOLATION_EVENT,
+      serde_json::json!({
+        "user_id": user_id,
+        "session_id": session_id,
+        "policy": policy.as_str(),
+        "mismatch": binding.hard,
+        "tolerated": binding.soft,
+      }),
+    )
+    .await?;
     tx.commit().await?;
     return Err(AuthError::InvalidToken);
   }
+  if !binding.soft.is_empty() {
+    audit::log_event_tx(
+      tx.as_mut(),
+      state.instance_id,
+      Some(client_ip),
+      binding::SOFT_MISMATCH_EVENT,
+      serde_json::json!({
+        "user_id": user_id,
+        "session_id": session_id,
+        "policy": policy.as_str(),
+        "mismatch": binding.soft,
+      }),
+    )
+    .await?;
+  }
 
   let user: User = sqlx::query_as::<_, User>(
         "SELECT id, instance_id, aud, role, email, encrypted_password, email_confirmed_at, phone, phone_confirmed_at, confirmed_at, last_sign_in_at, raw_app_meta_data, raw_user_meta_data, is_super_admin, is_sso_user, is_anonymous, banned_until, deleted_at, created_at, updated_at FROM auth.users WHERE id = $1"
@@ -391,23 +534,51 @@ async fn handle_refresh_grant(
 
   session::ensure_user_is_active(&user)?;
 
-  // Reject banned users before issuing a new token
-  // Perform token rotation atomically: revoke old, insert new, update session.
-  let new_refresh_token = session::generate_refresh_token();
-  let new_refresh_token_hash = sha256_hex(&new_refresh_token);
+  // Session limits apply to sessions created before they were configured or
+  // lowered, so check them here as well as at sign-in.
+  let limits = session::limits_for(state, &user);
+  if let (Some(max_lifetime_secs), Some(created_at)) = (limits.max_lifetime_secs, session_row.created_at)
+    && created_at + chrono::Duration::seconds(max_lifetime_secs) <= now
+  {
+    return Err(AuthError::SessionNotFound);
+  }
+  if let Some(max_per_user) = limits.max_per_user {
+    session::lock_user_sessions(&mut tx, user.id).await?;
+    let active = session::active_session_ids(&mut tx, state, user.id, now).await?;
+    let max_per_user = max_per_user as usize;
+    // Evicting the oldest keeps the newest sessions; rejecting new sign-ins
+    // keeps the oldest.
+    let kept = match limits.strategy {
+      SessionLimitStrategy::EvictOldest => &active[active.len().saturating_sub(max_per_user)..],
+      SessionLimitStrategy::Reject => &active[..active.len().min(max_per_user)],
+    };
+    if active.contains(&session_id) && !kept.contains(&session_id) {
+      session::evict_session(&mut tx, state, user.id, session_id, &limits, Some(client_ip), now).await?;
+      tx.commit().await?;
+      return Err(AuthError::SessionNotFound);
+    }
+  }
 
-  sqlx::query(
+  // Perform token rotation atomically: revoke old, insert new, update session.
+  let new_refresh_token = match reused_child {
+    Some(child) => child,
+    None => {
+      let child = session::derive_child_refresh_token(&state.refresh_token_reuse_key, rt_str);
+      sqlx::query(
         "INSERT INTO auth.refresh_tokens (instance_id, user_id, token, session_id, revoked, parent, created_at, updated_at) VALUES ($1, $2, $3, $4, false, $5, $6, $7)"
-    )
-    .bind(state.instance_id)
-    .bind(user.id.to_string())
-    .bind(&new_refresh_token_hash)
-    .bind(session_id)
-    .bind(&rt_hash)
-    .bind(now)
-    .bind(now)
-    .execute(&mut *tx)
-    .await?;
+      )
+      .bind(state.instance_id)
+      .bind(user.id.to_string())
+      .bind(sha256_hex(&child))
+      .bind(session_id)
+      .bind(&rt_hash)
+      .bind(now)
+      .bind(now)
+      .execute(&mut *tx)
+      .await?;
+      child
+    },
+  };
 
   sqlx::query("UPDATE auth.sessions SET refreshed_at = $1, updated_at = $2 WHERE id = $3")
     // refreshed_at is 'timestamp without time zone' in the schema, so use naive_utc()
@@ -420,16 +591,47 @@ async fn handle_refresh_grant(
   tx.commit().await?;
 
   session::build_token_response(
-    &state,
+    state,
     &user,
     session_id,
     session_row.aal.as_deref().unwrap_or("aal1"),
     amr,
     new_refresh_token,
+    session::oauth_grant(&session_row).as_ref(),
   )
   .await
 }
 
+/// Returns the child of a revoked refresh token when the token was rotated
+/// within `REFRESH_TOKEN_REUSE_INTERVAL` and that child is still the
+/// session's live token.
+async fn reusable_child_token(
+  state: &AppState,
+  tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+  rt: &RefreshToken,
+  rt_str: &str,
+  now: chrono::DateTime<Utc>,
+) -> Result<Option<String>> {
+  let (Some(session_id), Some(revoked_at)) = (rt.session_id, rt.updated_at) else {
+    return Ok(None);
+  };
+  if state.refresh_token_reuse_interval <= 0
+    || revoked_at + chrono::Duration::seconds(state.refresh_token_reuse_interval) < now
+  {
+    return Ok(None);
+  }
+  let child = session::derive_child_refresh_token(&state.refresh_token_reuse_key, rt_str);
+  let live = sqlx::query_scalar::<_, i64>(
+    "SELECT id FROM auth.refresh_tokens WHERE token = $1 AND parent = $2 AND session_id = $3 AND revoked = false",
+  )
+  .bind(sha256_hex(&child))
+  .bind(&rt.token)
+  .bind(session_id)
+  .fetch_optional(&mut **tx)
+  .await?;
+  Ok(live.map(|_| child))
+}
+
 async fn handle_mfa_totp_grant(
   state: AppState,
   headers: HeaderMap,
@@ -443,6 +645,16 @@ async fn handle_mfa_totp_grant(
     .ok_or_else(|| {
       AuthError::ValidationFailed("Authorization header with Bearer MFA token is required".to_string())
     })?;
+  if let Some(recovery_code) = body.get("recovery_code").and_then(|v| v.as_str()) {
+    return mfa::verify_pending_recovery_code(
+      &state,
+      client_ip,
+      user_agent_from_headers(&headers),
+      mfa_token,
+      recovery_code,
+    )
+    .await;
+  }
   let factor_id = body
     .get("factor_id")
     .and_then(|v| v.as_str())
@@ -454,6 +666,15 @@ async fn handle_mfa_totp_grant(
     .get("code")
     .and_then(|v| v.as_str())
     .ok_or_else(|| AuthError::ValidationFailed("code is required".to_string()))?;
+  let remember_device_for = body
+    .get("remember_device_for")
+    .filter(|value| !value.is_null())
+    .map(|value| {
+      value.as_i64().ok_or_else(|| {
+        AuthError::ValidationFailed("remember_device_for must be a number of seconds".to_string())
+      })
+    })
+    .transpose()?;
 
   mfa::verify_pending_totp(
     &state,
@@ -462,10 +683,165 @@ async fn handle_mfa_totp_grant(
     mfa_token,
     factor_id,
     code,
+    remember_device_for,
   )
   .await
 }
 
+async fn handle_mfa_phone_grant(
+  state: AppState,
+  headers: HeaderMap,
+  client_ip: IpAddr,
+  body: serde_json::Value,
+) -> Result<TokenResponse> {
+  let mfa_token = headers
+    .get(axum::http::header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(extract_bearer_token_value)
+    .ok_or_else(|| {
+      AuthError::ValidationFailed("Authorization header with Bearer MFA token is required".to_string())
+    })?;
+  let factor_id = body
+    .get("factor_id")
+    .and_then(|v| v.as_str())
+    .ok_or_else(|| AuthError::ValidationFailed("factor_id is required".to_string()))?
+    .parse::<Uuid>()
+    .map_err(|_| AuthError::ValidationFailed("factor_id must be a UUID".to_string()))?;
+  let challenge_id = body
+    .get("challenge_id")
+    .and_then(|v| v.as_str())
+    .map(|value| {
+      value
+        .parse::<Uuid>()
+        .map_err(|_| AuthError::ValidationFailed("challenge_id must be a UUID".to_string()))
+    })
+    .transpose()?;
+  let code = body
+    .get("code")
+    .and_then(|v| v.as_str())
+    .ok_or_else(|| AuthError::ValidationFailed("code is required".to_string()))?;
+
+  mfa::verify_pending_phone(
+    &state,
+    client_ip,
+    user_agent_from_headers(&headers),
+    mfa_token,
+    factor_id,
+    challenge_id,
+    code,
+  )
+  .await
+}
+
+async fn handle_mfa_webauthn_grant(
+  state: AppState,
+  headers: HeaderMap,
+  client_ip: IpAddr,
+  body: serde_json::Value,
+) -> Result<TokenResponse> {
+  let mfa_token = headers
+    .get(axum::http::header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(extract_bearer_token_value)
+    .ok_or_else(|| {
+      AuthError::ValidationFailed("Authorization header with Bearer MFA token is required".to_string())
+    })?;
+  let credential = assertion_from_body(&body)?;
+
+  mfa::verify_pending_webauthn(
+    &state,
+    client_ip,
+    user_agent_from_headers(&headers),
+    mfa_token,
+    &credential,
+  )
+  .await
+}
+
+async fn handle_webauthn_grant(
+  state: AppState,
+  headers: HeaderMap,
+  client_ip: IpAddr,
+  body: serde_json::Value,
+) -> Result<TokenResponse> {
+  let credential = assertion_from_body(&body)?;
+  passkey::sign_in(&state, client_ip, user_agent_from_headers(&headers), &credential).await
+}
+
+fn assertion_from_body(body: &serde_json::Value) -> Result<webauthn::AssertionCredential> {
+  let credential = body
+    .get("credential")
+    .cloned()
+    .ok_or_else(|| AuthError::ValidationFailed("credential is required".to_string()))?;
+  serde_json::from_value(credential)
+    .map_err(|e| AuthError::ValidationFailed(format!("credential is not a WebAuthn assertion: {e}")))
+}
+
+async fn handle_pkce_grant(
+  state: AppState,
+  client_ip: IpAddr,
+  user_agent: Option<String>,
+  body: serde_json::Value,
+) -> Result<TokenGrantResponse> {
+  let auth_code = body
+    .get("auth_code")
+    .and_then(|v| v.as_str())
+    .ok_or_else(|| AuthError::ValidationFailed("auth_code is required".to_string()))?;
+  let code_verifier = body
+    .get("code_verifier")
+    .and_then(|v| v.as_str())
+    .ok_or_else(|| AuthError::ValidationFailed("code_verifier is required".to_string()))?;
+
+  let grant = match pkce::exchange_auth_code(&state.db, auth_code, code_verifier).await {
+    Ok(grant) => grant,
+    Err(err @ (AuthError::InvalidToken | AuthError::TokenExpired)) => {
+      audit::log_event(
+        &state.db,
+        state.instance_id,
+        Some(client_ip),
+        "pkce_exchange_failed",
+        serde_json::json!({
+          "reason": if matches!(err, AuthError::TokenExpired) { "expired_code" } else { "invalid_code" },
+        }),
+      )
+      .await?;
+      return Err(err);
+    },
+    Err(err) => return Err(err),
+  };
+  let user = session::load_active_user(&state, grant.user_id).await?;
+  let method = grant.authentication_method;
+
+  let factors = mfa::verified_factors_by_user_id(&state.db, user.id).await?;
+  if !factors.is_empty() {
+    let pending = mfa::create_pending_login(&state, user.id, &method).await?;
+    return Ok(TokenGrantResponse::PendingMfa(pending));
+  }
+
+  let response = session::issue_session_for_client(
+    &state,
+    &user,
+    &method,
+    session::ClientContext {
+      user_agent,
+      ip: Some(client_ip),
+    },
+  )
+  .await?;
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_ip),
+    "pkce_exchange_succeeded",
+    serde_json::json!({
+      "user_id": user.id,
+      "authentication_method": method,
+    }),
+  )
+  .await?;
+  Ok(TokenGrantResponse::Token(Box::new(response)))
+}
+
 fn password_grant_rate_limit_key(email: &str, client_ip: IpAddr) -> String {
   let normalized_email = email.trim().to_ascii_lowercase();
   format!(
@@ -478,6 +854,141 @@ fn password_grant_ip_rate_limit_key(client_ip: IpAddr) -> String {
   format!("password-ip:{client_ip}")
 }
 
+async fn handle_client_credentials_grant(
+  state: AppState,
+  client_ip: IpAddr,
+  headers: &HeaderMap,
+  body: serde_json::Value,
+) -> Result<OAuthTokenResponse> {
+  let field = |name: &str| body.get(name).and_then(|value| value.as_str());
+  let credentials = oauth::client_credentials(
+    headers
+      .get(axum::http::header::AUTHORIZATION)
+      .and_then(|value| value.to_str().ok()),
+    field("client_id"),
+    field("client_secret"),
+  )?;
+  let client = oauth::authenticate_client(&state.db, &credentials).await?;
+  client_credentials_grant(&state, client_ip, &client, field("scope")).await
+}
+
+async fn handle_device_code_grant(
+  state: AppState,
+  client_ip: IpAddr,
+  headers: &HeaderMap,
+  body: serde_json::Value,
+) -> Result<OAuthTokenResponse> {
+  let field = |name: &str| body.get(name).and_then(|value| value.as_str());
+  let credentials = oauth::client_credentials(
+    headers
+      .get(axum::http::header::AUTHORIZATION)
+      .and_then(|value| value.to_str().ok()),
+    field("client_id"),
+    field("client_secret"),
+  )?;
+  let client = oauth::authenticate_client(&state.db, &credentials).await?;
+  device::device_code_grant(
+    &state,
+    &client,
+    field("device_code"),
+    session::ClientContext {
+      user_agent: user_agent_from_headers(headers),
+      ip: Some(client_ip),
+    },
+  )
+  .await
+}
+
+/// Lets an admin act as another user for a short while. The bearer token must
+/// belong to an admin; `subject_token` names the target user by ID.
+async fn handle_token_exchange_grant(
+  state: AppState,
+  client_ip: IpAddr,
+  headers: &HeaderMap,
+  body: serde_json::Value,
+) -> Result<TokenExchangeResponse> {
+  let (_, admin) = authenticate_admin(&state, headers).await?;
+  let field = |name: &str| body.get(name).and_then(|value| value.as_str());
+  if field("subject_token_type") != Some(oauth::TOKEN_TYPE_USER_ID) {
+    return Err(AuthError::oauth(
+      "invalid_request",
+      format!("subject_token_type must be {}", oauth::TOKEN_TYPE_USER_ID),
+    ));
+  }
+  if field("requested_token_type").is_some_and(|value| value != oauth::TOKEN_TYPE_ACCESS_TOKEN) {
+    return Err(AuthError::oauth(
+      "invalid_request",
+      "Only access tokens can be requested",
+    ));
+  }
+  let user_id = field("subject_token")
+    .and_then(|value| value.parse::<Uuid>().ok())
+    .ok_or_else(|| AuthError::oauth("invalid_request", "subject_token must be a user ID"))?;
+
+  let user = session::load_active_user(&state, user_id)
+    .await
+    .map_err(|_| AuthError::oauth("invalid_target", "The user does not exist or is not active"))?;
+  if is_admin_role(&user) || user.is_super_admin.unwrap_or(false) {
+    return Err(AuthError::oauth(
+      "invalid_target",
+      "Admins cannot be impersonated",
+    ));
+  }
+
+  let lifetime_secs = IMPERSONATION_TOKEN_LIFETIME_SECS.min(state.jwt_exp);
+  let response = session::issue_impersonation_session(
+    &state,
+    &user,
+    jwt::Actor {
+      sub: admin.id.to_string(),
+      email: admin.email.clone(),
+    },
+    lifetime_secs,
+    session::ClientContext {
+      user_agent: user_agent_from_headers(headers),
+      ip: Some(client_ip),
+    },
+  )
+  .await?;
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_ip),
+    "impersonation_started",
+    serde_json::json!({
+      "user_id": user.id,
+      "admin_id": admin.id,
+      "admin_email": admin.email,
+      "expires_at": response.expires_at,
+    }),
+  )
+  .await?;
+  Ok(response)
+}
+
+/// Shared by `/token` and `/oauth/token`.
+pub(crate) async fn client_credentials_grant(
+  state: &AppState,
+  client_ip: IpAddr,
+  client: &oauth::OAuthClient,
+  scope: Option<&str>,
+) -> Result<OAuthTokenResponse> {
+  let response = oauth::issue_client_credentials_token(state, client, scope).await?;
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_ip),
+    "client_credentials_token_issued",
+    serde_json::json!({
+      "client_id": client.client_id,
+      "role": client.role,
+      "scope": response.scope,
+    }),
+  )
+  .await?;
+  Ok(response)
+}
+
 fn user_agent_from_headers(headers: &HeaderMap) -> Option<String> {
   headers
     .get(axum::http::header::USER_AGENT)
@@ -485,7 +996,7 @@ fn user_agent_from_headers(headers: &HeaderMap) -> Option<String> {
     .map(str::to_owned)
 }
 
-async fn revoke_refresh_token_family(
+pub(crate) async fn revoke_refresh_token_family(
   tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
   session_id: Uuid,
   now: chrono::DateTime<Utc>,
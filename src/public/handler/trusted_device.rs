@@ -0,0 +1,118 @@
+use axum::Json;
+use axum::extract::{
+  ConnectInfo,
+  Path,
+  State,
+};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use chrono::{
+  DateTime,
+  Utc,
+};
+use serde::Serialize;
+use sqlx::FromRow;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::auth::binding::{
+  UserAgentInfo,
+  parse_user_agent,
+};
+use crate::auth::{
+  audit,
+  session,
+};
+use crate::error::{
+  AuthError,
+  Result,
+};
+use crate::middleware::auth::AuthUser;
+use crate::state::AppState;
+
+#[derive(Debug, FromRow)]
+struct TrustedDeviceRow {
+  id: Uuid,
+  factor_id: Uuid,
+  ip: Option<String>,
+  user_agent: Option<String>,
+  created_at: DateTime<Utc>,
+  last_used_at: Option<DateTime<Utc>>,
+  expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrustedDeviceResponse {
+  pub id: Uuid,
+  /// Factor verified when the device was trusted
+  pub factor_id: Uuid,
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  pub device: Option<UserAgentInfo>,
+  pub created_at: DateTime<Utc>,
+  pub last_used_at: Option<DateTime<Utc>>,
+  pub expires_at: DateTime<Utc>,
+}
+
+/// Lists the devices the caller chose to remember after MFA that have not
+/// expired yet, newest first.
+pub async fn list_trusted_devices(
+  State(state): State<AppState>,
+  AuthUser { user, .. }: AuthUser,
+) -> Result<Json<Vec<TrustedDeviceResponse>>> {
+  let rows = sqlx::query_as::<_, TrustedDeviceRow>(
+    "SELECT id, factor_id, host(ip) as ip, user_agent, created_at, last_used_at, expires_at FROM auth.mfa_trusted_devices WHERE user_id = $1 AND expires_at > now() ORDER BY created_at DESC",
+  )
+  .bind(user.id)
+  .fetch_all(&state.db)
+  .await?;
+
+  Ok(Json(
+    rows
+      .into_iter()
+      .map(|row| TrustedDeviceResponse {
+        id: row.id,
+        factor_id: row.factor_id,
+        ip: row.ip,
+        device: row.user_agent.as_deref().map(parse_user_agent),
+        user_agent: row.user_agent,
+        created_at: row.created_at,
+        last_used_at: row.last_used_at,
+        expires_at: row.expires_at,
+      })
+      .collect(),
+  ))
+}
+
+/// Stops trusting one of the caller's devices, so its next password sign-in
+/// asks for MFA again. Sessions it already has are left alone.
+pub async fn revoke_trusted_device(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  AuthUser { claims, user }: AuthUser,
+  Path(device_id): Path<Uuid>,
+) -> Result<impl IntoResponse> {
+  session::forbid_impersonation(&claims)?;
+  let removed = sqlx::query("DELETE FROM auth.mfa_trusted_devices WHERE id = $1 AND user_id = $2")
+    .bind(device_id)
+    .bind(user.id)
+    .execute(&state.db)
+    .await?
+    .rows_affected();
+  if removed == 0 {
+    return Err(AuthError::TrustedDeviceNotFound);
+  }
+  audit::log_event(
+    &state.db,
+    state.instance_id,
+    Some(client_addr.ip()),
+    "trusted_device_revoked",
+    serde_json::json!({
+      "user_id": user.id,
+      "trusted_device_id": device_id,
+    }),
+  )
+  .await?;
+
+  Ok(StatusCode::NO_CONTENT)
+}
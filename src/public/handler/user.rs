@@ -11,7 +11,9 @@ use uuid::Uuid;
 use crate::auth::{
   audit,
   password,
+  pkce,
   session,
+  trusted_device,
 };
 use crate::error::{
   AuthError,
@@ -23,8 +25,13 @@ use crate::model::{
   UserResponse,
 };
 use crate::public::handler::admin::validate_password_policy;
-use crate::public::handler::signup::is_valid_e164_phone;
+use crate::public::handler::signup::{
+  is_valid_e164_phone,
+  send_confirmation_email,
+};
+use crate::public::handler::sso::merged_app_metadata;
 use crate::state::AppState;
+use crate::utils::sha256_hex;
 
 pub async fn get_user(
   State(state): State<AppState>,
@@ -60,8 +67,19 @@ pub async fn update_user(
 
   let now = Utc::now();
   let changing_sensitive_fields = req.password.is_some() || req.email.is_some() || req.phone.is_some();
-
   if changing_sensitive_fields {
+    session::forbid_impersonation(&claims)?;
+  }
+  // Anonymous users have nothing to reauthenticate with; setting an email or
+  // password is how they become permanent, keeping the same user id.
+  let upgrading_anonymous = user.is_anonymous && (req.email.is_some() || req.password.is_some());
+  if upgrading_anonymous && req.email.is_none() && user.email.is_none() {
+    return Err(AuthError::ValidationFailed(
+      "An email address is required to upgrade an anonymous user".to_string(),
+    ));
+  }
+
+  if changing_sensitive_fields && !user.is_anonymous {
     session::require_reauthentication(
       &state,
       &claims,
@@ -74,9 +92,10 @@ pub async fn update_user(
   }
 
   let mut tx = state.db.begin().await?;
+  let mut confirmation_token = None;
 
   if let Some(ref pw) = req.password {
-    validate_password_policy(pw)?;
+    validate_password_policy(&state, pw, req.email.as_deref().or(user.email.as_deref()))?;
     let hashed = password::hash_password(pw)?;
     sqlx::query("UPDATE auth.users SET encrypted_password = $1, updated_at = $2 WHERE id = $3")
       .bind(hashed)
@@ -97,6 +116,7 @@ pub async fn update_user(
       .bind(current_session_id)
       .execute(&mut *tx)
       .await?;
+    let revoked_trusted_devices = trusted_device::revoke_all(tx.as_mut(), user_id).await?;
     audit::log_event_tx(
       tx.as_mut(),
       state.instance_id,
@@ -105,6 +125,7 @@ pub async fn update_user(
       serde_json::json!({
         "user_id": user_id,
         "revoked_other_sessions": true,
+        "revoked_trusted_devices": revoked_trusted_devices,
       }),
     )
     .await?;
@@ -136,13 +157,36 @@ pub async fn update_user(
         "Email address is already in use".to_string(),
       ));
     }
-    // Clear email_confirmed_at since the email has changed (requires re-confirmation)
-    sqlx::query("UPDATE auth.users SET email = $1, email_confirmed_at = NULL, updated_at = $2 WHERE id = $3")
+    if upgrading_anonymous {
+      // The upgraded address goes through the same confirmation as a signup.
+      let (token, confirmed_at) = if state.mailer_autoconfirm {
+        (None, Some(now))
+      } else {
+        (Some(pkce::email_token(false)), None)
+      };
+      sqlx::query(
+        "UPDATE auth.users SET email = $1, email_confirmed_at = $2, confirmation_token = $3, confirmation_sent_at = $4, updated_at = $5 WHERE id = $6",
+      )
+      .bind(email)
+      .bind(confirmed_at)
+      .bind(token.as_deref().map(sha256_hex))
+      .bind(token.as_ref().map(|_| now))
+      .bind(now)
+      .bind(user_id)
+      .execute(&mut *tx)
+      .await?;
+      confirmation_token = token;
+    } else {
+      // Clear email_confirmed_at since the email has changed (requires re-confirmation)
+      sqlx::query(
+        "UPDATE auth.users SET email = $1, email_confirmed_at = NULL, updated_at = $2 WHERE id = $3",
+      )
       .bind(email)
       .bind(now)
       .bind(user_id)
       .execute(&mut *tx)
       .await?;
+    }
   }
 
   if let Some(ref phone) = req.phone {
@@ -160,8 +204,39 @@ pub async fn update_user(
       .await?;
   }
 
+  if upgrading_anonymous {
+    let mut app_metadata = merged_app_metadata(user.raw_app_meta_data.clone(), "email");
+    if let Some(object) = app_metadata.as_object_mut() {
+      object
+        .entry("provider".to_string())
+        .or_insert_with(|| serde_json::json!("email"));
+    }
+    sqlx::query(
+      "UPDATE auth.users SET is_anonymous = false, raw_app_meta_data = $1, updated_at = $2 WHERE id = $3",
+    )
+    .bind(app_metadata)
+    .bind(now)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+    audit::log_event_tx(
+      tx.as_mut(),
+      state.instance_id,
+      Some(client_addr.ip()),
+      "anonymous_user_upgraded",
+      serde_json::json!({
+        "user_id": user_id,
+      }),
+    )
+    .await?;
+  }
+
   tx.commit().await?;
 
+  if let (Some(token), Some(email)) = (confirmation_token.as_deref(), req.email.as_deref()) {
+    send_confirmation_email(&state, email, token).await;
+  }
+
   let user: User = sqlx::query_as::<_, User>(
         "SELECT id, instance_id, aud, role, email, encrypted_password, email_confirmed_at, phone, phone_confirmed_at, confirmed_at, last_sign_in_at, raw_app_meta_data, raw_user_meta_data, is_super_admin, is_sso_user, is_anonymous, banned_until, deleted_at, created_at, updated_at FROM auth.users WHERE id = $1"
     )
@@ -1,8 +1,10 @@
 use axum::Json;
 use axum::extract::{
   ConnectInfo,
+  Query,
   State,
 };
+use axum::response::Redirect;
 use chrono::Utc;
 use serde::Deserialize;
 use std::net::{
@@ -11,6 +13,7 @@ use std::net::{
 };
 
 use crate::auth::{
+  pkce,
   rate_limit,
   session,
 };
@@ -23,7 +26,10 @@ use crate::model::{
   UserResponse,
   VerifyGrantResponse,
 };
-use crate::public::handler::mfa;
+use crate::public::handler::{
+  mfa,
+  sso,
+};
 use crate::state::AppState;
 use crate::utils::sha256_hex;
 
@@ -57,6 +63,56 @@ pub async fn verify(
   }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct VerifyLinkQuery {
+  #[serde(rename = "type")]
+  pub verify_type: String,
+  pub token: String,
+}
+
+/// Email links of PKCE flows open here. The link is verified and the browser
+/// goes back to the flow's `redirect_to` with a `code` for `grant_type=pkce`.
+pub async fn verify_link(
+  State(state): State<AppState>,
+  ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+  Query(query): Query<VerifyLinkQuery>,
+) -> Result<Redirect> {
+  if !pkce::is_pkce_token(&query.token) {
+    return Err(AuthError::ValidationFailed(
+      "This link has no PKCE flow; verify it with POST /verify".to_string(),
+    ));
+  }
+
+  let client_ip = client_addr.ip();
+  let now = Utc::now();
+  let (user, authentication_method) = match query.verify_type.as_str() {
+    "signup" => (
+      consume_confirmation_token(&state, &query.token, client_ip, now).await?,
+      pkce::METHOD_SIGNUP,
+    ),
+    "recovery" => (
+      consume_recovery_token(&state, &query.token, client_ip, now).await?,
+      pkce::METHOD_RECOVERY,
+    ),
+    "magiclink" => (
+      consume_magic_link_token(&state, &query.token, client_ip, now).await?,
+      pkce::METHOD_MAGICLINK,
+    ),
+    _ => {
+      return Err(AuthError::ValidationFailed(format!(
+        "Unsupported verify type: {}",
+        query.verify_type
+      )));
+    },
+  };
+  session::ensure_user_is_active(&user)?;
+
+  let (auth_code, redirect_to) =
+    pkce::issue_email_auth_code(&state.db, user.id, authentication_method).await?;
+  let redirect_to = sso::validated_redirect_to(&state, redirect_to.as_deref())?;
+  Ok(Redirect::to(&sso::build_redirect_url(redirect_to, &auth_code)?))
+}
+
 async fn handle_signup_verify(
   state: AppState,
   client_ip: IpAddr,
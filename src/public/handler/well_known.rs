@@ -0,0 +1,65 @@
+use axum::Json;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+
+use crate::auth::oauth;
+use crate::state::AppState;
+
+/// Publishes the public halves of the asymmetric signing keys so downstream
+/// services can verify access tokens without holding a shared secret.
+pub async fn jwks(State(state): State<AppState>) -> impl IntoResponse {
+  (
+    [(header::CACHE_CONTROL, "public, max-age=600")],
+    Json(state.jwt_keys.read().await.jwks()),
+  )
+}
+
+/// OpenID Connect discovery for clients that sign users in through Haya.
+/// Endpoints are derived from the issuer, so `JWT_ISSUER` must be Haya's public URL.
+/// Without an asymmetric signing key no id_tokens are issued, so `openid` and
+/// the id_token algorithms are left out and only the OAuth 2.0 metadata remains.
+pub async fn openid_configuration(State(state): State<AppState>) -> impl IntoResponse {
+  let issuer = state.issuer.trim_end_matches('/');
+  let id_token_algorithm = state
+    .jwt_keys
+    .read()
+    .await
+    .id_token_signing_key()
+    .map(|key| format!("{:?}", key.algorithm));
+  let mut document = serde_json::json!({
+    "issuer": state.issuer,
+    "authorization_endpoint": format!("{issuer}/oauth/authorize"),
+    "token_endpoint": format!("{issuer}/oauth/token"),
+    "device_authorization_endpoint": format!("{issuer}/oauth/device/authorize"),
+    "userinfo_endpoint": format!("{issuer}/oauth/userinfo"),
+    "introspection_endpoint": format!("{issuer}/oauth/introspect"),
+    "revocation_endpoint": format!("{issuer}/oauth/revoke"),
+    "jwks_uri": format!("{issuer}/.well-known/jwks.json"),
+    "response_types_supported": ["code"],
+    "grant_types_supported": oauth::GRANT_TYPES,
+    "subject_types_supported": ["public"],
+    "scopes_supported": oauth::SUPPORTED_SCOPES,
+    "claims_supported": oauth::SUPPORTED_CLAIMS,
+    "token_endpoint_auth_methods_supported": oauth::TOKEN_ENDPOINT_AUTH_METHODS,
+    "introspection_endpoint_auth_methods_supported": [
+      oauth::AUTH_METHOD_CLIENT_SECRET_BASIC,
+      oauth::AUTH_METHOD_CLIENT_SECRET_POST,
+    ],
+    "revocation_endpoint_auth_methods_supported": oauth::TOKEN_ENDPOINT_AUTH_METHODS,
+    "code_challenge_methods_supported": ["S256"],
+  });
+  match id_token_algorithm {
+    Some(algorithm) => {
+      document["id_token_signing_alg_values_supported"] = serde_json::json!([algorithm]);
+    },
+    None => {
+      document["scopes_supported"] = oauth::SUPPORTED_SCOPES
+        .iter()
+        .filter(|scope| **scope != oauth::SCOPE_OPENID)
+        .copied()
+        .collect();
+    },
+  }
+  ([(header::CACHE_CONTROL, "public, max-age=600")], Json(document))
+}
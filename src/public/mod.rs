@@ -7,6 +7,7 @@ use std::time::Duration;
 use tokio::net::TcpListener;
 
 use crate::auth::{
+  keyring,
   oidc,
   rate_limit,
 };
@@ -26,6 +27,8 @@ pub async fn serve(port: u16, state: AppState) -> anyhow::Result<()> {
         let providers = oidc::load_providers_from_db(&reload_state.db).await?;
         *reload_state.oidc_providers.write().await = providers;
         tracing::info!("Reloaded OIDC provider configuration");
+        keyring::reload(&reload_state).await?;
+        tracing::info!("Reloaded JWT signing keys");
         Ok(())
       }
     })
@@ -3,6 +3,7 @@ use std::time::Duration;
 use axum::Router;
 use axum::extract::DefaultBodyLimit;
 use axum::http::{
+  HeaderName,
   HeaderValue,
   Method,
 };
@@ -64,6 +65,9 @@ fn build_cors_layer() -> CorsLayer {
       axum::http::header::CONTENT_TYPE,
       axum::http::header::ACCEPT,
     ])
+    .expose_headers([HeaderName::from_static(
+      handler::mfa::RECOVERY_CODES_REMAINING_HEADER,
+    )])
     .allow_credentials(false)
     .max_age(Duration::from_secs(3600))
 }
@@ -91,14 +95,40 @@ pub fn create_router(state: AppState) -> Router {
   Router::new()
     .route("/health", get(handler::health::health_check))
     .route("/settings", get(handler::settings::get_settings))
+    .route("/.well-known/jwks.json", get(handler::well_known::jwks))
+    .route(
+      "/.well-known/openid-configuration",
+      get(handler::well_known::openid_configuration),
+    )
+    .route("/oauth/authorize", get(handler::oauth::authorize))
+    .route(
+      "/oauth/authorizations/{id}",
+      get(handler::oauth::get_authorization).post(handler::oauth::decide_authorization),
+    )
+    .route("/oauth/token", post(handler::oauth::token))
+    .route("/oauth/device/authorize", post(handler::device::authorize))
+    .route(
+      "/oauth/device",
+      get(handler::device::get_device_authorization).post(handler::device::decide_device_authorization),
+    )
+    .route("/oauth/introspect", post(handler::oauth::introspect))
+    .route("/oauth/revoke", post(handler::oauth::revoke))
+    .route(
+      "/oauth/userinfo",
+      get(handler::oauth::userinfo).post(handler::oauth::userinfo),
+    )
     .route("/authorize", get(handler::sso::authorize))
+    .route("/user/identities/authorize", get(handler::sso::link_identity))
     .route(
       "/callback",
       get(handler::sso::callback).post(handler::sso::callback_form),
     )
     .route("/signup", post(handler::signup::signup))
     .route("/token", post(handler::token::token))
-    .route("/verify", post(handler::verify::verify))
+    .route(
+      "/verify",
+      get(handler::verify::verify_link).post(handler::verify::verify),
+    )
     .route("/recover", post(handler::recover::recover))
     .route("/reauthenticate", post(handler::reauthenticate::reauthenticate))
     .route("/resend", post(handler::resend::resend))
@@ -106,10 +136,17 @@ pub fn create_router(state: AppState) -> Router {
     .route("/otp", post(handler::otp::send_otp))
     .route(
       "/factors",
-      get(handler::mfa::list_factors).post(handler::mfa::create_totp_factor),
+      get(handler::mfa::list_factors).post(handler::mfa::create_factor),
     )
     .route("/mfa/factors", post(handler::mfa::list_pending_factors))
-    .route("/factors/{id}/verify", post(handler::mfa::verify_totp_factor))
+    .route("/mfa/challenge", post(handler::mfa::challenge_pending_factor))
+    .route("/webauthn/challenge", post(handler::passkey::challenge))
+    .route(
+      "/factors/recovery-codes",
+      post(handler::mfa::generate_recovery_codes),
+    )
+    .route("/factors/{id}/challenge", post(handler::mfa::challenge_factor))
+    .route("/factors/{id}/verify", post(handler::mfa::verify_factor))
     .route(
       "/factors/{id}",
       axum::routing::delete(handler::mfa::delete_factor),
@@ -119,6 +156,19 @@ pub fn create_router(state: AppState) -> Router {
       "/user",
       get(handler::user::get_user).put(handler::user::update_user),
     )
+    .route("/user/sessions", get(handler::session::list_sessions))
+    .route(
+      "/user/sessions/{id}",
+      axum::routing::delete(handler::session::revoke_session),
+    )
+    .route(
+      "/user/trusted_devices",
+      get(handler::trusted_device::list_trusted_devices),
+    )
+    .route(
+      "/user/trusted_devices/{id}",
+      axum::routing::delete(handler::trusted_device::revoke_trusted_device),
+    )
     .route(
       "/admin/users",
       get(handler::admin::admin_list_users).post(handler::admin::admin_create_user),
@@ -0,0 +1,206 @@
+//! Outbound SMS for phone MFA codes.
+//!
+//! ## Configuration (environment variables)
+//! | Variable            | Default                | Description                                      |
+//! |---------------------|------------------------|--------------------------------------------------|
+//! | `SMS_PROVIDER`      | *(required to enable)* | `http` or `file`                                 |
+//! | `SMS_HTTP_URL`      | *(required for http)*  | Twilio-compatible messages endpoint              |
+//! | `SMS_HTTP_USERNAME` |                        | Basic auth username, e.g. a Twilio account SID   |
+//! | `SMS_HTTP_PASSWORD` |                        | Basic auth password, e.g. a Twilio auth token    |
+//! | `SMS_FROM`          |                        | Sender number or alphanumeric sender ID          |
+//! | `SMS_FILE_PATH`     | *(stdout)*             | File the `file` provider appends messages to     |
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
+
+use anyhow::Context as _;
+
+const HTTP_SEND_TIMEOUT_SECS: u64 = 10;
+
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+/// Delivers a text message to an E.164 phone number.
+pub trait SmsSender: Debug + Send + Sync {
+  fn send<'a>(&'a self, to: &'a str, body: &'a str) -> SendFuture<'a>;
+}
+
+/// Builds the sender selected by `SMS_PROVIDER`.
+pub fn from_config(
+  provider: &str,
+  http_url: Option<String>,
+  http_username: Option<String>,
+  http_password: Option<String>,
+  from: Option<String>,
+  file_path: Option<String>,
+) -> anyhow::Result<Box<dyn SmsSender>> {
+  match provider {
+    "http" => {
+      let url = http_url.context("SMS_HTTP_URL is required when SMS_PROVIDER is http")?;
+      Ok(Box::new(HttpSmsSender::new(
+        &url,
+        http_username,
+        http_password,
+        from,
+      )?))
+    },
+    "file" => Ok(Box::new(FileSmsSender {
+      path: file_path.map(PathBuf::from),
+    })),
+    other => anyhow::bail!("SMS_PROVIDER must be http or file, got {other}"),
+  }
+}
+
+/// Posts `To`, `From` and `Body` as a form to a Twilio-compatible endpoint,
+/// such as `https://api.twilio.com/2010-04-01/Accounts/<sid>/Messages.json`.
+pub struct HttpSmsSender {
+  client: reqwest::Client,
+  url: url::Url,
+  username: Option<String>,
+  password: Option<String>,
+  from: Option<String>,
+}
+
+impl Debug for HttpSmsSender {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("HttpSmsSender")
+      .field("url", &self.url.as_str())
+      .field("from", &self.from)
+      .finish_non_exhaustive()
+  }
+}
+
+impl HttpSmsSender {
+  pub fn new(
+    url: &str,
+    username: Option<String>,
+    password: Option<String>,
+    from: Option<String>,
+  ) -> anyhow::Result<Self> {
+    let url = url::Url::parse(url).context("SMS_HTTP_URL is not a valid URL")?;
+    if !matches!(url.scheme(), "http" | "https") {
+      anyhow::bail!("SMS_HTTP_URL must be an http(s) URL");
+    }
+    let client = reqwest::Client::builder()
+      .timeout(Duration::from_secs(HTTP_SEND_TIMEOUT_SECS))
+      .build()?;
+    Ok(Self {
+      client,
+      url,
+      username,
+      password,
+      from,
+    })
+  }
+}
+
+impl SmsSender for HttpSmsSender {
+  fn send<'a>(&'a self, to: &'a str, body: &'a str) -> SendFuture<'a> {
+    Box::pin(async move {
+      let mut form = vec![("To", to), ("Body", body)];
+      if let Some(from) = self.from.as_deref() {
+        form.push(("From", from));
+      }
+      let mut request = self.client.post(self.url.clone()).form(&form);
+      if let Some(username) = self.username.as_deref() {
+        request = request.basic_auth(username, self.password.as_deref());
+      }
+      let response = request.send().await.context("SMS provider request failed")?;
+      let status = response.status();
+      if !status.is_success() {
+        anyhow::bail!("SMS provider returned {status}");
+      }
+      Ok(())
+    })
+  }
+}
+
+/// Writes each message as a line to a file, or to stdout when no path is
+/// set. Meant for development, where no real provider is available.
+#[derive(Debug)]
+pub struct FileSmsSender {
+  pub path: Option<PathBuf>,
+}
+
+impl SmsSender for FileSmsSender {
+  fn send<'a>(&'a self, to: &'a str, body: &'a str) -> SendFuture<'a> {
+    Box::pin(async move {
+      let line = format!("{} to={to} body={body:?}\n", chrono::Utc::now().to_rfc3339());
+      match self.path.as_ref() {
+        Some(path) => {
+          let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+          file.write_all(line.as_bytes())?;
+        },
+        None => print!("{line}"),
+      }
+      Ok(())
+    })
+  }
+}
+
+/// Records messages instead of sending them.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockSmsSender {
+  pub sent: std::sync::Mutex<Vec<(String, String)>>,
+}
+
+#[cfg(test)]
+impl SmsSender for MockSmsSender {
+  fn send<'a>(&'a self, to: &'a str, body: &'a str) -> SendFuture<'a> {
+    self
+      .sent
+      .lock()
+      .expect("mock SMS sender lock")
+      .push((to.to_string(), body.to_string()));
+    Box::pin(async { Ok(()) })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn from_config_rejects_unknown_provider() {
+    assert!(from_config("carrier-pigeon", None, None, None, None, None).is_err());
+  }
+
+  #[test]
+  fn http_provider_requires_url() {
+    assert!(from_config("http", None, None, None, None, None).is_err());
+    assert!(
+      from_config(
+        "http",
+        Some("ftp://example.com".to_string()),
+        None,
+        None,
+        None,
+        None
+      )
+      .is_err()
+    );
+  }
+
+  #[tokio::test]
+  async fn file_provider_appends_messages() {
+    let path = std::env::temp_dir().join(format!("haya-sms-{}.log", uuid::Uuid::new_v4()));
+    let sender = FileSmsSender {
+      path: Some(path.clone()),
+    };
+    sender.send("+15555550100", "first").await.unwrap();
+    sender.send("+15555550100", "second").await.unwrap();
+
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(written.lines().count(), 2);
+    assert!(written.contains("to=+15555550100 body=\"second\""));
+  }
+}
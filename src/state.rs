@@ -5,23 +5,72 @@ use sqlx::PgPool;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
+use crate::auth::binding::SessionBindingPolicy;
+use crate::auth::breached_password::BreachedPasswords;
+use crate::auth::captcha::Captcha;
+use crate::auth::hook::AccessTokenHook;
+use crate::auth::jwt::JwtKeyring;
+use crate::auth::mfa::{
+  EncryptionKeys,
+  TotpPolicy,
+};
 use crate::auth::oidc::OidcProviderConfig;
+use crate::auth::password_policy::PasswordPolicy;
+use crate::auth::session_limits::SessionLimitPolicy;
+use crate::auth::webauthn::RelyingParty;
 use crate::mailer::Mailer;
+use crate::sms::SmsSender;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
   pub db: PgPool,
   pub http_client: reqwest::Client,
-  pub jwt_secret: String,
-  pub mfa_encryption_key: [u8; 32],
+  /// Keys from `JWT_SECRET` / `JWT_SIGNING_KEY_PATH`; the fallback when no database key is active
+  pub jwt_configured_keys: Arc<JwtKeyring>,
+  /// Keys used to sign new access tokens and verify presented ones, reloaded on SIGHUP
+  pub jwt_keys: Arc<RwLock<JwtKeyring>>,
+  /// Keys that encrypt MFA secrets and signing keys at rest (env: `MFA_ENCRYPTION_KEY`, `MFA_ENCRYPTION_KEYS`)
+  pub mfa_keys: Arc<EncryptionKeys>,
+  /// Parameters for new TOTP factors and the accepted clock skew
+  pub totp: TotpPolicy,
+  /// Longest a device can skip MFA after "remember this device"; `0` disables
+  /// it (env: `MFA_TRUSTED_DEVICE_MAX_LIFETIME_SECS`)
+  pub trusted_device_max_lifetime_secs: i64,
   pub jwt_exp: i64,
+  /// Lifetime of `client_credentials` access tokens (env: `CLIENT_CREDENTIALS_JWT_EXPIRY`)
+  pub client_credentials_jwt_exp: i64,
   pub refresh_token_exp: i64,
   pub session_idle_timeout_secs: i64,
+  /// Seconds a rotated refresh token may still be presented and get its child
+  /// back (env: `REFRESH_TOKEN_REUSE_INTERVAL`)
+  pub refresh_token_reuse_interval: i64,
+  /// Key that rotated refresh tokens are derived with (env: `REFRESH_TOKEN_SECRET`)
+  pub refresh_token_reuse_key: [u8; 32],
+  /// Default refresh binding for sessions whose client sets none (env: `SESSION_BINDING_POLICY`)
+  pub session_binding: SessionBindingPolicy,
+  /// Maximum session age and sessions per user, by role
+  pub session_limits: Arc<SessionLimitPolicy>,
+  /// WebAuthn relying party that security keys and passkeys are scoped to
+  pub webauthn: Arc<RelyingParty>,
+  /// Rewrites access-token claims before signing (env: `ACCESS_TOKEN_HOOK_URI`)
+  pub access_token_hook: Option<Arc<AccessTokenHook>>,
+  /// Whether `/signup` without credentials creates an anonymous user (env: `ANONYMOUS_SIGN_INS_ENABLED`)
+  pub anonymous_sign_ins: bool,
+  /// Rules new passwords must follow (env: `PASSWORD_*`)
+  pub password_policy: Arc<PasswordPolicy>,
+  /// Breached passwords that new passwords are checked against (env: `BREACHED_PASSWORDS_PATH`)
+  pub breached_passwords: Option<Arc<BreachedPasswords>>,
+  /// CAPTCHA required for anonymous sign-ins (env: `CAPTCHA_PROVIDER`)
+  pub captcha: Option<Arc<Captcha>>,
   /// Base URL of the site (used for generating email links in recovery/confirmation)
   pub site_url: String,
   pub allowed_redirect_origins: Vec<String>,
   pub allowed_redirect_path_prefixes: Vec<String>,
   pub oidc_form_post: bool,
+  /// Page that signs the user in and approves `/oauth/authorize` requests (env: `OAUTH_CONSENT_URL`)
+  pub oauth_consent_url: String,
+  /// Page where users enter a device's `user_code` (env: `OAUTH_DEVICE_VERIFICATION_URL`)
+  pub oauth_device_verification_url: String,
   /// Display name shown in email templates (env: `SITE_NAME`)
   pub site_name: String,
   pub issuer: String,
@@ -32,6 +81,8 @@ pub struct AppState {
   pub mailer_autoconfirm: bool,
   /// SMTP mailer; `None` when `SMTP_HOST` is not configured
   pub mailer: Option<Arc<Mailer>>,
+  /// Sends phone MFA codes; `None` when `SMS_PROVIDER` is not configured
+  pub sms: Option<Arc<dyn SmsSender>>,
 }
 
 #[derive(Debug, Clone)]
@@ -44,15 +95,69 @@ pub struct RuntimeConfig {
   pub allowed_redirect_path_prefixes: Vec<String>,
   pub oidc_form_post: bool,
   pub cors_allowed_origins: Vec<String>,
+  pub oauth_consent_url: String,
+  pub oauth_device_verification_url: String,
   pub site_name: String,
   pub issuer: String,
   pub jwt_exp: i64,
+  pub client_credentials_jwt_exp: i64,
   pub refresh_token_exp: i64,
   pub session_idle_timeout_secs: i64,
+  pub refresh_token_reuse_interval: i64,
+  /// Whether `REFRESH_TOKEN_SECRET` is set: `env` or `unset`
+  pub refresh_token_secret_source: &'static str,
+  pub session_binding_policy: String,
+  /// Why `SESSION_BINDING_POLICY` could not be parsed, if it could not
+  pub session_binding_error: Option<String>,
+  pub session_max_lifetime_secs: i64,
+  pub session_max_per_user: i64,
+  pub session_limit_strategy: String,
+  /// Roles with their own entry in `SESSION_ROLE_LIMITS`
+  pub session_limit_roles: Vec<String>,
+  /// Why the session limit settings could not be loaded, if they could not
+  pub session_limits_error: Option<String>,
+  pub webauthn_rp_id: String,
+  pub webauthn_origins: Vec<String>,
+  /// Why the WebAuthn settings could not be loaded, if they could not
+  pub webauthn_error: Option<String>,
+  pub access_token_hook_uri: Option<String>,
+  pub access_token_hook_timeout_ms: u64,
+  /// Why `ACCESS_TOKEN_HOOK_URI` / `ACCESS_TOKEN_HOOK_SECRET` could not be loaded, if they could not
+  pub access_token_hook_error: Option<String>,
+  pub anonymous_sign_ins: bool,
+  pub password_min_length: usize,
+  pub password_max_length: usize,
+  pub password_required_characters: Vec<String>,
+  pub password_banned_words: Vec<String>,
+  pub password_min_score: u8,
+  /// Why the `PASSWORD_*` settings could not be loaded, if they could not
+  pub password_policy_error: Option<String>,
+  pub breached_passwords_path: Option<String>,
+  /// Why `BREACHED_PASSWORDS_PATH` cannot be used, if it cannot
+  pub breached_passwords_error: Option<String>,
+  pub captcha_provider: Option<String>,
+  /// Why `CAPTCHA_PROVIDER` / `CAPTCHA_SECRET` could not be loaded, if they could not
+  pub captcha_error: Option<String>,
   pub pid_file: String,
   pub jwt_secret_len: usize,
+  pub jwt_algorithm: String,
+  pub jwt_signing_key_path: Option<String>,
   pub mailer_autoconfirm: bool,
   pub smtp_configured: bool,
+  pub sms_provider: Option<String>,
+  /// Why the `SMS_*` settings could not be loaded, if they could not
+  pub sms_error: Option<String>,
   pub dev_mode: bool,
   pub mfa_key_source: &'static str,
+  /// Prefix new encrypted values are written with, such as `v1` or `v2:<key id>`
+  pub mfa_key_version: String,
+  /// Why `MFA_ENCRYPTION_KEYS` could not be loaded, if it could not
+  pub mfa_key_error: Option<String>,
+  pub totp_algorithm: String,
+  pub totp_digits: u32,
+  pub totp_period: i64,
+  pub totp_skew_steps: i64,
+  /// Why the `MFA_TOTP_*` settings could not be loaded, if they could not
+  pub totp_error: Option<String>,
+  pub trusted_device_max_lifetime_secs: i64,
 }
@@ -3,6 +3,7 @@ use std::path::PathBuf;
 use std::time::Duration;
 
 use anyhow::Context;
+use hmac::Mac as _;
 use sha2::{
   Digest,
   Sha256,
@@ -16,6 +17,20 @@ pub fn sha256_hex(value: &str) -> String {
   format!("{digest:x}")
 }
 
+/// Compares two secrets without leaking where they differ: both are MACed
+/// and the tags compared in constant time, so unequal lengths are safe too.
+pub fn constant_time_eq(left: &str, right: &str) -> bool {
+  let mut left_mac =
+    hmac::Hmac::<Sha256>::new_from_slice(b"haya-constant-time-compare").expect("static key is valid");
+  left_mac.update(left.as_bytes());
+  let left_tag = left_mac.finalize().into_bytes();
+
+  let mut right_mac =
+    hmac::Hmac::<Sha256>::new_from_slice(b"haya-constant-time-compare").expect("static key is valid");
+  right_mac.update(right.as_bytes());
+  right_mac.verify_slice(&left_tag).is_ok()
+}
+
 pub async fn shutdown_signal() {
   let ctrl_c = async {
     signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
@@ -43,6 +43,7 @@ use uuid::Uuid;
 
 const JWT_SECRET: &str = "integration-test-jwt-secret-with-32-bytes";
 const MFA_KEY_MATERIAL: &str = "integration-test-mfa-key-material-32bytes";
+const REFRESH_TOKEN_SECRET: &str = "integration-test-refresh-token-secret-32b";
 const TEST_ISSUER: &str = "https://haya.test.invalid";
 const TOTP_PERIOD_SECS: i64 = 30;
 const TOTP_DIGITS: u32 = 6;
@@ -82,6 +83,8 @@ struct TestClaims {
   is_anonymous: bool,
   user_metadata: serde_json::Value,
   app_metadata: serde_json::Value,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  act: Option<serde_json::Value>,
 }
 
 fn database_url() -> Option<String> {
@@ -106,6 +109,7 @@ async fn migrate_database(database_url: &str) {
     .env("DATABASE_URL", database_url)
     .env("JWT_SECRET", JWT_SECRET)
     .env("MFA_ENCRYPTION_KEY", MFA_KEY_MATERIAL)
+    .env("REFRESH_TOKEN_SECRET", REFRESH_TOKEN_SECRET)
     .arg("db")
     .arg("migrate")
     .status()
@@ -140,6 +144,7 @@ async fn test_context() -> Option<TestContext> {
     .env("DATABASE_URL", &database_url)
     .env("JWT_SECRET", JWT_SECRET)
     .env("MFA_ENCRYPTION_KEY", MFA_KEY_MATERIAL)
+    .env("REFRESH_TOKEN_SECRET", REFRESH_TOKEN_SECRET)
     .env("JWT_ISSUER", TEST_ISSUER)
     .env("PORT", port.to_string())
     .env("SITE_URL", &site_url)
@@ -220,6 +225,17 @@ fn issue_access_token(
   user_id: Uuid,
   session_id: Uuid,
   email: &str,
+) -> String {
+  issue_access_token_with_actor(issuer, jwt_secret, user_id, session_id, email, None)
+}
+
+fn issue_access_token_with_actor(
+  issuer: &str,
+  jwt_secret: &str,
+  user_id: Uuid,
+  session_id: Uuid,
+  email: &str,
+  act: Option<serde_json::Value>,
 ) -> String {
   let now = Utc::now().timestamp();
   let claims = TestClaims {
@@ -237,6 +253,7 @@ fn issue_access_token(
     is_anonymous: false,
     user_metadata: serde_json::json!({}),
     app_metadata: serde_json::json!({}),
+    act,
   };
 
   encode(
@@ -463,3 +480,280 @@ async fn totp_enrollment_blocks_after_too_many_attempts() {
 
   cleanup_user(&ctx.pool, user_id).await;
 }
+
+async fn insert_refresh_token(pool: &PgPool, user_id: Uuid, session_id: Uuid) -> String {
+  let token = unique_suffix();
+  let now = Utc::now();
+  sqlx::query(
+    "INSERT INTO auth.refresh_tokens (user_id, token, session_id, revoked, created_at, updated_at) VALUES ($1, $2, $3, false, $4, $4)",
+  )
+  .bind(user_id.to_string())
+  .bind(format!("{:x}", Sha256::digest(token.as_bytes())))
+  .bind(session_id)
+  .bind(now)
+  .execute(pool)
+  .await
+  .expect("insert refresh token");
+  token
+}
+
+async fn refresh(ctx: &TestContext, refresh_token: &str) -> reqwest::Response {
+  ctx
+    .client
+    .post(format!("{}/token?grant_type=refresh_token", ctx.base_url))
+    .json(&serde_json::json!({ "refresh_token": refresh_token }))
+    .send()
+    .await
+    .expect("call token refresh")
+}
+
+#[tokio::test]
+async fn rotated_refresh_token_is_reusable_only_within_the_reuse_interval() {
+  let Some(ctx) = test_context().await else {
+    return;
+  };
+
+  let email = format!("refresh-reuse-{}@example.com", unique_suffix());
+  let user_id = insert_user(&ctx.pool, &email).await;
+  let session_id = create_session(&ctx.pool, user_id).await;
+  let parent = insert_refresh_token(&ctx.pool, user_id, session_id).await;
+
+  let first = refresh(&ctx, &parent).await;
+  assert_eq!(first.status(), StatusCode::OK);
+  let first: serde_json::Value = first.json().await.expect("decode first refresh");
+  let child = first["refresh_token"]
+    .as_str()
+    .expect("child refresh token")
+    .to_string();
+  assert_ne!(child, parent);
+
+  // A client that lost the rotation race gets the same child back.
+  let raced = refresh(&ctx, &parent).await;
+  assert_eq!(raced.status(), StatusCode::OK);
+  let raced: serde_json::Value = raced.json().await.expect("decode raced refresh");
+  assert_eq!(raced["refresh_token"].as_str(), Some(child.as_str()));
+
+  // Past REFRESH_TOKEN_REUSE_INTERVAL the parent is treated as stolen and the
+  // whole family is revoked, child included.
+  sqlx::query("UPDATE auth.refresh_tokens SET updated_at = $1 WHERE token = $2")
+    .bind(Utc::now() - chrono::Duration::seconds(60))
+    .bind(format!("{:x}", Sha256::digest(parent.as_bytes())))
+    .execute(&ctx.pool)
+    .await
+    .expect("age rotated refresh token");
+  let replayed = refresh(&ctx, &parent).await;
+  assert_eq!(replayed.status(), StatusCode::UNAUTHORIZED);
+
+  let session: Option<Uuid> = sqlx::query_scalar("SELECT id FROM auth.sessions WHERE id = $1")
+    .bind(session_id)
+    .fetch_optional(&ctx.pool)
+    .await
+    .expect("fetch session");
+  assert_eq!(session, None);
+  assert_eq!(refresh(&ctx, &child).await.status(), StatusCode::UNAUTHORIZED);
+
+  cleanup_user(&ctx.pool, user_id).await;
+}
+
+async fn list_sessions(ctx: &TestContext, access_token: &str) -> Vec<serde_json::Value> {
+  let response = ctx
+    .client
+    .get(format!("{}/user/sessions", ctx.base_url))
+    .bearer_auth(access_token)
+    .send()
+    .await
+    .expect("call list sessions");
+  assert_eq!(response.status(), StatusCode::OK);
+  response.json().await.expect("decode sessions")
+}
+
+async fn revoke_session(ctx: &TestContext, access_token: &str, session_id: Uuid) -> reqwest::Response {
+  ctx
+    .client
+    .delete(format!("{}/user/sessions/{session_id}", ctx.base_url))
+    .bearer_auth(access_token)
+    .send()
+    .await
+    .expect("call revoke session")
+}
+
+#[tokio::test]
+async fn user_sessions_lists_only_the_callers_sessions() {
+  let Some(ctx) = test_context().await else {
+    return;
+  };
+
+  let email = format!("sessions-list-{}@example.com", unique_suffix());
+  let user_id = insert_user(&ctx.pool, &email).await;
+  let current_session = create_session(&ctx.pool, user_id).await;
+  let other_session = create_session(&ctx.pool, user_id).await;
+  let stranger_email = format!("sessions-stranger-{}@example.com", unique_suffix());
+  let stranger_id = insert_user(&ctx.pool, &stranger_email).await;
+  let stranger_session = create_session(&ctx.pool, stranger_id).await;
+
+  let access_token = issue_access_token(&ctx.issuer, &ctx.jwt_secret, user_id, current_session, &email);
+  let sessions = list_sessions(&ctx, &access_token).await;
+
+  let mut listed: Vec<(String, bool)> = sessions
+    .iter()
+    .map(|session| {
+      (
+        session["id"].as_str().expect("session id").to_string(),
+        session["current"].as_bool().expect("current flag"),
+      )
+    })
+    .collect();
+  listed.sort();
+  let mut expected = vec![
+    (current_session.to_string(), true),
+    (other_session.to_string(), false),
+  ];
+  expected.sort();
+  assert_eq!(listed, expected);
+  assert!(!listed.iter().any(|(id, _)| *id == stranger_session.to_string()));
+
+  cleanup_user(&ctx.pool, user_id).await;
+  cleanup_user(&ctx.pool, stranger_id).await;
+}
+
+#[tokio::test]
+async fn revoking_a_session_is_limited_to_the_caller_and_revokes_its_refresh_tokens() {
+  let Some(ctx) = test_context().await else {
+    return;
+  };
+
+  let email = format!("sessions-revoke-{}@example.com", unique_suffix());
+  let user_id = insert_user(&ctx.pool, &email).await;
+  let current_session = create_session(&ctx.pool, user_id).await;
+  let other_session = create_session(&ctx.pool, user_id).await;
+  let other_refresh_token = insert_refresh_token(&ctx.pool, user_id, other_session).await;
+  let stranger_email = format!("sessions-victim-{}@example.com", unique_suffix());
+  let stranger_id = insert_user(&ctx.pool, &stranger_email).await;
+  let stranger_session = create_session(&ctx.pool, stranger_id).await;
+  let stranger_refresh_token = insert_refresh_token(&ctx.pool, stranger_id, stranger_session).await;
+
+  let access_token = issue_access_token(&ctx.issuer, &ctx.jwt_secret, user_id, current_session, &email);
+
+  // Someone else's session id looks exactly like an unknown one.
+  let response = revoke_session(&ctx, &access_token, stranger_session).await;
+  assert_eq!(response.status(), StatusCode::NOT_FOUND);
+  let stranger_remaining: Option<Uuid> = sqlx::query_scalar("SELECT id FROM auth.sessions WHERE id = $1")
+    .bind(stranger_session)
+    .fetch_optional(&ctx.pool)
+    .await
+    .expect("fetch stranger session");
+  assert_eq!(stranger_remaining, Some(stranger_session));
+  assert_eq!(
+    refresh(&ctx, &stranger_refresh_token).await.status(),
+    StatusCode::OK
+  );
+
+  let response = revoke_session(&ctx, &access_token, other_session).await;
+  assert_eq!(response.status(), StatusCode::NO_CONTENT);
+  let active_tokens: i64 =
+    sqlx::query_scalar("SELECT count(*) FROM auth.refresh_tokens WHERE session_id = $1 AND revoked = false")
+      .bind(other_session)
+      .fetch_one(&ctx.pool)
+      .await
+      .expect("count refresh tokens");
+  assert_eq!(active_tokens, 0);
+  assert_eq!(
+    refresh(&ctx, &other_refresh_token).await.status(),
+    StatusCode::UNAUTHORIZED
+  );
+
+  let sessions = list_sessions(&ctx, &access_token).await;
+  assert_eq!(sessions.len(), 1);
+  assert_eq!(
+    sessions[0]["id"].as_str(),
+    Some(current_session.to_string().as_str())
+  );
+
+  cleanup_user(&ctx.pool, user_id).await;
+  cleanup_user(&ctx.pool, stranger_id).await;
+}
+
+#[tokio::test]
+async fn impersonated_tokens_cannot_change_credentials_or_enroll_factors() {
+  let Some(ctx) = test_context().await else {
+    return;
+  };
+
+  let email = format!("impersonated-{}@example.com", unique_suffix());
+  let user_id = insert_user(&ctx.pool, &email).await;
+  let session_id = create_session(&ctx.pool, user_id).await;
+  let admin_id = Uuid::new_v4();
+  let access_token = issue_access_token_with_actor(
+    &ctx.issuer,
+    &ctx.jwt_secret,
+    user_id,
+    session_id,
+    &email,
+    Some(serde_json::json!({ "sub": admin_id.to_string(), "email": "admin@example.com" })),
+  );
+
+  let credential_changes = [
+    serde_json::json!({ "password": "an-impersonator-password-123" }),
+    serde_json::json!({ "email": format!("taken-over-{}@example.com", unique_suffix()) }),
+  ];
+  for body in credential_changes {
+    let response = ctx
+      .client
+      .put(format!("{}/user", ctx.base_url))
+      .bearer_auth(&access_token)
+      .json(&body)
+      .send()
+      .await
+      .expect("call update user");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN, "PUT /user with {body}");
+    let body: serde_json::Value = response.json().await.expect("decode update user error");
+    assert_eq!(body["error_code"].as_str(), Some("impersonation_forbidden"));
+  }
+
+  let response = ctx
+    .client
+    .post(format!("{}/factors", ctx.base_url))
+    .bearer_auth(&access_token)
+    .json(&serde_json::json!({ "factor_type": "totp" }))
+    .send()
+    .await
+    .expect("call enroll factor");
+  assert_eq!(response.status(), StatusCode::FORBIDDEN);
+  let body: serde_json::Value = response.json().await.expect("decode enroll error");
+  assert_eq!(body["error_code"].as_str(), Some("impersonation_forbidden"));
+
+  let (stored_email, has_password): (Option<String>, bool) =
+    sqlx::query_as("SELECT email, encrypted_password IS NOT NULL FROM auth.users WHERE id = $1")
+      .bind(user_id)
+      .fetch_one(&ctx.pool)
+      .await
+      .expect("fetch user");
+  assert_eq!(stored_email.as_deref(), Some(email.as_str()));
+  assert!(!has_password);
+  let factors: i64 = sqlx::query_scalar("SELECT count(*) FROM auth.mfa_factors WHERE user_id = $1")
+    .bind(user_id)
+    .fetch_one(&ctx.pool)
+    .await
+    .expect("count factors");
+  assert_eq!(factors, 0);
+
+  // Every request made with the token is audited, refused ones included.
+  let audited: Vec<(String, String)> = sqlx::query_as(
+    "SELECT payload->>'method', payload->>'path' FROM auth.audit_log_entries WHERE payload->>'event' = 'impersonated_request' AND payload->>'user_id' = $1 AND payload->>'admin_id' = $2 ORDER BY created_at",
+  )
+  .bind(user_id.to_string())
+  .bind(admin_id.to_string())
+  .fetch_all(&ctx.pool)
+  .await
+  .expect("fetch audit entries");
+  assert_eq!(
+    audited,
+    vec![
+      ("PUT".to_string(), "/user".to_string()),
+      ("PUT".to_string(), "/user".to_string()),
+      ("POST".to_string(), "/factors".to_string()),
+    ]
+  );
+
+  cleanup_user(&ctx.pool, user_id).await;
+}